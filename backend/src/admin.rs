@@ -0,0 +1,257 @@
+use crate::{group_call, stored_millis, stored_timestamp, AppState, ServerMessage, User};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, post},
+    Json, Router,
+};
+
+/// Operator-only routes, all guarded by the `ADMIN_TOKEN` bearer token
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/api/admin/users", get(list_online_users))
+        .route("/api/admin/users/:user_id/disconnect", post(disconnect_user))
+        .route("/api/admin/messages/:message_id", delete(delete_message))
+        .route("/api/admin/calls", get(recent_calls))
+        .route("/api/admin/reports", get(recent_reports))
+        .route("/api/debug/state", get(debug_state))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Reject requests without `Authorization: Bearer <ADMIN_TOKEN>`.
+/// Without a configured token the admin API is disabled entirely.
+async fn require_admin(State(state): State<AppState>, request: Request, next: Next) -> Result<Response, StatusCode> {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(next.run(request).await),
+        _ => {
+            tracing::warn!("Rejected admin request to {}", request.uri().path());
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Compare tokens without leaking how many leading bytes matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn list_online_users(State(state): State<AppState>) -> Json<Vec<User>> {
+    let users: Vec<User> = state
+        .online_users
+        .iter()
+        .map(|u| u.value().clone())
+        .collect();
+    Json(users)
+}
+
+/// Presence bookkeeping only, never message content
+#[derive(Serialize)]
+struct DebugState {
+    online_users: Vec<User>,
+    socket_count: usize,
+    connection_count: usize,
+    /// Users with a live socket but no presence entry; should always be empty
+    sockets_without_presence: Vec<String>,
+    /// Messages not queued because a client's outgoing queue was full
+    dropped_messages: u64,
+    /// Clients disconnected for reading too slowly
+    overflow_disconnects: u64,
+}
+
+async fn debug_state(State(state): State<AppState>) -> Json<DebugState> {
+    let online_users: Vec<User> = state
+        .online_users
+        .iter()
+        .map(|u| u.value().clone())
+        .collect();
+    let sockets_without_presence = state
+        .user_sockets
+        .iter()
+        .filter(|entry| !state.online_users.contains_key(entry.key()))
+        .map(|entry| entry.key().clone())
+        .collect();
+
+    Json(DebugState {
+        online_users,
+        socket_count: state.user_sockets.len(),
+        // Includes sockets that haven't logged in yet
+        connection_count: state.connections_per_ip.iter().map(|entry| *entry.value()).sum(),
+        sockets_without_presence,
+        dropped_messages: state.outbox_stats.dropped_messages.load(Ordering::Relaxed),
+        overflow_disconnects: state.outbox_stats.overflow_disconnects.load(Ordering::Relaxed),
+    })
+}
+
+/// Calls reported by `/api/admin/calls`
+const RECENT_CALLS_LIMIT: i32 = 100;
+
+/// A call from the history with the averages of the stats its participants reported
+#[derive(Serialize)]
+struct CallSummary {
+    id: String,
+    caller_id: String,
+    callee_id: String,
+    /// `audio`, `video` or `screen`
+    call_type: String,
+    /// `ringing`, `answered`, `ended`, `missed` or `rejected`
+    status: String,
+    started_at: DateTime<Utc>,
+    ended_at: Option<DateTime<Utc>>,
+    /// 1 (unusable) to 5 (excellent), set when the call ends
+    quality_score: Option<f64>,
+    stats_count: i64,
+    avg_bitrate_kbps: Option<f64>,
+    avg_packet_loss_percent: Option<f64>,
+    avg_round_trip_time_ms: Option<f64>,
+    avg_jitter_ms: Option<f64>,
+}
+
+/// Recent calls and their quality, for looking into bad-call complaints
+async fn recent_calls(State(state): State<AppState>) -> Result<Json<Vec<CallSummary>>, StatusCode> {
+    let calls = state.db.get_recent_calls(RECENT_CALLS_LIMIT).await.map_err(|e| {
+        tracing::error!("Failed to load calls: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(
+        calls
+            .into_iter()
+            .map(|c| CallSummary {
+                started_at: stored_timestamp(&c.started_at, "call started_at", &c.id),
+                ended_at: c.ended_at.as_deref().map(|at| stored_timestamp(at, "call ended_at", &c.id)),
+                id: c.id,
+                caller_id: c.caller_id,
+                callee_id: c.callee_id,
+                call_type: c.call_type,
+                status: c.status,
+                quality_score: c.quality_score,
+                stats_count: c.stats_count,
+                avg_bitrate_kbps: c.avg_bitrate_kbps,
+                avg_packet_loss_percent: c.avg_packet_loss_percent,
+                avg_round_trip_time_ms: c.avg_round_trip_time_ms,
+                avg_jitter_ms: c.avg_jitter_ms,
+            })
+            .collect(),
+    ))
+}
+
+/// Reports listed by `/api/admin/reports`
+const RECENT_REPORTS_LIMIT: i32 = 200;
+
+/// A user's report, with the message it is about
+#[derive(Serialize)]
+struct ReportSummary {
+    id: String,
+    reporter_id: String,
+    message_id: String,
+    reason: String,
+    created_at: DateTime<Utc>,
+    /// `None` once the message has been deleted
+    message: Option<ReportedMessage>,
+}
+
+#[derive(Serialize)]
+struct ReportedMessage {
+    from_user_id: String,
+    to_user_id: String,
+    /// Ciphertext when `encrypted`; the server can't read end-to-end encrypted messages
+    content: String,
+    encrypted: bool,
+    timestamp: DateTime<Utc>,
+}
+
+async fn recent_reports(State(state): State<AppState>) -> Result<Json<Vec<ReportSummary>>, StatusCode> {
+    let reports = state.db.get_recent_reports(RECENT_REPORTS_LIMIT).await.map_err(|e| {
+        tracing::error!("Failed to load reports: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(
+        reports
+            .into_iter()
+            .map(|r| {
+                let message = match (r.from_user_id, r.to_user_id, r.content, r.timestamp) {
+                    (Some(from_user_id), Some(to_user_id), Some(content), Some(timestamp)) => Some(ReportedMessage {
+                        from_user_id,
+                        to_user_id,
+                        content,
+                        encrypted: r.encrypted.unwrap_or(false),
+                        timestamp: stored_millis(timestamp, "message", &r.message_id),
+                    }),
+                    _ => None,
+                };
+                ReportSummary {
+                    created_at: stored_timestamp(&r.created_at, "report created_at", &r.id),
+                    id: r.id,
+                    reporter_id: r.reporter_id,
+                    message_id: r.message_id,
+                    reason: r.reason,
+                    message,
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// Close a user's socket. The send task forwards `Disconnected` and then ends
+/// the connection; presence cleanup happens here so peers are told right away.
+async fn disconnect_user(State(state): State<AppState>, Path(user_id): Path<String>) -> StatusCode {
+    let Some((_, user_tx)) = state.user_sockets.remove(&user_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    let _ = user_tx.send(ServerMessage::Disconnected {
+        reason: "Disconnected by an administrator".to_string(),
+    });
+    state.online_users.remove(&user_id);
+    group_call::leave_all(&state, &user_id);
+    let _ = state.db.update_last_seen(&user_id).await;
+
+    state
+        .broadcast_presence(&user_id, ServerMessage::UserOffline { user_id: user_id.clone() })
+        .await;
+
+    tracing::info!("Admin disconnected user {}", user_id);
+    StatusCode::NO_CONTENT
+}
+
+async fn delete_message(State(state): State<AppState>, Path(message_id): Path<String>) -> StatusCode {
+    let message = match state.db.get_message_by_id(&message_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return StatusCode::NOT_FOUND,
+        Err(e) => {
+            tracing::error!("Failed to load message {}: {:?}", message_id, e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+
+    if let Err(e) = state.db.delete_message(&message_id).await {
+        tracing::error!("Failed to delete message {}: {:?}", message_id, e);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    // Remove it from both participants' open chats
+    for participant in [&message.from_user_id, &message.to_user_id] {
+        state.send_event(participant, ServerMessage::MessageDeleted {
+            message_id: message_id.clone(),
+        });
+    }
+
+    tracing::info!("Admin deleted message {}", message_id);
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,140 @@
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+/// Marks a stored value as ciphertext; anything else is stored as sent
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+/// Length of the AES-GCM authentication tag appended to each ciphertext
+const TAG_LEN: usize = 16;
+
+/// Encrypts attachment data before it is written to the database, so a leaked
+/// database file doesn't expose users' files. Each value gets a fresh random
+/// nonce, stored with it as `enc:v1:<base64(nonce || ciphertext || tag)>`.
+pub struct AtRestCipher {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl AtRestCipher {
+    /// Build a cipher from a base64-encoded 32-byte AES-256 key
+    pub fn from_base64(key: &str) -> Result<Self, String> {
+        let bytes = STANDARD
+            .decode(key.trim())
+            .map_err(|_| "key is not valid base64".to_string())?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| format!("key must be 32 bytes, got {}", bytes.len()))?;
+
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, String> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce_bytes).map_err(|_| "failed to generate nonce".to_string())?;
+
+        let mut in_out = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce_bytes), Aad::empty(), &mut in_out)
+            .map_err(|_| "encryption failed".to_string())?;
+
+        let mut sealed = nonce_bytes.to_vec();
+        sealed.extend_from_slice(&in_out);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(sealed)))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<String, String> {
+        let Some(encoded) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(stored.to_string());
+        };
+
+        let sealed = STANDARD.decode(encoded).map_err(|_| "ciphertext is not valid base64".to_string())?;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err("ciphertext is truncated".to_string());
+        }
+        let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| "invalid nonce".to_string())?;
+
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| "decryption failed (wrong key or tampered data)".to_string())?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| "decrypted data is not UTF-8".to_string())
+    }
+}
+
+/// Whether a stored value was written encrypted
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Bytes a value of `plaintext_len` bytes takes up once encrypted and encoded
+pub fn encrypted_len(plaintext_len: usize) -> usize {
+    ENCRYPTED_PREFIX.len() + (NONCE_LEN + plaintext_len + TAG_LEN).div_ceil(3) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    const OTHER_KEY: &str = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+
+    #[test]
+    fn round_trip() {
+        let cipher = AtRestCipher::from_base64(KEY).unwrap();
+
+        let sealed = cipher.encrypt("aGVsbG8gd29ybGQ=").unwrap();
+
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("aGVsbG8gd29ybGQ="));
+        assert_eq!(sealed.len(), encrypted_len("aGVsbG8gd29ybGQ=".len()));
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), "aGVsbG8gd29ybGQ=");
+    }
+
+    #[test]
+    fn each_value_gets_its_own_nonce() {
+        let cipher = AtRestCipher::from_base64(KEY).unwrap();
+
+        assert_ne!(cipher.encrypt("same").unwrap(), cipher.encrypt("same").unwrap());
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let cipher = AtRestCipher::from_base64(KEY).unwrap();
+        let sealed = cipher.encrypt("secret file").unwrap();
+
+        let mut bytes = STANDARD.decode(&sealed[ENCRYPTED_PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(bytes));
+
+        assert!(cipher.decrypt(&tampered).is_err());
+        assert!(cipher.decrypt(&sealed[..sealed.len() - 8]).is_err());
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let sealed = AtRestCipher::from_base64(KEY).unwrap().encrypt("secret file").unwrap();
+
+        let other = AtRestCipher::from_base64(OTHER_KEY).unwrap();
+
+        assert!(other.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn plaintext_from_before_encryption_reads_as_is() {
+        let cipher = AtRestCipher::from_base64(KEY).unwrap();
+
+        assert_eq!(cipher.decrypt("aGVsbG8=").unwrap(), "aGVsbG8=");
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        assert!(AtRestCipher::from_base64("not base64!").is_err());
+        assert!(AtRestCipher::from_base64("c2hvcnQ=").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::moderation::FilterAction;
+use crate::redact::Secret;
+use std::str::FromStr;
+
+/// SQLite database used when `DATABASE_URL` isn't set, created on first start
+const DEFAULT_DATABASE_URL: &str = "sqlite:chat.db?mode=rwc";
+
+/// Frontend origin used for local development when no allow-list is configured
+const DEFAULT_ALLOWED_ORIGIN: &str = "https://localhost:3001";
+
+/// Attachment types accepted when `ALLOWED_FILE_TYPES` isn't set: common media and documents
+const DEFAULT_ALLOWED_FILE_TYPES: &str =
+    "image/png,image/jpeg,image/gif,image/webp,audio/*,video/mp4,video/webm,video/quicktime,application/pdf,text/plain";
+
+/// Room for the JSON envelope and text fields around a file's base64 payload
+const WS_MESSAGE_OVERHEAD_BYTES: usize = 64 * 1024;
+
+/// Runtime configuration, read from environment variables at startup
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `DEV_MODE`: relaxes security settings for local development
+    pub dev_mode: bool,
+    /// `DATABASE_URL`: SQLite database to use; `sqlite::memory:` keeps everything in memory
+    pub database_url: String,
+    /// `PORT`: port to listen on; `0` picks a free one
+    pub port: u16,
+    /// `DISABLE_TLS`: serve plain HTTP/WS, e.g. behind a TLS-terminating proxy or in tests
+    pub disable_tls: bool,
+    /// `CORS_ALLOWED_ORIGINS`: comma-separated list of origins allowed to call the API
+    pub cors_allowed_origins: Vec<String>,
+    /// `MAX_CONNECTIONS_PER_IP`: concurrent WebSocket connections allowed from one address
+    pub max_connections_per_ip: usize,
+    /// `DEFAULT_PAGE_SIZE`: history page size when a request doesn't give a `limit`
+    pub default_page_size: i32,
+    /// `MAX_PAGE_SIZE`: upper bound for the `limit` of message history requests
+    pub max_page_size: i32,
+    /// `ADMIN_TOKEN`: bearer token for the admin API; the API is disabled when unset
+    pub admin_token: Option<String>,
+    /// `BCRYPT_COST`: work factor for new password hashes; weaker stored hashes are upgraded at login
+    pub bcrypt_cost: u32,
+    /// `LOGIN_MAX_FAILURES`: failed logins per username before further attempts are refused
+    pub login_max_failures: usize,
+    /// `LOGIN_FAILURE_WINDOW_SECS`: how long failed logins count towards the lockout
+    pub login_failure_window_secs: u64,
+    /// `LINK_PREVIEWS`: fetch OpenGraph previews for links in unencrypted messages
+    pub link_previews: bool,
+    /// `MAX_FILE_SIZE`: largest file, in bytes, a single WebSocket message can carry
+    pub max_file_size: usize,
+    /// `HISTORY_MAX_BYTES`: approximate JSON size of one page of message history; older messages beyond it lose their file data or wait for the next page
+    pub history_max_bytes: usize,
+    /// `MAX_REACTIONS_PER_MESSAGE`: distinct users who may react to one message
+    pub max_reactions_per_message: usize,
+    /// `MESSAGE_RETENTION_DAYS`: delete messages older than this; unset or `0` keeps them forever
+    pub message_retention_days: Option<u32>,
+    /// `STORAGE_QUOTA_BYTES`: file data, as stored (base64), each user may keep in total
+    pub storage_quota_bytes: u64,
+    /// `CALL_RING_TIMEOUT_SECS`: how long an offered call rings before it counts as missed
+    pub call_ring_timeout_secs: u64,
+    /// `AUTH_TIMEOUT_SECS`: how long a new WebSocket connection has to log in or register
+    pub auth_timeout_secs: u64,
+    /// `OUTBOUND_QUEUE_CAPACITY`: messages queued for one client before it counts as too slow
+    pub outbound_queue_capacity: usize,
+    /// `PRESENCE_BATCH_MS`: online/offline changes within this window reach each client as one `PresenceUpdate`; `0` sends them one by one
+    pub presence_batch_ms: u64,
+    /// `RESUME_BUFFER_SIZE`: recent events kept per user for clients that `Resume` after reconnecting
+    pub resume_buffer_size: usize,
+    /// `RESUME_BUFFER_BYTES`: size limit, in bytes of JSON, of the events kept per user for `Resume`
+    pub resume_buffer_bytes: usize,
+    /// `RESUME_RETENTION_SECS`: how long a user's events are kept for `Resume` after they go offline
+    pub resume_retention_secs: u64,
+    /// `REACTION_RATE_LIMIT`: reaction changes one user may make per minute; more are dropped
+    pub reaction_rate_limit: usize,
+    /// `TYPING_RATE_LIMIT`: typing indicators one user may send per minute, after throttling; more are dropped
+    pub typing_rate_limit: usize,
+    /// `TYPING_THROTTLE_MS`: repeated typing indicators for one chat within this window are forwarded once
+    pub typing_throttle_ms: u64,
+    /// `CONTENT_FILTER_WORDLIST`: file of words (one per line) filtered from messages; unset disables filtering
+    pub content_filter_wordlist: Option<String>,
+    /// `CONTENT_FILTER_ACTION`: `reject` messages containing a filtered word, or `mask` the word
+    pub content_filter_action: FilterAction,
+    /// `ALLOWED_FILE_TYPES`: comma-separated MIME types files may have; `type/*` and `*` are wildcards
+    pub allowed_file_types: Vec<String>,
+    /// `ATTACHMENT_ENCRYPTION_KEY`: base64 AES-256 key; when set, file data is encrypted at rest
+    pub attachment_encryption_key: Option<Secret>,
+}
+
+impl Config {
+    /// Load configuration from the environment, falling back to defaults
+    pub fn from_env() -> Self {
+        let cors_allowed_origins = std::env::var("CORS_ALLOWED_ORIGINS")
+            .map(|value| parse_list(&value))
+            .unwrap_or_else(|_| vec![DEFAULT_ALLOWED_ORIGIN.to_string()]);
+        let max_page_size = env_or("MAX_PAGE_SIZE", 200).max(1);
+
+        Self {
+            dev_mode: env_flag("DEV_MODE"),
+            database_url: std::env::var("DATABASE_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string()),
+            port: env_or("PORT", 3002),
+            disable_tls: env_flag("DISABLE_TLS"),
+            cors_allowed_origins,
+            max_connections_per_ip: env_or("MAX_CONNECTIONS_PER_IP", 20),
+            max_page_size,
+            // A default above the maximum would just be clamped on every request
+            default_page_size: env_or("DEFAULT_PAGE_SIZE", 50).clamp(1, max_page_size),
+            admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            // bcrypt only accepts costs in 4..=31
+            bcrypt_cost: env_or("BCRYPT_COST", bcrypt::DEFAULT_COST).clamp(4, 31),
+            login_max_failures: env_or("LOGIN_MAX_FAILURES", 5),
+            login_failure_window_secs: env_or("LOGIN_FAILURE_WINDOW_SECS", 300),
+            link_previews: env_flag("LINK_PREVIEWS"),
+            max_file_size: env_or("MAX_FILE_SIZE", 5 * 1024 * 1024),
+            history_max_bytes: env_or("HISTORY_MAX_BYTES", 8 * 1024 * 1024),
+            max_reactions_per_message: env_or("MAX_REACTIONS_PER_MESSAGE", 100),
+            message_retention_days: Some(env_or("MESSAGE_RETENTION_DAYS", 0)).filter(|&days| days > 0),
+            storage_quota_bytes: env_or("STORAGE_QUOTA_BYTES", 500 * 1024 * 1024),
+            call_ring_timeout_secs: env_or("CALL_RING_TIMEOUT_SECS", 30).max(1),
+            auth_timeout_secs: env_or("AUTH_TIMEOUT_SECS", 30).max(1),
+            outbound_queue_capacity: env_or("OUTBOUND_QUEUE_CAPACITY", 1024).max(1),
+            presence_batch_ms: env_or("PRESENCE_BATCH_MS", 0),
+            resume_buffer_size: env_or("RESUME_BUFFER_SIZE", 256).max(1),
+            resume_buffer_bytes: env_or("RESUME_BUFFER_BYTES", 256 * 1024),
+            resume_retention_secs: env_or("RESUME_RETENTION_SECS", 600),
+            reaction_rate_limit: env_or("REACTION_RATE_LIMIT", 60).max(1),
+            typing_rate_limit: env_or("TYPING_RATE_LIMIT", 120).max(1),
+            typing_throttle_ms: env_or("TYPING_THROTTLE_MS", 1000),
+            content_filter_wordlist: std::env::var("CONTENT_FILTER_WORDLIST").ok().filter(|path| !path.is_empty()),
+            content_filter_action: env_or("CONTENT_FILTER_ACTION", FilterAction::Reject),
+            allowed_file_types: parse_list(
+                &std::env::var("ALLOWED_FILE_TYPES").unwrap_or_else(|_| DEFAULT_ALLOWED_FILE_TYPES.to_string()),
+            )
+            .into_iter()
+            .map(|file_type| file_type.to_ascii_lowercase())
+            .collect(),
+            attachment_encryption_key: std::env::var("ATTACHMENT_ENCRYPTION_KEY")
+                .ok()
+                .filter(|key| !key.is_empty())
+                .map(Secret::from),
+        }
+    }
+
+    /// Largest incoming WebSocket message/frame: one base64-encoded file of
+    /// `max_file_size` plus the surrounding JSON
+    pub fn max_ws_message_size(&self) -> usize {
+        self.max_file_size.div_ceil(3) * 4 + WS_MESSAGE_OVERHEAD_BYTES
+    }
+}
+
+/// Read a boolean flag; `1`, `true`, `yes` and `on` count as enabled
+fn env_flag(key: &str) -> bool {
+    std::env::var(key)
+        .map(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Read and parse a variable, using `default` when it is unset or invalid
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            tracing::warn!("Invalid value for {}: {:?}, using default", key, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Split a comma-separated list, dropping empty entries
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
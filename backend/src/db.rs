@@ -1,10 +1,77 @@
-use chrono::Utc;
-use sqlx::{sqlite::SqlitePool, FromRow, Row};
-use std::collections::HashMap;
+use crate::at_rest::{self, AtRestCipher};
+use crate::link_preview::LinkPreview;
+use crate::redact::FileData;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions, SqliteRow}, FromRow, Row, SqliteExecutor};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
 /// Database layer for persistent storage
 pub struct Database {
     pool: SqlitePool,
+    /// Set when attachment data is encrypted at rest (`ATTACHMENT_ENCRYPTION_KEY`)
+    cipher: Option<AtRestCipher>,
+}
+
+/// Tables whose rows belong to a message and go when it is deleted
+const MESSAGE_CHILD_TABLES: [&str; 5] = ["reactions", "attachments", "link_previews", "starred_messages", "mentions"];
+
+/// Most ids bound into one `IN (...)` query; older SQLite builds allow only 999 variables
+const MAX_IN_CLAUSE_IDS: usize = 500;
+
+/// Bytes of file data a user has stored, as kept in `users.storage_used`: the
+/// encoded files of every message they sent, not counting generated thumbnails.
+/// Correlated on `users.id`.
+const STORAGE_USED_SQL: &str = r#"
+    (SELECT COALESCE(SUM(LENGTH(file_data)), 0) FROM messages WHERE from_user_id = users.id)
+    + (SELECT COALESCE(SUM(LENGTH(a.file_data)), 0) FROM attachments a JOIN messages m ON m.id = a.message_id WHERE m.from_user_id = users.id)
+"#;
+
+/// SQL expression converting an RFC 3339 text column to epoch milliseconds (0 if unparseable)
+fn rfc3339_to_millis(column: &str) -> String {
+    format!("COALESCE(CAST(ROUND((julianday({}) - 2440587.5) * 86400000.0) AS INTEGER), 0)", column)
+}
+
+/// Fixed-width RFC 3339 timestamp for columns compared as strings in SQL
+/// (`scheduled_messages.send_at`, `messages.expires_at`, `muted_conversations.muted_until`)
+pub fn sortable_timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Key under which usernames must be unique (`users.username_lower`), so "Alice"
+/// and "alice" can't be two accounts
+fn normalize_username(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// Escape `%`, `_` and the escape character itself so user text matches
+/// literally in a `LIKE ... ESCAPE '\'` pattern
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Whether a write was refused because a row it references doesn't exist,
+/// e.g. a reaction to a message that was never stored or has been deleted
+pub fn is_foreign_key_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(e) if e.is_foreign_key_violation())
+}
+
+/// Whether a write was refused by a unique index, e.g. a second message with
+/// the same `client_msg_id` from one sender
+pub fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(e) if e.is_unique_violation())
+}
+
+/// Whether a SQLite URL names an in-memory database (`sqlite::memory:` or `mode=memory`)
+fn is_in_memory(database_url: &str) -> bool {
+    database_url.contains(":memory:") || database_url.contains("mode=memory")
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -12,8 +79,13 @@ pub struct DbUser {
     pub id: String,
     pub username: String,
     pub password_hash: String,
-    pub created_at: String,
-    pub last_seen: String,
+    /// Epoch milliseconds
+    pub last_seen: i64,
+    pub public_key: Option<String>,
+    /// Id of the current row in `avatars`, if the user has uploaded one
+    pub avatar: Option<String>,
+    /// Free-form name shown to other users; `username` stays the login handle
+    pub display_name: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -22,26 +94,129 @@ pub struct DbMessage {
     pub from_user_id: String,
     pub to_user_id: String,
     pub content: String,
-    pub timestamp: String,
+    /// Epoch milliseconds
+    pub timestamp: i64,
     pub read: bool,
-    pub file_data: Option<String>,
+    pub file_data: Option<FileData>,
     pub file_name: Option<String>,
     pub file_type: Option<String>,
     pub audio_duration: Option<f64>,
+    pub thumbnail_data: Option<FileData>,
+    pub encrypted: bool,
+    pub delivered: bool,
+    pub client_msg_id: Option<String>,
+    /// Set for disappearing messages, in `sortable_timestamp` format
+    pub expires_at: Option<String>,
+    /// Pixel size of an image attachment, when its header could be read
+    pub media_width: Option<u32>,
+    pub media_height: Option<u32>,
+    /// The sender's device, as the client identified it
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
+    /// Position in the conversation (1, 2, ...), assigned by `save_message`;
+    /// deleted messages leave gaps
+    pub seq: i64,
+    /// The message this one quotes and the quoted snippet, kept as sent
+    pub quoted_message_id: Option<String>,
+    pub quoted_text: Option<String>,
 }
 
 #[derive(Debug, Clone, FromRow)]
-pub struct DbReaction {
+pub struct DbScheduledMessage {
+    pub id: String,
+    pub from_user_id: String,
+    pub to_user_id: String,
+    pub content: String,
+    pub encrypted: bool,
+    pub send_at: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DbAttachment {
     pub message_id: String,
+    pub position: i32,
+    pub file_data: FileData,
+    pub file_name: String,
+    pub file_type: String,
+    pub thumbnail_data: Option<FileData>,
+    pub media_width: Option<u32>,
+    pub media_height: Option<u32>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct DbReaction {
     pub user_id: String,
     pub emoji: String,
 }
 
+#[derive(Debug, Clone, FromRow)]
+pub struct DbReactionDetail {
+    pub emoji: String,
+    pub user_id: String,
+    pub username: String,
+    pub display_name: Option<String>,
+}
+
+/// One call as the admin API reports it, with its stats averaged
+#[derive(Debug, Clone, FromRow)]
+pub struct DbCallSummary {
+    pub id: String,
+    pub caller_id: String,
+    pub callee_id: String,
+    pub call_type: String,
+    pub status: String,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub quality_score: Option<f64>,
+    pub stats_count: i64,
+    pub avg_bitrate_kbps: Option<f64>,
+    pub avg_packet_loss_percent: Option<f64>,
+    pub avg_round_trip_time_ms: Option<f64>,
+    pub avg_jitter_ms: Option<f64>,
+}
+
+/// A report as the admin API lists it, with the reported message if it still exists
+#[derive(Debug, Clone, FromRow)]
+pub struct DbReport {
+    pub id: String,
+    pub reporter_id: String,
+    pub message_id: String,
+    pub reason: String,
+    pub created_at: String,
+    pub from_user_id: Option<String>,
+    pub to_user_id: Option<String>,
+    pub content: Option<String>,
+    pub encrypted: Option<bool>,
+    pub timestamp: Option<i64>,
+}
+
+/// A WebRTC stats sample reported by one side of a call
+#[derive(Debug, Clone)]
+pub struct DbCallStats {
+    pub bitrate_kbps: Option<f64>,
+    pub packet_loss_percent: Option<f64>,
+    pub round_trip_time_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+}
+
 impl Database {
     /// Create a new database connection and initialize schema
-    pub async fn new(database_url: &str) -> Result<Self, sqlx::Error> {
-        let pool = SqlitePool::connect(database_url).await?;
-        let db = Self { pool };
+    pub async fn new(database_url: &str, cipher: Option<AtRestCipher>) -> Result<Self, sqlx::Error> {
+        // SQLite only checks the schema's foreign keys on connections that ask for it
+        let options = SqliteConnectOptions::from_str(database_url)?.foreign_keys(true);
+        let pool = if is_in_memory(database_url) {
+            // An in-memory database lives only as long as a connection to it, so
+            // keep one open for good instead of letting the pool close idle ones
+            SqlitePoolOptions::new()
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options)
+                .await?
+        } else {
+            SqlitePool::connect_with(options).await?
+        };
+        let db = Self { pool, cipher };
         db.init_schema().await?;
         Ok(db)
     }
@@ -55,8 +230,29 @@ impl Database {
                 id TEXT PRIMARY KEY,
                 username TEXT UNIQUE NOT NULL,
                 password_hash TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                last_seen TEXT NOT NULL
+                created_at INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                public_key TEXT,
+                avatar TEXT,
+                display_name TEXT,
+                storage_used INTEGER NOT NULL DEFAULT 0,
+                send_read_receipts INTEGER NOT NULL DEFAULT 1,
+                username_lower TEXT
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create avatars table (processed images; `users.avatar` points at the current one)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS avatars (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                data BLOB NOT NULL,
+                file_type TEXT NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES users(id)
             )
             "#,
         )
@@ -71,12 +267,25 @@ impl Database {
                 from_user_id TEXT NOT NULL,
                 to_user_id TEXT NOT NULL,
                 content TEXT NOT NULL,
-                timestamp TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
                 read INTEGER NOT NULL DEFAULT 0,
                 file_data TEXT,
                 file_name TEXT,
                 file_type TEXT,
                 audio_duration REAL,
+                thumbnail_data TEXT,
+                encrypted INTEGER NOT NULL DEFAULT 0,
+                delivered INTEGER NOT NULL DEFAULT 0,
+                client_msg_id TEXT,
+                hidden_for_sender INTEGER NOT NULL DEFAULT 0,
+                hidden_for_recipient INTEGER NOT NULL DEFAULT 0,
+                expires_at TEXT,
+                media_width INTEGER,
+                media_height INTEGER,
+                device_id TEXT,
+                device_name TEXT,
+                quoted_message_id TEXT,
+                quoted_text TEXT,
                 FOREIGN KEY (from_user_id) REFERENCES users(id),
                 FOREIGN KEY (to_user_id) REFERENCES users(id)
             )
@@ -85,6 +294,86 @@ impl Database {
         .execute(&self.pool)
         .await?;
 
+        // Columns added after the initial schema
+        self.ensure_column("messages", "thumbnail_data", "TEXT").await?;
+        self.ensure_column("messages", "encrypted", "INTEGER NOT NULL DEFAULT 0").await?;
+        if self.ensure_column("messages", "delivered", "INTEGER NOT NULL DEFAULT 0").await? {
+            // Messages stored before delivery tracking existed were already handed out
+            sqlx::query("UPDATE messages SET delivered = 1")
+                .execute(&self.pool)
+                .await?;
+        }
+        self.ensure_column("messages", "client_msg_id", "TEXT").await?;
+        self.ensure_column("messages", "hidden_for_sender", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.ensure_column("messages", "hidden_for_recipient", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.ensure_column("messages", "expires_at", "TEXT").await?;
+        self.ensure_column("messages", "media_width", "INTEGER").await?;
+        self.ensure_column("messages", "media_height", "INTEGER").await?;
+        self.ensure_column("messages", "device_id", "TEXT").await?;
+        self.ensure_column("messages", "device_name", "TEXT").await?;
+        self.ensure_column("messages", "quoted_message_id", "TEXT").await?;
+        self.ensure_column("messages", "quoted_text", "TEXT").await?;
+        self.ensure_column("users", "public_key", "TEXT").await?;
+        self.ensure_column("users", "avatar", "TEXT").await?;
+        self.ensure_column("users", "display_name", "TEXT").await?;
+        let backfill_storage_used = self.ensure_column("users", "storage_used", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.ensure_column("users", "send_read_receipts", "INTEGER NOT NULL DEFAULT 1").await?;
+        self.ensure_column("users", "username_lower", "TEXT").await?;
+        self.migrate_timestamps_to_millis().await?;
+
+        // Last `seq` handed out in each conversation (keyed by the two user ids in
+        // order), so numbers of deleted messages are never reused
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS conversation_seqs (
+                user_low TEXT NOT NULL,
+                user_high TEXT NOT NULL,
+                last_seq INTEGER NOT NULL,
+                PRIMARY KEY (user_low, user_high)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.ensure_column("messages", "seq", "INTEGER").await?;
+        // Checked separately, so an upgrade interrupted after adding the column still numbers them
+        let unnumbered: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM messages WHERE seq IS NULL)")
+            .fetch_one(&self.pool)
+            .await?;
+        if unnumbered {
+            // Number existing messages in the order they were sent, in one pass, and
+            // seed the counters in the same transaction so they never lag behind
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(
+                r#"
+                UPDATE messages SET seq = numbered.rn
+                FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY MIN(from_user_id, to_user_id), MAX(from_user_id, to_user_id)
+                        ORDER BY timestamp, id
+                    ) AS rn
+                    FROM messages
+                ) AS numbered
+                WHERE numbered.id = messages.id
+                "#,
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                r#"
+                INSERT OR REPLACE INTO conversation_seqs (user_low, user_high, last_seq)
+                SELECT MIN(from_user_id, to_user_id), MAX(from_user_id, to_user_id), MAX(seq)
+                FROM messages
+                GROUP BY 1, 2
+                "#,
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        self.enforce_unique_usernames().await?;
+
         // Create reactions table
         sqlx::query(
             r#"
@@ -101,6 +390,289 @@ impl Database {
         .execute(&self.pool)
         .await?;
 
+        // Create attachments table (messages may carry several files)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS attachments (
+                message_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                file_data TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                file_type TEXT NOT NULL,
+                thumbnail_data TEXT,
+                media_width INTEGER,
+                media_height INTEGER,
+                PRIMARY KEY (message_id, position),
+                FOREIGN KEY (message_id) REFERENCES messages(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.ensure_column("attachments", "media_width", "INTEGER").await?;
+        self.ensure_column("attachments", "media_height", "INTEGER").await?;
+
+        // Create link previews table (one preview per message, for its first link)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS link_previews (
+                message_id TEXT PRIMARY KEY,
+                url TEXT NOT NULL,
+                title TEXT,
+                description TEXT,
+                image TEXT,
+                FOREIGN KEY (message_id) REFERENCES messages(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create mentions table (users a message @mentions, resolved when it was sent)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS mentions (
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                PRIMARY KEY (message_id, user_id),
+                FOREIGN KEY (message_id) REFERENCES messages(id),
+                FOREIGN KEY (user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create scheduled messages table (moved into `messages` once `send_at` passes)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS scheduled_messages (
+                id TEXT PRIMARY KEY,
+                from_user_id TEXT NOT NULL,
+                to_user_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                encrypted INTEGER NOT NULL DEFAULT 0,
+                send_at TEXT NOT NULL,
+                FOREIGN KEY (from_user_id) REFERENCES users(id),
+                FOREIGN KEY (to_user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create archived conversations table (per user; the other side is unaffected)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS archived_conversations (
+                user_id TEXT NOT NULL,
+                other_user_id TEXT NOT NULL,
+                archived_at TEXT NOT NULL,
+                PRIMARY KEY (user_id, other_user_id),
+                FOREIGN KEY (user_id) REFERENCES users(id),
+                FOREIGN KEY (other_user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create drafts table (unsent text per conversation, so it follows the user across devices)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS drafts (
+                user_id TEXT NOT NULL,
+                other_user_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (user_id, other_user_id),
+                FOREIGN KEY (user_id) REFERENCES users(id),
+                FOREIGN KEY (other_user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create muted conversations table (per user; NULL muted_until = muted indefinitely)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS muted_conversations (
+                user_id TEXT NOT NULL,
+                other_user_id TEXT NOT NULL,
+                muted_until TEXT,
+                PRIMARY KEY (user_id, other_user_id),
+                FOREIGN KEY (user_id) REFERENCES users(id),
+                FOREIGN KEY (other_user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create notification preferences table (per user and conversation; no row = notify for everything)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS notification_prefs (
+                user_id TEXT NOT NULL,
+                other_user_id TEXT NOT NULL,
+                level TEXT NOT NULL,
+                PRIMARY KEY (user_id, other_user_id),
+                FOREIGN KEY (user_id) REFERENCES users(id),
+                FOREIGN KEY (other_user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create contacts table (one-directional: the owner's address book)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS contacts (
+                owner_id TEXT NOT NULL,
+                contact_id TEXT NOT NULL,
+                added_at TEXT NOT NULL,
+                PRIMARY KEY (owner_id, contact_id),
+                FOREIGN KEY (owner_id) REFERENCES users(id),
+                FOREIGN KEY (contact_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create calls table (call history; timestamps in `sortable_timestamp` format)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS calls (
+                id TEXT PRIMARY KEY,
+                caller_id TEXT NOT NULL,
+                callee_id TEXT NOT NULL,
+                call_type TEXT NOT NULL DEFAULT 'video',
+                status TEXT NOT NULL DEFAULT 'ringing',
+                started_at TEXT NOT NULL,
+                ended_at TEXT,
+                quality_score REAL,
+                FOREIGN KEY (caller_id) REFERENCES users(id),
+                FOREIGN KEY (callee_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.ensure_column("calls", "call_type", "TEXT NOT NULL DEFAULT 'video'").await?;
+        if self.ensure_column("calls", "status", "TEXT NOT NULL DEFAULT 'ringing'").await? {
+            sqlx::query("UPDATE calls SET status = 'ended' WHERE ended_at IS NOT NULL")
+                .execute(&self.pool)
+                .await?;
+        }
+
+        // Create starred messages table (each user's saved messages; `starred_at`
+        // in `sortable_timestamp` format)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS starred_messages (
+                user_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                starred_at TEXT NOT NULL,
+                PRIMARY KEY (user_id, message_id),
+                FOREIGN KEY (user_id) REFERENCES users(id),
+                FOREIGN KEY (message_id) REFERENCES messages(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create read markers table: how far each user has read each conversation,
+        // as the `seq` of the last message read (and its time, in epoch millis).
+        // Older databases only have the per-message `read` flags, so markers start
+        // out where those left off.
+        let backfill_read_markers = !self.table_exists("read_markers").await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS read_markers (
+                user_id TEXT NOT NULL,
+                other_user_id TEXT NOT NULL,
+                last_read_ts INTEGER NOT NULL,
+                last_read_seq INTEGER,
+                PRIMARY KEY (user_id, other_user_id),
+                FOREIGN KEY (user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if backfill_read_markers {
+            sqlx::query(
+                r#"
+                INSERT INTO read_markers (user_id, other_user_id, last_read_ts)
+                SELECT to_user_id, from_user_id, MAX(timestamp)
+                FROM messages
+                WHERE read = 1
+                GROUP BY to_user_id, from_user_id
+                "#,
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+
+        // Markers used to be timestamps alone; they now cover every message received
+        // up to that time. Checked by value, so an interrupted upgrade still fills them in.
+        self.ensure_column("read_markers", "last_read_seq", "INTEGER").await?;
+        sqlx::query(
+            r#"
+            UPDATE read_markers SET last_read_seq = COALESCE((
+                SELECT MAX(seq) FROM messages
+                WHERE to_user_id = read_markers.user_id AND from_user_id = read_markers.other_user_id
+                  AND timestamp <= read_markers.last_read_ts
+            ), 0)
+            WHERE last_read_seq IS NULL
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create reports table (messages users flagged for moderation). Reports
+        // outlive the message, so `message_id` isn't a foreign key.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS reports (
+                id TEXT PRIMARY KEY,
+                reporter_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE (reporter_id, message_id),
+                FOREIGN KEY (reporter_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create call stats table (samples clients report during a call)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS call_stats (
+                call_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                reported_at TEXT NOT NULL,
+                bitrate_kbps REAL,
+                packet_loss_percent REAL,
+                round_trip_time_ms REAL,
+                jitter_ms REAL,
+                FOREIGN KEY (call_id) REFERENCES calls(id),
+                FOREIGN KEY (user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
         // Create indexes for better query performance
         sqlx::query(
             r#"
@@ -126,300 +698,2279 @@ impl Database {
         .execute(&self.pool)
         .await?;
 
-        tracing::info!("Database schema initialized");
-        Ok(())
-    }
+        // Incoming messages by read state, for unread counts and `MarkConversationRead`
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_messages_to_read ON messages(to_user_id, read)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
 
-    // ============ USER OPERATIONS ============
+        // One direction of a conversation in time order, for history pages and
+        // per-conversation unread counts. A conversation is looked up as two
+        // (from, to) pairs, so this one index covers both directions.
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages(from_user_id, to_user_id, timestamp)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
 
-    /// Create a new user
-    pub async fn create_user(
-        &self,
-        id: &str,
-        username: &str,
-        password_hash: &str,
-    ) -> Result<DbUser, sqlx::Error> {
-        let now = Utc::now().to_rfc3339();
-        
+        // The same by `seq`, for catching up from the last message a client has
         sqlx::query(
             r#"
-            INSERT INTO users (id, username, password_hash, created_at, last_seen)
-            VALUES (?, ?, ?, ?, ?)
+            CREATE INDEX IF NOT EXISTS idx_messages_conversation_seq ON messages(from_user_id, to_user_id, seq)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_scheduled_messages_send_at ON scheduled_messages(send_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_messages_expires_at ON messages(expires_at) WHERE expires_at IS NOT NULL
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_contacts_contact ON contacts(contact_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_call_stats_call ON call_stats(call_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_calls_started_at ON calls(started_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_reports_created_at ON reports(created_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_starred_messages_user ON starred_messages(user_id, starred_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Idempotency keys are unique per sender; NULLs (no key) never collide
+        sqlx::query(
+            r#"
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_client_key ON messages(from_user_id, client_msg_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Counted once the tables it sums up all exist
+        if backfill_storage_used {
+            sqlx::query(&format!("UPDATE users SET storage_used = {}", STORAGE_USED_SQL))
+                .execute(&self.pool)
+                .await?;
+        }
+
+        tracing::info!("Database schema initialized");
+        Ok(())
+    }
+
+    /// Fill in `username_lower` for accounts created before it existed, then make
+    /// usernames unique regardless of case. Of existing accounts whose names differ
+    /// only by case, the oldest keeps its name and the others get a numeric suffix
+    /// ("Alice" -> "Alice-2"), so the unique index can always be added.
+    async fn enforce_unique_usernames(&self) -> Result<(), sqlx::Error> {
+        let unnormalized: Vec<(String, String)> =
+            sqlx::query_as("SELECT id, username FROM users WHERE username_lower IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+        if !unnormalized.is_empty() {
+            let mut tx = self.pool.begin().await?;
+            for (id, username) in &unnormalized {
+                sqlx::query("UPDATE users SET username_lower = ? WHERE id = ?")
+                    .bind(normalize_username(username))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+
+        // Oldest account first within each clash; the id breaks ties
+        let colliding: Vec<(String, String, String)> = sqlx::query_as(
+            r#"
+            SELECT id, username, username_lower FROM users
+            WHERE username_lower IN (SELECT username_lower FROM users GROUP BY username_lower HAVING COUNT(*) > 1)
+            ORDER BY username_lower, created_at, id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        if !colliding.is_empty() {
+            let mut tx = self.pool.begin().await?;
+            let mut previous_lower: Option<&str> = None;
+            for (id, username, username_lower) in &colliding {
+                if previous_lower != Some(username_lower.as_str()) {
+                    previous_lower = Some(username_lower);
+                    continue;
+                }
+
+                let mut suffix = 2;
+                let renamed = loop {
+                    let candidate = format!("{}-{}", username, suffix);
+                    let taken: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE username_lower = ?)")
+                        .bind(normalize_username(&candidate))
+                        .fetch_one(&mut *tx)
+                        .await?;
+                    if !taken {
+                        break candidate;
+                    }
+                    suffix += 1;
+                };
+
+                tracing::warn!("Renaming user {} from {:?} to {:?}: the name differs only by case from an older account", id, username, renamed);
+                sqlx::query("UPDATE users SET username = ?, username_lower = ? WHERE id = ?")
+                    .bind(&renamed)
+                    .bind(normalize_username(&renamed))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_users_username_lower ON users(username_lower)")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Add a column to an existing table if it is missing, returning whether it was added.
+    /// `CREATE TABLE IF NOT EXISTS` leaves tables from older versions untouched.
+    async fn ensure_column(&self, table: &str, column: &str, definition: &str) -> Result<bool, sqlx::Error> {
+        let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.pool)
+            .await?;
+
+        let exists = columns
+            .iter()
+            .any(|row| row.get::<String, _>("name") == column);
+
+        if !exists {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await?;
+            tracing::info!("Migrated schema: added {}.{}", table, column);
+        }
+
+        Ok(!exists)
+    }
+
+    /// Databases created before timestamps were stored as epoch milliseconds keep
+    /// RFC 3339 text in `users.created_at`/`last_seen` and `messages.timestamp`.
+    /// SQLite can't change a column's type, so those tables are rebuilt with
+    /// INTEGER columns and the values converted. Unparseable values are logged and become 0.
+    async fn migrate_timestamps_to_millis(&self) -> Result<(), sqlx::Error> {
+        let migrate_users = self.column_has_type("users", "last_seen", "TEXT").await?;
+        let migrate_messages = self.column_has_type("messages", "timestamp", "TEXT").await?;
+        if !migrate_users && !migrate_messages {
+            return Ok(());
+        }
+
+        let mut columns = Vec::new();
+        if migrate_users {
+            columns.extend([("users", "created_at"), ("users", "last_seen")]);
+        }
+        if migrate_messages {
+            columns.push(("messages", "timestamp"));
+        }
+        for (table, column) in columns {
+            for id in self.unparseable_timestamps(table, column).await? {
+                tracing::warn!("Unparseable {}.{} on {}; migrating it as the Unix epoch", table, column, id);
+            }
+        }
+
+        // Foreign keys have to be off while the referenced tables are swapped out,
+        // and the pragma only applies outside a transaction, on this connection
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await?;
+
+        let result = async {
+            let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+            if migrate_users {
+                sqlx::query(
+                    r#"
+                    CREATE TABLE users_new (
+                        id TEXT PRIMARY KEY,
+                        username TEXT UNIQUE NOT NULL,
+                        password_hash TEXT NOT NULL,
+                        created_at INTEGER NOT NULL,
+                        last_seen INTEGER NOT NULL,
+                        public_key TEXT,
+                        avatar TEXT,
+                        display_name TEXT,
+                        storage_used INTEGER NOT NULL DEFAULT 0,
+                        send_read_receipts INTEGER NOT NULL DEFAULT 1,
+                        username_lower TEXT
+                    )
+                    "#,
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query(&format!(
+                    r#"
+                    INSERT INTO users_new (id, username, password_hash, created_at, last_seen, public_key, avatar, display_name, storage_used, send_read_receipts, username_lower)
+                    SELECT id, username, password_hash, {}, {}, public_key, avatar, display_name, storage_used, send_read_receipts, username_lower
+                    FROM users
+                    "#,
+                    rfc3339_to_millis("created_at"),
+                    rfc3339_to_millis("last_seen"),
+                ))
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query("DROP TABLE users").execute(&mut *tx).await?;
+                sqlx::query("ALTER TABLE users_new RENAME TO users").execute(&mut *tx).await?;
+                tracing::info!("Migrated schema: users timestamps to epoch milliseconds");
+            }
+
+            if migrate_messages {
+                sqlx::query(
+                    r#"
+                    CREATE TABLE messages_new (
+                        id TEXT PRIMARY KEY,
+                        from_user_id TEXT NOT NULL,
+                        to_user_id TEXT NOT NULL,
+                        content TEXT NOT NULL,
+                        timestamp INTEGER NOT NULL,
+                        read INTEGER NOT NULL DEFAULT 0,
+                        file_data TEXT,
+                        file_name TEXT,
+                        file_type TEXT,
+                        audio_duration REAL,
+                        thumbnail_data TEXT,
+                        encrypted INTEGER NOT NULL DEFAULT 0,
+                        delivered INTEGER NOT NULL DEFAULT 0,
+                        client_msg_id TEXT,
+                        hidden_for_sender INTEGER NOT NULL DEFAULT 0,
+                        hidden_for_recipient INTEGER NOT NULL DEFAULT 0,
+                        expires_at TEXT,
+                        media_width INTEGER,
+                        media_height INTEGER,
+                        device_id TEXT,
+                        device_name TEXT,
+                        quoted_message_id TEXT,
+                        quoted_text TEXT,
+                        FOREIGN KEY (from_user_id) REFERENCES users(id),
+                        FOREIGN KEY (to_user_id) REFERENCES users(id)
+                    )
+                    "#,
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query(&format!(
+                    r#"
+                    INSERT INTO messages_new (id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, hidden_for_sender, hidden_for_recipient, expires_at, media_width, media_height, device_id, device_name, quoted_message_id, quoted_text)
+                    SELECT id, from_user_id, to_user_id, content, {}, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, hidden_for_sender, hidden_for_recipient, expires_at, media_width, media_height, device_id, device_name, quoted_message_id, quoted_text
+                    FROM messages
+                    "#,
+                    rfc3339_to_millis("timestamp"),
+                ))
+                .execute(&mut *tx)
+                .await?;
+
+                // Its indexes go with it; init_schema recreates them afterwards
+                sqlx::query("DROP TABLE messages").execute(&mut *tx).await?;
+                sqlx::query("ALTER TABLE messages_new RENAME TO messages").execute(&mut *tx).await?;
+                tracing::info!("Migrated schema: messages timestamps to epoch milliseconds");
+            }
+
+            tx.commit().await
+        }
+        .await;
+
+        sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await?;
+        result
+    }
+
+    /// Ids of rows whose text timestamp in `table.column` SQLite can't parse
+    async fn unparseable_timestamps(&self, table: &str, column: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(&format!("SELECT id FROM {} WHERE julianday({}) IS NULL ORDER BY id", table, column))
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn table_exists(&self, table: &str) -> Result<bool, sqlx::Error> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(table)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count > 0)
+    }
+
+    async fn column_has_type(&self, table: &str, column: &str, column_type: &str) -> Result<bool, sqlx::Error> {
+        let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(columns.iter().any(|row| {
+            row.get::<String, _>("name") == column && row.get::<String, _>("type").eq_ignore_ascii_case(column_type)
+        }))
+    }
+
+    /// Prepare file data for storage, encrypting it when a key is configured
+    fn seal_file_data(&self, data: &str) -> Result<String, sqlx::Error> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(data).map_err(sqlx::Error::Protocol),
+            None => Ok(data.to_string()),
+        }
+    }
+
+    /// Read stored file data back. Values that can't be decrypted (wrong or missing
+    /// key, corruption) are logged and dropped rather than failing the whole read.
+    fn open_file_data(&self, stored: String) -> Option<FileData> {
+        if !at_rest::is_encrypted(&stored) {
+            return Some(FileData::from(stored));
+        }
+
+        let result = match &self.cipher {
+            Some(cipher) => cipher.decrypt(&stored),
+            None => Err("no ATTACHMENT_ENCRYPTION_KEY configured".to_string()),
+        };
+        match result {
+            Ok(data) => Some(FileData::from(data)),
+            Err(e) => {
+                tracing::error!("Failed to decrypt stored file data: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Bytes `len` bytes of file data take up once stored, for the storage quota
+    pub fn stored_file_size(&self, len: usize) -> usize {
+        match self.cipher {
+            Some(_) => at_rest::encrypted_len(len),
+            None => len,
+        }
+    }
+
+    /// Map a `messages` row to a `DbMessage`
+    fn row_to_message(&self, row: &SqliteRow) -> DbMessage {
+        DbMessage {
+            id: row.get("id"),
+            from_user_id: row.get("from_user_id"),
+            to_user_id: row.get("to_user_id"),
+            content: row.get("content"),
+            timestamp: row.get("timestamp"),
+            read: row.get::<i32, _>("read") != 0,
+            file_data: row.get::<Option<String>, _>("file_data").and_then(|data| self.open_file_data(data)),
+            file_name: row.get("file_name"),
+            file_type: row.get("file_type"),
+            audio_duration: row.get("audio_duration"),
+            thumbnail_data: row.get::<Option<String>, _>("thumbnail_data").and_then(|data| self.open_file_data(data)),
+            encrypted: row.get::<i32, _>("encrypted") != 0,
+            delivered: row.get::<i32, _>("delivered") != 0,
+            client_msg_id: row.get("client_msg_id"),
+            expires_at: row.get("expires_at"),
+            media_width: row.get("media_width"),
+            media_height: row.get("media_height"),
+            device_id: row.get("device_id"),
+            device_name: row.get("device_name"),
+            seq: row.get::<Option<i64>, _>("seq").unwrap_or_default(),
+            quoted_message_id: row.get("quoted_message_id"),
+            quoted_text: row.get("quoted_text"),
+        }
+    }
+
+    // ============ USER OPERATIONS ============
+
+    /// Create a new user
+    pub async fn create_user(
+        &self,
+        id: &str,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<DbUser, sqlx::Error> {
+        let now = Utc::now().timestamp_millis();
+        
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username, username_lower, password_hash, created_at, last_seen)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(id)
+        .bind(username)
+        .bind(normalize_username(username))
+        .bind(password_hash)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(DbUser {
+            id: id.to_string(),
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+            last_seen: now,
+            public_key: None,
+            avatar: None,
+            display_name: None,
+        })
+    }
+
+    /// Get user by username, ignoring case. Should legacy accounts differ only by
+    /// case, the exact spelling wins.
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<DbUser>, sqlx::Error> {
+        let user = sqlx::query_as::<_, DbUser>(
+            r#"
+            SELECT id, username, password_hash, last_seen, public_key, avatar, display_name
+            FROM users
+            WHERE username_lower = ?
+            ORDER BY username = ? DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(normalize_username(username))
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Get user by ID
+    pub async fn get_user_by_id(&self, id: &str) -> Result<Option<DbUser>, sqlx::Error> {
+        let user = sqlx::query_as::<_, DbUser>(
+            r#"
+            SELECT id, username, password_hash, last_seen, public_key, avatar, display_name
+            FROM users
+            WHERE id = ?
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Get all users
+    pub async fn get_all_users(&self) -> Result<Vec<DbUser>, sqlx::Error> {
+        let users = sqlx::query_as::<_, DbUser>(
+            r#"
+            SELECT id, username, password_hash, last_seen, public_key, avatar, display_name
+            FROM users
+            ORDER BY username
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    /// Users with the given ids; unknown ids are skipped
+    pub async fn get_users_by_ids(&self, ids: &[String]) -> Result<Vec<DbUser>, sqlx::Error> {
+        let mut users = Vec::with_capacity(ids.len());
+
+        for chunk in ids.chunks(MAX_IN_CLAUSE_IDS) {
+            let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+            let query = format!(
+                "SELECT id, username, password_hash, last_seen, public_key, avatar, display_name FROM users WHERE id IN ({})",
+                placeholders.join(",")
+            );
+
+            let mut query_builder = sqlx::query_as::<_, DbUser>(&query);
+            for id in chunk {
+                query_builder = query_builder.bind(id);
+            }
+
+            users.extend(query_builder.fetch_all(&self.pool).await?);
+        }
+
+        Ok(users)
+    }
+
+    /// Update user's last seen timestamp
+    pub async fn update_last_seen(&self, user_id: &str) -> Result<(), sqlx::Error> {
+        let now = Utc::now().timestamp_millis();
+        
+        sqlx::query(
+            r#"
+            UPDATE users SET last_seen = ? WHERE id = ?
+            "#,
+        )
+        .bind(now)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replace a user's password hash (e.g. after upgrading its bcrypt cost)
+    pub async fn update_password_hash(&self, user_id: &str, password_hash: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET password_hash = ? WHERE id = ?
+            "#,
+        )
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Store the public key a user's clients use for end-to-end encryption
+    pub async fn set_public_key(&self, user_id: &str, public_key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET public_key = ? WHERE id = ?
+            "#,
+        )
+        .bind(public_key)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Set or (with `None`) clear a user's display name
+    pub async fn set_display_name(&self, user_id: &str, display_name: Option<&str>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET display_name = ? WHERE id = ?
+            "#,
+        )
+        .bind(display_name)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Choose whether the user's reads are reported to senders
+    pub async fn set_send_read_receipts(&self, user_id: &str, enabled: bool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET send_read_receipts = ? WHERE id = ?
+            "#,
+        )
+        .bind(enabled)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether the user's reads are reported to senders (the default)
+    pub async fn sends_read_receipts(&self, user_id: &str) -> Result<bool, sqlx::Error> {
+        let enabled: Option<bool> = sqlx::query_scalar("SELECT send_read_receipts FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(enabled.unwrap_or(true))
+    }
+
+    /// Replace a user's avatar. Returns the new avatar id.
+    pub async fn set_avatar(&self, user_id: &str, data: &[u8], file_type: &str) -> Result<String, sqlx::Error> {
+        let avatar_id = uuid::Uuid::new_v4().to_string();
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM avatars WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("INSERT INTO avatars (id, user_id, data, file_type) VALUES (?, ?, ?, ?)")
+            .bind(&avatar_id)
+            .bind(user_id)
+            .bind(data)
+            .bind(file_type)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("UPDATE users SET avatar = ? WHERE id = ?")
+            .bind(&avatar_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(avatar_id)
+    }
+
+    /// Get a user's current avatar image and its MIME type
+    pub async fn get_avatar(&self, user_id: &str) -> Result<Option<(Vec<u8>, String)>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT a.data, a.file_type
+            FROM users u
+            INNER JOIN avatars a ON a.id = u.avatar
+            WHERE u.id = ?
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| (row.get("data"), row.get("file_type"))))
+    }
+
+    // ============ MESSAGE OPERATIONS ============
+
+    /// Save a message and its attachments, all or nothing, and return its `seq`
+    pub async fn save_message(&self, message: &DbMessage, attachments: &[DbAttachment]) -> Result<i64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let seq = self.insert_message(&mut tx, message).await?;
+        self.insert_attachments(&mut tx, attachments).await?;
+        tx.commit().await?;
+
+        Ok(seq)
+    }
+
+    /// Insert a message as the next in its conversation and return its `seq`.
+    /// Run it in a transaction, so a failed insert doesn't use up a number.
+    async fn insert_message(&self, conn: &mut SqliteConnection, message: &DbMessage) -> Result<i64, sqlx::Error> {
+        let file_data = message.file_data.as_deref().map(|data| self.seal_file_data(data)).transpose()?;
+        let thumbnail_data = message.thumbnail_data.as_deref().map(|data| self.seal_file_data(data)).transpose()?;
+
+        let seq: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO conversation_seqs (user_low, user_high, last_seq)
+            VALUES (MIN(?1, ?2), MAX(?1, ?2), 1)
+            ON CONFLICT (user_low, user_high) DO UPDATE SET last_seq = last_seq + 1
+            RETURNING last_seq
+            "#,
+        )
+        .bind(&message.from_user_id)
+        .bind(&message.to_user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO messages (id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&message.id)
+        .bind(&message.from_user_id)
+        .bind(&message.to_user_id)
+        .bind(&message.content)
+        .bind(message.timestamp)
+        .bind(message.read as i32)
+        .bind(file_data)
+        .bind(&message.file_name)
+        .bind(&message.file_type)
+        .bind(message.audio_duration)
+        .bind(thumbnail_data)
+        .bind(message.encrypted as i32)
+        .bind(message.delivered as i32)
+        .bind(&message.client_msg_id)
+        .bind(&message.expires_at)
+        .bind(message.media_width)
+        .bind(message.media_height)
+        .bind(&message.device_id)
+        .bind(&message.device_name)
+        .bind(seq)
+        .bind(&message.quoted_message_id)
+        .bind(&message.quoted_text)
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(seq)
+    }
+
+    /// Get messages between two users with pagination, as seen by `user1_id`
+    /// (messages they cleared for themselves and expired ones are left out).
+    /// Each direction is a range of `idx_messages_conversation`.
+    pub async fn get_messages_between_users(
+        &self,
+        user1_id: &str,
+        user2_id: &str,
+        limit: i32,
+        offset: i32,
+    ) -> Result<Vec<DbMessage>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE ((from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?))
+              AND NOT ((from_user_id = ? AND hidden_for_sender = 1) OR (to_user_id = ? AND hidden_for_recipient = 1))
+              AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY timestamp DESC, id DESC
+            LIMIT ? OFFSET ?
+            "#,
+        )
+        .bind(user1_id)
+        .bind(user2_id)
+        .bind(user2_id)
+        .bind(user1_id)
+        .bind(user1_id)
+        .bind(user1_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let messages: Vec<DbMessage> = rows.iter().map(|row| self.row_to_message(row)).collect();
+
+        Ok(messages)
+    }
+
+    /// Messages between two users numbered after `after_seq`, in `seq` order,
+    /// as seen by `user1_id`; at most `limit` of them
+    pub async fn get_messages_after(
+        &self,
+        user1_id: &str,
+        user2_id: &str,
+        after_seq: i64,
+        limit: i32,
+    ) -> Result<Vec<DbMessage>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE ((from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?))
+              AND seq > ?
+              AND NOT ((from_user_id = ? AND hidden_for_sender = 1) OR (to_user_id = ? AND hidden_for_recipient = 1))
+              AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY seq ASC
+            LIMIT ?
+            "#,
+        )
+        .bind(user1_id)
+        .bind(user2_id)
+        .bind(user2_id)
+        .bind(user1_id)
+        .bind(after_seq)
+        .bind(user1_id)
+        .bind(user1_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(|row| self.row_to_message(row)).collect())
+    }
+
+    /// Get all messages for a user (for loading conversation list). The list
+    /// only shows previews, so file data and thumbnails aren't loaded.
+    pub async fn get_user_conversations(&self, user_id: &str) -> Result<Vec<DbMessage>, sqlx::Error> {
+        // Get the latest message from each conversation; `id` breaks timestamp
+        // ties, so there is exactly one per conversation
+        let rows = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM (
+                SELECT id, from_user_id, to_user_id, content, timestamp, read, NULL AS file_data, file_name, file_type, audio_duration, NULL AS thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY CASE WHEN from_user_id = ? THEN to_user_id ELSE from_user_id END
+                        ORDER BY timestamp DESC, id DESC
+                    ) AS position
+                FROM messages
+                WHERE (from_user_id = ? OR to_user_id = ?)
+                  AND NOT ((from_user_id = ? AND hidden_for_sender = 1) OR (to_user_id = ? AND hidden_for_recipient = 1))
+                  AND (expires_at IS NULL OR expires_at > ?)
+            )
+            WHERE position = 1
+            ORDER BY timestamp DESC, id DESC
+            "#,
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let messages: Vec<DbMessage> = rows.iter().map(|row| self.row_to_message(row)).collect();
+
+        Ok(messages)
+    }
+
+    /// Get a single message by id
+    pub async fn get_message_by_id(&self, message_id: &str) -> Result<Option<DbMessage>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE id = ?
+            "#,
+        )
+        .bind(message_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(|row| self.row_to_message(row)))
+    }
+
+    /// A single message as `user_id` sees it: `None` unless they are a participant
+    /// and the message is neither cleared on their side nor expired
+    pub async fn get_message_for_user(&self, message_id: &str, user_id: &str) -> Result<Option<DbMessage>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE id = ?
+              AND (from_user_id = ? OR to_user_id = ?)
+              AND NOT ((from_user_id = ? AND hidden_for_sender = 1) OR (to_user_id = ? AND hidden_for_recipient = 1))
+              AND (expires_at IS NULL OR expires_at > ?)
+            "#,
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(|row| self.row_to_message(row)))
+    }
+
+    /// Find a message previously sent by `from_user_id` with the given idempotency key
+    pub async fn get_message_by_client_key(&self, from_user_id: &str, client_msg_id: &str) -> Result<Option<DbMessage>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE from_user_id = ? AND client_msg_id = ?
+            "#,
+        )
+        .bind(from_user_id)
+        .bind(client_msg_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(|row| self.row_to_message(row)))
+    }
+
+    /// Delete a message together with its reactions, attachments and link preview
+    pub async fn delete_message(&self, message_id: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let sender_id: Option<String> = sqlx::query_scalar("SELECT from_user_id FROM messages WHERE id = ?")
+            .bind(message_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        for table in MESSAGE_CHILD_TABLES {
+            sqlx::query(&format!("DELETE FROM {} WHERE message_id = ?", table))
+                .bind(message_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM messages WHERE id = ?")
+            .bind(message_id)
+            .execute(&mut *tx)
+            .await?;
+
+        if let Some(sender_id) = sender_id {
+            Self::recompute_storage_used(&mut *tx, &sender_id).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Permanently delete every message between two users, along with their reactions,
+    /// attachments and link previews. Returns the number of messages removed.
+    pub async fn delete_conversation(&self, user1_id: &str, user2_id: &str) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        for table in MESSAGE_CHILD_TABLES {
+            sqlx::query(&format!(
+                r#"
+                DELETE FROM {} WHERE message_id IN (
+                    SELECT id FROM messages
+                    WHERE (from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?)
+                )
+                "#,
+                table
+            ))
+            .bind(user1_id)
+            .bind(user2_id)
+            .bind(user2_id)
+            .bind(user1_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let result = sqlx::query(
+            r#"
+            DELETE FROM messages
+            WHERE (from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?)
+            "#,
+        )
+        .bind(user1_id)
+        .bind(user2_id)
+        .bind(user2_id)
+        .bind(user1_id)
+        .execute(&mut *tx)
+        .await?;
+
+        Self::recompute_storage_used(&mut *tx, user1_id).await?;
+        Self::recompute_storage_used(&mut *tx, user2_id).await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Permanently delete the messages `from_user_id` sent to `to_user_id`, along with
+    /// their reactions, attachments and link previews; the other side's messages stay.
+    /// Returns the ids of the deleted messages.
+    pub async fn delete_sent_messages(&self, from_user_id: &str, to_user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM messages WHERE from_user_id = ? AND to_user_id = ?")
+            .bind(from_user_id)
+            .bind(to_user_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        for table in MESSAGE_CHILD_TABLES {
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE message_id IN (SELECT id FROM messages WHERE from_user_id = ? AND to_user_id = ?)",
+                table
+            ))
+            .bind(from_user_id)
+            .bind(to_user_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query("DELETE FROM messages WHERE from_user_id = ? AND to_user_id = ?")
+            .bind(from_user_id)
+            .bind(to_user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        Self::recompute_storage_used(&mut *tx, from_user_id).await?;
+
+        tx.commit().await?;
+
+        Ok(ids)
+    }
+
+    /// Delete the oldest messages sent before `cutoff` (epoch milliseconds), at most
+    /// `MAX_IN_CLAUSE_IDS` at a time, along with their reactions, attachments and link
+    /// previews. Returns the number of messages removed; call again until it returns 0.
+    pub async fn purge_messages_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT id, from_user_id FROM messages WHERE timestamp < ? ORDER BY timestamp LIMIT ?")
+            .bind(cutoff)
+            .bind(MAX_IN_CLAUSE_IDS as i64)
+            .fetch_all(&mut *tx)
+            .await?;
+        let (ids, sender_ids): (Vec<String>, HashSet<String>) = rows.into_iter().unzip();
+
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let placeholders = vec!["?"; ids.len()].join(",");
+        let tables = MESSAGE_CHILD_TABLES.map(|table| (table, "message_id")).into_iter().chain([("messages", "id")]);
+        for (table, column) in tables {
+            let query = format!("DELETE FROM {} WHERE {} IN ({})", table, column, placeholders);
+            let mut query_builder = sqlx::query(&query);
+            for id in &ids {
+                query_builder = query_builder.bind(id);
+            }
+            query_builder.execute(&mut *tx).await?;
+        }
+
+        for sender_id in &sender_ids {
+            Self::recompute_storage_used(&mut *tx, sender_id).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(ids.len() as u64)
+    }
+
+    /// Hide every message between two users from `user_id` only; the other side keeps them
+    pub async fn hide_conversation(&self, user_id: &str, other_user_id: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            UPDATE messages SET hidden_for_sender = 1 WHERE from_user_id = ? AND to_user_id = ?
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE messages SET hidden_for_recipient = 1 WHERE from_user_id = ? AND to_user_id = ?
+            "#,
+        )
+        .bind(other_user_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Disappearing messages whose `expires_at` is at or before `now` (a `sortable_timestamp`)
+    pub async fn get_expired_messages(&self, now: &str) -> Result<Vec<DbMessage>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE expires_at IS NOT NULL AND expires_at <= ?
+            "#,
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let messages: Vec<DbMessage> = rows.iter().map(|row| self.row_to_message(row)).collect();
+
+        Ok(messages)
+    }
+
+    /// Get up to `limit` messages addressed to a user that never reached one of
+    /// their sockets, oldest first
+    pub async fn get_undelivered_messages(&self, user_id: &str, limit: i64) -> Result<Vec<DbMessage>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE to_user_id = ? AND delivered = 0
+              AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY timestamp ASC, id ASC
+            LIMIT ?
+            "#,
+        )
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let messages: Vec<DbMessage> = rows.iter().map(|row| self.row_to_message(row)).collect();
+
+        Ok(messages)
+    }
+
+    /// Mark messages as delivered to their recipient
+    pub async fn mark_messages_delivered(&self, message_ids: &[String]) -> Result<(), sqlx::Error> {
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+
+        for chunk in message_ids.chunks(MAX_IN_CLAUSE_IDS) {
+            let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+            let query = format!(
+                "UPDATE messages SET delivered = 1 WHERE id IN ({})",
+                placeholders.join(",")
+            );
+
+            let mut query_builder = sqlx::query(&query);
+            for id in chunk {
+                query_builder = query_builder.bind(id);
+            }
+
+            query_builder.execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark a message as read
+    pub async fn mark_message_read(&self, message_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE messages SET read = 1 WHERE id = ?
+            "#,
+        )
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark every unread message from `from_user_id` to `user_id` as read.
+    /// Returns the number of messages that were updated.
+    pub async fn mark_conversation_read(&self, user_id: &str, from_user_id: &str) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE messages SET read = 1
+            WHERE to_user_id = ? AND from_user_id = ? AND read = 0
+            "#,
+        )
+        .bind(user_id)
+        .bind(from_user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Move `user_id`'s read marker for the conversation with `other_user_id` up to
+    /// the message numbered `last_read_seq`, read at `last_read_ts` (epoch millis).
+    /// Markers only move forward; returns false if it was already at or past that point.
+    pub async fn advance_read_marker(
+        &self,
+        user_id: &str,
+        other_user_id: &str,
+        last_read_seq: i64,
+        last_read_ts: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO read_markers (user_id, other_user_id, last_read_ts, last_read_seq)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (user_id, other_user_id) DO UPDATE SET
+                last_read_seq = excluded.last_read_seq,
+                last_read_ts = MAX(read_markers.last_read_ts, excluded.last_read_ts)
+            WHERE excluded.last_read_seq > COALESCE(read_markers.last_read_seq, 0)
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .bind(last_read_ts)
+        .bind(last_read_seq)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The `seq` of the latest message between two users, 0 before the first
+    pub async fn get_conversation_seq(&self, user_a: &str, user_b: &str) -> Result<i64, sqlx::Error> {
+        let seq: Option<i64> = sqlx::query_scalar(
+            "SELECT last_seq FROM conversation_seqs WHERE user_low = MIN(?1, ?2) AND user_high = MAX(?1, ?2)",
+        )
+        .bind(user_a)
+        .bind(user_b)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(seq.unwrap_or(0))
+    }
+
+    /// The `seq` of the latest message `user_id` got from `from_user_id` at or
+    /// before `until` (epoch millis), 0 if there is none
+    pub async fn get_last_seq_received_by(&self, user_id: &str, from_user_id: &str, until: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(MAX(seq), 0) FROM messages WHERE to_user_id = ? AND from_user_id = ? AND timestamp <= ?",
+        )
+        .bind(user_id)
+        .bind(from_user_id)
+        .bind(until)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Read markers (the `seq` of the last message read) for several `(user_id, other_user_id)`
+    /// conversations at once (batch load); conversations nobody has read yet are left out
+    pub async fn get_read_markers_batch(&self, conversations: &[(String, String)]) -> Result<HashMap<(String, String), i64>, sqlx::Error> {
+        let mut markers = HashMap::new();
+
+        // Two bound variables per conversation
+        for chunk in conversations.chunks(MAX_IN_CLAUSE_IDS / 2) {
+            let placeholders = vec!["(?, ?)"; chunk.len()].join(",");
+            let query = format!(
+                "SELECT user_id, other_user_id, COALESCE(last_read_seq, 0) FROM read_markers WHERE (user_id, other_user_id) IN (VALUES {})",
+                placeholders
+            );
+
+            let mut query_builder = sqlx::query_as::<_, (String, String, i64)>(&query);
+            for (user_id, other_user_id) in chunk {
+                query_builder = query_builder.bind(user_id).bind(other_user_id);
+            }
+
+            for (user_id, other_user_id, last_read_seq) in query_builder.fetch_all(&self.pool).await? {
+                markers.insert((user_id, other_user_id), last_read_seq);
+            }
+        }
+
+        Ok(markers)
+    }
+
+    /// Unread messages across all of `user_id`'s conversations: the sum of
+    /// `get_unread_counts`, in one query
+    pub async fn get_total_unread_count(&self, user_id: &str) -> Result<i32, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT COUNT(*) as count
+            FROM messages m
+            LEFT JOIN read_markers r ON r.user_id = m.to_user_id AND r.other_user_id = m.from_user_id
+            WHERE m.to_user_id = ? AND m.from_user_id != m.to_user_id
+              AND m.seq > COALESCE(r.last_read_seq, 0)
+              AND m.hidden_for_recipient = 0
+              AND (m.expires_at IS NULL OR m.expires_at > ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.get::<i32, _>("count"))
+    }
+
+    /// Unread messages per sender: those newer than `user_id`'s read marker for
+    /// the conversation, ignoring messages they cleared and ones that expired.
+    /// Notes to self are never unread; senders with nothing unread are left out.
+    pub async fn get_unread_counts(&self, user_id: &str) -> Result<HashMap<String, i32>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT m.from_user_id, COUNT(*) as count
+            FROM messages m
+            LEFT JOIN read_markers r ON r.user_id = m.to_user_id AND r.other_user_id = m.from_user_id
+            WHERE m.to_user_id = ? AND m.from_user_id != m.to_user_id
+              AND m.seq > COALESCE(r.last_read_seq, 0)
+              AND m.hidden_for_recipient = 0
+              AND (m.expires_at IS NULL OR m.expires_at > ?)
+            GROUP BY m.from_user_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("from_user_id"), row.get("count")))
+            .collect())
+    }
+
+    /// Oldest message from `from_user_id` past `user_id`'s read marker, ignoring
+    /// messages they cleared and ones that expired
+    pub async fn get_first_unread_message_id(&self, user_id: &str, from_user_id: &str) -> Result<Option<String>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT id
+            FROM messages
+            WHERE to_user_id = ? AND from_user_id = ? AND to_user_id != from_user_id
+              AND seq > COALESCE((SELECT last_read_seq FROM read_markers WHERE user_id = ? AND other_user_id = ?), 0)
+              AND hidden_for_recipient = 0
+              AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY seq ASC
+            LIMIT 1
+            "#,
+        )
+        .bind(user_id)
+        .bind(from_user_id)
+        .bind(user_id)
+        .bind(from_user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| row.get("id")))
+    }
+
+    // ============ CONTACT OPERATIONS ============
+
+    /// Add `contact_id` to `owner_id`'s contacts (no-op if already there)
+    pub async fn add_contact(&self, owner_id: &str, contact_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO contacts (owner_id, contact_id, added_at)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(owner_id)
+        .bind(contact_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_contact(&self, owner_id: &str, contact_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM contacts WHERE owner_id = ? AND contact_id = ?")
+            .bind(owner_id)
+            .bind(contact_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A user's contacts with the time each was added, ordered by username
+    pub async fn get_contacts(&self, owner_id: &str) -> Result<Vec<(DbUser, String)>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT u.id, u.username, u.password_hash, u.last_seen, u.public_key, u.avatar, u.display_name, c.added_at
+            FROM contacts c
+            INNER JOIN users u ON u.id = c.contact_id
+            WHERE c.owner_id = ?
+            ORDER BY u.username
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| Ok((DbUser::from_row(row)?, row.get("added_at"))))
+            .collect()
+    }
+
+    /// Ids of the users `owner_id` has added as contacts
+    pub async fn get_contact_ids(&self, owner_id: &str) -> Result<HashSet<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT contact_id FROM contacts WHERE owner_id = ?")
+            .bind(owner_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("contact_id")).collect())
+    }
+
+    /// Users who have `contact_id` in their contacts
+    pub async fn get_contact_owner_ids(&self, contact_id: &str) -> Result<HashSet<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT owner_id FROM contacts WHERE contact_id = ?")
+            .bind(contact_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("owner_id")).collect())
+    }
+
+    /// Users who have added at least one contact
+    pub async fn get_users_with_contacts(&self) -> Result<HashSet<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT DISTINCT owner_id FROM contacts")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("owner_id")).collect())
+    }
+
+    // ============ ARCHIVE OPERATIONS ============
+
+    /// Archive a conversation for `user_id` only
+    pub async fn archive_conversation(&self, user_id: &str, other_user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO archived_conversations (user_id, other_user_id, archived_at)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Unarchive a conversation for `user_id`. Returns false if it wasn't archived.
+    pub async fn unarchive_conversation(&self, user_id: &str, other_user_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM archived_conversations WHERE user_id = ? AND other_user_id = ?")
+            .bind(user_id)
+            .bind(other_user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Ids of the users whose conversations `user_id` has archived
+    pub async fn get_archived_user_ids(&self, user_id: &str) -> Result<HashSet<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT other_user_id FROM archived_conversations WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("other_user_id")).collect())
+    }
+
+    // ============ MUTE OPERATIONS ============
+
+    /// Mute a conversation for `user_id` until `muted_until` (a `sortable_timestamp`),
+    /// or indefinitely. Replaces any earlier mute.
+    pub async fn mute_conversation(&self, user_id: &str, other_user_id: &str, muted_until: Option<&str>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO muted_conversations (user_id, other_user_id, muted_until)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .bind(muted_until)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn unmute_conversation(&self, user_id: &str, other_user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM muted_conversations WHERE user_id = ? AND other_user_id = ?")
+            .bind(user_id)
+            .bind(other_user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Conversations `user_id` has muted as of `now`, keyed by the other participant,
+    /// with when each mute ends. Timed mutes that have run out are left out.
+    pub async fn get_muted_conversations(&self, user_id: &str, now: &str) -> Result<HashMap<String, Option<String>>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT other_user_id, muted_until FROM muted_conversations
+            WHERE user_id = ? AND (muted_until IS NULL OR muted_until > ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("other_user_id"), row.get("muted_until")))
+            .collect())
+    }
+
+    pub async fn is_conversation_muted(&self, user_id: &str, other_user_id: &str, now: &str) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1 FROM muted_conversations
+            WHERE user_id = ? AND other_user_id = ? AND (muted_until IS NULL OR muted_until > ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Set how much the conversation with `other_user_id` may notify `user_id`
+    pub async fn set_notification_level(&self, user_id: &str, other_user_id: &str, level: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO notification_prefs (user_id, other_user_id, level) VALUES (?, ?, ?)")
+            .bind(user_id)
+            .bind(other_user_id)
+            .bind(level)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Go back to the default level for a conversation
+    pub async fn clear_notification_level(&self, user_id: &str, other_user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM notification_prefs WHERE user_id = ? AND other_user_id = ?")
+            .bind(user_id)
+            .bind(other_user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Levels `user_id` set for their conversations, keyed by the other participant
+    pub async fn get_notification_levels(&self, user_id: &str) -> Result<HashMap<String, String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT other_user_id, level FROM notification_prefs WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("other_user_id"), row.get("level")))
+            .collect())
+    }
+
+    pub async fn get_notification_level(&self, user_id: &str, other_user_id: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT level FROM notification_prefs WHERE user_id = ? AND other_user_id = ?")
+            .bind(user_id)
+            .bind(other_user_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Remove timed mutes that ended by `now`, returning `(user_id, other_user_id)` of each
+    pub async fn take_expired_mutes(&self, now: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            DELETE FROM muted_conversations
+            WHERE muted_until IS NOT NULL AND muted_until <= ?
+            RETURNING user_id, other_user_id
+            "#,
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("user_id"), row.get("other_user_id")))
+            .collect())
+    }
+
+    // ============ DRAFT OPERATIONS ============
+
+    /// Save (or replace) the draft `user_id` is writing to `other_user_id`
+    pub async fn save_draft(&self, user_id: &str, other_user_id: &str, content: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO drafts (user_id, other_user_id, content, updated_at)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .bind(content)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_draft(&self, user_id: &str, other_user_id: &str) -> Result<Option<String>, sqlx::Error> {
+        let row = sqlx::query("SELECT content FROM drafts WHERE user_id = ? AND other_user_id = ?")
+            .bind(user_id)
+            .bind(other_user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|row| row.get("content")))
+    }
+
+    /// All of a user's drafts, keyed by the other participant
+    pub async fn get_drafts(&self, user_id: &str) -> Result<HashMap<String, String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT other_user_id, content FROM drafts WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("other_user_id"), row.get("content")))
+            .collect())
+    }
+
+    pub async fn delete_draft(&self, user_id: &str, other_user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM drafts WHERE user_id = ? AND other_user_id = ?")
+            .bind(user_id)
+            .bind(other_user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // ============ SCHEDULED MESSAGE OPERATIONS ============
+
+    /// Store a message for later delivery
+    pub async fn save_scheduled_message(&self, message: &DbScheduledMessage) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_messages (id, from_user_id, to_user_id, content, encrypted, send_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&message.id)
+        .bind(&message.from_user_id)
+        .bind(&message.to_user_id)
+        .bind(&message.content)
+        .bind(message.encrypted as i32)
+        .bind(&message.send_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Cancel a pending scheduled message. Only its sender may cancel it;
+    /// returns false if there was nothing to cancel.
+    pub async fn cancel_scheduled_message(&self, id: &str, from_user_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM scheduled_messages WHERE id = ? AND from_user_id = ?")
+            .bind(id)
+            .bind(from_user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Scheduled messages whose `send_at` is at or before `now` (a `sortable_timestamp`), oldest first
+    pub async fn get_due_scheduled_messages(&self, now: &str) -> Result<Vec<DbScheduledMessage>, sqlx::Error> {
+        sqlx::query_as::<_, DbScheduledMessage>(
+            r#"
+            SELECT id, from_user_id, to_user_id, content, encrypted, send_at
+            FROM scheduled_messages
+            WHERE send_at <= ?
+            ORDER BY send_at ASC
+            "#,
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Atomically replace scheduled message `scheduled_id` with `message` and return its `seq`.
+    /// Returns `None` if it was cancelled in the meantime (nothing is inserted then).
+    pub async fn promote_scheduled_message(&self, scheduled_id: &str, message: &DbMessage) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let removed = sqlx::query("DELETE FROM scheduled_messages WHERE id = ?")
+            .bind(scheduled_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        if removed == 0 {
+            return Ok(None);
+        }
+
+        let seq = self.insert_message(&mut tx, message).await?;
+        tx.commit().await?;
+
+        Ok(Some(seq))
+    }
+
+    // ============ ATTACHMENT OPERATIONS ============
+
+    /// Insert the attachments of a message, as part of saving it
+    async fn insert_attachments(&self, conn: &mut SqliteConnection, attachments: &[DbAttachment]) -> Result<(), sqlx::Error> {
+        for attachment in attachments {
+            sqlx::query(
+                r#"
+                INSERT INTO attachments (message_id, position, file_data, file_name, file_type, thumbnail_data, media_width, media_height)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&attachment.message_id)
+            .bind(attachment.position)
+            .bind(self.seal_file_data(&attachment.file_data)?)
+            .bind(&attachment.file_name)
+            .bind(&attachment.file_type)
+            .bind(attachment.thumbnail_data.as_deref().map(|data| self.seal_file_data(data)).transpose()?)
+            .bind(attachment.media_width)
+            .bind(attachment.media_height)
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get attachments for multiple messages (batch load), each list in upload order
+    pub async fn get_attachments_batch(&self, message_ids: &[String]) -> Result<HashMap<String, Vec<DbAttachment>>, sqlx::Error> {
+        if message_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        // Each message's attachments fall in one chunk, so they stay in order
+        let mut rows = Vec::with_capacity(message_ids.len());
+        for chunk in message_ids.chunks(MAX_IN_CLAUSE_IDS) {
+            let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+            let query = format!(
+                "SELECT message_id, position, file_data, file_name, file_type, thumbnail_data, media_width, media_height FROM attachments WHERE message_id IN ({}) ORDER BY position",
+                placeholders.join(",")
+            );
+
+            let mut query_builder = sqlx::query(&query);
+            for id in chunk {
+                query_builder = query_builder.bind(id);
+            }
+
+            rows.extend(query_builder.fetch_all(&self.pool).await?);
+        }
+
+        let mut attachments_map: HashMap<String, Vec<DbAttachment>> = HashMap::new();
+        for row in rows {
+            // An attachment whose data can't be read back is left out
+            let Some(file_data) = self.open_file_data(row.get("file_data")) else {
+                continue;
+            };
+            let attachment = DbAttachment {
+                message_id: row.get("message_id"),
+                position: row.get("position"),
+                file_data,
+                file_name: row.get("file_name"),
+                file_type: row.get("file_type"),
+                thumbnail_data: row.get::<Option<String>, _>("thumbnail_data").and_then(|data| self.open_file_data(data)),
+                media_width: row.get("media_width"),
+                media_height: row.get("media_height"),
+            };
+
+            attachments_map
+                .entry(attachment.message_id.clone())
+                .or_default()
+                .push(attachment);
+        }
+
+        Ok(attachments_map)
+    }
+
+    // ============ STORAGE OPERATIONS ============
+
+    /// Count `bytes` of new file data against a user's storage, unless that would take
+    /// them past `quota`. Returns whether the bytes were accepted.
+    pub async fn reserve_storage(&self, user_id: &str, bytes: u64, quota: u64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE users SET storage_used = storage_used + ? WHERE id = ? AND storage_used + ? <= ?")
+            .bind(bytes as i64)
+            .bind(user_id)
+            .bind(bytes as i64)
+            .bind(quota as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Bytes of file data a user currently has stored
+    pub async fn get_storage_used(&self, user_id: &str) -> Result<u64, sqlx::Error> {
+        let used: Option<i64> = sqlx::query_scalar("SELECT storage_used FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(used.unwrap_or(0).max(0) as u64)
+    }
+
+    /// Recount a user's storage from the messages they have stored, e.g. after a
+    /// reservation whose message was never saved or after messages were deleted
+    pub async fn refresh_storage_used(&self, user_id: &str) -> Result<(), sqlx::Error> {
+        Self::recompute_storage_used(&self.pool, user_id).await
+    }
+
+    async fn recompute_storage_used<'e, E: SqliteExecutor<'e>>(executor: E, user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!("UPDATE users SET storage_used = {} WHERE id = ?", STORAGE_USED_SQL))
+            .bind(user_id)
+            .execute(executor)
+            .await?;
+
+        Ok(())
+    }
+
+    // ============ LINK PREVIEW OPERATIONS ============
+
+    /// Save (or replace) the link preview of a message
+    pub async fn save_link_preview(&self, message_id: &str, preview: &LinkPreview) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO link_previews (message_id, url, title, description, image)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(message_id)
+        .bind(&preview.url)
+        .bind(&preview.title)
+        .bind(&preview.description)
+        .bind(&preview.image)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get link previews for multiple messages (batch load)
+    pub async fn get_link_previews_batch(&self, message_ids: &[String]) -> Result<HashMap<String, LinkPreview>, sqlx::Error> {
+        if message_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut rows = Vec::with_capacity(message_ids.len());
+        for chunk in message_ids.chunks(MAX_IN_CLAUSE_IDS) {
+            let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+            let query = format!(
+                "SELECT message_id, url, title, description, image FROM link_previews WHERE message_id IN ({})",
+                placeholders.join(",")
+            );
+
+            let mut query_builder = sqlx::query(&query);
+            for id in chunk {
+                query_builder = query_builder.bind(id);
+            }
+
+            rows.extend(query_builder.fetch_all(&self.pool).await?);
+        }
+
+        let previews_map = rows
+            .into_iter()
+            .map(|row| {
+                let preview = LinkPreview {
+                    url: row.get("url"),
+                    title: row.get("title"),
+                    description: row.get("description"),
+                    image: row.get("image"),
+                };
+                (row.get("message_id"), preview)
+            })
+            .collect();
+
+        Ok(previews_map)
+    }
+
+    // ============ MENTION OPERATIONS ============
+
+    /// Ids of the users with these (lowercased) usernames; unknown names are left out
+    pub async fn get_user_ids_by_usernames(&self, usernames: &[String]) -> Result<Vec<String>, sqlx::Error> {
+        if usernames.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders = vec!["?"; usernames.len()].join(",");
+        let query = format!("SELECT id FROM users WHERE username_lower IN ({})", placeholders);
+
+        let mut query_builder = sqlx::query_scalar::<_, String>(&query);
+        for username in usernames {
+            query_builder = query_builder.bind(username);
+        }
+
+        query_builder.fetch_all(&self.pool).await
+    }
+
+    /// Record the users a message mentions
+    pub async fn save_mentions(&self, message_id: &str, user_ids: &[String]) -> Result<(), sqlx::Error> {
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin().await?;
+        for user_id in user_ids {
+            sqlx::query("INSERT OR IGNORE INTO mentions (message_id, user_id) VALUES (?, ?)")
+                .bind(message_id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get mentioned user ids for multiple messages (batch load)
+    pub async fn get_mentions_batch(&self, message_ids: &[String]) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        if message_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut mentions_map: HashMap<String, Vec<String>> = HashMap::new();
+        for chunk in message_ids.chunks(MAX_IN_CLAUSE_IDS) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            let query = format!("SELECT message_id, user_id FROM mentions WHERE message_id IN ({})", placeholders);
+
+            let mut query_builder = sqlx::query_as::<_, (String, String)>(&query);
+            for id in chunk {
+                query_builder = query_builder.bind(id);
+            }
+
+            for (message_id, user_id) in query_builder.fetch_all(&self.pool).await? {
+                mentions_map.entry(message_id).or_default().push(user_id);
+            }
+        }
+
+        Ok(mentions_map)
+    }
+
+    // ============ CALL OPERATIONS ============
+
+    /// Record a call being placed. Returns false if `call_id` is already taken,
+    /// e.g. by a renegotiation offer for a call that is already recorded.
+    pub async fn start_call(&self, call_id: &str, caller_id: &str, callee_id: &str, call_type: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO calls (id, caller_id, callee_id, call_type, started_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(call_id)
+        .bind(caller_id)
+        .bind(callee_id)
+        .bind(call_type)
+        .bind(sortable_timestamp(Utc::now()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store a stats sample from a participant of `call_id`. Returns false if the
+    /// user isn't part of the call or already reported `max_samples` samples for it.
+    pub async fn add_call_stats(&self, call_id: &str, user_id: &str, stats: &DbCallStats, max_samples: usize) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO call_stats (call_id, user_id, reported_at, bitrate_kbps, packet_loss_percent, round_trip_time_ms, jitter_ms)
+            SELECT ?, ?, ?, ?, ?, ?, ?
+            WHERE EXISTS (SELECT 1 FROM calls WHERE id = ? AND (caller_id = ? OR callee_id = ?))
+              AND (SELECT COUNT(*) FROM call_stats WHERE call_id = ? AND user_id = ?) < ?
             "#,
         )
-        .bind(id)
-        .bind(username)
-        .bind(password_hash)
-        .bind(&now)
-        .bind(&now)
+        .bind(call_id)
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(stats.bitrate_kbps)
+        .bind(stats.packet_loss_percent)
+        .bind(stats.round_trip_time_ms)
+        .bind(stats.jitter_ms)
+        .bind(call_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(call_id)
+        .bind(user_id)
+        .bind(max_samples as i64)
         .execute(&self.pool)
         .await?;
 
-        Ok(DbUser {
-            id: id.to_string(),
-            username: username.to_string(),
-            password_hash: password_hash.to_string(),
-            created_at: now.clone(),
-            last_seen: now,
-        })
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Get user by username
-    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<DbUser>, sqlx::Error> {
-        let user = sqlx::query_as::<_, DbUser>(
+    /// Averages of the stats reported for a call, `None` where nothing was reported
+    pub async fn get_call_stats_averages(&self, call_id: &str) -> Result<DbCallStats, sqlx::Error> {
+        let row = sqlx::query(
             r#"
-            SELECT id, username, password_hash, created_at, last_seen
-            FROM users
-            WHERE username = ?
+            SELECT AVG(bitrate_kbps) as bitrate_kbps, AVG(packet_loss_percent) as packet_loss_percent,
+                   AVG(round_trip_time_ms) as round_trip_time_ms, AVG(jitter_ms) as jitter_ms
+            FROM call_stats
+            WHERE call_id = ?
             "#,
         )
-        .bind(username)
-        .fetch_optional(&self.pool)
+        .bind(call_id)
+        .fetch_one(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(DbCallStats {
+            bitrate_kbps: row.get("bitrate_kbps"),
+            packet_loss_percent: row.get("packet_loss_percent"),
+            round_trip_time_ms: row.get("round_trip_time_ms"),
+            jitter_ms: row.get("jitter_ms"),
+        })
     }
 
-    /// Get user by ID
-    pub async fn get_user_by_id(&self, id: &str) -> Result<Option<DbUser>, sqlx::Error> {
-        let user = sqlx::query_as::<_, DbUser>(
+    /// Mark a ringing call as answered. Returns false if it isn't ringing any more.
+    pub async fn answer_call(&self, call_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE calls SET status = 'answered' WHERE id = ? AND status = 'ringing'")
+            .bind(call_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Close a call nobody answered. Returns false if it isn't ringing any more.
+    pub async fn mark_call_missed(&self, call_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE calls SET status = 'missed', ended_at = ? WHERE id = ? AND status = 'ringing'")
+            .bind(sortable_timestamp(Utc::now()))
+            .bind(call_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The latest call between two users that hasn't ended, for signals from
+    /// clients that don't send a `call_id`
+    pub async fn get_open_call_id(&self, user1_id: &str, user2_id: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar(
             r#"
-            SELECT id, username, password_hash, created_at, last_seen
-            FROM users
-            WHERE id = ?
+            SELECT id FROM calls
+            WHERE ended_at IS NULL
+              AND ((caller_id = ? AND callee_id = ?) OR (caller_id = ? AND callee_id = ?))
+            ORDER BY started_at DESC
+            LIMIT 1
             "#,
         )
-        .bind(id)
+        .bind(user1_id)
+        .bind(user2_id)
+        .bind(user2_id)
+        .bind(user1_id)
         .fetch_optional(&self.pool)
-        .await?;
-
-        Ok(user)
+        .await
     }
 
-    /// Get all users
-    pub async fn get_all_users(&self) -> Result<Vec<DbUser>, sqlx::Error> {
-        let users = sqlx::query_as::<_, DbUser>(
+    /// Record that the callee declined a ringing call. Returns false if the call
+    /// isn't ringing any more or `callee_id` isn't its callee.
+    pub async fn reject_call(&self, call_id: &str, callee_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
             r#"
-            SELECT id, username, password_hash, created_at, last_seen
-            FROM users
-            ORDER BY username
+            UPDATE calls SET status = 'rejected', ended_at = ?
+            WHERE id = ? AND status = 'ringing' AND callee_id = ?
             "#,
         )
-        .fetch_all(&self.pool)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(call_id)
+        .bind(callee_id)
+        .execute(&self.pool)
         .await?;
 
-        Ok(users)
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Update user's last seen timestamp
-    pub async fn update_last_seen(&self, user_id: &str) -> Result<(), sqlx::Error> {
-        let now = Utc::now().to_rfc3339();
-        
-        sqlx::query(
+    /// Mark a call as ended by one of its participants, storing its quality score.
+    /// A call hung up while still ringing counts as missed.
+    /// Returns false if the call is unknown, already ended or not `user_id`'s.
+    pub async fn end_call(&self, call_id: &str, user_id: &str, quality_score: Option<f64>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
             r#"
-            UPDATE users SET last_seen = ? WHERE id = ?
+            UPDATE calls
+            SET ended_at = ?, quality_score = ?, status = CASE WHEN status = 'ringing' THEN 'missed' ELSE 'ended' END
+            WHERE id = ? AND ended_at IS NULL AND (caller_id = ? OR callee_id = ?)
             "#,
         )
-        .bind(&now)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(quality_score)
+        .bind(call_id)
+        .bind(user_id)
         .bind(user_id)
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    // ============ MESSAGE OPERATIONS ============
-
-    /// Save a message to the database
-    pub async fn save_message(&self, message: &DbMessage) -> Result<(), sqlx::Error> {
-        sqlx::query(
+    /// The most recent calls with their averaged stats, newest first
+    pub async fn get_recent_calls(&self, limit: i32) -> Result<Vec<DbCallSummary>, sqlx::Error> {
+        sqlx::query_as::<_, DbCallSummary>(
             r#"
-            INSERT INTO messages (id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            SELECT c.id, c.caller_id, c.callee_id, c.call_type, c.status, c.started_at, c.ended_at, c.quality_score,
+                   COUNT(s.call_id) as stats_count,
+                   AVG(s.bitrate_kbps) as avg_bitrate_kbps,
+                   AVG(s.packet_loss_percent) as avg_packet_loss_percent,
+                   AVG(s.round_trip_time_ms) as avg_round_trip_time_ms,
+                   AVG(s.jitter_ms) as avg_jitter_ms
+            FROM calls c
+            LEFT JOIN call_stats s ON s.call_id = c.id
+            GROUP BY c.id
+            ORDER BY c.started_at DESC
+            LIMIT ?
             "#,
         )
-        .bind(&message.id)
-        .bind(&message.from_user_id)
-        .bind(&message.to_user_id)
-        .bind(&message.content)
-        .bind(&message.timestamp)
-        .bind(message.read as i32)
-        .bind(&message.file_data)
-        .bind(&message.file_name)
-        .bind(&message.file_type)
-        .bind(message.audio_duration)
-        .execute(&self.pool)
-        .await?;
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
 
-        Ok(())
+    // ============ STARRED MESSAGE OPERATIONS ============
+
+    /// Add a message to `user_id`'s starred messages. Returns false if it already was.
+    pub async fn star_message(&self, user_id: &str, message_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("INSERT OR IGNORE INTO starred_messages (user_id, message_id, starred_at) VALUES (?, ?, ?)")
+            .bind(user_id)
+            .bind(message_id)
+            .bind(sortable_timestamp(Utc::now()))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Get messages between two users with pagination
-    pub async fn get_messages_between_users(
-        &self,
-        user1_id: &str,
-        user2_id: &str,
-        limit: i32,
-        offset: i32,
-    ) -> Result<Vec<DbMessage>, sqlx::Error> {
+    /// Returns false if the message wasn't starred
+    pub async fn unstar_message(&self, user_id: &str, message_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM starred_messages WHERE user_id = ? AND message_id = ?")
+            .bind(user_id)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// `user_id`'s starred messages from every conversation, most recently starred
+    /// first, leaving out ones they cleared and ones that expired
+    pub async fn get_starred_messages(&self, user_id: &str, limit: i32, offset: i32) -> Result<Vec<DbMessage>, sqlx::Error> {
         let rows = sqlx::query(
             r#"
-            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration
-            FROM messages
-            WHERE (from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?)
-            ORDER BY timestamp DESC
+            SELECT m.id, m.from_user_id, m.to_user_id, m.content, m.timestamp, m.read, m.file_data, m.file_name, m.file_type, m.audio_duration, m.thumbnail_data, m.encrypted, m.delivered, m.client_msg_id, m.expires_at, m.media_width, m.media_height, m.device_id, m.device_name, m.seq, m.quoted_message_id, m.quoted_text
+            FROM starred_messages s
+            JOIN messages m ON m.id = s.message_id
+            WHERE s.user_id = ?
+              AND NOT ((m.from_user_id = ? AND m.hidden_for_sender = 1) OR (m.to_user_id = ? AND m.hidden_for_recipient = 1))
+              AND (m.expires_at IS NULL OR m.expires_at > ?)
+            ORDER BY s.starred_at DESC
             LIMIT ? OFFSET ?
             "#,
         )
-        .bind(user1_id)
-        .bind(user2_id)
-        .bind(user2_id)
-        .bind(user1_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
         .bind(limit)
         .bind(offset)
         .fetch_all(&self.pool)
         .await?;
 
-        let messages: Vec<DbMessage> = rows
-            .iter()
-            .map(|row| DbMessage {
-                id: row.get("id"),
-                from_user_id: row.get("from_user_id"),
-                to_user_id: row.get("to_user_id"),
-                content: row.get("content"),
-                timestamp: row.get("timestamp"),
-                read: row.get::<i32, _>("read") != 0,
-                file_data: row.get("file_data"),
-                file_name: row.get("file_name"),
-                file_type: row.get("file_type"),
-                audio_duration: row.get("audio_duration"),
-            })
-            .collect();
-
-        Ok(messages)
+        Ok(rows.iter().map(|row| self.row_to_message(row)).collect())
     }
 
-    /// Get all messages for a user (for loading conversation list)
-    pub async fn get_user_conversations(&self, user_id: &str) -> Result<Vec<DbMessage>, sqlx::Error> {
-        // Get the latest message from each conversation
+    /// Messages in any of `user_id`'s conversations whose text contains `query`
+    /// (case-insensitive for ASCII), newest first. Encrypted messages are skipped.
+    pub async fn search_messages(&self, user_id: &str, query: &str, limit: i32, offset: i32) -> Result<Vec<DbMessage>, sqlx::Error> {
         let rows = sqlx::query(
             r#"
-            SELECT m.id, m.from_user_id, m.to_user_id, m.content, m.timestamp, m.read, m.file_data, m.file_name, m.file_type, m.audio_duration
-            FROM messages m
-            INNER JOIN (
-                SELECT 
-                    CASE 
-                        WHEN from_user_id = ? THEN to_user_id 
-                        ELSE from_user_id 
-                    END as other_user,
-                    MAX(timestamp) as max_ts
-                FROM messages
-                WHERE from_user_id = ? OR to_user_id = ?
-                GROUP BY other_user
-            ) latest ON (
-                (m.from_user_id = ? AND m.to_user_id = latest.other_user) OR
-                (m.from_user_id = latest.other_user AND m.to_user_id = ?)
-            ) AND m.timestamp = latest.max_ts
-            ORDER BY m.timestamp DESC
+            SELECT id, from_user_id, to_user_id, content, timestamp, read, file_data, file_name, file_type, audio_duration, thumbnail_data, encrypted, delivered, client_msg_id, expires_at, media_width, media_height, device_id, device_name, seq, quoted_message_id, quoted_text
+            FROM messages
+            WHERE (from_user_id = ? OR to_user_id = ?)
+              AND encrypted = 0
+              AND content LIKE ? ESCAPE '\'
+              AND NOT ((from_user_id = ? AND hidden_for_sender = 1) OR (to_user_id = ? AND hidden_for_recipient = 1))
+              AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY timestamp DESC, id DESC
+            LIMIT ? OFFSET ?
             "#,
         )
         .bind(user_id)
         .bind(user_id)
+        .bind(format!("%{}%", escape_like(query)))
         .bind(user_id)
         .bind(user_id)
-        .bind(user_id)
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(limit)
+        .bind(offset)
         .fetch_all(&self.pool)
         .await?;
 
-        let messages: Vec<DbMessage> = rows
-            .iter()
-            .map(|row| DbMessage {
-                id: row.get("id"),
-                from_user_id: row.get("from_user_id"),
-                to_user_id: row.get("to_user_id"),
-                content: row.get("content"),
-                timestamp: row.get("timestamp"),
-                read: row.get::<i32, _>("read") != 0,
-                file_data: row.get("file_data"),
-                file_name: row.get("file_name"),
-                file_type: row.get("file_type"),
-                audio_duration: row.get("audio_duration"),
-            })
-            .collect();
-
-        Ok(messages)
+        Ok(rows.iter().map(|row| self.row_to_message(row)).collect())
     }
 
-    /// Mark a message as read
-    pub async fn mark_message_read(&self, message_id: &str) -> Result<(), sqlx::Error> {
-        sqlx::query(
+    // ============ REPORT OPERATIONS ============
+
+    /// Store a report. Returns false if the user already reported this message.
+    pub async fn add_report(&self, report_id: &str, reporter_id: &str, message_id: &str, reason: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
             r#"
-            UPDATE messages SET read = 1 WHERE id = ?
+            INSERT OR IGNORE INTO reports (id, reporter_id, message_id, reason, created_at)
+            VALUES (?, ?, ?, ?, ?)
             "#,
         )
+        .bind(report_id)
+        .bind(reporter_id)
         .bind(message_id)
+        .bind(reason)
+        .bind(sortable_timestamp(Utc::now()))
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Get unread message count for a user from another user
-    pub async fn get_unread_count(&self, user_id: &str, from_user_id: &str) -> Result<i32, sqlx::Error> {
-        let row = sqlx::query(
+    /// The newest reports, with the content of the messages they are about
+    pub async fn get_recent_reports(&self, limit: i32) -> Result<Vec<DbReport>, sqlx::Error> {
+        sqlx::query_as::<_, DbReport>(
             r#"
-            SELECT COUNT(*) as count
-            FROM messages
-            WHERE to_user_id = ? AND from_user_id = ? AND read = 0
+            SELECT r.id, r.reporter_id, r.message_id, r.reason, r.created_at,
+                   m.from_user_id, m.to_user_id, m.content, m.encrypted, m.timestamp
+            FROM reports r
+            LEFT JOIN messages m ON m.id = r.message_id
+            ORDER BY r.created_at DESC
+            LIMIT ?
             "#,
         )
-        .bind(user_id)
-        .bind(from_user_id)
-        .fetch_one(&self.pool)
-        .await?;
-
-        Ok(row.get::<i32, _>("count"))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
     }
 
     // ============ REACTION OPERATIONS ============
 
-    /// Add or update a reaction
-    pub async fn add_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<(), sqlx::Error> {
-        sqlx::query(
+    /// Add or update a reaction. Returns false if the message already has
+    /// `max_reactions` reactions from other users.
+    pub async fn add_reaction(&self, message_id: &str, user_id: &str, emoji: &str, max_reactions: usize) -> Result<bool, sqlx::Error> {
+        // Changing an existing reaction is always allowed; new reactors only while under the cap
+        let result = sqlx::query(
             r#"
             INSERT OR REPLACE INTO reactions (message_id, user_id, emoji)
-            VALUES (?, ?, ?)
+            SELECT ?, ?, ?
+            WHERE EXISTS (SELECT 1 FROM reactions WHERE message_id = ? AND user_id = ?)
+               OR (SELECT COUNT(*) FROM reactions WHERE message_id = ?) < ?
             "#,
         )
         .bind(message_id)
         .bind(user_id)
         .bind(emoji)
+        .bind(message_id)
+        .bind(user_id)
+        .bind(message_id)
+        .bind(max_reactions as i64)
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Remove a reaction
-    pub async fn remove_reaction(&self, message_id: &str, user_id: &str) -> Result<(), sqlx::Error> {
-        sqlx::query(
+    /// Remove a reaction, returning the emoji it had (`None` if there was none)
+    pub async fn remove_reaction(&self, message_id: &str, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
             r#"
             DELETE FROM reactions WHERE message_id = ? AND user_id = ?
+            RETURNING emoji
             "#,
         )
         .bind(message_id)
         .bind(user_id)
-        .execute(&self.pool)
-        .await?;
-
-        Ok(())
+        .fetch_optional(&self.pool)
+        .await
     }
 
     /// Get all reactions for a message
     pub async fn get_reactions(&self, message_id: &str) -> Result<HashMap<String, String>, sqlx::Error> {
         let rows = sqlx::query_as::<_, DbReaction>(
             r#"
-            SELECT message_id, user_id, emoji
+            SELECT user_id, emoji
             FROM reactions
             WHERE message_id = ?
             "#,
@@ -436,25 +2987,43 @@ impl Database {
         Ok(reactions)
     }
 
+    /// Reactions to a message with who made them, grouped by emoji
+    pub async fn get_reaction_details(&self, message_id: &str) -> Result<Vec<DbReactionDetail>, sqlx::Error> {
+        sqlx::query_as::<_, DbReactionDetail>(
+            r#"
+            SELECT r.emoji, r.user_id, u.username, u.display_name
+            FROM reactions r
+            INNER JOIN users u ON u.id = r.user_id
+            WHERE r.message_id = ?
+            ORDER BY r.emoji, u.username
+            "#,
+        )
+        .bind(message_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
     /// Get reactions for multiple messages (batch load)
     pub async fn get_reactions_batch(&self, message_ids: &[String]) -> Result<HashMap<String, HashMap<String, String>>, sqlx::Error> {
         if message_ids.is_empty() {
             return Ok(HashMap::new());
         }
 
-        // Build the IN clause
-        let placeholders: Vec<&str> = message_ids.iter().map(|_| "?").collect();
-        let query = format!(
-            "SELECT message_id, user_id, emoji FROM reactions WHERE message_id IN ({})",
-            placeholders.join(",")
-        );
+        let mut rows = Vec::with_capacity(message_ids.len());
+        for chunk in message_ids.chunks(MAX_IN_CLAUSE_IDS) {
+            let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+            let query = format!(
+                "SELECT message_id, user_id, emoji FROM reactions WHERE message_id IN ({})",
+                placeholders.join(",")
+            );
 
-        let mut query_builder = sqlx::query(&query);
-        for id in message_ids {
-            query_builder = query_builder.bind(id);
-        }
+            let mut query_builder = sqlx::query(&query);
+            for id in chunk {
+                query_builder = query_builder.bind(id);
+            }
 
-        let rows = query_builder.fetch_all(&self.pool).await?;
+            rows.extend(query_builder.fetch_all(&self.pool).await?);
+        }
 
         let mut reactions_map: HashMap<String, HashMap<String, String>> = HashMap::new();
         for row in rows {
@@ -471,19 +3040,24 @@ impl Database {
         Ok(reactions_map)
     }
 
-    /// Get total message count between two users (for pagination)
+    /// Get total message count between two users as seen by `user1_id` (for pagination)
     pub async fn get_message_count_between_users(&self, user1_id: &str, user2_id: &str) -> Result<i32, sqlx::Error> {
         let row = sqlx::query(
             r#"
             SELECT COUNT(*) as count
             FROM messages
-            WHERE (from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?)
+            WHERE ((from_user_id = ? AND to_user_id = ?) OR (from_user_id = ? AND to_user_id = ?))
+              AND NOT ((from_user_id = ? AND hidden_for_sender = 1) OR (to_user_id = ? AND hidden_for_recipient = 1))
+              AND (expires_at IS NULL OR expires_at > ?)
             "#,
         )
         .bind(user1_id)
         .bind(user2_id)
         .bind(user2_id)
         .bind(user1_id)
+        .bind(user1_id)
+        .bind(user1_id)
+        .bind(sortable_timestamp(Utc::now()))
         .fetch_one(&self.pool)
         .await?;
 
@@ -491,3 +3065,361 @@ impl Database {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plain text message from `from` to `to`, sent now
+    fn test_message(id: &str, from: &str, to: &str) -> DbMessage {
+        DbMessage {
+            id: id.to_string(),
+            from_user_id: from.to_string(),
+            to_user_id: to.to_string(),
+            content: format!("message {}", id),
+            timestamp: Utc::now().timestamp_millis(),
+            read: false,
+            file_data: None,
+            file_name: None,
+            file_type: None,
+            audio_duration: None,
+            thumbnail_data: None,
+            encrypted: false,
+            delivered: false,
+            client_msg_id: None,
+            expires_at: None,
+            media_width: None,
+            media_height: None,
+            device_id: None,
+            device_name: None,
+            seq: 0,
+            quoted_message_id: None,
+            quoted_text: None,
+        }
+    }
+
+    /// A database as the first release left it: RFC 3339 text timestamps and
+    /// none of the columns added since
+    async fn create_baseline_database(url: &str) {
+        let options = SqliteConnectOptions::from_str(url).unwrap();
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        for statement in [
+            "CREATE TABLE users (
+                id TEXT PRIMARY KEY,
+                username TEXT UNIQUE NOT NULL,
+                password_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                last_seen TEXT NOT NULL
+            )",
+            "CREATE TABLE messages (
+                id TEXT PRIMARY KEY,
+                from_user_id TEXT NOT NULL,
+                to_user_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                read INTEGER NOT NULL DEFAULT 0,
+                file_data TEXT,
+                file_name TEXT,
+                file_type TEXT,
+                audio_duration REAL,
+                FOREIGN KEY (from_user_id) REFERENCES users(id),
+                FOREIGN KEY (to_user_id) REFERENCES users(id)
+            )",
+            "INSERT INTO users VALUES
+                ('alice', 'alice', '', '2024-01-01T08:00:00Z', '2024-01-01T12:00:00+02:00'),
+                ('bob', 'bob', '', '2024-01-01T08:30:00Z', '2024-01-01T10:30:00.250Z')",
+            // Stored out of order, and not all in UTC
+            "INSERT INTO messages (id, from_user_id, to_user_id, content, timestamp) VALUES
+                ('m2', 'alice', 'bob', 'second', '2024-01-01T12:00:00+02:00'),
+                ('m3', 'bob', 'alice', 'third', '2024-01-01T10:30:00.250Z'),
+                ('m1', 'alice', 'bob', 'first', '2024-01-01T09:00:00Z')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn in_memory_database_runs_a_full_cycle() {
+        let db = Database::new("sqlite::memory:", None).await.unwrap();
+
+        let alice = db.create_user("alice-id", "alice", "hash").await.unwrap();
+        db.create_user("bob-id", "bob", "hash").await.unwrap();
+        let found = db.get_user_by_username("alice").await.unwrap().unwrap();
+        assert_eq!((found.id.as_str(), found.password_hash.as_str()), (alice.id.as_str(), "hash"));
+
+        assert_eq!(db.save_message(&test_message("m1", "alice-id", "bob-id"), &[]).await.unwrap(), 1);
+        assert_eq!(db.save_message(&test_message("m2", "bob-id", "alice-id"), &[]).await.unwrap(), 2);
+
+        let messages = db.get_messages_between_users("alice-id", "bob-id", 10, 0).await.unwrap();
+        let ids: Vec<&str> = messages.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["m2", "m1"]);
+        assert_eq!(db.get_message_count_between_users("alice-id", "bob-id").await.unwrap(), 2);
+        assert_eq!(db.get_unread_counts("bob-id").await.unwrap().get("alice-id"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn read_markers_load_in_one_batch() {
+        let db = Database::new("sqlite::memory:", None).await.unwrap();
+        for id in ["alice", "bob", "carol"] {
+            db.create_user(id, id, "hash").await.unwrap();
+        }
+        db.advance_read_marker("bob", "alice", 1, 1_000).await.unwrap();
+        db.advance_read_marker("bob", "alice", 2, 2_000).await.unwrap();
+        db.advance_read_marker("alice", "carol", 5, 500).await.unwrap();
+
+        let conversations: Vec<(String, String)> = [("bob", "alice"), ("alice", "bob"), ("alice", "carol")]
+            .iter()
+            .map(|(user_id, other_user_id)| (user_id.to_string(), other_user_id.to_string()))
+            .collect();
+        let markers = db.get_read_markers_batch(&conversations).await.unwrap();
+
+        assert_eq!(markers.len(), 2);
+        assert_eq!(markers[&("bob".to_string(), "alice".to_string())], 2);
+        assert_eq!(markers[&("alice".to_string(), "carol".to_string())], 5);
+        assert!(db.get_read_markers_batch(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_sharing_a_millisecond_with_the_marker_stay_unread() {
+        let db = Database::new("sqlite::memory:", None).await.unwrap();
+        db.create_user("alice", "alice", "hash").await.unwrap();
+        db.create_user("bob", "bob", "hash").await.unwrap();
+        for id in ["m1", "m2"] {
+            let mut message = test_message(id, "alice", "bob");
+            message.timestamp = 1_000;
+            db.save_message(&message, &[]).await.unwrap();
+        }
+
+        assert!(db.advance_read_marker("bob", "alice", 1, 1_000).await.unwrap());
+
+        assert_eq!(db.get_unread_counts("bob").await.unwrap().get("alice"), Some(&1));
+        assert_eq!(db.get_total_unread_count("bob").await.unwrap(), 1);
+        assert_eq!(db.get_first_unread_message_id("bob", "alice").await.unwrap().as_deref(), Some("m2"));
+        // Only forward
+        assert!(!db.advance_read_marker("bob", "alice", 1, 2_000).await.unwrap());
+        assert_eq!(db.get_last_seq_received_by("bob", "alice", 1_000).await.unwrap(), 2);
+        assert_eq!(db.get_conversation_seq("bob", "alice").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn id_lists_longer_than_one_in_clause_are_chunked() {
+        let db = Database::new("sqlite::memory:", None).await.unwrap();
+        db.create_user("alice", "alice", "hash").await.unwrap();
+        db.create_user("bob", "bob", "hash").await.unwrap();
+
+        let ids: Vec<String> = (0..MAX_IN_CLAUSE_IDS + 1).map(|i| format!("m{}", i)).collect();
+        for id in &ids {
+            db.save_message(&test_message(id, "alice", "bob"), &[]).await.unwrap();
+        }
+        let last = &ids[MAX_IN_CLAUSE_IDS];
+        db.add_reaction(last, "bob", "👍", 10).await.unwrap();
+
+        let reactions = db.get_reactions_batch(&ids).await.unwrap();
+        assert_eq!(reactions[last]["bob"], "👍");
+
+        assert_eq!(db.get_undelivered_messages("bob", 10).await.unwrap().len(), 10);
+        db.mark_messages_delivered(&ids).await.unwrap();
+        assert!(db.get_undelivered_messages("bob", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn baseline_database_is_migrated() {
+        let path = std::env::temp_dir().join(format!("chat-backend-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        create_baseline_database(&url).await;
+
+        let db = Database::new(&url, None).await.unwrap();
+
+        let columns: HashMap<String, String> = sqlx::query("PRAGMA table_info(messages)")
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get("name"), row.get("type")))
+            .collect();
+        assert_eq!(columns["timestamp"], "INTEGER");
+        for column in ["encrypted", "delivered", "device_name", "seq", "quoted_message_id", "quoted_text"] {
+            assert!(columns.contains_key(column), "messages.{} is missing", column);
+        }
+
+        let (created_at, last_seen): (i64, i64) = sqlx::query_as("SELECT created_at, last_seen FROM users WHERE id = 'alice'")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(created_at, 1_704_096_000_000);
+        assert_eq!(last_seen, 1_704_103_200_000);
+
+        // Newest first, numbered in the order they were sent
+        let messages = db.get_messages_between_users("alice", "bob", 10, 0).await.unwrap();
+        let order: Vec<(&str, i64, i64)> = messages.iter().map(|m| (m.id.as_str(), m.timestamp, m.seq)).collect();
+        assert_eq!(
+            order,
+            [("m3", 1_704_105_000_250, 3), ("m2", 1_704_103_200_000, 2), ("m1", 1_704_099_600_000, 1)]
+        );
+
+        // New messages continue the numbering and keep their quote
+        let reply = DbMessage {
+            quoted_message_id: Some("m3".to_string()),
+            quoted_text: Some("third".to_string()),
+            ..test_message("m4", "alice", "bob")
+        };
+        assert_eq!(db.save_message(&reply, &[]).await.unwrap(), 4);
+        let stored = db.get_message_for_user("m4", "bob").await.unwrap().unwrap();
+        assert_eq!(stored.quoted_text.as_deref(), Some("third"));
+
+        db.pool.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn usernames_differing_by_case_are_renamed_oldest_first() {
+        let path = std::env::temp_dir().join(format!("chat-backend-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        create_baseline_database(&url).await;
+
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::from_str(&url).unwrap()).await.unwrap();
+        // "ALICE-2" is taken already, so the renamed accounts skip that suffix
+        sqlx::query(
+            "INSERT INTO users VALUES
+                ('alice-upper', 'ALICE', '', '2024-01-02T08:00:00Z', '2024-01-02T08:00:00Z'),
+                ('alice-title', 'Alice', '', '2024-01-01T09:00:00Z', '2024-01-01T09:00:00Z'),
+                ('alice-two', 'ALICE-2', '', '2023-12-01T00:00:00Z', '2023-12-01T00:00:00Z')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+
+        let db = Database::new(&url, None).await.unwrap();
+
+        let mut usernames: Vec<(String, String)> = sqlx::query_as("SELECT id, username FROM users")
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        usernames.sort();
+        assert_eq!(
+            usernames,
+            [
+                ("alice".to_string(), "alice".to_string()),
+                ("alice-title".to_string(), "Alice-3".to_string()),
+                ("alice-two".to_string(), "ALICE-2".to_string()),
+                ("alice-upper".to_string(), "ALICE-4".to_string()),
+                ("bob".to_string(), "bob".to_string()),
+            ]
+        );
+
+        // The oldest account still logs in under its name, whatever the case
+        let found = db.get_user_by_username("ALICE").await.unwrap().unwrap();
+        assert_eq!(found.id, "alice");
+        // And the unique index is in place
+        assert!(db.create_user("another-alice", "aLiCe", "hash").await.is_err());
+
+        db.pool.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn malformed_timestamps_are_migrated_as_the_epoch() {
+        let path = std::env::temp_dir().join(format!("chat-backend-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        create_baseline_database(&url).await;
+
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::from_str(&url).unwrap()).await.unwrap();
+        for statement in [
+            "INSERT INTO messages (id, from_user_id, to_user_id, content, timestamp) VALUES
+                ('garbled', 'bob', 'alice', 'when?', 'last tuesday'),
+                ('empty', 'alice', 'bob', 'never', '')",
+            "UPDATE users SET last_seen = 'not a date' WHERE id = 'bob'",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        // The rows the migration warns about
+        let unmigrated = Database { pool, cipher: None };
+        assert_eq!(unmigrated.unparseable_timestamps("messages", "timestamp").await.unwrap(), ["empty", "garbled"]);
+        assert_eq!(unmigrated.unparseable_timestamps("users", "last_seen").await.unwrap(), ["bob"]);
+        assert!(unmigrated.unparseable_timestamps("users", "created_at").await.unwrap().is_empty());
+        unmigrated.pool.close().await;
+
+        let db = Database::new(&url, None).await.unwrap();
+
+        // Mapped to the epoch, so they sort as the oldest rather than jumping to the end
+        let messages = db.get_messages_between_users("alice", "bob", 10, 0).await.unwrap();
+        let order: Vec<(&str, i64)> = messages.iter().map(|m| (m.id.as_str(), m.timestamp)).collect();
+        assert_eq!(&order[..3], [("m3", 1_704_105_000_250), ("m2", 1_704_103_200_000), ("m1", 1_704_099_600_000)]);
+        let mut malformed = order[3..].to_vec();
+        malformed.sort();
+        assert_eq!(malformed, [("empty", 0), ("garbled", 0)]);
+
+        let bob = db.get_user_by_id("bob").await.unwrap().unwrap();
+        assert_eq!(bob.last_seen, 0);
+
+        db.pool.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn file_data_is_encrypted_at_rest() {
+        let cipher = AtRestCipher::from_base64("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let db = Database::new("sqlite::memory:", Some(cipher)).await.unwrap();
+        for id in ["alice", "bob"] {
+            db.create_user(id, id, "").await.unwrap();
+        }
+        let file = "UExBSU5URVhUIEZJTEUgQ09OVEVOVFM=";
+
+        let message = DbMessage {
+            file_data: Some(FileData::from(file.to_string())),
+            file_name: Some("notes.txt".to_string()),
+            file_type: Some("text/plain".to_string()),
+            ..test_message("m1", "alice", "bob")
+        };
+        db.save_message(&message, &[]).await.unwrap();
+
+        let raw: String = sqlx::query_scalar("SELECT file_data FROM messages WHERE id = 'm1'")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert!(at_rest::is_encrypted(&raw));
+        assert!(!raw.contains(file));
+
+        let stored = db.get_message_for_user("m1", "bob").await.unwrap().unwrap();
+        assert_eq!(stored.file_data.as_deref(), Some(file));
+    }
+
+    #[tokio::test]
+    async fn old_messages_are_purged_with_their_reactions() {
+        let db = Database::new("sqlite::memory:", None).await.unwrap();
+        for id in ["alice", "bob"] {
+            db.create_user(id, id, "").await.unwrap();
+        }
+        let cutoff = (Utc::now() - chrono::Duration::days(30)).timestamp_millis();
+
+        let old = DbMessage {
+            timestamp: cutoff - 1,
+            ..test_message("old", "alice", "bob")
+        };
+        db.save_message(&old, &[]).await.unwrap();
+        db.save_message(&test_message("recent", "bob", "alice"), &[]).await.unwrap();
+        for id in ["old", "recent"] {
+            db.add_reaction(id, "bob", "👍", 10).await.unwrap();
+        }
+
+        assert_eq!(db.purge_messages_before(cutoff).await.unwrap(), 1);
+        assert_eq!(db.purge_messages_before(cutoff).await.unwrap(), 0);
+
+        let remaining: Vec<String> = db
+            .get_messages_between_users("alice", "bob", 10, 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(remaining, ["recent"]);
+        let reacted: Vec<String> = sqlx::query_scalar("SELECT message_id FROM reactions")
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(reacted, ["recent"]);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::outbox::ClientSender;
+use crate::ServerMessage;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the logs of users who went offline are checked for expiry
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Recent events pushed to each user, numbered per user, so a client that
+/// reconnects can `Resume` from the last number it saw instead of refetching
+/// its history. Only users who logged in since the server started are tracked,
+/// and only their latest events, up to `capacity` of them and `max_bytes` in
+/// total. Events are kept without file contents (`ServerMessage::for_replay`).
+/// A user's log is dropped once they have been offline for `retention`.
+pub struct EventLog {
+    users: DashMap<String, UserEvents>,
+    capacity: usize,
+    max_bytes: usize,
+    retention: Duration,
+}
+
+#[derive(Default)]
+struct UserEvents {
+    /// Number of the latest event; 0 before the first
+    last_seq: u64,
+    events: VecDeque<Recorded>,
+    /// Serialized size of `events`
+    bytes: usize,
+    /// When the user's last connection closed; `None` while they are connected
+    offline_since: Option<Instant>,
+}
+
+struct Recorded {
+    seq: u64,
+    message: ServerMessage,
+    bytes: usize,
+}
+
+/// Outcome of a `replay`
+pub struct Replay {
+    /// Number of the latest event, to resume from next time
+    pub last_seq: u64,
+    /// False when events after the client's cursor were already dropped from the
+    /// log (or the cursor is from before a restart); the client has to refetch
+    pub complete: bool,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize, max_bytes: usize, retention: Duration) -> Self {
+        Self {
+            users: DashMap::new(),
+            capacity,
+            max_bytes,
+            retention,
+        }
+    }
+
+    /// Start keeping events for `user_id` and return the number of their latest one
+    pub fn track(&self, user_id: &str) -> u64 {
+        let mut entry = self.users.entry(user_id.to_string()).or_default();
+        entry.offline_since = None;
+        entry.last_seq
+    }
+
+    /// Note that `user_id` has no connection left, starting their log's retention period
+    pub fn went_offline(&self, user_id: &str) {
+        if let Some(mut entry) = self.users.get_mut(user_id) {
+            entry.offline_since = Some(Instant::now());
+        }
+    }
+
+    /// Record `message` for `user_id` and queue it on `tx` if they are connected.
+    /// Both happen under the user's entry, so a concurrent `replay` can't reorder them.
+    /// Returns whether the message was queued, or `None` without a connection.
+    pub fn send(&self, user_id: &str, message: ServerMessage, tx: Option<&ClientSender>) -> Option<bool> {
+        let Some(mut entry) = self.users.get_mut(user_id) else {
+            return tx.map(|tx| tx.send(message).is_ok());
+        };
+
+        entry.last_seq += 1;
+        let seq = entry.last_seq;
+        self.record(&mut entry, seq, message.for_replay());
+
+        tx.map(|tx| tx.send_sequenced(message, seq).is_ok())
+    }
+
+    /// Queue `message` on `tx` and record it for `user_id`, but only if it was
+    /// queued. For events that have another way to reach a user who missed them,
+    /// like new messages, which stay pending until the next login.
+    pub fn send_if_queued(&self, user_id: &str, message: ServerMessage, tx: &ClientSender) -> bool {
+        let Some(mut entry) = self.users.get_mut(user_id) else {
+            return tx.send(message).is_ok();
+        };
+
+        let seq = entry.last_seq + 1;
+        let recorded = message.for_replay();
+        if tx.send_sequenced(message, seq).is_err() {
+            return false;
+        }
+        entry.last_seq = seq;
+        self.record(&mut entry, seq, recorded);
+        true
+    }
+
+    /// Keep `message` as event `seq`, dropping the oldest events to stay within
+    /// the count and byte limits. An event over the byte limit by itself isn't
+    /// kept; a `Resume` from before it then reports the log incomplete.
+    fn record(&self, entry: &mut UserEvents, seq: u64, message: ServerMessage) {
+        let bytes = serde_json::to_vec(&message).map_or(0, |json| json.len());
+
+        while !entry.events.is_empty() && (entry.events.len() >= self.capacity || entry.bytes + bytes > self.max_bytes) {
+            if let Some(dropped) = entry.events.pop_front() {
+                entry.bytes -= dropped.bytes;
+            }
+        }
+        if bytes > self.max_bytes {
+            return;
+        }
+
+        entry.bytes += bytes;
+        entry.events.push_back(Recorded { seq, message, bytes });
+    }
+
+    /// Queue every recorded event of `user_id` after `last_seq` on `tx`, oldest first
+    pub fn replay(&self, user_id: &str, last_seq: u64, tx: &ClientSender) -> Replay {
+        let entry = self.users.entry(user_id.to_string()).or_default();
+
+        let oldest_kept = entry.events.front().map_or(entry.last_seq + 1, |event| event.seq);
+        let complete = last_seq <= entry.last_seq && last_seq.saturating_add(1) >= oldest_kept;
+
+        for event in entry.events.iter().filter(|event| event.seq > last_seq) {
+            let _ = tx.send_sequenced(event.message.clone(), event.seq);
+        }
+
+        Replay {
+            last_seq: entry.last_seq,
+            complete,
+        }
+    }
+
+    /// Drop the logs of users who have been offline for longer than the retention period
+    pub fn prune(&self) {
+        self.users
+            .retain(|_, entry| entry.offline_since.is_none_or(|since| since.elapsed() < self.retention));
+    }
+}
+
+/// Prune the event log every `PRUNE_INTERVAL`. Runs for the lifetime of the server.
+pub async fn run_pruner(log: Arc<EventLog>) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        log.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox;
+
+    fn deleted(message_id: &str) -> ServerMessage {
+        ServerMessage::MessageDeleted { message_id: message_id.to_string() }
+    }
+
+    fn size(message: &ServerMessage) -> usize {
+        serde_json::to_vec(message).unwrap().len()
+    }
+
+    #[test]
+    fn oldest_events_are_dropped_to_stay_within_the_byte_limit() {
+        let log = EventLog::new(100, 2 * size(&deleted("m1")), Duration::from_secs(60));
+        log.track("alice");
+        for id in ["m1", "m2", "m3"] {
+            log.send("alice", deleted(id), None);
+        }
+
+        let (tx, _rx) = outbox::channel(16, Arc::default());
+        let replay = log.replay("alice", 1, &tx);
+        assert_eq!(replay.last_seq, 3);
+        assert!(replay.complete);
+        assert!(!log.replay("alice", 0, &tx).complete);
+    }
+
+    #[test]
+    fn logs_are_dropped_after_the_user_stays_offline() {
+        let log = EventLog::new(100, 1024, Duration::ZERO);
+        log.track("alice");
+        log.track("bob");
+        log.send("alice", deleted("m1"), None);
+        log.send("bob", deleted("m1"), None);
+
+        log.went_offline("alice");
+        log.prune();
+
+        assert!(!log.users.contains_key("alice"));
+        assert!(log.users.contains_key("bob"));
+        // No longer tracked: nothing is recorded until the next login
+        assert_eq!(log.send("alice", deleted("m2"), None), None);
+        assert!(!log.users.contains_key("alice"));
+    }
+}
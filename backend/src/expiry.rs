@@ -0,0 +1,65 @@
+use crate::db::sortable_timestamp;
+use crate::{AppState, ServerMessage};
+use chrono::Utc;
+use std::time::Duration;
+
+/// How often expired disappearing messages are purged. Reads already hide
+/// them as soon as they expire, so this only bounds how long they linger on disk.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delete disappearing messages and lift timed mutes once they expire.
+/// Runs for the lifetime of the server.
+pub async fn run(state: AppState) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let now = sortable_timestamp(Utc::now());
+        lift_expired_mutes(&state, &now).await;
+
+        let expired = match state.db.get_expired_messages(&now).await {
+            Ok(expired) => expired,
+            Err(e) => {
+                tracing::error!("Failed to load expired messages: {:?}", e);
+                continue;
+            }
+        };
+
+        for message in expired {
+            if let Err(e) = state.db.delete_message(&message.id).await {
+                tracing::error!("Failed to delete expired message {}: {:?}", message.id, e);
+                continue;
+            }
+
+            // Remove it from both participants' open chats
+            for participant in [&message.from_user_id, &message.to_user_id] {
+                state.send_event(participant, ServerMessage::MessageDeleted {
+                    message_id: message.id.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Tell users whose timed mutes ran out that the conversation is unmuted again
+async fn lift_expired_mutes(state: &AppState, now: &str) {
+    let lifted = match state.db.take_expired_mutes(now).await {
+        Ok(lifted) => lifted,
+        Err(e) => {
+            tracing::error!("Failed to lift expired mutes: {:?}", e);
+            return;
+        }
+    };
+
+    for (user_id, other_user_id) in lifted {
+        if let Some(tx) = state.user_sockets.get(&user_id) {
+            let _ = tx.send(ServerMessage::ConversationMuted {
+                user_id: other_user_id,
+                muted: false,
+                muted_until: None,
+            });
+        }
+    }
+}
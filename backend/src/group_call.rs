@@ -0,0 +1,150 @@
+use crate::{AppState, ServerMessage};
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Most people in one group call; every participant holds a connection to every other
+pub const MAX_GROUP_CALL_PARTICIPANTS: usize = 8;
+
+/// Group calls in progress by call id
+pub type GroupCalls = Arc<DashMap<String, GroupCall>>;
+
+/// A mesh call: each pair of participants negotiates its own peer connection, with
+/// the server relaying their signals. Only invited users can join.
+#[derive(Debug, Default)]
+pub struct GroupCall {
+    invited: HashSet<String>,
+    participants: HashSet<String>,
+}
+
+/// Start a group call with `creator_id` in it and invite `user_ids`.
+/// Returns the replies for the creator.
+pub fn start(state: &AppState, creator_id: &str, user_ids: Vec<String>) -> Vec<ServerMessage> {
+    let invited: HashSet<String> = user_ids.into_iter().filter(|id| id != creator_id).collect();
+    if invited.is_empty() || invited.len() >= MAX_GROUP_CALL_PARTICIPANTS {
+        return vec![ServerMessage::Error {
+            message: format!(
+                "A group call needs between 1 and {} other participants",
+                MAX_GROUP_CALL_PARTICIPANTS - 1
+            ),
+            code: None,
+        }];
+    }
+
+    let call_id = Uuid::new_v4().to_string();
+    let invited_ids: Vec<String> = invited.iter().cloned().collect();
+    state.group_calls.insert(
+        call_id.clone(),
+        GroupCall {
+            invited,
+            participants: HashSet::from([creator_id.to_string()]),
+        },
+    );
+
+    for user_id in &invited_ids {
+        if let Some(tx) = state.user_sockets.get(user_id) {
+            let _ = tx.send(ServerMessage::GroupCallInvite {
+                call_id: call_id.clone(),
+                from_user_id: creator_id.to_string(),
+                invited: invited_ids.clone(),
+            });
+        }
+    }
+
+    tracing::info!("Group call {} started with {} invited", call_id, invited_ids.len());
+    vec![ServerMessage::GroupCallStarted {
+        call_id,
+        invited: invited_ids,
+    }]
+}
+
+/// Add an invited user to a call. The joiner gets the current participants (the
+/// returned reply) and sends offers to them; everyone already in the call is told
+/// about the joiner.
+pub fn join(state: &AppState, call_id: &str, user_id: &str) -> Vec<ServerMessage> {
+    let others: Vec<String> = {
+        let Some(mut call) = state.group_calls.get_mut(call_id) else {
+            return vec![ServerMessage::Error {
+                message: "Call not found".to_string(),
+                code: None,
+            }];
+        };
+        if !call.invited.contains(user_id) && !call.participants.contains(user_id) {
+            return vec![ServerMessage::Error {
+                message: "Not invited to this call".to_string(),
+                code: None,
+            }];
+        }
+        if !call.participants.contains(user_id) && call.participants.len() >= MAX_GROUP_CALL_PARTICIPANTS {
+            return vec![ServerMessage::Error {
+                message: "Call is full".to_string(),
+                code: None,
+            }];
+        }
+        call.participants.insert(user_id.to_string());
+        call.participants.iter().filter(|id| *id != user_id).cloned().collect()
+    };
+
+    notify(state, &others, ServerMessage::CallParticipantJoined {
+        call_id: call_id.to_string(),
+        user_id: user_id.to_string(),
+    });
+    vec![ServerMessage::CallParticipants {
+        call_id: call_id.to_string(),
+        user_ids: others,
+    }]
+}
+
+/// Remove a user from a call, ending it once nobody is left
+pub fn leave(state: &AppState, call_id: &str, user_id: &str) {
+    let remaining: Vec<String> = {
+        let Some(mut call) = state.group_calls.get_mut(call_id) else {
+            return;
+        };
+        if !call.participants.remove(user_id) {
+            return;
+        }
+        call.participants.iter().cloned().collect()
+    };
+
+    if remaining.is_empty() {
+        state.group_calls.remove_if(call_id, |_, call| call.participants.is_empty());
+        tracing::info!("Group call {} ended", call_id);
+    }
+
+    notify(state, &remaining, ServerMessage::CallParticipantLeft {
+        call_id: call_id.to_string(),
+        user_id: user_id.to_string(),
+    });
+}
+
+/// Take a disconnecting user out of every call they were in
+pub fn leave_all(state: &AppState, user_id: &str) {
+    let call_ids: Vec<String> = state
+        .group_calls
+        .iter()
+        .filter(|entry| entry.participants.contains(user_id))
+        .map(|entry| entry.key().clone())
+        .collect();
+
+    for call_id in call_ids {
+        leave(state, &call_id, user_id);
+    }
+}
+
+/// Whether both users are currently in the call, so signals between them may be relayed
+pub fn are_participants(state: &AppState, call_id: &str, from_user_id: &str, to_user_id: &str) -> bool {
+    state
+        .group_calls
+        .get(call_id)
+        .is_some_and(|call| call.participants.contains(from_user_id) && call.participants.contains(to_user_id))
+}
+
+fn notify(state: &AppState, user_ids: &[String], msg: ServerMessage) {
+    for user_id in user_ids {
+        if let Some(tx) = state.user_sockets.get(user_id) {
+            let _ = tx.send(msg.clone());
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Async locks created on demand per key (e.g. a message id), so work on one
+/// key is serialized without holding up any other. A key's lock is dropped
+/// again once nobody holds or waits for it.
+#[derive(Default)]
+pub struct KeyedLocks {
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+impl KeyedLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for exclusive use of `key`, held until the guard is dropped
+    pub async fn lock(&self, key: &str) -> KeyedGuard<'_> {
+        let lock = self.locks.entry(key.to_string()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        KeyedGuard {
+            locks: self,
+            key: key.to_string(),
+            guard: Some(guard),
+        }
+    }
+
+    /// Keys that currently have a lock
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.len()
+    }
+}
+
+pub struct KeyedGuard<'a> {
+    locks: &'a KeyedLocks,
+    key: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for KeyedGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        // Only the map's own reference left: nobody else holds or waits for it.
+        // Anyone about to take it needs this shard, so they can't slip in between.
+        self.locks.locks.remove_if(&self.key, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn same_key_waits_for_the_holder() {
+        let locks = Arc::new(KeyedLocks::new());
+        let guard = locks.lock("m1").await;
+
+        let waiting = {
+            let locks = locks.clone();
+            tokio::spawn(async move {
+                let _guard = locks.lock("m1").await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(guard);
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_keys_are_not_blocked() {
+        let locks = KeyedLocks::new();
+        let _guard = locks.lock("m1").await;
+
+        let other = tokio::time::timeout(Duration::from_millis(100), locks.lock("m2")).await;
+
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unused_locks_are_dropped() {
+        let locks = KeyedLocks::new();
+
+        drop(locks.lock("m1").await);
+
+        assert_eq!(locks.len(), 0);
+    }
+}
@@ -0,0 +1,449 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{redirect, Client, Url};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a preview fetch may take, end to end
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes of a page read while looking for OpenGraph tags
+const MAX_BODY_BYTES: usize = 512 * 1024;
+
+/// Most redirects followed for a single preview
+const MAX_REDIRECTS: usize = 3;
+
+/// Longest title/description kept, in characters
+const MAX_TEXT_CHARS: usize = 300;
+
+/// OpenGraph metadata of the first link in a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+/// Fetches link previews without letting message authors reach internal hosts
+pub struct LinkPreviewer {
+    client: Client,
+}
+
+impl LinkPreviewer {
+    pub fn new() -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .user_agent("chat-backend link preview")
+            .dns_resolver(Arc::new(PublicOnlyResolver))
+            .redirect(redirect::Policy::custom(|attempt| {
+                match check_redirect(attempt.url(), attempt.previous().len()) {
+                    Ok(()) => attempt.follow(),
+                    Err(reason) => attempt.error(reason),
+                }
+            }))
+            .build()?;
+
+        Ok(Self { client })
+    }
+
+    /// Fetch and parse the OpenGraph tags of `url`.
+    /// Returns `None` if the page is unreachable, not HTML, or has nothing to show.
+    pub async fn fetch(&self, url: &str) -> Option<LinkPreview> {
+        let url = Url::parse(url).ok()?;
+        if !is_allowed_url(&url) {
+            tracing::debug!("Refusing link preview for {}", url);
+            return None;
+        }
+
+        self.fetch_page(url).await
+    }
+
+    /// Fetch `url` without the address check on the URL itself; redirects and
+    /// DNS lookups are still filtered by the client
+    async fn fetch_page(&self, url: Url) -> Option<LinkPreview> {
+        let mut response = match self.client.get(url.clone()).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::debug!("Link preview fetch failed for {}: {}", url, e);
+                return None;
+            }
+        };
+
+        let is_html = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        if !response.status().is_success() || !is_html {
+            return None;
+        }
+
+        // Read at most MAX_BODY_BYTES; the <head> is all we need
+        let mut body = Vec::new();
+        while let Ok(Some(chunk)) = response.chunk().await {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_BODY_BYTES {
+                body.truncate(MAX_BODY_BYTES);
+                break;
+            }
+        }
+
+        parse_open_graph(&String::from_utf8_lossy(&body), response.url())
+    }
+}
+
+/// Resolves host names but drops every address that isn't publicly routable,
+/// so a DNS name pointing at e.g. 127.0.0.1 can't be used to probe the server
+struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", name.as_str()).into());
+            }
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Whether to follow a redirect to `url` after `previous` hops already taken
+fn check_redirect(url: &Url, previous: usize) -> Result<(), &'static str> {
+    if previous >= MAX_REDIRECTS {
+        Err("too many redirects")
+    } else if !is_allowed_url(url) {
+        Err("redirect to a disallowed address")
+    } else {
+        Ok(())
+    }
+}
+
+/// Only plain http(s) URLs, and never IP literals in private ranges
+/// (those bypass the resolver entirely)
+fn is_allowed_url(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+
+    // IPv6 hosts come bracketed, e.g. "[::1]"
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => is_public_ip(ip),
+        Err(_) => true,
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Shared address space (carrier-grade NAT), 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // "This network", 0.0.0.0/8
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// First http(s) URL in a message, if any
+pub fn find_first_url(content: &str) -> Option<&str> {
+    content
+        .split_whitespace()
+        .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+        // Drop punctuation that usually ends a sentence rather than the URL
+        .map(|word| word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']))
+}
+
+/// Pull `og:title`, `og:description` and `og:image` (falling back to `<title>`
+/// and the plain description meta tag) out of an HTML document
+fn parse_open_graph(html: &str, base: &Url) -> Option<LinkPreview> {
+    let mut title = None;
+    let mut description = None;
+    let mut image = None;
+    let mut fallback_description = None;
+
+    let mut rest = html;
+    while let Some(start) = find_ignore_case(rest, "<meta") {
+        let tag_body = &rest[start + "<meta".len()..];
+        let Some(end) = tag_body.find('>') else {
+            break;
+        };
+        let tag = &tag_body[..end];
+        rest = &tag_body[end..];
+
+        let key = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+        let (Some(key), Some(content)) = (key, attribute(tag, "content")) else {
+            continue;
+        };
+
+        match key.to_ascii_lowercase().as_str() {
+            "og:title" => title = title.or(Some(content)),
+            "og:description" => description = description.or(Some(content)),
+            "og:image" => image = image.or(Some(content)),
+            "description" => fallback_description = fallback_description.or(Some(content)),
+            _ => {}
+        }
+    }
+
+    let title = title.or_else(|| {
+        let start = find_ignore_case(html, "<title")?;
+        let after = &html[start..];
+        let open_end = after.find('>')? + 1;
+        let close = find_ignore_case(&after[open_end..], "</title")?;
+        Some(after[open_end..open_end + close].to_string())
+    });
+
+    let preview = LinkPreview {
+        url: base.to_string(),
+        title: title.map(|t| clean_text(&t)).filter(|t| !t.is_empty()),
+        description: description
+            .or(fallback_description)
+            .map(|d| clean_text(&d))
+            .filter(|d| !d.is_empty()),
+        // Relative image paths are resolved against the final (post-redirect) URL
+        image: image
+            .and_then(|i| base.join(&decode_entities(&i)).ok())
+            .filter(is_allowed_url)
+            .map(|i| i.to_string()),
+    };
+
+    if preview.title.is_none() && preview.description.is_none() && preview.image.is_none() {
+        return None;
+    }
+
+    Some(preview)
+}
+
+/// Value of `name="..."` (or single-quoted) inside a tag's attribute list
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search_from = 0;
+
+    while let Some(found) = lower[search_from..].find(name) {
+        let at = search_from + found;
+        search_from = at + name.len();
+
+        // Must be a whole attribute name, not e.g. the tail of `data-name`
+        let preceded_ok = at == 0 || lower.as_bytes()[at - 1].is_ascii_whitespace();
+        let after = lower[search_from..].trim_start();
+        if !preceded_ok || !after.starts_with('=') {
+            continue;
+        }
+
+        let value_start = tag.len() - after.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            continue;
+        }
+        let end = value[1..].find(quote)?;
+        return Some(value[1..1 + end].to_string());
+    }
+
+    None
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Decode entities, collapse whitespace and cap the length
+fn clean_text(text: &str) -> String {
+    let text = decode_entities(text).split_whitespace().collect::<Vec<_>>().join(" ");
+    text.chars().take(MAX_TEXT_CHARS).collect()
+}
+
+/// Decode the handful of entities that commonly show up in meta tags
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve one canned HTTP response per connection on a loopback port
+    async fn serve(response: String) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0u8; 4096];
+                let _ = socket.read(&mut request).await;
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+        Url::parse(&format!("http://{}/post", addr)).unwrap()
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, headers, body.len(), body)
+    }
+
+    fn previewer() -> LinkPreviewer {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        LinkPreviewer::new().unwrap()
+    }
+
+    fn allowed(url: &str) -> bool {
+        is_allowed_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn private_ip_literals_are_refused() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://127.1.2.3:8080/admin",
+            "http://localhost.:80@127.0.0.1/",
+            "http://10.0.0.5/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://100.64.0.1/",
+            "http://100.127.255.254/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:10.0.0.1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+        ] {
+            assert!(!allowed(url), "{} should be refused", url);
+        }
+    }
+
+    #[test]
+    fn public_hosts_are_allowed() {
+        for url in ["https://example.com/page", "http://93.184.216.34/", "http://100.128.0.1/", "https://[2606:4700::1111]/"] {
+            assert!(allowed(url), "{} should be allowed", url);
+        }
+    }
+
+    #[test]
+    fn only_http_schemes_are_allowed() {
+        for url in ["file:///etc/passwd", "ftp://example.com/", "gopher://example.com/"] {
+            assert!(!allowed(url), "{} should be refused", url);
+        }
+    }
+
+    #[test]
+    fn redirects_to_private_addresses_are_refused() {
+        let private = Url::parse("http://127.0.0.1:6379/").unwrap();
+        assert_eq!(check_redirect(&private, 0), Err("redirect to a disallowed address"));
+        let internal = Url::parse("http://[::ffff:192.168.0.1]/").unwrap();
+        assert_eq!(check_redirect(&internal, 1), Err("redirect to a disallowed address"));
+
+        let public = Url::parse("https://example.com/next").unwrap();
+        assert_eq!(check_redirect(&public, 0), Ok(()));
+        assert_eq!(check_redirect(&public, MAX_REDIRECTS), Err("too many redirects"));
+    }
+
+    #[test]
+    fn open_graph_images_on_private_addresses_are_dropped() {
+        let base = Url::parse("https://example.com/post").unwrap();
+        let html = r#"<meta property="og:title" content="Post"><meta property="og:image" content="http://10.0.0.1/x.png">"#;
+
+        let preview = parse_open_graph(html, &base).unwrap();
+
+        assert_eq!(preview.title.as_deref(), Some("Post"));
+        assert_eq!(preview.image, None);
+    }
+
+    #[test]
+    fn open_graph_falls_back_to_title_and_resolves_relative_images() {
+        let base = Url::parse("https://example.com/blog/post").unwrap();
+        let html = r#"<head><title>Tom &amp; Jerry</title>
+            <meta name="description" content="A   classic">
+            <meta property="og:image" content="/img/cover.png"></head>"#;
+
+        let preview = parse_open_graph(html, &base).unwrap();
+
+        assert_eq!(preview.title.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(preview.description.as_deref(), Some("A classic"));
+        assert_eq!(preview.image.as_deref(), Some("https://example.com/img/cover.png"));
+    }
+
+    #[tokio::test]
+    async fn fetches_open_graph_tags_from_a_page() {
+        let body = r#"<html><head><meta property="og:title" content="Hello"><meta property="og:description" content="From the mock"><meta property="og:image" content="https://example.com/cover.png"></head></html>"#;
+        let url = serve(response("200 OK", "Content-Type: text/html; charset=utf-8\r\n", body)).await;
+
+        let preview = previewer().fetch_page(url.clone()).await.unwrap();
+
+        assert_eq!(preview.url, url.to_string());
+        assert_eq!(preview.title.as_deref(), Some("Hello"));
+        assert_eq!(preview.description.as_deref(), Some("From the mock"));
+        assert_eq!(preview.image.as_deref(), Some("https://example.com/cover.png"));
+    }
+
+    #[tokio::test]
+    async fn loopback_pages_are_never_fetched() {
+        let page = r#"<meta property="og:title" content="Internal">"#;
+        let url = serve(response("200 OK", "Content-Type: text/html\r\n", page)).await;
+
+        assert!(previewer().fetch(url.as_str()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn non_html_responses_have_no_preview() {
+        let url = serve(response("200 OK", "Content-Type: application/json\r\n", "{}")).await;
+
+        assert!(previewer().fetch_page(url).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn redirects_to_loopback_are_not_followed() {
+        let page = r#"<meta property="og:title" content="Internal">"#;
+        let target = serve(response("200 OK", "Content-Type: text/html\r\n", page)).await;
+        let url = serve(response("302 Found", &format!("Location: {}\r\n", target), "")).await;
+
+        assert!(previewer().fetch_page(url).await.is_none());
+    }
+}
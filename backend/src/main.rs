@@ -1,23 +1,63 @@
+mod admin;
+mod at_rest;
+mod config;
 mod db;
+mod event_log;
+mod expiry;
+mod group_call;
+mod keyed_lock;
+mod link_preview;
+mod media;
+mod mentions;
+mod moderation;
+mod outbox;
+mod presence;
+mod rate_limit;
+mod redact;
+mod retention;
+mod ringing;
+mod scheduler;
+mod sse;
+mod typing;
 
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, Query, State,
+        ConnectInfo, Path, Query, State,
     },
-    response::Response,
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
+use axum::http::{header, HeaderMap, HeaderValue, Method};
 use chrono::{DateTime, Utc};
+use config::Config;
+use group_call::GroupCalls;
 use dashmap::DashMap;
-use db::{Database, DbMessage};
+use db::{Database, DbAttachment, DbMessage, DbScheduledMessage, DbUser};
+use event_log::EventLog;
+use keyed_lock::KeyedLocks;
+use link_preview::{LinkPreview, LinkPreviewer};
+use moderation::ContentFilter;
+use outbox::{ClientSender, OutboxStats};
+use presence::PresenceSender;
+use rate_limit::RateLimiter;
+use redact::{FileData, Secret};
+use ringing::RingingCalls;
+use sse::SseConnections;
+use typing::TypingThrottle;
+use flate2::{write::ZlibEncoder, Compression};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use serde_json::error::Category;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tower_http::cors::CorsLayer;
+use std::time::Duration;
+use tower_http::{compression::CompressionLayer, cors::CorsLayer, trace::TraceLayer};
+use tracing::Instrument;
 use uuid::Uuid;
 use axum_server::tls_rustls::RustlsConfig;
 
@@ -25,8 +65,16 @@ use axum_server::tls_rustls::RustlsConfig;
 struct User {
     id: String,
     username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>, // falls back to `username` in clients when unset
     online: bool,
+    // Finer-grained than `online`: what the user set with `SetStatus` while connected
+    status: UserStatus,
     last_seen: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>, // for end-to-end encryption between clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,122 +86,944 @@ struct ChatMessage {
     timestamp: DateTime<Utc>,
     read: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    file_data: Option<String>, // base64 encoded file
+    file_data: Option<FileData>, // base64 encoded file
     #[serde(skip_serializing_if = "Option::is_none")]
     file_name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     file_type: Option<String>, // MIME type
     #[serde(skip_serializing_if = "Option::is_none")]
     audio_duration: Option<f64>, // Duration in seconds for voice messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_data: Option<FileData>, // downscaled preview for image attachments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_width: Option<u32>, // pixel size of an image attachment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_height: Option<u32>,
+    // Which of the sender's devices sent it, as the client identified itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+    // `content` is ciphertext the server can't read (so server-side search skips it)
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    delivered: bool, // reached at least one of the recipient's sockets
+    // Additional files; the single `file_*` fields above are kept for older clients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_preview: Option<LinkPreview>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>, // disappearing messages only
     #[serde(default)]
     reactions: HashMap<String, String>, // user_id -> emoji
+    // Derived from `reactions` so clients can render counts directly
+    #[serde(default)]
+    reaction_summary: HashMap<String, u32>, // emoji -> count
+    // Users the text @mentions; names that matched no user stay plain text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mentions: Vec<String>,
+    // File data was left out to keep a history page within `HISTORY_MAX_BYTES`,
+    // or of a message replayed by `Resume`; `GetMessage` returns the message in full
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    file_data_omitted: bool,
+    // Position in the conversation, counting up from 1; a gap means a message was deleted
+    #[serde(default)]
+    seq: i64,
+    // A snippet of another message this one replies to, kept as sent so it
+    // still shows after the original is edited or deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quoted_message_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quoted_text: Option<String>,
+}
+
+/// One entry of a user's inbox: the other participant and the latest message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Conversation {
+    user: User,
+    last_message: ChatMessage,
+    unread_count: i32,
+    archived: bool,
+    muted: bool,
+    // Unset for indefinite mutes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    muted_until: Option<DateTime<Utc>>,
+    notification_level: NotificationLevel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    draft: Option<String>,
+    // The user's own notes: `user` is the user themselves
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    notes_to_self: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MutedConversation {
+    user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    muted_until: Option<DateTime<Utc>>,
+}
+
+/// A conversation whose notification level isn't the default `all`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NotificationPref {
+    user_id: String,
+    level: NotificationLevel,
+}
+
+/// A message matching a `SearchAllMessages` query
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SearchResult {
+    // The other participant of the conversation the message is in
+    other_user_id: String,
+    message: ChatMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Contact {
+    user: User,
+    added_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_message: Option<ChatMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReactionDetail {
+    emoji: String,
+    user_id: String,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+}
+
+/// Presence shown to other users. Connected users choose between `online`,
+/// `away` and `busy` (do not disturb); `offline` means not connected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum UserStatus {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    Offline,
+}
+
+/// Which messages of a conversation should notify the user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NotificationLevel {
+    #[default]
+    All,
+    /// Only messages that @mention the user
+    Mentions,
+    #[serde(rename = "none")]
+    Nothing,
+}
+
+impl NotificationLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::Mentions => "mentions",
+            NotificationLevel::Nothing => "none",
+        }
+    }
+
+    /// Parse a stored level; anything unknown counts as the default
+    fn from_stored(level: Option<&str>) -> Self {
+        match level {
+            Some("mentions") => NotificationLevel::Mentions,
+            Some("none") => NotificationLevel::Nothing,
+            _ => NotificationLevel::All,
+        }
+    }
+
+    /// Whether a message should notify, given whether it @mentions the user
+    fn notifies(self, mentioned: bool) -> bool {
+        match self {
+            NotificationLevel::All => true,
+            NotificationLevel::Mentions => mentioned,
+            NotificationLevel::Nothing => false,
+        }
+    }
+}
+
+/// What a call carries, so the callee's UI can render it appropriately
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CallType {
+    Audio,
+    // Clients from before call types only made video calls
+    #[default]
+    Video,
+    Screen,
+}
+
+impl CallType {
+    fn as_str(self) -> &'static str {
+        match self {
+            CallType::Audio => "audio",
+            CallType::Video => "video",
+            CallType::Screen => "screen",
+        }
+    }
+}
+
+/// WebRTC stats a client samples during a call; every field is optional
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CallStatsReport {
+    bitrate_kbps: Option<f64>,
+    packet_loss_percent: Option<f64>,
+    round_trip_time_ms: Option<f64>,
+    jitter_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Attachment {
+    file_data: FileData, // base64 encoded file
+    file_name: String,
+    file_type: String, // MIME type
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_data: Option<FileData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_height: Option<u32>,
+}
+
+/// The body of `ClientMessage::SendMessage`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OutgoingMessage {
+    to_user_id: String,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_data: Option<FileData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_duration: Option<f64>,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    /// Makes this a disappearing message, deleted for both sides after this long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in_seconds: Option<u64>,
+    /// Client-side id echoed back in `MessageAck`. Also an idempotency key:
+    /// resending with the same id returns the stored message instead of a duplicate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_msg_id: Option<String>,
+    /// Identifies the sending device, e.g. "Chrome on Windows"; stored with the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+    /// Reply to a message the sender can see, optionally quoting part of its text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quoted_message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quoted_text: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum ClientMessage {
     // Auth messages
-    Register { username: String, password: String },
-    Login { username: String, password: Option<String> },
+    Register { username: String, password: Secret },
+    Login { username: String, password: Option<Secret> },
     // Chat messages
-    SendMessage { 
-        to_user_id: String, 
+    SendMessage(Box<OutgoingMessage>),
+    /// Store a message and deliver it once `send_at` has passed
+    ScheduleMessage {
+        to_user_id: String,
         content: String,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        file_data: Option<String>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        file_name: Option<String>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        file_type: Option<String>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        audio_duration: Option<f64>,
+        send_at: DateTime<Utc>,
+        #[serde(default)]
+        encrypted: bool,
     },
+    CancelScheduledMessage { id: String },
     MarkAsRead { message_id: String },
+    MarkConversationRead { other_user_id: String },
+    /// Everything from `other_user_id` up to and including `last_read_ts` has been read,
+    /// or up to the message numbered `last_read_seq` if given: a time covers every
+    /// message sent in that millisecond. Cheaper than per-message receipts; the
+    /// marker never moves back.
+    UpdateReadMarker {
+        other_user_id: String,
+        last_read_ts: DateTime<Utc>,
+        #[serde(default)]
+        last_read_seq: Option<i64>,
+    },
+    /// Every conversation of the caller, newest first, each flagged `archived` or not
+    GetConversations,
+    GetArchivedConversations,
+    /// Unread messages over all conversations, e.g. for an app badge
+    GetTotalUnread,
+    ArchiveConversation { other_user_id: String },
+    /// Store unsent text for a conversation; empty content discards the draft
+    SaveDraft { other_user_id: String, content: String },
+    GetDraft { other_user_id: String },
+    UnarchiveConversation { other_user_id: String },
+    /// Messages keep arriving but are flagged `muted` so the client skips notifications.
+    /// Without `until` the mute lasts until `UnmuteConversation`.
+    MuteConversation {
+        other_user_id: String,
+        #[serde(default)]
+        until: Option<DateTime<Utc>>,
+    },
+    UnmuteConversation { other_user_id: String },
+    GetMutedConversations,
+    /// Notify for `all` messages of a conversation, only ones that @mention the user, or `none`
+    SetNotificationPref { other_user_id: String, level: NotificationLevel },
+    GetNotificationPrefs,
+    /// Delete the whole conversation for both sides, or with `only_for_me` just hide it from the caller
+    ClearConversation {
+        other_user_id: String,
+        #[serde(default)]
+        only_for_me: bool,
+    },
+    /// Delete, for both sides, just the messages the caller sent in a conversation
+    DeleteMyMessages { other_user_id: String },
     Typing { to_user_id: String, is_typing: bool },
     GetOnlineUsers,
+    /// Look up specific users instead of the whole directory; unknown ids are left out
+    GetUsers { ids: Vec<String> },
+    // Contacts also decide who receives this user's presence updates
+    AddContact { user_id: String },
+    RemoveContact { user_id: String },
+    GetContacts,
+    /// Like `GetOnlineUsers`, but only reports changes relative to the ids the client already shows
+    GetOnlineUsersSince { known_ids: Vec<String> },
+    /// One message with its reactions, e.g. for reply previews; only participants may read it
+    GetMessage { message_id: String },
     GetMessageHistory { other_user_id: String, limit: Option<i32>, offset: Option<i32> },
+    /// Catch up on a conversation: messages after the one numbered `after_seq`,
+    /// oldest first, in pages of `limit`; continue from the last one's `seq` while `has_more`
+    GetMessagesAfter { other_user_id: String, after_seq: i64, limit: Option<i32> },
+    /// After reconnecting and logging in: replay the events pushed since `last_seq`,
+    /// the `seq` of the last one the client received (or of its `LoginSuccess`)
+    Resume { last_seq: u64 },
     AddReaction { message_id: String, emoji: String },
     RemoveReaction { message_id: String },
-    // WebRTC signaling messages
-    CallOffer { to_user_id: String, offer: String },
-    CallAnswer { to_user_id: String, answer: String },
+    /// Remove the user's reaction if it is `emoji`, otherwise react with `emoji`
+    ToggleReaction { message_id: String, emoji: String },
+    /// Who reacted with what, for participants of the message
+    GetReactionDetails { message_id: String },
+    /// Save a message to the caller's starred messages; only participants may star it
+    StarMessage { message_id: String },
+    UnstarMessage { message_id: String },
+    /// Starred messages from every conversation, most recently starred first
+    GetStarredMessages { limit: Option<i32>, offset: Option<i32> },
+    /// Search message text across all of the user's conversations, newest first.
+    /// End-to-end encrypted messages can't be searched.
+    SearchAllMessages {
+        query: String,
+        limit: Option<i32>,
+        offset: Option<i32>,
+    },
+    /// Flag a received or sent message for moderators; each user may report a message once
+    ReportMessage { message_id: String, reason: String },
+    /// How much of the storage quota the user's files take up
+    GetStorageUsage,
+    // End-to-end encryption key exchange
+    SetPublicKey { public_key: String },
+    GetPublicKey { user_id: String },
+    // Profile
+    SetAvatar { file_data: FileData, file_type: String },
+    /// An empty name clears it
+    SetDisplayName { name: String },
+    /// Show the user as `online`, `away` or `busy` until they change it or disconnect
+    SetStatus { status: UserStatus },
+    /// With `enabled: false` the user's reads still count locally but senders
+    /// get no `MessageRead`/`ConversationRead`
+    SetReadReceiptPrivacy { enabled: bool },
+    // WebRTC signaling messages. `call_id` ties signals to the call history;
+    // an offer without one starts a new call and the server picks the id.
+    CallOffer {
+        to_user_id: String,
+        offer: String,
+        #[serde(default)]
+        call_id: Option<String>,
+        #[serde(default)]
+        call_type: CallType,
+    },
+    CallAnswer {
+        to_user_id: String,
+        answer: String,
+        #[serde(default)]
+        call_id: Option<String>,
+        #[serde(default)]
+        call_type: CallType,
+    },
     IceCandidate { to_user_id: String, candidate: String },
-    CallEnd { to_user_id: String },
+    CallEnd {
+        to_user_id: String,
+        #[serde(default)]
+        call_id: Option<String>,
+    },
+    /// The callee declining a ringing call; `CallEnd` is for hanging up after answering
+    CallReject {
+        to_user_id: String,
+        #[serde(default)]
+        call_id: Option<String>,
+    },
+    /// Periodic quality sample from either side of a call
+    CallStats { call_id: String, stats: CallStatsReport },
+    // Group calls: a mesh where each pair of participants exchanges its own
+    // offer/answer/ICE, relayed only between members of the same call
+    StartGroupCall { user_ids: Vec<String> },
+    JoinCall { call_id: String },
+    LeaveCall { call_id: String },
+    GroupCallOffer { call_id: String, to_user_id: String, offer: String },
+    GroupCallAnswer { call_id: String, to_user_id: String, answer: String },
+    GroupIceCandidate { call_id: String, to_user_id: String, candidate: String },
+}
+
+/// Machine-readable reason attached to some `ServerMessage::Error`s
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    /// The upload would take the sender past `STORAGE_QUOTA_BYTES`
+    QuotaExceeded,
+    /// A file's type isn't in `ALLOWED_FILE_TYPES`
+    FileTypeNotAllowed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum ServerMessage {
-    // Auth responses
-    LoginSuccess { user: User },
-    RegisterSuccess { user: User },
+    // Auth responses. `last_seq` is the user's latest event number, for `Resume`.
+    LoginSuccess { user: User, last_seq: u64 },
+    RegisterSuccess { user: User, last_seq: u64 },
     AuthError { message: String },
     // Chat messages
     UserOnline { user: User },
     UserOffline { user_id: String },
-    NewMessage { message: ChatMessage },
-    MessageHistory { messages: Vec<ChatMessage>, total_count: i32, has_more: bool },
+    // Profile changes (display name, avatar)
+    UserUpdated { user: User },
+    // A connected user switched between online, away and busy
+    UserStatusChanged { user_id: String, status: UserStatus },
+    // Confirms the user's own read receipt setting; `enabled` means receipts are sent
+    ReadReceiptPrivacy { enabled: bool },
+    NewMessage {
+        message: Box<ChatMessage>,
+        // The recipient muted this conversation: show it, but don't notify
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        muted: bool,
+        // The message @mentions the recipient: highlight it and notify distinctly
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        mentioned: bool,
+        // Whether to notify, going by the recipient's mute and notification level
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        notify: bool,
+    },
+    // Lets the sender reconcile an optimistic message with the stored one
+    MessageAck { client_msg_id: String, message_id: String },
+    // `id` becomes the message id once it is sent
+    MessageScheduled { id: String, to_user_id: String, send_at: DateTime<Utc> },
+    ScheduledMessageCancelled { id: String },
+    // Ends the replay of a `Resume`. Without `complete`, some missed events were no
+    // longer kept and the client should refetch what it shows.
+    Resumed { last_seq: u64, complete: bool },
+    MessageHistory {
+        messages: Vec<ChatMessage>,
+        total_count: i32,
+        has_more: bool,
+        // Offset of the next older page; the size limit can end a page before `limit`
+        next_offset: i32,
+        // Where to scroll to when opening the conversation; unset when everything is read
+        #[serde(skip_serializing_if = "Option::is_none")]
+        first_unread_message_id: Option<String>,
+    },
+    Message { message: Box<ChatMessage> },
+    // Reply to `GetMessagesAfter`
+    MessagesAfter { other_user_id: String, messages: Vec<ChatMessage>, has_more: bool },
     MessageRead { message_id: String, user_id: String },
+    ConversationRead { user_id: String },
+    // `user_id` has read the messages they got from the recipient up to `last_read_ts`,
+    // the one numbered `last_read_seq`
+    ReadMarker { user_id: String, last_read_ts: DateTime<Utc>, last_read_seq: i64 },
+    Conversations { conversations: Vec<Conversation> },
+    TotalUnread { count: i32 },
+    Draft { other_user_id: String, content: Option<String> },
+    // Also sent when a new incoming message unarchives a conversation
+    ConversationArchived { user_id: String, archived: bool },
+    // Also sent when a timed mute runs out
+    ConversationMuted {
+        user_id: String,
+        muted: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        muted_until: Option<DateTime<Utc>>,
+    },
+    MutedConversations { conversations: Vec<MutedConversation> },
+    NotificationPrefChanged { user_id: String, level: NotificationLevel },
+    NotificationPrefs { conversations: Vec<NotificationPref> },
+    // `user_id` is the other participant of the cleared conversation
+    ConversationCleared { user_id: String },
     Typing { from_user_id: String, is_typing: bool },
     OnlineUsers { users: Vec<User> },
-    Error { message: String },
+    Users { users: Vec<User> },
+    Contacts { contacts: Vec<Contact> },
+    // Online/offline changes batched over `PRESENCE_BATCH_MS`, replacing `UserOnline`/`UserOffline`
+    PresenceUpdate { online: Vec<User>, offline: Vec<String> },
+    // Reply to `GetOnlineUsersSince`: users to add and ids to drop
+    OnlineUsersDelta { added: Vec<User>, removed: Vec<String> },
+    Error {
+        message: String,
+        // Set for errors clients handle specially
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
     Success { message: String },
-    MessageReaction { message_id: String, user_id: String, emoji: Option<String> },
+    // `emoji` is `user_id`'s reaction after the change; `reactions` is the message's full set.
+    // `removed_emoji` is set when a reaction was removed: the emoji it had.
+    MessageReaction {
+        message_id: String,
+        user_id: String,
+        emoji: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        removed_emoji: Option<String>,
+        #[serde(default)]
+        reactions: HashMap<String, String>,
+    },
+    ReactionDetails { message_id: String, reactions: Vec<ReactionDetail> },
+    // Confirms `StarMessage` (`starred: true`) and `UnstarMessage`
+    MessageStarred { message_id: String, starred: bool },
+    StarredMessages { messages: Vec<ChatMessage> },
+    SearchResults {
+        query: String,
+        results: Vec<SearchResult>,
+        has_more: bool,
+    },
+    // Confirms a `ReportMessage`
+    MessageReported { message_id: String },
+    // Bytes of file data stored by the user, and the most they may store
+    StorageUsage { used: u64, quota: u64 },
+    PublicKey { user_id: String, public_key: Option<String> },
+    MessageDeleted { message_id: String },
+    // Several messages deleted at once, e.g. by `DeleteMyMessages`
+    MessagesDeleted { message_ids: Vec<String> },
+    // Fetched in the background after the message itself was delivered
+    LinkPreview { message_id: String, preview: LinkPreview },
+    // Sent right before the server closes the connection
+    Disconnected { reason: String },
     // WebRTC signaling messages
-    CallOffer { from_user_id: String, offer: String },
-    CallAnswer { from_user_id: String, answer: String },
+    CallOffer { from_user_id: String, offer: String, call_id: String, call_type: CallType },
+    CallAnswer {
+        from_user_id: String,
+        answer: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        call_id: Option<String>,
+        call_type: CallType,
+    },
     IceCandidate { from_user_id: String, candidate: String },
-    CallEnd { from_user_id: String },
+    CallEnd {
+        from_user_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        call_id: Option<String>,
+    },
+    CallRejected {
+        from_user_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        call_id: Option<String>,
+    },
+    // Tells the caller the id a new call was recorded under
+    CallStarted { call_id: String, to_user_id: String },
+    // Sent to both sides when an offered call rang out unanswered; `user_id` is the other side
+    CallTimeout { call_id: String, user_id: String },
+    // Group calls
+    GroupCallStarted { call_id: String, invited: Vec<String> },
+    GroupCallInvite { call_id: String, from_user_id: String, invited: Vec<String> },
+    // Sent to a joiner: who is already in the call (the joiner sends them offers)
+    CallParticipants { call_id: String, user_ids: Vec<String> },
+    CallParticipantJoined { call_id: String, user_id: String },
+    CallParticipantLeft { call_id: String, user_id: String },
+    GroupCallOffer { call_id: String, from_user_id: String, to_user_id: String, offer: String },
+    GroupCallAnswer { call_id: String, from_user_id: String, to_user_id: String, answer: String },
+    GroupIceCandidate { call_id: String, from_user_id: String, to_user_id: String, candidate: String },
 }
 
 type OnlineUsers = Arc<DashMap<String, User>>;
-type UserSockets = Arc<DashMap<String, tokio::sync::mpsc::UnboundedSender<ServerMessage>>>;
+type UserSockets = Arc<DashMap<String, ClientSender>>;
+type ConnectionsPerIp = Arc<DashMap<IpAddr, usize>>;
 
 #[derive(Clone)]
 struct AppState {
     db: Arc<Database>,
+    config: Arc<Config>,
     online_users: OnlineUsers,
     user_sockets: UserSockets,
+    connections_per_ip: ConnectionsPerIp,
+    /// Failed logins per (lowercased) username
+    login_failures: Arc<RateLimiter>,
+    /// Reaction changes per user, capped by `REACTION_RATE_LIMIT`
+    reaction_limiter: Arc<RateLimiter>,
+    /// Forwarded typing starts per user, capped by `TYPING_RATE_LIMIT`
+    typing_limiter: Arc<RateLimiter>,
+    typing_throttle: Arc<TypingThrottle>,
+    /// Present only when `LINK_PREVIEWS` is enabled
+    link_previewer: Option<Arc<LinkPreviewer>>,
+    /// Present only when `CONTENT_FILTER_WORDLIST` is set
+    content_filter: Option<Arc<ContentFilter>>,
+    sse_connections: SseConnections,
+    group_calls: GroupCalls,
+    ringing_calls: RingingCalls,
+    outbox_stats: Arc<OutboxStats>,
+    presence: PresenceSender,
+    event_log: Arc<EventLog>,
+    /// Serializes reaction changes per message so each broadcast reflects the
+    /// stored state and broadcasts reach clients in the order the changes were made
+    reaction_locks: Arc<KeyedLocks>,
+}
+
+impl ServerMessage {
+    /// The copy kept in the event log: new messages without attachment contents
+    /// or thumbnails (`file_data_omitted`), which clients fetch with `GetMessage`
+    fn for_replay(&self) -> ServerMessage {
+        let mut message = self.clone();
+        if let ServerMessage::NewMessage { message: chat_message, .. } = &mut message {
+            omit_file_data(chat_message);
+            chat_message.thumbnail_data = None;
+            for attachment in chat_message.attachments.iter_mut() {
+                attachment.thumbnail_data = None;
+            }
+        }
+        message
+    }
+}
+
+impl AppState {
+    /// Fresh state around an initialized database, with nobody connected
+    fn new(
+        config: Config,
+        db: Database,
+        link_previewer: Option<Arc<LinkPreviewer>>,
+        content_filter: Option<Arc<ContentFilter>>,
+    ) -> Self {
+        Self {
+            db: Arc::new(db),
+            online_users: Arc::new(DashMap::new()),
+            user_sockets: Arc::new(DashMap::new()),
+            connections_per_ip: Arc::new(DashMap::new()),
+            login_failures: Arc::new(RateLimiter::new(
+                config.login_max_failures,
+                Duration::from_secs(config.login_failure_window_secs),
+            )),
+            reaction_limiter: Arc::new(RateLimiter::new(config.reaction_rate_limit, RATE_LIMIT_WINDOW)),
+            typing_limiter: Arc::new(RateLimiter::new(config.typing_rate_limit, RATE_LIMIT_WINDOW)),
+            typing_throttle: Arc::new(TypingThrottle::new(Duration::from_millis(config.typing_throttle_ms))),
+            link_previewer,
+            content_filter,
+            sse_connections: Arc::new(DashMap::new()),
+            group_calls: Arc::new(DashMap::new()),
+            ringing_calls: Arc::new(DashMap::new()),
+            outbox_stats: Arc::new(OutboxStats::default()),
+            presence: presence::channel(),
+            event_log: Arc::new(EventLog::new(
+                config.resume_buffer_size,
+                config.resume_buffer_bytes,
+                Duration::from_secs(config.resume_retention_secs),
+            )),
+            reaction_locks: Arc::new(KeyedLocks::new()),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Push an event to `user_id`'s connection, if they have one, and keep it in
+    /// their event log for `Resume`. Returns whether it was queued, or `None`
+    /// when the user isn't connected.
+    fn send_event(&self, user_id: &str, message: ServerMessage) -> Option<bool> {
+        let tx = self.user_sockets.get(user_id).map(|tx| tx.clone());
+        self.event_log.send(user_id, message, tx.as_ref())
+    }
+
+    /// `user_id`'s current status; `Offline` unless they are connected
+    fn user_status(&self, user_id: &str) -> UserStatus {
+        self.online_users.get(user_id).map_or(UserStatus::Offline, |user| user.status)
+    }
+
+    /// Send a presence change (`UserOnline`/`UserOffline`) about `user_id` to the
+    /// connected users who have them as a contact, via the presence channel
+    async fn broadcast_presence(&self, user_id: &str, message: ServerMessage) {
+        presence::publish_to_contacts(self, user_id, message).await;
+    }
+
+    /// Reload a user's profile after a change, refresh their presence entry and
+    /// publish `UserUpdated` to every connected client (the user's own included).
+    /// Call this after any mutation of a field exposed in `User`.
+    async fn broadcast_user_updated(&self, user_id: &str) {
+        let db_user = match self.db.get_user_by_id(user_id).await {
+            Ok(Some(db_user)) => db_user,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::error!("Failed to reload user {}: {:?}", user_id, e);
+                return;
+            }
+        };
+
+        let user = db_user_to_user(&db_user, self.user_status(user_id));
+        if let Some(mut entry) = self.online_users.get_mut(user_id) {
+            *entry = user.clone();
+        }
+
+        presence::publish_to_everyone(self, user_id, ServerMessage::UserUpdated { user });
+    }
 }
 
+/// Holds one of an IP's connection slots; releases it when the socket closes
+struct ConnectionSlot {
+    ip: IpAddr,
+    connections_per_ip: ConnectionsPerIp,
+}
+
+impl ConnectionSlot {
+    /// Reserve a slot for `ip`, or `None` if it is already at the limit
+    fn acquire(connections_per_ip: &ConnectionsPerIp, ip: IpAddr, limit: usize) -> Option<Self> {
+        let mut count = connections_per_ip.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+
+        Some(Self {
+            ip,
+            connections_per_ip: connections_per_ip.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.connections_per_ip.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+        }
+        self.connections_per_ip.remove_if(&self.ip, |_, count| *count == 0);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WebSocketParams {
+    /// `deflate` opts in to zlib-compressed binary frames for large payloads.
+    /// This stands in for `permessage-deflate`, which tungstenite (and so axum)
+    /// can't negotiate; see `encode_frame`.
+    compress: Option<String>,
+}
+
+/// Message schema version, negotiated through the WebSocket subprotocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProtocolVersion {
+    V1,
+}
+
+impl ProtocolVersion {
+    /// Subprotocols the server speaks, in order of preference
+    const SUPPORTED: &'static [&'static str] = &["chat.v1"];
+
+    fn from_subprotocol(name: &str) -> Option<Self> {
+        match name {
+            "chat.v1" => Some(Self::V1),
+            _ => None,
+        }
+    }
+
+    /// Pick a version from the client's `Sec-WebSocket-Protocol` offer.
+    /// Clients that offer nothing predate versioning and get v1; `None` means
+    /// the client only offered versions this server doesn't know.
+    fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let offered: Vec<&str> = headers
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        if offered.is_empty() {
+            return Some(Self::V1);
+        }
+
+        Self::SUPPORTED
+            .iter()
+            .find(|supported| offered.contains(supported))
+            .and_then(|name| Self::from_subprotocol(name))
+    }
+}
+
+/// How long to wait for our close frame to go out after the client hung up
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outgoing frames at least this large are compressed for clients that opted in
+const COMPRESSION_THRESHOLD_BYTES: usize = 16 * 1024;
+
 #[derive(Debug, Deserialize)]
 struct PaginationParams {
     limit: Option<i32>,
     offset: Option<i32>,
 }
 
+/// One page of `GET /api/messages/:user1_id/:user2_id`, with the same
+/// metadata as the WebSocket `MessageHistory`
+#[derive(Debug, Serialize)]
+struct MessagePage {
+    messages: Vec<ChatMessage>,
+    total_count: i32,
+    has_more: bool,
+    next_offset: i32,
+}
+
+/// Most attachments a single message may carry
+const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+/// Longest lifetime of a disappearing message (30 days)
+const MAX_MESSAGE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// How far ahead a message may be scheduled
+const MAX_SCHEDULE_AHEAD_DAYS: i64 = 365;
+
+/// Longest display name, in characters
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+
+/// Longest `device_id`/`device_name` accepted on a message
+const MAX_DEVICE_FIELD_CHARS: usize = 64;
+
+/// Longest `quoted_text` accepted on a reply
+const MAX_QUOTED_TEXT_CHARS: usize = 500;
+
+/// Largest draft stored per conversation, in bytes
+const MAX_DRAFT_LENGTH: usize = 64 * 1024;
+
+/// Most ids accepted in one `GetUsers` request
+const MAX_USER_LOOKUP_IDS: usize = 1000;
+
+/// Pending messages loaded and queued at a time when a user logs in
+const PENDING_PAGE_SIZE: i64 = 200;
+
+/// Longest emoji accepted for a reaction, in bytes (room for ZWJ sequences with modifiers)
+const MAX_REACTION_EMOJI_BYTES: usize = 64;
+
+/// Longest reason accepted with a report, in characters
+const MAX_REPORT_REASON_CHARS: usize = 1000;
+
+/// Longest search query accepted, in characters
+const MAX_SEARCH_QUERY_CHARS: usize = 200;
+
+/// Upper bound for an encoded public key (generous for RSA-4096 in PEM/JWK form)
+const MAX_PUBLIC_KEY_LENGTH: usize = 8 * 1024;
+
+/// Longest message text shown in conversation and contact lists, in characters
+const PREVIEW_MAX_CHARS: usize = 100;
+
+/// Window of `REACTION_RATE_LIMIT` and `TYPING_RATE_LIMIT`
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Stats samples stored per participant and call (an hour at one sample every 5 seconds)
+const MAX_CALL_STATS_SAMPLES: usize = 720;
+
+/// Validate client-supplied pagination shared by the WebSocket and REST paths.
+/// Negative values are rejected; `limit` defaults to and is clamped by the configured sizes.
+/// A zero `limit` counts as one, since an empty page that has more after it would
+/// keep a client paging forever.
+fn resolve_pagination(config: &Config, limit: Option<i32>, offset: Option<i32>) -> Result<(i32, i32), &'static str> {
+    let limit = limit.unwrap_or(config.default_page_size);
+    let offset = offset.unwrap_or(0);
+
+    if limit < 0 || offset < 0 {
+        return Err("limit and offset must not be negative");
+    }
+
+    Ok((limit.min(config.max_page_size).max(1), offset))
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
+    // The TLS listener and the link preview client share one rustls crypto provider
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .expect("Failed to install the TLS crypto provider");
+
+    let config = Config::from_env();
+
+    let cipher = config.attachment_encryption_key.as_ref().map(|key| {
+        at_rest::AtRestCipher::from_base64(key.expose())
+            .unwrap_or_else(|e| panic!("Invalid ATTACHMENT_ENCRYPTION_KEY: {}", e))
+    });
+    if cipher.is_some() {
+        tracing::info!("Attachment data is encrypted at rest");
+    }
+
     // Initialize database
-    let db = Database::new("sqlite:chat.db?mode=rwc")
+    let db = Database::new(&config.database_url, cipher)
         .await
         .expect("Failed to connect to database");
     
     tracing::info!("Database connected and initialized");
 
-    let online_users: OnlineUsers = Arc::new(DashMap::new());
-    let user_sockets: UserSockets = Arc::new(DashMap::new());
-
-    let state = AppState {
-        db: Arc::new(db),
-        online_users,
-        user_sockets,
+    let link_previewer = if config.link_previews {
+        let previewer = LinkPreviewer::new().expect("Failed to build link preview client");
+        tracing::info!("Link previews enabled");
+        Some(Arc::new(previewer))
+    } else {
+        None
     };
 
+    let content_filter = config.content_filter_wordlist.as_deref().map(|path| {
+        let filter = ContentFilter::load(path, config.content_filter_action)
+            .unwrap_or_else(|e| panic!("Failed to read CONTENT_FILTER_WORDLIST {}: {}", path, e));
+        tracing::info!("Content filter enabled: {} words, action {:?}", filter.len(), filter.action());
+        Arc::new(filter)
+    });
+
+    let state = AppState::new(config.clone(), db, link_previewer, content_filter);
+
+    tokio::spawn(scheduler::run(state.clone()));
+    tokio::spawn(expiry::run(state.clone()));
+    tokio::spawn(event_log::run_pruner(state.event_log.clone()));
+    if let Some(days) = config.message_retention_days {
+        tracing::info!("Deleting messages older than {} days", days);
+        tokio::spawn(retention::run(state.clone(), days));
+    }
+
+    // Only the REST routes are compressed; the WebSocket upgrade stays outside the layer
+    let api = Router::new()
+        .route("/api/users", get(get_users))
+        .route("/api/messages/:user1_id/:user2_id", get(get_messages_api))
+        .route("/api/avatars/:user_id", get(get_avatar))
+        .merge(admin::router(state.clone()))
+        .layer(CompressionLayer::new());
+
     let app = Router::new()
         .route("/", get(health_check))
         .route("/ws", get(websocket_handler))
-        .route("/api/users", get(get_users))
-        .route("/api/messages/{user1_id}/{user2_id}", get(get_messages_api))
-        .layer(CorsLayer::permissive())
+        .merge(sse::router(config.max_ws_message_size()))
+        .merge(api)
+        .layer(TraceLayer::new_for_http())
+        .layer(cors_layer(&config))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3002));
-    
+    // Bound up front so the log reports the real port when `PORT` is 0
+    let listener = std::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], config.port)))
+        .expect("Failed to bind listener");
+    let addr = listener.local_addr().expect("Failed to read listener address");
+
+    if config.disable_tls {
+        tracing::warn!("DISABLE_TLS enabled: serving plain HTTP");
+        tracing::info!("Server running on http://{}", addr);
+
+        axum_server::from_tcp(listener)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+        return;
+    }
+
     // Configure TLS
-    let config = RustlsConfig::from_pem_file(
+    let tls_config = RustlsConfig::from_pem_file(
         "../certs/cert.pem",
         "../certs/key.pem"
     )
@@ -162,33 +1032,71 @@ async fn main() {
 
     tracing::info!("Server running on https://{}", addr);
 
-    axum_server::bind_rustls(addr, config)
+    axum_server::from_tcp_rustls(listener, tls_config)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
+/// Build the CORS layer from the configured origin allow-list.
+/// Any origin is accepted only when running in explicit dev mode.
+fn cors_layer(config: &Config) -> CorsLayer {
+    if config.dev_mode {
+        tracing::warn!("DEV_MODE enabled: CORS allows any origin");
+        return CorsLayer::permissive();
+    }
+
+    let origins: Vec<HeaderValue> = config
+        .cors_allowed_origins
+        .iter()
+        .filter_map(|origin| match origin.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                tracing::warn!("Ignoring invalid CORS origin: {}", origin);
+                None
+            }
+        })
+        .collect();
+
+    tracing::info!("CORS allowed origins: {:?}", config.cors_allowed_origins);
+
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+}
+
 async fn health_check() -> &'static str {
     "Chat server is running with SQLite persistence"
 }
 
+async fn get_avatar(State(state): State<AppState>, Path(user_id): Path<String>) -> Response {
+    match state.db.get_avatar(&user_id).await {
+        Ok(Some((data, file_type))) => (
+            [
+                (header::CONTENT_TYPE, file_type),
+                (header::CACHE_CONTROL, "public, max-age=3600".to_string()),
+            ],
+            data,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get avatar: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Every account. Nobody logs in to this API, so presence is left out and all
+/// users show as offline; clients get live status over their own connection,
+/// where `presence::Visibility` applies.
 async fn get_users(State(state): State<AppState>) -> Json<Vec<User>> {
-    // Get all users from database, with online status from memory
     match state.db.get_all_users().await {
         Ok(db_users) => {
             let users: Vec<User> = db_users
                 .iter()
-                .map(|u| {
-                    let is_online = state.online_users.contains_key(&u.id);
-                    User {
-                        id: u.id.clone(),
-                        username: u.username.clone(),
-                        online: is_online,
-                        last_seen: chrono::DateTime::parse_from_rfc3339(&u.last_seen)
-                            .map(|dt| dt.with_timezone(&Utc))
-                            .unwrap_or_else(|_| Utc::now()),
-                    }
-                })
+                .map(|u| db_user_to_user(u, UserStatus::Offline))
                 .collect();
             Json(users)
         }
@@ -199,519 +1107,3227 @@ async fn get_users(State(state): State<AppState>) -> Json<Vec<User>> {
     }
 }
 
+/// A page of the conversation between two users, in chronological order like
+/// `MessageHistory`: `offset` counts back from the newest message, and the page
+/// is returned oldest first. Nobody logs in to this API, so attachment contents
+/// and thumbnails are always left out (`file_data_omitted`); clients get those
+/// with `GetMessage` on their own connection.
 async fn get_messages_api(
     State(state): State<AppState>,
     Path((user1_id, user2_id)): Path<(String, String)>,
     Query(params): Query<PaginationParams>,
-) -> Json<Vec<ChatMessage>> {
-    let limit = params.limit.unwrap_or(50);
-    let offset = params.offset.unwrap_or(0);
+) -> Result<Json<MessagePage>, (StatusCode, &'static str)> {
+    let (limit, offset) = resolve_pagination(&state.config, params.limit, params.offset)
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
 
     match state.db.get_messages_between_users(&user1_id, &user2_id, limit, offset).await {
         Ok(db_messages) => {
-            let message_ids: Vec<String> = db_messages.iter().map(|m| m.id.clone()).collect();
-            let reactions_map = state.db.get_reactions_batch(&message_ids).await.unwrap_or_default();
-
-            let messages: Vec<ChatMessage> = db_messages
-                .into_iter()
-                .map(|m| {
-                    let reactions = reactions_map.get(&m.id).cloned();
-                    db_message_to_chat_message(m, reactions)
-                })
-                .collect();
-            Json(messages)
+            let mut messages = load_chat_messages(&state, db_messages).await;
+            for message in messages.iter_mut() {
+                omit_file_data(message);
+                message.thumbnail_data = None;
+                for attachment in message.attachments.iter_mut() {
+                    attachment.thumbnail_data = None;
+                }
+            }
+            // The page comes newest first; trim it to size from the oldest end,
+            // then flip it into chronological order
+            fit_history_page(&mut messages, state.config.history_max_bytes);
+            messages.reverse();
+            let total_count = state.db.get_message_count_between_users(&user1_id, &user2_id)
+                .await
+                .unwrap_or(0);
+            let next_offset = offset.saturating_add(messages.len() as i32);
+            Ok(Json(MessagePage {
+                messages,
+                total_count,
+                has_more: next_offset < total_count,
+                next_offset,
+            }))
         }
         Err(e) => {
             tracing::error!("Failed to get messages: {:?}", e);
-            Json(vec![])
+            Ok(Json(MessagePage {
+                messages: vec![],
+                total_count: 0,
+                has_more: false,
+                next_offset: offset,
+            }))
         }
     }
 }
 
-fn db_message_to_chat_message(m: DbMessage, reactions: Option<HashMap<String, String>>) -> ChatMessage {
-    ChatMessage {
-        id: m.id,
-        from_user_id: m.from_user_id,
-        to_user_id: m.to_user_id,
-        content: m.content,
-        timestamp: chrono::DateTime::parse_from_rfc3339(&m.timestamp)
-            .map(|dt| dt.with_timezone(&Utc))
-            .unwrap_or_else(|_| Utc::now()),
-        read: m.read,
-        file_data: m.file_data,
-        file_name: m.file_name,
-        file_type: m.file_type,
-        audio_duration: m.audio_duration,
-        reactions: reactions.unwrap_or_default(),
+/// Cost factor a bcrypt hash was created with; it's encoded in the hash as `$2b$<cost>$...`
+fn bcrypt_hash_cost(hash: &str) -> Option<u32> {
+    hash.split('$').nth(2)?.parse().ok()
+}
+
+/// Hash a password on the blocking pool: bcrypt is slow on purpose, and run
+/// inline it would stall every other connection served by the same worker
+async fn hash_password(password: Secret, cost: u32) -> Result<String, String> {
+    match tokio::task::spawn_blocking(move || bcrypt::hash(password.expose(), cost)).await {
+        Ok(hash) => hash.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
     }
 }
 
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
-    State(state): State<AppState>,
-) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+/// Check a password against a stored hash on the blocking pool, like `hash_password`
+async fn verify_password(password: Secret, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || bcrypt::verify(password.expose(), &hash).unwrap_or(false))
+        .await
+        .unwrap_or(false)
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState) {
-    let (mut sender, mut receiver) = socket.split();
-    let (user_tx, mut user_rx) = tokio::sync::mpsc::unbounded_channel();
-    let mut current_user_id: Option<String> = None;
+/// Re-hash a verified password in the background when its stored hash uses
+/// a lower cost than currently configured, so hashes stay current over time.
+fn upgrade_password_hash(state: &AppState, db_user: &DbUser, password: Secret) {
+    let target_cost = state.config.bcrypt_cost;
+    match bcrypt_hash_cost(&db_user.password_hash) {
+        Some(cost) if cost >= target_cost => return,
+        _ => {}
+    }
 
-    // Task to send messages to the client
-    let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = user_rx.recv().await {
-            if let Ok(text) = serde_json::to_string(&msg) {
-                if sender.send(Message::Text(text)).await.is_err() {
-                    break;
-                }
-            }
+    let db = state.db.clone();
+    let user_id = db_user.id.clone();
+    tokio::spawn(async move {
+        match hash_password(password, target_cost).await {
+            Ok(hash) => match db.update_password_hash(&user_id, &hash).await {
+                Ok(()) => tracing::info!("Upgraded password hash for {} to cost {}", user_id, target_cost),
+                Err(e) => tracing::error!("Failed to store upgraded password hash: {:?}", e),
+            },
+            Err(e) => tracing::error!("Failed to re-hash password: {}", e),
         }
     });
+}
 
-    let state_clone = state.clone();
-    let user_tx_clone = user_tx.clone();
+fn db_user_to_user(u: &DbUser, status: UserStatus) -> User {
+    let online = status != UserStatus::Offline;
+    User {
+        id: u.id.clone(),
+        username: u.username.clone(),
+        display_name: u.display_name.clone(),
+        online,
+        status,
+        // Online users are, by definition, seen right now
+        last_seen: if online {
+            Utc::now()
+        } else {
+            stored_millis(u.last_seen, "last_seen", &u.id)
+        },
+        public_key: u.public_key.clone(),
+        avatar_url: u.avatar.as_deref().map(|avatar_id| avatar_url(&u.id, avatar_id)),
+    }
+}
 
-    // Task to receive messages from the client
-    let mut recv_task = tokio::spawn(async move {
-        let state = state_clone;
-        let user_tx = user_tx_clone;
+/// Where clients fetch an avatar; the version changes with every upload so it can be cached
+fn avatar_url(user_id: &str, avatar_id: &str) -> String {
+    format!("/api/avatars/{}?v={}", user_id, avatar_id)
+}
+
+fn db_attachment_to_attachment(a: DbAttachment) -> Attachment {
+    Attachment {
+        file_data: a.file_data,
+        file_name: a.file_name,
+        file_type: a.file_type,
+        thumbnail_data: a.thumbnail_data,
+        media_width: a.media_width,
+        media_height: a.media_height,
+    }
+}
+
+/// Load a user's contacts with presence and the latest message exchanged with each
+async fn load_contacts(state: &AppState, owner_id: &str) -> Result<Vec<Contact>, sqlx::Error> {
+    let mut contacts = Vec::new();
+    for (db_user, added_at) in state.db.get_contacts(owner_id).await? {
+        let last_message = state
+            .db
+            .get_messages_between_users(owner_id, &db_user.id, 1, 0)
+            .await?
+            .into_iter()
+            .next()
+            .map(|m| message_preview(db_message_to_chat_message(m, None)));
+
+        contacts.push(Contact {
+            user: db_user_to_user(&db_user, state.user_status(&db_user.id)),
+            added_at: stored_timestamp(&added_at, "contact added_at", &db_user.id),
+            last_message,
+        });
+    }
+
+    Ok(contacts)
+}
+
+/// Build `user_id`'s conversation list, optionally only the archived ones
+async fn load_conversations(state: &AppState, user_id: &str, archived_only: bool) -> Result<Vec<Conversation>, sqlx::Error> {
+    let last_messages = state.db.get_user_conversations(user_id).await?;
+    let archived = state.db.get_archived_user_ids(user_id).await?;
+    let muted = state.db.get_muted_conversations(user_id, &db::sortable_timestamp(Utc::now())).await?;
+    let notification_levels = state.db.get_notification_levels(user_id).await?;
+    let mut drafts = state.db.get_drafts(user_id).await?;
+    let unread_counts = state.db.get_unread_counts(user_id).await?;
+
+    let last_messages: Vec<(String, DbMessage)> = last_messages
+        .into_iter()
+        .map(|m| {
+            let other_user_id = if m.from_user_id == user_id { m.to_user_id.clone() } else { m.from_user_id.clone() };
+            (other_user_id, m)
+        })
+        .filter(|(other_user_id, _)| !archived_only || archived.contains(other_user_id))
+        .collect();
+    let other_user_ids: Vec<String> = last_messages.iter().map(|(id, _)| id.clone()).collect();
+    let users: HashMap<String, DbUser> = state
+        .db
+        .get_users_by_ids(&other_user_ids)
+        .await?
+        .into_iter()
+        .map(|u| (u.id.clone(), u))
+        .collect();
+
+    let mut conversations = Vec::new();
+    for (other_user_id, m) in last_messages {
+        let Some(other) = users.get(&other_user_id) else {
+            continue;
+        };
+
+        let user = db_user_to_user(other, state.user_status(&other.id));
+        conversations.push(Conversation {
+            user,
+            last_message: message_preview(db_message_to_chat_message(m, None)),
+            unread_count: unread_counts.get(&other.id).copied().unwrap_or(0),
+            archived: archived.contains(&other.id),
+            muted: muted.contains_key(&other.id),
+            muted_until: muted.get(&other.id).and_then(|until| parse_timestamp(until.as_deref()?)),
+            notification_level: NotificationLevel::from_stored(notification_levels.get(&other.id).map(String::as_str)),
+            draft: drafts.remove(&other.id),
+            notes_to_self: other.id == user_id,
+        });
+    }
+
+    Ok(conversations)
+}
+
+/// Slim a message down for the conversation and contact lists: file data is
+/// dropped (a file-only message reads as "📎 name") and long text is cut short.
+/// The full message is only sent with the history.
+fn message_preview(mut message: ChatMessage) -> ChatMessage {
+    message.file_data = None;
+    message.thumbnail_data = None;
+    message.attachments.clear();
+    message.link_preview = None;
+
+    if message.content.is_empty() {
+        if let Some(file_name) = &message.file_name {
+            message.content = format!("📎 {}", file_name);
+        }
+    } else if !message.encrypted && message.content.chars().count() > PREVIEW_MAX_CHARS {
+        // Ciphertext is left whole; a truncated one couldn't be decrypted
+        let mut truncated: String = message.content.chars().take(PREVIEW_MAX_CHARS).collect();
+        truncated.push('…');
+        message.content = truncated;
+    }
+
+    message
+}
+
+/// Keep a page of history, newest message first, within `max_bytes` of JSON.
+/// Newer messages are kept whole while they fit; after that file data is left
+/// out (`file_data_omitted`), and the page ends before the first message that
+/// doesn't fit even so. The newest message is always kept, so a page is never
+/// empty while there are messages; the next page starts after what was kept.
+fn fit_history_page(messages: &mut Vec<ChatMessage>, max_bytes: usize) {
+    let json_size = |message: &ChatMessage| serde_json::to_vec(message).map_or(0, |json| json.len());
+
+    let mut used = 0;
+    for i in 0..messages.len() {
+        let message = &mut messages[i];
+        let mut size = json_size(message);
+        if used + size > max_bytes {
+            omit_file_data(message);
+            size = json_size(message);
+        }
+        if i > 0 && used + size > max_bytes {
+            messages.truncate(i);
+            return;
+        }
+        used += size;
+    }
+}
+
+/// Drop a message's file payloads, keeping names, types and thumbnails
+fn omit_file_data(message: &mut ChatMessage) {
+    let had_file = message.file_data.take().is_some();
+    for attachment in message.attachments.iter_mut() {
+        attachment.file_data = FileData::from(String::new());
+    }
+    message.file_data_omitted = had_file || !message.attachments.is_empty();
+}
+
+/// Run message text through the content filter, if one is configured. The
+/// server can't read encrypted text, so only plain messages are filtered.
+fn filter_content(state: &AppState, content: String, encrypted: bool) -> Result<String, String> {
+    match &state.content_filter {
+        Some(filter) if !encrypted => filter.apply(content),
+        _ => Ok(content),
+    }
+}
+
+/// Whether `user_id` lets senders know they read their messages.
+/// If the setting can't be loaded, no receipt is sent.
+async fn sends_read_receipts(state: &AppState, user_id: &str) -> bool {
+    state.db.sends_read_receipts(user_id).await.unwrap_or_else(|e| {
+        tracing::error!("Failed to load read receipt setting: {:?}", e);
+        false
+    })
+}
+
+/// A new message brings an archived conversation back into the recipient's inbox
+async fn unarchive_for_recipient(state: &AppState, message: &ChatMessage) {
+    match state.db.unarchive_conversation(&message.to_user_id, &message.from_user_id).await {
+        Ok(true) => {
+            state.send_event(&message.to_user_id, ServerMessage::ConversationArchived {
+                user_id: message.from_user_id.clone(),
+                archived: false,
+            });
+        }
+        Ok(false) => {}
+        Err(e) => tracing::error!("Failed to unarchive conversation: {:?}", e),
+    }
+}
+
+/// Push a freshly stored message to its recipient, if they are online, and
+/// record the delivery. Otherwise it stays pending until their next login.
+async fn deliver_to_recipient(state: &AppState, message: &mut ChatMessage) {
+    if !state.user_sockets.contains_key(&message.to_user_id) {
+        return;
+    }
+
+    let muted = state
+        .db
+        .is_conversation_muted(&message.to_user_id, &message.from_user_id, &db::sortable_timestamp(Utc::now()))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to check mute state: {:?}", e);
+            false
+        });
+    let level = state
+        .db
+        .get_notification_level(&message.to_user_id, &message.from_user_id)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to load notification level: {:?}", e);
+            None
+        });
+    let mentioned = message.mentions.contains(&message.to_user_id);
+
+    // Fetched again, in case the recipient went offline meanwhile. The event is
+    // only kept for `Resume` once queued; otherwise the message stays pending and
+    // reaches them at their next login, and must not be replayed on top of that.
+    let Some(tx) = state.user_sockets.get(&message.to_user_id).map(|tx| tx.clone()) else {
+        return;
+    };
+    let new_message = ServerMessage::NewMessage {
+        message: Box::new(message.clone()),
+        muted,
+        mentioned,
+        notify: !muted && NotificationLevel::from_stored(level.as_deref()).notifies(mentioned),
+    };
+
+    if state.event_log.send_if_queued(&message.to_user_id, new_message, &tx) {
+        message.delivered = true;
+        if let Err(e) = state.db.mark_messages_delivered(std::slice::from_ref(&message.id)).await {
+            tracing::error!("Failed to mark message delivered: {:?}", e);
+        }
+    } else {
+        // The recipient's socket is gone but its cleanup hasn't run yet.
+        // Drop the stale channel; the message is pushed on their next login.
+        tracing::warn!("Stale socket for {}, message {} left undelivered", message.to_user_id, message.id);
+        state.user_sockets.remove_if(&message.to_user_id, |_, tx| tx.is_closed());
+    }
+}
+
+/// Fetch a preview for the first link in `message` in the background, store it
+/// and push it to both participants. No-op when link previews are disabled.
+fn spawn_link_preview(state: &AppState, message: &ChatMessage) {
+    let Some(previewer) = state.link_previewer.clone() else {
+        return;
+    };
+    let Some(url) = link_preview::find_first_url(&message.content).map(str::to_string) else {
+        return;
+    };
+
+    let state = state.clone();
+    let message_id = message.id.clone();
+    let mut participants = vec![message.from_user_id.clone(), message.to_user_id.clone()];
+    participants.dedup();
+
+    tokio::spawn(async move {
+        let Some(preview) = previewer.fetch(&url).await else {
+            return;
+        };
+
+        match state.db.save_link_preview(&message_id, &preview).await {
+            Ok(()) => {}
+            Err(e) if db::is_foreign_key_violation(&e) => {
+                tracing::debug!("Message {} was deleted before its link preview arrived", message_id);
+                return;
+            }
+            Err(e) => {
+                tracing::error!("Failed to save link preview: {:?}", e);
+                return;
+            }
+        }
+
+        for participant in &participants {
+            state.send_event(participant, ServerMessage::LinkPreview {
+                message_id: message_id.clone(),
+                preview: preview.clone(),
+            });
+        }
+    });
+}
+
+/// Load the attachments of several messages, keyed by message id
+async fn load_attachments(state: &AppState, message_ids: &[String]) -> HashMap<String, Vec<Attachment>> {
+    match state.db.get_attachments_batch(message_ids).await {
+        Ok(attachments_map) => attachments_map
+            .into_iter()
+            .map(|(id, list)| (id, list.into_iter().map(db_attachment_to_attachment).collect()))
+            .collect(),
+        Err(e) => {
+            tracing::error!("Failed to load attachments: {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Turn stored messages into what clients get, with reactions (and their
+/// per-emoji summary), attachments and link previews filled in. Every
+/// transport returning history goes through this, so they all agree.
+async fn load_chat_messages(state: &AppState, db_messages: Vec<DbMessage>) -> Vec<ChatMessage> {
+    let message_ids: Vec<String> = db_messages.iter().map(|m| m.id.clone()).collect();
+    let mut reactions_map = state.db.get_reactions_batch(&message_ids).await.unwrap_or_default();
+    let mut attachments_map = load_attachments(state, &message_ids).await;
+    let mut previews_map = state.db.get_link_previews_batch(&message_ids).await.unwrap_or_default();
+    let mut mentions_map = state.db.get_mentions_batch(&message_ids).await.unwrap_or_default();
+
+    // A message is read once it is behind its recipient's read marker, even if
+    // its own flag was never set
+    let conversations: Vec<(String, String)> = db_messages
+        .iter()
+        .map(|m| (m.to_user_id.clone(), m.from_user_id.clone()))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let read_markers = state.db.get_read_markers_batch(&conversations).await.unwrap_or_else(|e| {
+        tracing::error!("Failed to load read markers: {:?}", e);
+        HashMap::new()
+    });
+
+    db_messages
+        .into_iter()
+        .map(|m| {
+            let reactions = reactions_map.remove(&m.id);
+            let attachments = attachments_map.remove(&m.id).unwrap_or_default();
+            let link_preview = previews_map.remove(&m.id);
+            let mentions = mentions_map.remove(&m.id).unwrap_or_default();
+            let read = m.read
+                || read_markers
+                    .get(&(m.to_user_id.clone(), m.from_user_id.clone()))
+                    .is_some_and(|&marker| m.seq <= marker);
+            ChatMessage {
+                attachments,
+                link_preview,
+                mentions,
+                read,
+                ..db_message_to_chat_message(m, reactions)
+            }
+        })
+        .collect()
+}
+
+fn db_message_to_chat_message(m: DbMessage, reactions: Option<HashMap<String, String>>) -> ChatMessage {
+    ChatMessage {
+        timestamp: stored_millis(m.timestamp, "message", &m.id),
+        id: m.id,
+        from_user_id: m.from_user_id,
+        to_user_id: m.to_user_id,
+        content: m.content,
+        read: m.read,
+        file_data: m.file_data,
+        file_name: m.file_name,
+        file_type: m.file_type,
+        audio_duration: m.audio_duration,
+        thumbnail_data: m.thumbnail_data,
+        media_width: m.media_width,
+        media_height: m.media_height,
+        device_id: m.device_id,
+        device_name: m.device_name,
+        encrypted: m.encrypted,
+        delivered: m.delivered,
+        attachments: Vec::new(),
+        link_preview: None,
+        expires_at: m.expires_at.as_deref().and_then(parse_timestamp),
+        reaction_summary: reactions.as_ref().map(summarize_reactions).unwrap_or_default(),
+        reactions: reactions.unwrap_or_default(),
+        mentions: Vec::new(),
+        file_data_omitted: false,
+        seq: m.seq,
+        quoted_message_id: m.quoted_message_id,
+        quoted_text: m.quoted_text,
+    }
+}
+
+/// Count how many users reacted with each emoji
+fn summarize_reactions(reactions: &HashMap<String, String>) -> HashMap<String, u32> {
+    let mut summary = HashMap::new();
+    for emoji in reactions.values() {
+        *summary.entry(emoji.clone()).or_insert(0) += 1;
+    }
+    summary
+}
+
+/// Explain why a client message couldn't be parsed without echoing its contents
+/// (serde's messages can quote the offending value, which may be a password)
+fn describe_parse_error(e: &serde_json::Error) -> String {
+    let detail = match e.classify() {
+        Category::Data => {
+            let text = e.to_string();
+            // Enum-valued fields (e.g. `call_type`) report unknown variants too
+            if text.starts_with("unknown variant") && text.contains("`Register`") {
+                "unknown message type".to_string()
+            } else if let Some(field) = text.strip_prefix("missing field ") {
+                // Field names come from our own schema, so they are safe to repeat
+                format!("missing field {}", field.split_whitespace().next().unwrap_or_default())
+            } else if e.line() == 0 {
+                // Tagged enums are parsed from a buffer, so there's no position to report
+                "invalid field value".to_string()
+            } else {
+                format!("invalid value at line {}, column {}", e.line(), e.column())
+            }
+        }
+        Category::Syntax | Category::Eof | Category::Io => {
+            format!("malformed JSON at line {}, column {}", e.line(), e.column())
+        }
+    };
+
+    format!("Invalid message format: {}", detail)
+}
+
+fn parse_timestamp(at: &str) -> Option<DateTime<Utc>> {
+    chrono::DateTime::parse_from_rfc3339(at)
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok()
+}
+
+/// Parse a timestamp column that should always be valid. A malformed value is
+/// logged and mapped to the Unix epoch: substituting the current time would
+/// make an old row look brand new and move it to the end of every history.
+fn stored_timestamp(at: &str, what: &str, id: &str) -> DateTime<Utc> {
+    parse_timestamp(at).unwrap_or_else(|| {
+        tracing::warn!("Malformed stored timestamp {:?} on {} {}", at, what, id);
+        DateTime::UNIX_EPOCH
+    })
+}
+
+/// Epoch-milliseconds counterpart of `stored_timestamp`
+fn stored_millis(ms: i64, what: &str, id: &str) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(ms).unwrap_or_else(|| {
+        tracing::warn!("Out-of-range stored timestamp {} on {} {}", ms, what, id);
+        DateTime::UNIX_EPOCH
+    })
+}
+
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<WebSocketParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(protocol) = ProtocolVersion::negotiate(&headers) else {
+        tracing::warn!("Rejected WebSocket from {}: no supported subprotocol offered", addr.ip());
+        return (StatusCode::BAD_REQUEST, "Unsupported protocol version").into_response();
+    };
+
+    let ip = addr.ip();
+    let Some(slot) = ConnectionSlot::acquire(&state.connections_per_ip, ip, state.config.max_connections_per_ip) else {
+        tracing::warn!("Rejected WebSocket from {}: connection limit reached", ip);
+        return (StatusCode::TOO_MANY_REQUESTS, "Too many connections").into_response();
+    };
+
+    let compress = params.compress.as_deref() == Some("deflate");
+    // Oversized frames are refused by the protocol layer before they are buffered
+    let max_message_size = state.config.max_ws_message_size();
+    ws.protocols(ProtocolVersion::SUPPORTED.iter().copied())
+        .max_message_size(max_message_size)
+        .max_frame_size(max_message_size)
+        .on_upgrade(move |socket| async move {
+            handle_socket(socket, state, compress, protocol).await;
+            drop(slot);
+        })
+}
+
+/// Encode an outgoing frame, compressing large payloads when the client opted in.
+/// Compressed frames are sent as binary so clients can tell them apart from plain JSON.
+/// Unlike `permessage-deflate`, small frames (most of the traffic) skip compression
+/// entirely, and nothing keeps a per-connection deflate context in memory.
+fn encode_frame(text: String, compress: bool) -> Message {
+    if !compress || text.len() < COMPRESSION_THRESHOLD_BYTES {
+        return Message::Text(text);
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    match encoder.write_all(text.as_bytes()).and_then(|_| encoder.finish()) {
+        Ok(compressed) => {
+            tracing::debug!("Compressed frame: {} -> {} bytes", text.len(), compressed.len());
+            Message::Binary(compressed)
+        }
+        Err(e) => {
+            tracing::error!("Failed to compress frame: {:?}", e);
+            Message::Text(text)
+        }
+    }
+}
+
+/// Tell both participants of a message its stored reactions after `user_id` changed theirs.
+/// Callers hold the message's `reaction_locks` entry so concurrent toggles can't be reported out of order.
+async fn broadcast_reactions(state: &AppState, message_id: &str, user_id: &str, removed_emoji: Option<String>) {
+    let message = match state.db.get_message_by_id(message_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("Failed to load reacted message: {:?}", e);
+            return;
+        }
+    };
+    let reactions = match state.db.get_reactions(message_id).await {
+        Ok(reactions) => reactions,
+        Err(e) => {
+            tracing::error!("Failed to load reactions: {:?}", e);
+            return;
+        }
+    };
+
+    let reaction = ServerMessage::MessageReaction {
+        message_id: message_id.to_string(),
+        user_id: user_id.to_string(),
+        emoji: reactions.get(user_id).cloned(),
+        removed_emoji,
+        reactions,
+    };
+    state.send_event(&message.from_user_id, reaction.clone());
+    // Notes to self have a single participant
+    if message.to_user_id != message.from_user_id {
+        state.send_event(&message.to_user_id, reaction);
+    }
+}
+
+/// Act on one message from a client. Shared by every transport: `current_user_id`
+/// is the connection's login state. Each message has a handler returning the
+/// replies for this connection, and this is the one place they are queued on
+/// `user_tx`; only binding a session and replaying a `Resume` write to it
+/// directly, since their order against live events matters.
+async fn handle_client_message(
+    state: &AppState,
+    current_user_id: &mut Option<String>,
+    user_tx: &ClientSender,
+    client_msg: ClientMessage,
+) {
+    let replies = match (client_msg, current_user_id.clone()) {
+        (ClientMessage::Register { username, password }, _) => match handle_register(state, username, password).await {
+            Ok(authenticated) => {
+                start_session(state, current_user_id, user_tx, authenticated).await;
+                Vec::new()
+            }
+            Err(replies) => replies,
+        },
+
+        (ClientMessage::Login { username, password }, _) => match handle_login(state, username, password).await {
+            Ok(authenticated) => {
+                start_session(state, current_user_id, user_tx, authenticated).await;
+                Vec::new()
+            }
+            Err(replies) => replies,
+        },
+
+        (ClientMessage::GetUsers { .. }, None) => vec![ServerMessage::Error {
+            message: "Not authenticated".to_string(),
+            code: None,
+        }],
+
+        // Everything else needs a login and is ignored without one
+        (_, None) => Vec::new(),
+
+        (ClientMessage::SendMessage(request), Some(user_id)) => handle_send_message(state, &user_id, *request).await,
+
+        (ClientMessage::GetMessage { message_id }, Some(user_id)) => handle_get_message(state, &user_id, &message_id).await,
+
+        (ClientMessage::GetMessageHistory { other_user_id, limit, offset }, Some(user_id)) => {
+            handle_get_message_history(state, &user_id, &other_user_id, limit, offset).await
+        }
+
+        (ClientMessage::GetMessagesAfter { other_user_id, after_seq, limit }, Some(user_id)) => {
+            handle_get_messages_after(state, &user_id, other_user_id, after_seq, limit).await
+        }
+
+        (ClientMessage::Resume { last_seq }, Some(user_id)) => {
+            // Replayed events are queued under the log's lock, ahead of anything newer
+            let replay = state.event_log.replay(&user_id, last_seq, user_tx);
+            vec![ServerMessage::Resumed {
+                last_seq: replay.last_seq,
+                complete: replay.complete,
+            }]
+        }
+
+        (ClientMessage::ScheduleMessage { to_user_id, content, send_at, encrypted }, Some(user_id)) => {
+            handle_schedule_message(state, &user_id, to_user_id, content, send_at, encrypted).await
+        }
+
+        (ClientMessage::CancelScheduledMessage { id }, Some(user_id)) => handle_cancel_scheduled_message(state, &user_id, id).await,
+
+        (ClientMessage::MarkAsRead { message_id }, Some(user_id)) => handle_mark_as_read(state, &user_id, message_id).await,
+
+        (ClientMessage::MarkConversationRead { other_user_id }, Some(user_id)) => {
+            handle_mark_conversation_read(state, &user_id, &other_user_id).await
+        }
+
+        (ClientMessage::UpdateReadMarker { other_user_id, last_read_ts, last_read_seq }, Some(user_id)) => {
+            handle_update_read_marker(state, &user_id, &other_user_id, last_read_ts, last_read_seq).await
+        }
+
+        (ClientMessage::GetConversations, Some(user_id)) => handle_get_conversations(state, &user_id, false).await,
+
+        (ClientMessage::GetArchivedConversations, Some(user_id)) => handle_get_conversations(state, &user_id, true).await,
+
+        (ClientMessage::GetTotalUnread, Some(user_id)) => handle_get_total_unread(state, &user_id).await,
+
+        (ClientMessage::ArchiveConversation { other_user_id }, Some(user_id)) => {
+            handle_set_archived(state, &user_id, other_user_id, true).await
+        }
+
+        (ClientMessage::UnarchiveConversation { other_user_id }, Some(user_id)) => {
+            handle_set_archived(state, &user_id, other_user_id, false).await
+        }
+
+        (ClientMessage::MuteConversation { other_user_id, until }, Some(user_id)) => {
+            handle_mute_conversation(state, &user_id, other_user_id, until).await
+        }
+
+        (ClientMessage::UnmuteConversation { other_user_id }, Some(user_id)) => {
+            handle_unmute_conversation(state, &user_id, other_user_id).await
+        }
+
+        (ClientMessage::GetMutedConversations, Some(user_id)) => handle_get_muted_conversations(state, &user_id).await,
+
+        (ClientMessage::SetNotificationPref { other_user_id, level }, Some(user_id)) => {
+            handle_set_notification_pref(state, &user_id, other_user_id, level).await
+        }
+
+        (ClientMessage::GetNotificationPrefs, Some(user_id)) => handle_get_notification_prefs(state, &user_id).await,
+
+        (ClientMessage::SaveDraft { other_user_id, content }, Some(user_id)) => {
+            handle_save_draft(state, &user_id, &other_user_id, &content).await
+        }
+
+        (ClientMessage::GetDraft { other_user_id }, Some(user_id)) => handle_get_draft(state, &user_id, other_user_id).await,
+
+        (ClientMessage::ClearConversation { other_user_id, only_for_me }, Some(user_id)) => {
+            handle_clear_conversation(state, &user_id, other_user_id, only_for_me).await
+        }
+
+        (ClientMessage::DeleteMyMessages { other_user_id }, Some(user_id)) => {
+            handle_delete_my_messages(state, &user_id, &other_user_id).await
+        }
+
+        (ClientMessage::Typing { to_user_id, is_typing }, Some(user_id)) => handle_typing(state, &user_id, &to_user_id, is_typing),
+
+        (ClientMessage::GetOnlineUsers, Some(user_id)) => vec![ServerMessage::OnlineUsers {
+            users: presence::visible_online_users(state, &user_id).await,
+        }],
+
+        (ClientMessage::GetUsers { ids }, Some(user_id)) => handle_get_users(state, &user_id, ids).await,
+
+        (ClientMessage::AddContact { user_id: contact_id }, Some(user_id)) => handle_add_contact(state, &user_id, &contact_id).await,
+
+        (ClientMessage::RemoveContact { user_id: contact_id }, Some(user_id)) => {
+            handle_remove_contact(state, &user_id, &contact_id).await
+        }
+
+        (ClientMessage::GetContacts, Some(user_id)) => handle_get_contacts(state, &user_id).await,
+
+        (ClientMessage::GetOnlineUsersSince { known_ids }, Some(user_id)) => {
+            handle_get_online_users_since(state, &user_id, known_ids).await
+        }
+
+        (ClientMessage::AddReaction { message_id, emoji }, Some(user_id)) => handle_add_reaction(state, &user_id, &message_id, &emoji).await,
+
+        (ClientMessage::RemoveReaction { message_id }, Some(user_id)) => handle_remove_reaction(state, &user_id, &message_id).await,
+
+        (ClientMessage::ToggleReaction { message_id, emoji }, Some(user_id)) => {
+            handle_toggle_reaction(state, &user_id, &message_id, &emoji).await
+        }
+
+        (ClientMessage::GetReactionDetails { message_id }, Some(user_id)) => {
+            handle_get_reaction_details(state, &user_id, message_id).await
+        }
+
+        (ClientMessage::GetStorageUsage, Some(user_id)) => handle_get_storage_usage(state, &user_id).await,
+
+        (ClientMessage::SetPublicKey { public_key }, Some(user_id)) => handle_set_public_key(state, &user_id, &public_key).await,
+
+        (ClientMessage::SetAvatar { file_data, file_type }, Some(user_id)) => {
+            handle_set_avatar(state, &user_id, file_data, file_type).await
+        }
+
+        (ClientMessage::SetDisplayName { name }, Some(user_id)) => handle_set_display_name(state, &user_id, &name).await,
+
+        (ClientMessage::StarMessage { message_id }, Some(user_id)) => handle_star_message(state, &user_id, message_id).await,
+
+        (ClientMessage::UnstarMessage { message_id }, Some(user_id)) => handle_unstar_message(state, &user_id, message_id).await,
+
+        (ClientMessage::GetStarredMessages { limit, offset }, Some(user_id)) => {
+            handle_get_starred_messages(state, &user_id, limit, offset).await
+        }
+
+        (ClientMessage::SearchAllMessages { query, limit, offset }, Some(user_id)) => {
+            handle_search_all_messages(state, &user_id, &query, limit, offset).await
+        }
+
+        (ClientMessage::ReportMessage { message_id, reason }, Some(user_id)) => {
+            handle_report_message(state, &user_id, message_id, &reason).await
+        }
+
+        (ClientMessage::SetStatus { status }, Some(user_id)) => handle_set_status(state, &user_id, status).await,
+
+        (ClientMessage::SetReadReceiptPrivacy { enabled }, Some(user_id)) => {
+            handle_set_read_receipt_privacy(state, &user_id, enabled).await
+        }
+
+        (ClientMessage::GetPublicKey { user_id }, Some(_)) => handle_get_public_key(state, user_id).await,
+
+        (ClientMessage::CallOffer { to_user_id, offer, call_id, call_type }, Some(user_id)) => {
+            handle_call_offer(state, &user_id, to_user_id, offer, call_id, call_type).await
+        }
+
+        (ClientMessage::CallAnswer { to_user_id, answer, call_id, call_type }, Some(user_id)) => {
+            handle_call_answer(state, &user_id, &to_user_id, answer, call_id, call_type).await
+        }
+
+        (ClientMessage::IceCandidate { to_user_id, candidate }, Some(user_id)) => {
+            relay(state, &to_user_id, ServerMessage::IceCandidate {
+                from_user_id: user_id,
+                candidate,
+            })
+        }
+
+        (ClientMessage::CallEnd { to_user_id, call_id }, Some(user_id)) => handle_call_end(state, &user_id, &to_user_id, call_id).await,
+
+        (ClientMessage::CallReject { to_user_id, call_id }, Some(user_id)) => {
+            handle_call_reject(state, &user_id, &to_user_id, call_id).await
+        }
+
+        (ClientMessage::CallStats { call_id, stats }, Some(user_id)) => handle_call_stats(state, &user_id, &call_id, stats).await,
+
+        (ClientMessage::StartGroupCall { user_ids }, Some(user_id)) => group_call::start(state, &user_id, user_ids),
+
+        (ClientMessage::JoinCall { call_id }, Some(user_id)) => group_call::join(state, &call_id, &user_id),
+
+        (ClientMessage::LeaveCall { call_id }, Some(user_id)) => {
+            group_call::leave(state, &call_id, &user_id);
+            Vec::new()
+        }
+
+        (ClientMessage::GroupCallOffer { call_id, to_user_id, offer }, Some(user_id)) => {
+            let signal = ServerMessage::GroupCallOffer {
+                call_id: call_id.clone(),
+                from_user_id: user_id.clone(),
+                to_user_id: to_user_id.clone(),
+                offer,
+            };
+            relay_group_signal(state, &call_id, &user_id, &to_user_id, signal)
+        }
+
+        (ClientMessage::GroupCallAnswer { call_id, to_user_id, answer }, Some(user_id)) => {
+            let signal = ServerMessage::GroupCallAnswer {
+                call_id: call_id.clone(),
+                from_user_id: user_id.clone(),
+                to_user_id: to_user_id.clone(),
+                answer,
+            };
+            relay_group_signal(state, &call_id, &user_id, &to_user_id, signal)
+        }
+
+        (ClientMessage::GroupIceCandidate { call_id, to_user_id, candidate }, Some(user_id)) => {
+            let signal = ServerMessage::GroupIceCandidate {
+                call_id: call_id.clone(),
+                from_user_id: user_id.clone(),
+                to_user_id: to_user_id.clone(),
+                candidate,
+            };
+            relay_group_signal(state, &call_id, &user_id, &to_user_id, signal)
+        }
+    };
+
+    for reply in replies {
+        let _ = user_tx.send(reply);
+    }
+}
+
+/// An account a connection is let into by `Register` or `Login`
+enum Authenticated {
+    /// Created by `Register`
+    Registered(User),
+    /// An existing account
+    LoggedIn(User),
+    /// Created by a passwordless `Login` for an unknown username
+    AutoRegistered(User),
+}
+
+/// Bind the connection to the account it authenticated as. Everything goes
+/// straight to `user_tx`: once the socket is bound, live events can reach it,
+/// and the success reply has to be queued ahead of them. A returning user then
+/// gets the messages that waited for them.
+async fn start_session(
+    state: &AppState,
+    current_user_id: &mut Option<String>,
+    user_tx: &ClientSender,
+    authenticated: Authenticated,
+) {
+    let user = match &authenticated {
+        Authenticated::Registered(user) | Authenticated::LoggedIn(user) | Authenticated::AutoRegistered(user) => user.clone(),
+    };
+    let user_id = user.id.clone();
+
+    state.online_users.insert(user_id.clone(), user.clone());
+    *current_user_id = Some(user_id.clone());
+    tracing::Span::current().record("user_id", user_id.as_str());
+    state.user_sockets.insert(user_id.clone(), user_tx.clone());
+    presence::subscribe(state, &user_id, user_tx);
+    // Read after the socket is registered, so later events reach it live
+    let last_seq = state.event_log.track(&user_id);
+
+    let success = match authenticated {
+        Authenticated::Registered(_) => ServerMessage::RegisterSuccess { user: user.clone(), last_seq },
+        Authenticated::LoggedIn(_) | Authenticated::AutoRegistered(_) => ServerMessage::LoginSuccess { user: user.clone(), last_seq },
+    };
+    let _ = user_tx.send(success);
+
+    // Send online users list (excluding self)
+    let online_users = presence::visible_online_users(state, &user_id).await;
+    let _ = user_tx.send(ServerMessage::OnlineUsers {
+        users: online_users,
+    });
+
+    // Notify other users
+    state.broadcast_presence(&user_id, ServerMessage::UserOnline { user }).await;
+
+    if matches!(authenticated, Authenticated::LoggedIn(_)) {
+        deliver_pending_messages(state, &user_id, user_tx).await;
+        // Update last seen
+        let _ = state.db.update_last_seen(&user_id).await;
+    }
+}
+
+/// Push the messages that arrived while `user_id` had no live socket, a page at
+/// a time so a long backlog can't overflow the queue. Each page is marked
+/// delivered once it is queued.
+async fn deliver_pending_messages(state: &AppState, user_id: &str, user_tx: &ClientSender) {
+    let muted = state
+        .db
+        .get_muted_conversations(user_id, &db::sortable_timestamp(Utc::now()))
+        .await
+        .unwrap_or_default();
+    let notification_levels = state.db.get_notification_levels(user_id).await.unwrap_or_default();
+
+    loop {
+        let pending = match state.db.get_undelivered_messages(user_id, PENDING_PAGE_SIZE).await {
+            Ok(pending) if !pending.is_empty() => pending,
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Failed to load undelivered messages: {:?}", e);
+                return;
+            }
+        };
+        let last_page = pending.len() < PENDING_PAGE_SIZE as usize;
+        let ids: Vec<String> = pending.iter().map(|m| m.id.clone()).collect();
+
+        // Reactions may have been added while the message waited
+        let page = load_chat_messages(state, pending)
+            .await
+            .into_iter()
+            .map(|mut message| {
+                message.delivered = true;
+                let muted = muted.contains_key(&message.from_user_id);
+                let mentioned = message.mentions.iter().any(|id| id == user_id);
+                let level = NotificationLevel::from_stored(notification_levels.get(&message.from_user_id).map(String::as_str));
+                let notify = !muted && level.notifies(mentioned);
+                ServerMessage::NewMessage { message: Box::new(message), muted, mentioned, notify }
+            })
+            .collect();
+
+        if user_tx.send_batch(page).await.is_err() {
+            return;
+        }
+        if let Err(e) = state.db.mark_messages_delivered(&ids).await {
+            tracing::error!("Failed to mark messages delivered: {:?}", e);
+            return;
+        }
+        if last_page {
+            return;
+        }
+    }
+}
+
+/// Create an account. Returns it for `start_session`, or the replies refusing it.
+async fn handle_register(state: &AppState, username: String, password: Secret) -> Result<Authenticated, Vec<ServerMessage>> {
+    // Check if username exists
+    match state.db.get_user_by_username(&username).await {
+        Ok(Some(_)) => Err(vec![ServerMessage::AuthError {
+            message: "Username already exists".to_string(),
+        }]),
+        Ok(None) => {
+            // Hash password and create user
+            let password_hash = match hash_password(password, state.config.bcrypt_cost).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    tracing::error!("Failed to hash password: {}", e);
+                    return Err(vec![ServerMessage::AuthError {
+                        message: "Failed to register user".to_string(),
+                    }]);
+                }
+            };
+            let user_id = Uuid::new_v4().to_string();
+
+            match state.db.create_user(&user_id, &username, &password_hash).await {
+                Ok(db_user) => {
+                    tracing::info!("User registered: {} ({})", username, user_id);
+                    Ok(Authenticated::Registered(db_user_to_user(&db_user, UserStatus::Online)))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to create user: {:?}", e);
+                    Err(vec![ServerMessage::AuthError {
+                        message: "Failed to register user".to_string(),
+                    }])
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!("Database error: {:?}", e);
+            Err(vec![ServerMessage::AuthError {
+                message: "Database error".to_string(),
+            }])
+        }
+    }
+}
+
+/// Check credentials for an existing account, or create one for a passwordless
+/// login. Returns the account for `start_session`, or the replies refusing it.
+async fn handle_login(state: &AppState, username: String, password: Option<Secret>) -> Result<Authenticated, Vec<ServerMessage>> {
+    // Throttle password guessing per account
+    let throttle_key = username.to_lowercase();
+    if state.login_failures.is_limited(&throttle_key) {
+        tracing::warn!("Login for {} refused: too many failed attempts", username);
+        return Err(vec![ServerMessage::AuthError {
+            message: "Too many attempts".to_string(),
+        }]);
+    }
+
+    // Check if user exists in database
+    match state.db.get_user_by_username(&username).await {
+        Ok(Some(db_user)) => {
+            // Logging in without a password only works for accounts that never set
+            // one (created by a passwordless login, with the hash of ""), and a miss
+            // counts toward the lockout like a wrong password
+            let candidate = password.clone().unwrap_or_else(|| Secret::from(String::new()));
+            let password_valid = verify_password(candidate, db_user.password_hash.clone()).await;
+
+            if !password_valid {
+                state.login_failures.record(&throttle_key);
+                return Err(vec![ServerMessage::AuthError {
+                    message: "Invalid password".to_string(),
+                }]);
+            }
+
+            state.login_failures.reset(&throttle_key);
+            if let Some(pwd) = password {
+                upgrade_password_hash(state, &db_user, pwd);
+            }
+
+            tracing::info!("User logged in: {} ({})", username, db_user.id);
+            Ok(Authenticated::LoggedIn(db_user_to_user(&db_user, UserStatus::Online)))
+        }
+        Ok(None) => {
+            if password.is_some() {
+                state.login_failures.record(&throttle_key);
+                return Err(vec![ServerMessage::AuthError {
+                    message: "User not found".to_string(),
+                }]);
+            }
+
+            // Auto-register for backward compatibility (passwordless)
+            let user_id = Uuid::new_v4().to_string();
+            let default_hash = hash_password(Secret::from(String::new()), state.config.bcrypt_cost)
+                .await
+                .unwrap_or_default();
+
+            match state.db.create_user(&user_id, &username, &default_hash).await {
+                Ok(db_user) => {
+                    tracing::info!("User auto-registered: {} ({})", username, user_id);
+                    Ok(Authenticated::AutoRegistered(db_user_to_user(&db_user, UserStatus::Online)))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to auto-register: {:?}", e);
+                    Err(vec![ServerMessage::AuthError {
+                        message: "Failed to create user".to_string(),
+                    }])
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!("Database error during login: {:?}", e);
+            Err(vec![ServerMessage::AuthError {
+                message: "Database error".to_string(),
+            }])
+        }
+    }
+}
+
+/// One message, if `user_id` can see it
+async fn handle_get_message(state: &AppState, user_id: &str, message_id: &str) -> Vec<ServerMessage> {
+    match state.db.get_message_for_user(message_id, user_id).await {
+        Ok(Some(m)) => load_chat_messages(state, vec![m])
+            .await
+            .pop()
+            .map(|message| ServerMessage::Message { message: Box::new(message) })
+            .into_iter()
+            .collect(),
+        // Same answer for missing and not-yours, so ids can't be probed
+        Ok(None) => vec![ServerMessage::Error {
+            message: "Message not found".to_string(),
+            code: None,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to get message: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load message".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// A page of the conversation with `other_user_id`, oldest first
+async fn handle_get_message_history(
+    state: &AppState,
+    user_id: &str,
+    other_user_id: &str,
+    limit: Option<i32>,
+    offset: Option<i32>,
+) -> Vec<ServerMessage> {
+    let (limit, offset) = match resolve_pagination(&state.config, limit, offset) {
+        Ok(page) => page,
+        Err(message) => {
+            return vec![ServerMessage::Error {
+                message: message.to_string(),
+                code: None,
+            }];
+        }
+    };
+
+    match state.db.get_messages_between_users(user_id, other_user_id, limit, offset).await {
+        Ok(db_messages) => {
+            let total_count = state.db.get_message_count_between_users(user_id, other_user_id)
+                .await
+                .unwrap_or(0);
+
+            // Same as the REST API: trim the newest-first page, then send it oldest first
+            let mut messages = load_chat_messages(state, db_messages).await;
+            fit_history_page(&mut messages, state.config.history_max_bytes);
+            messages.reverse();
+
+            let next_offset = offset.saturating_add(messages.len() as i32);
+            let has_more = next_offset < total_count;
+            let first_unread_message_id = state.db.get_first_unread_message_id(user_id, other_user_id)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::error!("Failed to find first unread message: {:?}", e);
+                    None
+                });
+
+            vec![ServerMessage::MessageHistory {
+                messages,
+                total_count,
+                has_more,
+                next_offset,
+                first_unread_message_id,
+            }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to get message history: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load message history".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Messages in the conversation numbered after `after_seq`, for catching up
+async fn handle_get_messages_after(
+    state: &AppState,
+    user_id: &str,
+    other_user_id: String,
+    after_seq: i64,
+    limit: Option<i32>,
+) -> Vec<ServerMessage> {
+    let (limit, _) = match resolve_pagination(&state.config, limit, None) {
+        Ok(page) => page,
+        Err(message) => {
+            return vec![ServerMessage::Error {
+                message: message.to_string(),
+                code: None,
+            }];
+        }
+    };
+
+    // One extra tells whether there is more after this page
+    match state.db.get_messages_after(user_id, &other_user_id, after_seq, limit.saturating_add(1)).await {
+        Ok(mut db_messages) => {
+            let has_more = db_messages.len() > limit as usize;
+            db_messages.truncate(limit as usize);
+            let messages = load_chat_messages(state, db_messages).await;
+
+            vec![ServerMessage::MessagesAfter {
+                other_user_id,
+                messages,
+                has_more,
+            }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to get messages after seq {}: {:?}", after_seq, e);
+            vec![ServerMessage::Error {
+                message: "Failed to load messages".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Store a message for the scheduler to send at `send_at`
+async fn handle_schedule_message(
+    state: &AppState,
+    from_user_id: &str,
+    to_user_id: String,
+    content: String,
+    send_at: DateTime<Utc>,
+    encrypted: bool,
+) -> Vec<ServerMessage> {
+    if send_at > Utc::now() + chrono::Duration::days(MAX_SCHEDULE_AHEAD_DAYS) {
+        return vec![ServerMessage::Error {
+            message: format!("Messages can be scheduled at most {} days ahead", MAX_SCHEDULE_AHEAD_DAYS),
+            code: None,
+        }];
+    }
+
+    let content = match filter_content(state, content, encrypted) {
+        Ok(content) => content,
+        Err(message) => {
+            tracing::info!("Scheduled message from {} blocked by the content filter", from_user_id);
+            return vec![ServerMessage::Error { message, code: None }];
+        }
+    };
+
+    // A time in the past is fine; it goes out on the next scheduler tick
+    let scheduled = DbScheduledMessage {
+        id: Uuid::new_v4().to_string(),
+        from_user_id: from_user_id.to_string(),
+        to_user_id: to_user_id.clone(),
+        content,
+        encrypted,
+        send_at: db::sortable_timestamp(send_at),
+    };
+
+    match state.db.save_scheduled_message(&scheduled).await {
+        Ok(()) => vec![ServerMessage::MessageScheduled {
+            id: scheduled.id,
+            to_user_id,
+            send_at,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to schedule message: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to schedule message".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_cancel_scheduled_message(state: &AppState, user_id: &str, id: String) -> Vec<ServerMessage> {
+    match state.db.cancel_scheduled_message(&id, user_id).await {
+        Ok(true) => vec![ServerMessage::ScheduledMessageCancelled { id }],
+        // Unknown, someone else's, or already sent
+        Ok(false) => vec![ServerMessage::Error {
+            message: "Scheduled message not found".to_string(),
+            code: None,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to cancel scheduled message: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to cancel scheduled message".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Mark one received message read and tell its sender, unless receipts are off
+async fn handle_mark_as_read(state: &AppState, user_id: &str, message_id: String) -> Vec<ServerMessage> {
+    // Only the recipient may mark a message read
+    let message = match state.db.get_message_for_user(&message_id, user_id).await {
+        Ok(Some(m)) if m.to_user_id == user_id => m,
+        Ok(_) => {
+            return vec![ServerMessage::Error {
+                message: "Message not found".to_string(),
+                code: None,
+            }];
+        }
+        Err(e) => {
+            tracing::error!("Failed to load read message: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    // Only this message: the conversation's read marker moves with
+    // `UpdateReadMarker`/`MarkConversationRead`
+    if let Err(e) = state.db.mark_message_read(&message_id).await {
+        tracing::error!("Failed to mark message as read: {:?}", e);
+    }
+
+    if message.from_user_id != user_id && sends_read_receipts(state, user_id).await {
+        state.send_event(&message.from_user_id, ServerMessage::MessageRead {
+            message_id,
+            user_id: user_id.to_string(),
+        });
+    }
+    Vec::new()
+}
+
+async fn handle_mark_conversation_read(state: &AppState, user_id: &str, other_user_id: &str) -> Vec<ServerMessage> {
+    // Up to the latest message by number, so one arriving in the same millisecond stays unread
+    let now = Utc::now().timestamp_millis();
+    let advanced = match state.db.get_conversation_seq(user_id, other_user_id).await {
+        Ok(latest) => state.db.advance_read_marker(user_id, other_user_id, latest, now).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = advanced {
+        tracing::error!("Failed to update read marker: {:?}", e);
+    }
+
+    match state.db.mark_conversation_read(user_id, other_user_id).await {
+        Ok(updated) => {
+            // Single receipt for the whole batch instead of one per message
+            if updated > 0 && sends_read_receipts(state, user_id).await {
+                state.send_event(other_user_id, ServerMessage::ConversationRead {
+                    user_id: user_id.to_string(),
+                });
+            }
+        }
+        Err(e) => {
+            tracing::error!("Failed to mark conversation as read: {:?}", e);
+        }
+    }
+    Vec::new()
+}
+
+async fn handle_update_read_marker(
+    state: &AppState,
+    user_id: &str,
+    other_user_id: &str,
+    last_read_ts: DateTime<Utc>,
+    last_read_seq: Option<i64>,
+) -> Vec<ServerMessage> {
+    // Nothing can have been read past now, or past the latest message
+    let last_read_ts = last_read_ts.min(Utc::now());
+    let last_read_seq = match last_read_seq {
+        Some(seq) => state.db.get_conversation_seq(user_id, other_user_id).await.map(|latest| seq.min(latest)),
+        None => state.db.get_last_seq_received_by(user_id, other_user_id, last_read_ts.timestamp_millis()).await,
+    };
+    let advanced = match last_read_seq {
+        Ok(seq) => state
+            .db
+            .advance_read_marker(user_id, other_user_id, seq, last_read_ts.timestamp_millis())
+            .await
+            .map(|advanced| advanced.then_some(seq)),
+        Err(e) => Err(e),
+    };
+
+    match advanced {
+        Ok(Some(last_read_seq)) => {
+            if sends_read_receipts(state, user_id).await {
+                state.send_event(other_user_id, ServerMessage::ReadMarker {
+                    user_id: user_id.to_string(),
+                    last_read_ts,
+                    last_read_seq,
+                });
+            }
+            Vec::new()
+        }
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            tracing::error!("Failed to update read marker: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to update read marker".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_conversations(state: &AppState, user_id: &str, archived_only: bool) -> Vec<ServerMessage> {
+    match load_conversations(state, user_id, archived_only).await {
+        Ok(conversations) => vec![ServerMessage::Conversations { conversations }],
+        Err(e) => {
+            tracing::error!("Failed to load conversations: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load conversations".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_total_unread(state: &AppState, user_id: &str) -> Vec<ServerMessage> {
+    match state.db.get_total_unread_count(user_id).await {
+        Ok(count) => vec![ServerMessage::TotalUnread { count }],
+        Err(e) => {
+            tracing::error!("Failed to count unread messages: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to count unread messages".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// `ArchiveConversation` and `UnarchiveConversation`
+async fn handle_set_archived(state: &AppState, user_id: &str, other_user_id: String, archive: bool) -> Vec<ServerMessage> {
+    let result = if archive {
+        state.db.archive_conversation(user_id, &other_user_id).await
+    } else {
+        state.db.unarchive_conversation(user_id, &other_user_id).await.map(|_| ())
+    };
+
+    match result {
+        Ok(()) => vec![ServerMessage::ConversationArchived {
+            user_id: other_user_id,
+            archived: archive,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to update archive state: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to update archive state".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_mute_conversation(
+    state: &AppState,
+    user_id: &str,
+    other_user_id: String,
+    until: Option<DateTime<Utc>>,
+) -> Vec<ServerMessage> {
+    if until.is_some_and(|until| until <= Utc::now()) {
+        return vec![ServerMessage::Error {
+            message: "Mute end must be in the future".to_string(),
+            code: None,
+        }];
+    }
+
+    let muted_until = until.map(db::sortable_timestamp);
+    match state.db.mute_conversation(user_id, &other_user_id, muted_until.as_deref()).await {
+        Ok(()) => vec![ServerMessage::ConversationMuted {
+            user_id: other_user_id,
+            muted: true,
+            muted_until: until,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to mute conversation: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to update mute state".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_unmute_conversation(state: &AppState, user_id: &str, other_user_id: String) -> Vec<ServerMessage> {
+    match state.db.unmute_conversation(user_id, &other_user_id).await {
+        Ok(()) => vec![ServerMessage::ConversationMuted {
+            user_id: other_user_id,
+            muted: false,
+            muted_until: None,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to unmute conversation: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to update mute state".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_muted_conversations(state: &AppState, user_id: &str) -> Vec<ServerMessage> {
+    match state.db.get_muted_conversations(user_id, &db::sortable_timestamp(Utc::now())).await {
+        Ok(muted) => {
+            let conversations = muted
+                .into_iter()
+                .map(|(user_id, until)| MutedConversation {
+                    user_id,
+                    muted_until: until.as_deref().and_then(parse_timestamp),
+                })
+                .collect();
+            vec![ServerMessage::MutedConversations { conversations }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to load muted conversations: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load muted conversations".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_set_notification_pref(
+    state: &AppState,
+    user_id: &str,
+    other_user_id: String,
+    level: NotificationLevel,
+) -> Vec<ServerMessage> {
+    // The default isn't stored
+    let result = match level {
+        NotificationLevel::All => state.db.clear_notification_level(user_id, &other_user_id).await,
+        level => state.db.set_notification_level(user_id, &other_user_id, level.as_str()).await,
+    };
+    match result {
+        Ok(()) => vec![ServerMessage::NotificationPrefChanged {
+            user_id: other_user_id,
+            level,
+        }],
+        Err(e) if db::is_foreign_key_violation(&e) => vec![ServerMessage::Error {
+            message: "User not found".to_string(),
+            code: None,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to store notification level: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to update notification preference".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_notification_prefs(state: &AppState, user_id: &str) -> Vec<ServerMessage> {
+    match state.db.get_notification_levels(user_id).await {
+        Ok(levels) => {
+            let conversations = levels
+                .into_iter()
+                .map(|(user_id, level)| NotificationPref {
+                    user_id,
+                    level: NotificationLevel::from_stored(Some(level.as_str())),
+                })
+                .collect();
+            vec![ServerMessage::NotificationPrefs { conversations }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to load notification preferences: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load notification preferences".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Store the unsent text for a conversation; saving an empty draft deletes it
+async fn handle_save_draft(state: &AppState, user_id: &str, other_user_id: &str, content: &str) -> Vec<ServerMessage> {
+    if content.len() > MAX_DRAFT_LENGTH {
+        return vec![ServerMessage::Error {
+            message: "Draft too long".to_string(),
+            code: None,
+        }];
+    }
+
+    let result = if content.is_empty() {
+        state.db.delete_draft(user_id, other_user_id).await
+    } else {
+        state.db.save_draft(user_id, other_user_id, content).await
+    };
+
+    match result {
+        Ok(()) => Vec::new(),
+        Err(e) => {
+            tracing::error!("Failed to save draft: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to save draft".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_draft(state: &AppState, user_id: &str, other_user_id: String) -> Vec<ServerMessage> {
+    match state.db.get_draft(user_id, &other_user_id).await {
+        Ok(content) => vec![ServerMessage::Draft { other_user_id, content }],
+        Err(e) => {
+            tracing::error!("Failed to load draft: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load draft".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Hide a conversation for `user_id`, or delete it for both sides
+async fn handle_clear_conversation(state: &AppState, user_id: &str, other_user_id: String, only_for_me: bool) -> Vec<ServerMessage> {
+    let result = if only_for_me {
+        state.db.hide_conversation(user_id, &other_user_id).await
+    } else {
+        state.db.delete_conversation(user_id, &other_user_id).await.map(|_| ())
+    };
+
+    match result {
+        Ok(()) => {
+            if !only_for_me {
+                state.send_event(&other_user_id, ServerMessage::ConversationCleared {
+                    user_id: user_id.to_string(),
+                });
+            }
+
+            tracing::info!("User {} cleared conversation with {} (only for self: {})", user_id, other_user_id, only_for_me);
+            vec![ServerMessage::ConversationCleared { user_id: other_user_id }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to clear conversation: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to clear conversation".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Delete everything `user_id` sent to `other_user_id`, for both of them
+async fn handle_delete_my_messages(state: &AppState, user_id: &str, other_user_id: &str) -> Vec<ServerMessage> {
+    match state.db.delete_sent_messages(user_id, other_user_id).await {
+        Ok(message_ids) => {
+            tracing::info!("User {} deleted {} messages sent to {}", user_id, message_ids.len(), other_user_id);
+            let deleted = ServerMessage::MessagesDeleted { message_ids };
+            if other_user_id != user_id {
+                state.send_event(other_user_id, deleted.clone());
+            }
+            // Logged for the sender too, so a resumed session drops them as well
+            state.send_event(user_id, deleted);
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::error!("Failed to delete sent messages: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to delete messages".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Forward a typing indicator, within the throttle and rate limit
+fn handle_typing(state: &AppState, from_user_id: &str, to_user_id: &str, is_typing: bool) -> Vec<ServerMessage> {
+    // Stops always go through, or an indicator could be left on. Repeats are
+    // dropped before they count against the limit, and a start the limit drops
+    // doesn't hold back the next one.
+    if is_typing
+        && (state.typing_throttle.is_throttled(from_user_id, to_user_id)
+            || !state.typing_limiter.try_record(from_user_id))
+    {
+        return Vec::new();
+    }
+    state.typing_throttle.forwarded(from_user_id, to_user_id, is_typing);
+    relay(state, to_user_id, ServerMessage::Typing {
+        from_user_id: from_user_id.to_string(),
+        is_typing,
+    })
+}
+
+/// Look up users by id. Presence hidden from the viewer shows as offline.
+async fn handle_get_users(state: &AppState, user_id: &str, mut ids: Vec<String>) -> Vec<ServerMessage> {
+    if ids.len() > MAX_USER_LOOKUP_IDS {
+        return vec![ServerMessage::Error {
+            message: format!("At most {} users can be looked up at once", MAX_USER_LOOKUP_IDS),
+            code: None,
+        }];
+    }
+
+    ids.sort_unstable();
+    ids.dedup();
+    match state.db.get_users_by_ids(&ids).await {
+        Ok(db_users) => {
+            let visibility = presence::Visibility::load(state, user_id).await;
+            let users = db_users
+                .iter()
+                .map(|u| {
+                    let status = if u.id == user_id || visibility.sees(&u.id) {
+                        state.user_status(&u.id)
+                    } else {
+                        UserStatus::Offline
+                    };
+                    db_user_to_user(u, status)
+                })
+                .collect();
+            vec![ServerMessage::Users { users }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to look up users: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to look up users".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_add_contact(state: &AppState, user_id: &str, contact_id: &str) -> Vec<ServerMessage> {
+    match state.db.get_user_by_id(contact_id).await {
+        Ok(Some(_)) if contact_id != user_id => {}
+        Ok(_) => {
+            return vec![ServerMessage::Error {
+                message: "User not found".to_string(),
+                code: None,
+            }];
+        }
+        Err(e) => {
+            tracing::error!("Failed to look up contact: {:?}", e);
+            return Vec::new();
+        }
+    }
+
+    if let Err(e) = state.db.add_contact(user_id, contact_id).await {
+        tracing::error!("Failed to add contact: {:?}", e);
+        return vec![ServerMessage::Error {
+            message: "Failed to update contacts".to_string(),
+            code: None,
+        }];
+    }
+
+    updated_contacts(state, user_id).await
+}
+
+async fn handle_remove_contact(state: &AppState, user_id: &str, contact_id: &str) -> Vec<ServerMessage> {
+    if let Err(e) = state.db.remove_contact(user_id, contact_id).await {
+        tracing::error!("Failed to remove contact: {:?}", e);
+        return vec![ServerMessage::Error {
+            message: "Failed to update contacts".to_string(),
+            code: None,
+        }];
+    }
+
+    updated_contacts(state, user_id).await
+}
+
+/// The contact list after a change; a failure to reload it is only logged
+async fn updated_contacts(state: &AppState, user_id: &str) -> Vec<ServerMessage> {
+    match load_contacts(state, user_id).await {
+        Ok(contacts) => vec![ServerMessage::Contacts { contacts }],
+        Err(e) => {
+            tracing::error!("Failed to load contacts: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+async fn handle_get_contacts(state: &AppState, user_id: &str) -> Vec<ServerMessage> {
+    match load_contacts(state, user_id).await {
+        Ok(contacts) => vec![ServerMessage::Contacts { contacts }],
+        Err(e) => {
+            tracing::error!("Failed to load contacts: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load contacts".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Who came online and who left, compared to the ids the client already knows
+async fn handle_get_online_users_since(state: &AppState, user_id: &str, known_ids: Vec<String>) -> Vec<ServerMessage> {
+    let known: HashSet<String> = known_ids.into_iter().collect();
+    let online = presence::visible_online_users(state, user_id).await;
+    let online_ids: HashSet<&str> = online.iter().map(|u| u.id.as_str()).collect();
+    let removed: Vec<String> = known
+        .iter()
+        .filter(|id| !online_ids.contains(id.as_str()))
+        .cloned()
+        .collect();
+    let added: Vec<User> = online.into_iter().filter(|u| !known.contains(&u.id)).collect();
+    vec![ServerMessage::OnlineUsersDelta { added, removed }]
+}
+
+/// Why a reaction can't be stored, if it can't
+fn check_reaction_emoji(emoji: &str) -> Option<ServerMessage> {
+    (emoji.is_empty() || emoji.len() > MAX_REACTION_EMOJI_BYTES).then(|| ServerMessage::Error {
+        message: "Invalid reaction".to_string(),
+        code: None,
+    })
+}
+
+/// Store `user_id`'s reaction, replacing any earlier one. `Err` holds the replies
+/// when it couldn't be stored.
+async fn store_reaction(state: &AppState, user_id: &str, message_id: &str, emoji: &str) -> Result<(), Vec<ServerMessage>> {
+    match state.db.add_reaction(message_id, user_id, emoji, state.config.max_reactions_per_message).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(vec![ServerMessage::Error {
+            message: "This message has reached the maximum number of reactions".to_string(),
+            code: None,
+        }]),
+        Err(e) if db::is_foreign_key_violation(&e) => Err(vec![ServerMessage::Error {
+            message: "Message not found".to_string(),
+            code: None,
+        }]),
+        Err(e) => {
+            tracing::error!("Failed to add reaction: {:?}", e);
+            Err(Vec::new())
+        }
+    }
+}
+
+/// Reactions can only be changed on messages `user_id` sent or received
+async fn check_reaction_target(state: &AppState, user_id: &str, message_id: &str) -> Result<(), Vec<ServerMessage>> {
+    match state.db.get_message_for_user(message_id, user_id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(vec![ServerMessage::Error {
+            message: "Message not found".to_string(),
+            code: None,
+        }]),
+        Err(e) => {
+            tracing::error!("Failed to load message: {:?}", e);
+            Err(Vec::new())
+        }
+    }
+}
+
+async fn handle_add_reaction(state: &AppState, from_user_id: &str, message_id: &str, emoji: &str) -> Vec<ServerMessage> {
+    if let Some(error) = check_reaction_emoji(emoji) {
+        return vec![error];
+    }
+    if let Err(replies) = check_reaction_target(state, from_user_id, message_id).await {
+        return replies;
+    }
+    // Checked last, so rejected requests don't count against the limit
+    if !state.reaction_limiter.try_record(from_user_id) {
+        return Vec::new();
+    }
+
+    let _guard = state.reaction_locks.lock(message_id).await;
+    if let Err(replies) = store_reaction(state, from_user_id, message_id, emoji).await {
+        return replies;
+    }
+
+    tracing::info!("User {} reacted to message {} with {}", from_user_id, message_id, emoji);
+    broadcast_reactions(state, message_id, from_user_id, None).await;
+    Vec::new()
+}
+
+async fn handle_remove_reaction(state: &AppState, from_user_id: &str, message_id: &str) -> Vec<ServerMessage> {
+    if let Err(replies) = check_reaction_target(state, from_user_id, message_id).await {
+        return replies;
+    }
+    if !state.reaction_limiter.try_record(from_user_id) {
+        return Vec::new();
+    }
+
+    let _guard = state.reaction_locks.lock(message_id).await;
+    let removed_emoji = match state.db.remove_reaction(message_id, from_user_id).await {
+        Ok(removed_emoji) => removed_emoji,
+        Err(e) => {
+            tracing::error!("Failed to remove reaction: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    tracing::info!("User {} removed reaction from message {}", from_user_id, message_id);
+    broadcast_reactions(state, message_id, from_user_id, removed_emoji).await;
+    Vec::new()
+}
+
+/// Remove `emoji` if it is the user's current reaction, otherwise set it
+async fn handle_toggle_reaction(state: &AppState, from_user_id: &str, message_id: &str, emoji: &str) -> Vec<ServerMessage> {
+    if let Some(error) = check_reaction_emoji(emoji) {
+        return vec![error];
+    }
+    if let Err(replies) = check_reaction_target(state, from_user_id, message_id).await {
+        return replies;
+    }
+    if !state.reaction_limiter.try_record(from_user_id) {
+        return Vec::new();
+    }
+
+    // Held across the read and the write so the toggle can't interleave with another change
+    let _guard = state.reaction_locks.lock(message_id).await;
+    let current = match state.db.get_reactions(message_id).await {
+        Ok(mut reactions) => reactions.remove(from_user_id),
+        Err(e) => {
+            tracing::error!("Failed to load reactions: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    if current.as_deref() == Some(emoji) {
+        if let Err(e) = state.db.remove_reaction(message_id, from_user_id).await {
+            tracing::error!("Failed to remove reaction: {:?}", e);
+            return Vec::new();
+        }
+        tracing::info!("User {} toggled off {} on message {}", from_user_id, emoji, message_id);
+        broadcast_reactions(state, message_id, from_user_id, current).await;
+        return Vec::new();
+    }
+
+    if let Err(replies) = store_reaction(state, from_user_id, message_id, emoji).await {
+        return replies;
+    }
+
+    tracing::info!("User {} toggled on {} on message {}", from_user_id, emoji, message_id);
+    // Replacing a different emoji removes that one
+    broadcast_reactions(state, message_id, from_user_id, current).await;
+    Vec::new()
+}
+
+/// Who reacted to a message with what, for a message `user_id` can see
+async fn handle_get_reaction_details(state: &AppState, user_id: &str, message_id: String) -> Vec<ServerMessage> {
+    match state.db.get_message_for_user(&message_id, user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return vec![ServerMessage::Error {
+                message: "Message not found".to_string(),
+                code: None,
+            }];
+        }
+        Err(e) => {
+            tracing::error!("Failed to get message: {:?}", e);
+            return Vec::new();
+        }
+    }
+
+    match state.db.get_reaction_details(&message_id).await {
+        Ok(details) => {
+            let reactions = details
+                .into_iter()
+                .map(|d| ReactionDetail {
+                    emoji: d.emoji,
+                    user_id: d.user_id,
+                    username: d.username,
+                    display_name: d.display_name,
+                })
+                .collect();
+            vec![ServerMessage::ReactionDetails { message_id, reactions }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to load reaction details: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load reactions".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_storage_usage(state: &AppState, user_id: &str) -> Vec<ServerMessage> {
+    match state.db.get_storage_used(user_id).await {
+        Ok(used) => vec![ServerMessage::StorageUsage {
+            used,
+            quota: state.config.storage_quota_bytes,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to load storage usage: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load storage usage".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_set_public_key(state: &AppState, user_id: &str, public_key: &str) -> Vec<ServerMessage> {
+    if public_key.is_empty() || public_key.len() > MAX_PUBLIC_KEY_LENGTH {
+        return vec![ServerMessage::Error {
+            message: "Invalid public key".to_string(),
+            code: None,
+        }];
+    }
+
+    match state.db.set_public_key(user_id, public_key).await {
+        Ok(()) => {
+            // `UserUpdated` carries the new key, so peers can refresh any they cached
+            state.broadcast_user_updated(user_id).await;
+
+            vec![ServerMessage::Success {
+                message: "Public key updated".to_string(),
+            }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to store public key: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to store public key".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_set_avatar(state: &AppState, user_id: &str, file_data: FileData, file_type: String) -> Vec<ServerMessage> {
+    let processed = tokio::task::spawn_blocking(move || media::process_avatar(&file_data, &file_type))
+        .await
+        .unwrap_or_else(|_| Err("Failed to process avatar".to_string()));
+
+    let (data, mime_type) = match processed {
+        Ok(processed) => processed,
+        Err(message) => return vec![ServerMessage::Error { message, code: None }],
+    };
+
+    match state.db.set_avatar(user_id, &data, mime_type).await {
+        Ok(_) => {
+            state.broadcast_user_updated(user_id).await;
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::error!("Failed to store avatar: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to store avatar".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_set_display_name(state: &AppState, user_id: &str, name: &str) -> Vec<ServerMessage> {
+    let name = name.trim();
+    if name.chars().count() > MAX_DISPLAY_NAME_CHARS || name.chars().any(char::is_control) {
+        return vec![ServerMessage::Error {
+            message: format!("Display name must be at most {} characters", MAX_DISPLAY_NAME_CHARS),
+            code: None,
+        }];
+    }
+
+    let display_name = Some(name).filter(|name| !name.is_empty());
+    match state.db.set_display_name(user_id, display_name).await {
+        Ok(()) => {
+            state.broadcast_user_updated(user_id).await;
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::error!("Failed to store display name: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to store display name".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_star_message(state: &AppState, user_id: &str, message_id: String) -> Vec<ServerMessage> {
+    match state.db.get_message_for_user(&message_id, user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return vec![ServerMessage::Error {
+                message: "Message not found".to_string(),
+                code: None,
+            }];
+        }
+        Err(e) => {
+            tracing::error!("Failed to load message to star: {:?}", e);
+            return Vec::new();
+        }
+    }
+
+    match state.db.star_message(user_id, &message_id).await {
+        Ok(_) => vec![ServerMessage::MessageStarred { message_id, starred: true }],
+        Err(e) => {
+            tracing::error!("Failed to star message: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to star message".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_unstar_message(state: &AppState, user_id: &str, message_id: String) -> Vec<ServerMessage> {
+    match state.db.unstar_message(user_id, &message_id).await {
+        Ok(_) => vec![ServerMessage::MessageStarred { message_id, starred: false }],
+        Err(e) => {
+            tracing::error!("Failed to unstar message: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to unstar message".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_starred_messages(state: &AppState, user_id: &str, limit: Option<i32>, offset: Option<i32>) -> Vec<ServerMessage> {
+    let (limit, offset) = match resolve_pagination(&state.config, limit, offset) {
+        Ok(page) => page,
+        Err(message) => {
+            return vec![ServerMessage::Error {
+                message: message.to_string(),
+                code: None,
+            }];
+        }
+    };
+
+    match state.db.get_starred_messages(user_id, limit, offset).await {
+        Ok(db_messages) => vec![ServerMessage::StarredMessages {
+            messages: load_chat_messages(state, db_messages).await,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to load starred messages: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load starred messages".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+/// Full-text search across every conversation `user_id` is in
+async fn handle_search_all_messages(
+    state: &AppState,
+    user_id: &str,
+    query: &str,
+    limit: Option<i32>,
+    offset: Option<i32>,
+) -> Vec<ServerMessage> {
+    let query = query.trim().to_string();
+    if query.is_empty() || query.chars().count() > MAX_SEARCH_QUERY_CHARS {
+        return vec![ServerMessage::Error {
+            message: format!("Search queries must be 1 to {} characters", MAX_SEARCH_QUERY_CHARS),
+            code: None,
+        }];
+    }
+
+    let (limit, offset) = match resolve_pagination(&state.config, limit, offset) {
+        Ok(page) => page,
+        Err(message) => {
+            return vec![ServerMessage::Error {
+                message: message.to_string(),
+                code: None,
+            }];
+        }
+    };
+
+    // One extra row tells whether there is another page
+    match state.db.search_messages(user_id, &query, limit + 1, offset).await {
+        Ok(mut db_messages) => {
+            let has_more = db_messages.len() > limit as usize;
+            db_messages.truncate(limit as usize);
+            let results = load_chat_messages(state, db_messages)
+                .await
+                .into_iter()
+                .map(|message| SearchResult {
+                    other_user_id: if message.from_user_id == user_id {
+                        message.to_user_id.clone()
+                    } else {
+                        message.from_user_id.clone()
+                    },
+                    message,
+                })
+                .collect();
+            vec![ServerMessage::SearchResults { query, results, has_more }]
+        }
+        Err(e) => {
+            tracing::error!("Failed to search messages: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to search messages".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_report_message(state: &AppState, user_id: &str, message_id: String, reason: &str) -> Vec<ServerMessage> {
+    let reason = reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_CHARS {
+        return vec![ServerMessage::Error {
+            message: format!("A report needs a reason of at most {} characters", MAX_REPORT_REASON_CHARS),
+            code: None,
+        }];
+    }
+
+    // Only participants can report, and only messages they can still see
+    match state.db.get_message_for_user(&message_id, user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return vec![ServerMessage::Error {
+                message: "Message not found".to_string(),
+                code: None,
+            }];
+        }
+        Err(e) => {
+            tracing::error!("Failed to load reported message: {:?}", e);
+            return Vec::new();
+        }
+    }
+
+    let report_id = Uuid::new_v4().to_string();
+    match state.db.add_report(&report_id, user_id, &message_id, reason).await {
+        Ok(true) => {
+            tracing::info!("User {} reported message {}", user_id, message_id);
+            vec![ServerMessage::MessageReported { message_id }]
+        }
+        Ok(false) => vec![ServerMessage::Error {
+            message: "You already reported this message".to_string(),
+            code: None,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to store report: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to report message".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_set_status(state: &AppState, user_id: &str, status: UserStatus) -> Vec<ServerMessage> {
+    if status == UserStatus::Offline {
+        return vec![ServerMessage::Error {
+            message: "Status must be online, away or busy".to_string(),
+            code: None,
+        }];
+    }
+
+    match state.online_users.get_mut(user_id) {
+        Some(mut user) => user.status = status,
+        None => return Vec::new(),
+    }
+
+    let changed = ServerMessage::UserStatusChanged { user_id: user_id.to_string(), status };
+    state.broadcast_presence(user_id, changed.clone()).await;
+    vec![changed]
+}
+
+async fn handle_set_read_receipt_privacy(state: &AppState, user_id: &str, enabled: bool) -> Vec<ServerMessage> {
+    match state.db.set_send_read_receipts(user_id, enabled).await {
+        Ok(()) => vec![ServerMessage::ReadReceiptPrivacy { enabled }],
+        Err(e) => {
+            tracing::error!("Failed to store read receipt setting: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to update read receipt setting".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
+
+async fn handle_get_public_key(state: &AppState, user_id: String) -> Vec<ServerMessage> {
+    match state.db.get_user_by_id(&user_id).await {
+        Ok(Some(db_user)) => vec![ServerMessage::PublicKey {
+            user_id,
+            public_key: db_user.public_key,
+        }],
+        Ok(None) => vec![ServerMessage::Error {
+            message: "User not found".to_string(),
+            code: None,
+        }],
+        Err(e) => {
+            tracing::error!("Failed to load public key: {:?}", e);
+            vec![ServerMessage::Error {
+                message: "Failed to load public key".to_string(),
+                code: None,
+            }]
+        }
+    }
+}
 
-        while let Some(Ok(Message::Text(text))) = receiver.next().await {
-            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                match client_msg {
-                    ClientMessage::Register { username, password } => {
-                        // Check if username exists
-                        match state.db.get_user_by_username(&username).await {
-                            Ok(Some(_)) => {
-                                let _ = user_tx.send(ServerMessage::AuthError {
-                                    message: "Username already exists".to_string(),
-                                });
-                            }
-                            Ok(None) => {
-                                // Hash password and create user
-                                let password_hash = bcrypt::hash(&password, bcrypt::DEFAULT_COST)
-                                    .unwrap_or_else(|_| password.clone());
-                                let user_id = Uuid::new_v4().to_string();
-
-                                match state.db.create_user(&user_id, &username, &password_hash).await {
-                                    Ok(_) => {
-                                        let user = User {
-                                            id: user_id.clone(),
-                                            username: username.clone(),
-                                            online: true,
-                                            last_seen: Utc::now(),
-                                        };
-
-                                        state.online_users.insert(user_id.clone(), user.clone());
-                                        current_user_id = Some(user_id.clone());
-                                        state.user_sockets.insert(user_id.clone(), user_tx.clone());
-
-                                        let _ = user_tx.send(ServerMessage::RegisterSuccess {
-                                            user: user.clone(),
-                                        });
-
-                                        // Send online users list
-                                        let online_users: Vec<User> = state
-                                            .online_users
-                                            .iter()
-                                            .filter(|u| u.value().id != user_id)
-                                            .map(|u| u.value().clone())
-                                            .collect();
-                                        let _ = user_tx.send(ServerMessage::OnlineUsers {
-                                            users: online_users,
-                                        });
-
-                                        // Notify others
-                                        for entry in state.user_sockets.iter() {
-                                            if entry.key() != &user_id {
-                                                let _ = entry.value().send(ServerMessage::UserOnline {
-                                                    user: user.clone(),
-                                                });
-                                            }
-                                        }
-
-                                        tracing::info!("User registered: {} ({})", username, user_id);
-                                    }
-                                    Err(e) => {
-                                        tracing::error!("Failed to create user: {:?}", e);
-                                        let _ = user_tx.send(ServerMessage::AuthError {
-                                            message: "Failed to register user".to_string(),
-                                        });
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Database error: {:?}", e);
-                                let _ = user_tx.send(ServerMessage::AuthError {
-                                    message: "Database error".to_string(),
-                                });
-                            }
-                        }
-                    }
+/// Pass a signal on to `to_user_id`'s connection, if they have one
+fn relay(state: &AppState, to_user_id: &str, message: ServerMessage) -> Vec<ServerMessage> {
+    if let Some(recipient_tx) = state.user_sockets.get(to_user_id) {
+        let _ = recipient_tx.send(message);
+    }
+    Vec::new()
+}
 
-                    ClientMessage::Login { username, password } => {
-                        // Check if user exists in database
-                        match state.db.get_user_by_username(&username).await {
-                            Ok(Some(db_user)) => {
-                                // Verify password if provided
-                                let password_valid = match password {
-                                    Some(ref pwd) => bcrypt::verify(pwd, &db_user.password_hash).unwrap_or(false),
-                                    None => true, // Allow passwordless login for existing users (backward compat)
-                                };
-
-                                if password_valid {
-                                    let user = User {
-                                        id: db_user.id.clone(),
-                                        username: db_user.username.clone(),
-                                        online: true,
-                                        last_seen: Utc::now(),
-                                    };
-
-                                    state.online_users.insert(db_user.id.clone(), user.clone());
-                                    current_user_id = Some(db_user.id.clone());
-                                    state.user_sockets.insert(db_user.id.clone(), user_tx.clone());
-
-                                    let _ = user_tx.send(ServerMessage::LoginSuccess {
-                                        user: user.clone(),
-                                    });
-
-                                    // Send online users list (excluding self)
-                                    let online_users: Vec<User> = state
-                                        .online_users
-                                        .iter()
-                                        .filter(|u| u.value().id != db_user.id)
-                                        .map(|u| u.value().clone())
-                                        .collect();
-                                    let _ = user_tx.send(ServerMessage::OnlineUsers {
-                                        users: online_users,
-                                    });
-
-                                    // Notify all other users
-                                    for entry in state.user_sockets.iter() {
-                                        if entry.key() != &db_user.id {
-                                            let _ = entry.value().send(ServerMessage::UserOnline {
-                                                user: user.clone(),
-                                            });
-                                        }
-                                    }
-
-                                    // Update last seen
-                                    let _ = state.db.update_last_seen(&db_user.id).await;
-
-                                    tracing::info!("User logged in: {} ({})", username, db_user.id);
-                                } else {
-                                    let _ = user_tx.send(ServerMessage::AuthError {
-                                        message: "Invalid password".to_string(),
-                                    });
-                                }
-                            }
-                            Ok(None) => {
-                                // Auto-register for backward compatibility (passwordless)
-                                if password.is_none() {
-                                    let user_id = Uuid::new_v4().to_string();
-                                    let default_hash = bcrypt::hash("", bcrypt::DEFAULT_COST)
-                                        .unwrap_or_default();
-
-                                    match state.db.create_user(&user_id, &username, &default_hash).await {
-                                        Ok(_) => {
-                                            let user = User {
-                                                id: user_id.clone(),
-                                                username: username.clone(),
-                                                online: true,
-                                                last_seen: Utc::now(),
-                                            };
-
-                                            state.online_users.insert(user_id.clone(), user.clone());
-                                            current_user_id = Some(user_id.clone());
-                                            state.user_sockets.insert(user_id.clone(), user_tx.clone());
-
-                                            let _ = user_tx.send(ServerMessage::LoginSuccess {
-                                                user: user.clone(),
-                                            });
-
-                                            // Send online users
-                                            let online_users: Vec<User> = state
-                                                .online_users
-                                                .iter()
-                                                .filter(|u| u.value().id != user_id)
-                                                .map(|u| u.value().clone())
-                                                .collect();
-                                            let _ = user_tx.send(ServerMessage::OnlineUsers {
-                                                users: online_users,
-                                            });
-
-                                            // Notify others
-                                            for entry in state.user_sockets.iter() {
-                                                if entry.key() != &user_id {
-                                                    let _ = entry.value().send(ServerMessage::UserOnline {
-                                                        user: user.clone(),
-                                                    });
-                                                }
-                                            }
-
-                                            tracing::info!("User auto-registered: {} ({})", username, user_id);
-                                        }
-                                        Err(e) => {
-                                            tracing::error!("Failed to auto-register: {:?}", e);
-                                            let _ = user_tx.send(ServerMessage::AuthError {
-                                                message: "Failed to create user".to_string(),
-                                            });
-                                        }
-                                    }
-                                } else {
-                                    let _ = user_tx.send(ServerMessage::AuthError {
-                                        message: "User not found".to_string(),
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Database error during login: {:?}", e);
-                                let _ = user_tx.send(ServerMessage::AuthError {
-                                    message: "Database error".to_string(),
-                                });
-                            }
-                        }
-                    }
+/// Relay an offer to an online user, recording the call the first time its id is seen
+async fn handle_call_offer(
+    state: &AppState,
+    from_user_id: &str,
+    to_user_id: String,
+    offer: String,
+    call_id: Option<String>,
+    call_type: CallType,
+) -> Vec<ServerMessage> {
+    let Some(recipient_tx) = state.user_sockets.get(&to_user_id).map(|tx| tx.clone()) else {
+        return Vec::new();
+    };
 
-                    ClientMessage::SendMessage { to_user_id, content, file_data, file_name, file_type, audio_duration } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            let message = ChatMessage {
-                                id: Uuid::new_v4().to_string(),
-                                from_user_id: from_user_id.clone(),
-                                to_user_id: to_user_id.clone(),
-                                content: content.clone(),
-                                timestamp: Utc::now(),
-                                read: false,
-                                file_data: file_data.clone(),
-                                file_name: file_name.clone(),
-                                file_type: file_type.clone(),
-                                audio_duration,
-                                reactions: HashMap::new(),
-                            };
-
-                            // Save to database
-                            let db_msg = DbMessage {
-                                id: message.id.clone(),
-                                from_user_id: message.from_user_id.clone(),
-                                to_user_id: message.to_user_id.clone(),
-                                content: message.content.clone(),
-                                timestamp: message.timestamp.to_rfc3339(),
-                                read: message.read,
-                                file_data: message.file_data.clone(),
-                                file_name: message.file_name.clone(),
-                                file_type: message.file_type.clone(),
-                                audio_duration: message.audio_duration,
-                            };
-
-                            if let Err(e) = state.db.save_message(&db_msg).await {
-                                tracing::error!("Failed to save message: {:?}", e);
-                            }
-
-                            // Send to recipient if online
-                            if let Some(recipient_tx) = state.user_sockets.get(&to_user_id) {
-                                let _ = recipient_tx.send(ServerMessage::NewMessage {
-                                    message: message.clone(),
-                                });
-                            }
-
-                            // Also send to sender for confirmation
-                            let _ = user_tx.send(ServerMessage::NewMessage {
-                                message,
-                            });
-                        }
-                    }
+    // Renegotiation offers reuse the id of the call they belong to
+    let call_id = call_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut replies = Vec::new();
+    match state.db.start_call(&call_id, from_user_id, &to_user_id, call_type.as_str()).await {
+        Ok(true) => {
+            ringing::start(state, &call_id, from_user_id, &to_user_id);
+            replies.push(ServerMessage::CallStarted {
+                call_id: call_id.clone(),
+                to_user_id: to_user_id.clone(),
+            });
+        }
+        Ok(false) => {}
+        Err(e) => tracing::error!("Failed to record call: {:?}", e),
+    }
 
-                    ClientMessage::GetMessageHistory { other_user_id, limit, offset } => {
-                        if let Some(user_id) = &current_user_id {
-                            let limit = limit.unwrap_or(50);
-                            let offset = offset.unwrap_or(0);
-
-                            match state.db.get_messages_between_users(user_id, &other_user_id, limit, offset).await {
-                                Ok(db_messages) => {
-                                    let total_count = state.db.get_message_count_between_users(user_id, &other_user_id)
-                                        .await
-                                        .unwrap_or(0);
-
-                                    let message_ids: Vec<String> = db_messages.iter().map(|m| m.id.clone()).collect();
-                                    let reactions_map = state.db.get_reactions_batch(&message_ids).await.unwrap_or_default();
-
-                                    let messages: Vec<ChatMessage> = db_messages
-                                        .into_iter()
-                                        .map(|m| {
-                                            let reactions = reactions_map.get(&m.id).cloned();
-                                            db_message_to_chat_message(m, reactions)
-                                        })
-                                        .collect();
-
-                                    // Messages are in DESC order, reverse for chronological display
-                                    let mut messages = messages;
-                                    messages.reverse();
-
-                                    let has_more = (offset + limit) < total_count;
-
-                                    let _ = user_tx.send(ServerMessage::MessageHistory {
-                                        messages,
-                                        total_count,
-                                        has_more,
-                                    });
-                                }
-                                Err(e) => {
-                                    tracing::error!("Failed to get message history: {:?}", e);
-                                    let _ = user_tx.send(ServerMessage::Error {
-                                        message: "Failed to load message history".to_string(),
-                                    });
-                                }
-                            }
-                        }
-                    }
+    let _ = recipient_tx.send(ServerMessage::CallOffer {
+        from_user_id: from_user_id.to_string(),
+        offer,
+        call_id,
+        call_type,
+    });
+    replies
+}
 
-                    ClientMessage::MarkAsRead { message_id } => {
-                        if let Err(e) = state.db.mark_message_read(&message_id).await {
-                            tracing::error!("Failed to mark message as read: {:?}", e);
-                        }
-
-                        // Notify sender that message was read (need to look up message first)
-                        // For simplicity, broadcast to current conversation
-                        if let Some(user_id) = &current_user_id {
-                            for entry in state.user_sockets.iter() {
-                                if entry.key() != user_id {
-                                    let _ = entry.value().send(ServerMessage::MessageRead {
-                                        message_id: message_id.clone(),
-                                        user_id: user_id.clone(),
-                                    });
-                                }
-                            }
-                        }
-                    }
+async fn handle_call_answer(
+    state: &AppState,
+    from_user_id: &str,
+    to_user_id: &str,
+    answer: String,
+    call_id: Option<String>,
+    call_type: CallType,
+) -> Vec<ServerMessage> {
+    if let Some(answered_id) = ringing::stop(state, call_id.as_deref(), from_user_id, to_user_id) {
+        if let Err(e) = state.db.answer_call(&answered_id).await {
+            tracing::error!("Failed to record answer for call {}: {:?}", answered_id, e);
+        }
+    }
 
-                    ClientMessage::Typing { to_user_id, is_typing } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Some(recipient_tx) = state.user_sockets.get(&to_user_id) {
-                                let _ = recipient_tx.send(ServerMessage::Typing {
-                                    from_user_id: from_user_id.clone(),
-                                    is_typing,
-                                });
-                            }
-                        }
-                    }
+    relay(state, to_user_id, ServerMessage::CallAnswer {
+        from_user_id: from_user_id.to_string(),
+        answer,
+        call_id,
+        call_type,
+    })
+}
 
-                    ClientMessage::GetOnlineUsers => {
-                        let online_users: Vec<User> = state
-                            .online_users
-                            .iter()
-                            .map(|u| u.value().clone())
-                            .collect();
-                        let _ = user_tx.send(ServerMessage::OnlineUsers {
-                            users: online_users,
-                        });
-                    }
+async fn handle_call_end(state: &AppState, from_user_id: &str, to_user_id: &str, call_id: Option<String>) -> Vec<ServerMessage> {
+    ringing::stop(state, call_id.as_deref(), from_user_id, to_user_id);
+    let ended_id = match &call_id {
+        Some(call_id) => Some(call_id.clone()),
+        None => state.db.get_open_call_id(from_user_id, to_user_id).await.unwrap_or_else(|e| {
+            tracing::error!("Failed to look up call: {:?}", e);
+            None
+        }),
+    };
+    if let Some(ended_id) = ended_id {
+        end_call(state, &ended_id, from_user_id).await;
+    }
 
-                    ClientMessage::AddReaction { message_id, emoji } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Err(e) = state.db.add_reaction(&message_id, from_user_id, &emoji).await {
-                                tracing::error!("Failed to add reaction: {:?}", e);
-                            }
-
-                            tracing::info!("User {} reacted to message {} with {}", from_user_id, message_id, emoji);
-
-                            // Broadcast to all connected users (simplified)
-                            for entry in state.user_sockets.iter() {
-                                let _ = entry.value().send(ServerMessage::MessageReaction {
-                                    message_id: message_id.clone(),
-                                    user_id: from_user_id.clone(),
-                                    emoji: Some(emoji.clone()),
-                                });
-                            }
-                        }
-                    }
+    relay(state, to_user_id, ServerMessage::CallEnd {
+        from_user_id: from_user_id.to_string(),
+        call_id,
+    })
+}
 
-                    ClientMessage::RemoveReaction { message_id } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Err(e) = state.db.remove_reaction(&message_id, from_user_id).await {
-                                tracing::error!("Failed to remove reaction: {:?}", e);
-                            }
-
-                            tracing::info!("User {} removed reaction from message {}", from_user_id, message_id);
-
-                            // Broadcast to all connected users
-                            for entry in state.user_sockets.iter() {
-                                let _ = entry.value().send(ServerMessage::MessageReaction {
-                                    message_id: message_id.clone(),
-                                    user_id: from_user_id.clone(),
-                                    emoji: None,
-                                });
-                            }
-                        }
-                    }
+async fn handle_call_reject(state: &AppState, from_user_id: &str, to_user_id: &str, call_id: Option<String>) -> Vec<ServerMessage> {
+    let ringing_id = ringing::stop(state, call_id.as_deref(), from_user_id, to_user_id);
+    if let Some(rejected_id) = call_id.as_deref().or(ringing_id.as_deref()) {
+        if let Err(e) = state.db.reject_call(rejected_id, from_user_id).await {
+            tracing::error!("Failed to record rejection of call {}: {:?}", rejected_id, e);
+        }
+    }
 
-                    ClientMessage::CallOffer { to_user_id, offer } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Some(recipient_tx) = state.user_sockets.get(&to_user_id) {
-                                let _ = recipient_tx.send(ServerMessage::CallOffer {
-                                    from_user_id: from_user_id.clone(),
-                                    offer,
-                                });
-                            }
-                        }
-                    }
+    relay(state, to_user_id, ServerMessage::CallRejected {
+        from_user_id: from_user_id.to_string(),
+        call_id,
+    })
+}
 
-                    ClientMessage::CallAnswer { to_user_id, answer } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Some(recipient_tx) = state.user_sockets.get(&to_user_id) {
-                                let _ = recipient_tx.send(ServerMessage::CallAnswer {
-                                    from_user_id: from_user_id.clone(),
-                                    answer,
-                                });
-                            }
-                        }
-                    }
+/// Store a connection-quality sample for a call `user_id` took part in
+async fn handle_call_stats(state: &AppState, user_id: &str, call_id: &str, stats: CallStatsReport) -> Vec<ServerMessage> {
+    let values = [stats.bitrate_kbps, stats.packet_loss_percent, stats.round_trip_time_ms, stats.jitter_ms];
+    let valid = values.iter().flatten().all(|v| v.is_finite() && *v >= 0.0)
+        && stats.packet_loss_percent.is_none_or(|loss| loss <= 100.0);
+    if !valid {
+        return vec![ServerMessage::Error {
+            message: "Invalid call stats".to_string(),
+            code: None,
+        }];
+    }
 
-                    ClientMessage::IceCandidate { to_user_id, candidate } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Some(recipient_tx) = state.user_sockets.get(&to_user_id) {
-                                let _ = recipient_tx.send(ServerMessage::IceCandidate {
-                                    from_user_id: from_user_id.clone(),
-                                    candidate,
-                                });
-                            }
-                        }
-                    }
+    let db_stats = db::DbCallStats {
+        bitrate_kbps: stats.bitrate_kbps,
+        packet_loss_percent: stats.packet_loss_percent,
+        round_trip_time_ms: stats.round_trip_time_ms,
+        jitter_ms: stats.jitter_ms,
+    };
+    // Samples for calls the user isn't part of, or past the cap, are dropped quietly
+    if let Err(e) = state.db.add_call_stats(call_id, user_id, &db_stats, MAX_CALL_STATS_SAMPLES).await {
+        tracing::error!("Failed to store call stats: {:?}", e);
+    }
+    Vec::new()
+}
+
+/// Relay a group call signal, if both users are in the call
+fn relay_group_signal(state: &AppState, call_id: &str, from_user_id: &str, to_user_id: &str, signal: ServerMessage) -> Vec<ServerMessage> {
+    if !group_call::are_participants(state, call_id, from_user_id, to_user_id) {
+        return Vec::new();
+    }
+    relay(state, to_user_id, signal)
+}
+
+/// A quote must point at a message the sender can see. Quoted text needs a
+/// source, and unless the source is encrypted it has to appear in it.
+async fn check_quote(
+    state: &AppState,
+    from_user_id: &str,
+    quoted_message_id: Option<&str>,
+    quoted_text: Option<&str>,
+) -> Result<(), &'static str> {
+    let Some(quoted_message_id) = quoted_message_id else {
+        return match quoted_text {
+            Some(_) => Err("quoted_text needs a quoted_message_id"),
+            None => Ok(()),
+        };
+    };
+
+    let original = match state.db.get_message_for_user(quoted_message_id, from_user_id).await {
+        Ok(Some(original)) => original,
+        Ok(None) => return Err("Quoted message not found"),
+        Err(e) => {
+            tracing::error!("Failed to load quoted message: {:?}", e);
+            return Err("Failed to load quoted message");
+        }
+    };
+
+    match quoted_text {
+        Some(text) if !original.encrypted && !original.content.contains(text) => {
+            Err("Quoted text isn't part of the quoted message")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Replies for a `SendMessage` whose `client_msg_id` is already stored: the ack
+/// and the stored message again. `None` if there is no such message.
+async fn confirm_stored_message(state: &AppState, from_user_id: &str, client_msg_id: &str) -> Option<Vec<ServerMessage>> {
+    let existing = match state.db.get_message_by_client_key(from_user_id, client_msg_id).await {
+        Ok(existing) => existing?,
+        Err(e) => {
+            tracing::error!("Failed to check for duplicate message: {:?}", e);
+            return None;
+        }
+    };
+
+    // Sent earlier, so it may have reactions by now
+    let message = load_chat_messages(state, vec![existing]).await.pop()?;
+    Some(vec![
+        ServerMessage::MessageAck {
+            client_msg_id: client_msg_id.to_string(),
+            message_id: message.id.clone(),
+        },
+        ServerMessage::NewMessage { message: Box::new(message), muted: false, mentioned: false, notify: false },
+    ])
+}
+
+/// Validate, store and deliver a message from `from_user_id`. Returns the
+/// replies for the sender's connection: an error, or the ack and their copy.
+async fn handle_send_message(state: &AppState, from_user_id: &str, request: OutgoingMessage) -> Vec<ServerMessage> {
+    let OutgoingMessage {
+        to_user_id,
+        content,
+        file_data,
+        file_name,
+        file_type,
+        audio_duration,
+        encrypted,
+        attachments,
+        expires_in_seconds,
+        client_msg_id,
+        device_id,
+        device_name,
+        quoted_message_id,
+        quoted_text,
+    } = request;
+
+    if attachments.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return vec![ServerMessage::Error {
+            message: format!("Too many attachments (max {})", MAX_ATTACHMENTS_PER_MESSAGE),
+            code: None,
+        }];
+    }
+
+    let device_fields = [("device_id", &device_id), ("device_name", &device_name)];
+    for (field, value) in device_fields {
+        if value.as_ref().is_some_and(|v| v.chars().count() > MAX_DEVICE_FIELD_CHARS || v.chars().any(char::is_control)) {
+            return vec![ServerMessage::Error {
+                message: format!("{} must be at most {} characters", field, MAX_DEVICE_FIELD_CHARS),
+                code: None,
+            }];
+        }
+    }
+
+    if expires_in_seconds.is_some_and(|secs| secs == 0 || secs > MAX_MESSAGE_TTL_SECS) {
+        return vec![ServerMessage::Error {
+            message: format!("expires_in_seconds must be between 1 and {}", MAX_MESSAGE_TTL_SECS),
+            code: None,
+        }];
+    }
+
+    // Store the type the content actually has, not just what the client says.
+    // Encrypted files are opaque to the server, so they keep their label.
+    let mut file_type = file_type;
+    let mut attachments = attachments;
+    if !encrypted {
+        let reject = |message: String| {
+            tracing::warn!("Rejected file from {}: {}", from_user_id, message);
+            vec![ServerMessage::Error { message, code: None }]
+        };
+        if let Some(data) = &file_data {
+            match media::sniff_file_type(data, file_type.as_deref()) {
+                Ok(detected) => file_type = Some(detected),
+                Err(message) => return reject(message),
+            }
+        }
+        for attachment in attachments.iter_mut() {
+            match media::sniff_file_type(&attachment.file_data, Some(&attachment.file_type)) {
+                Ok(detected) => attachment.file_type = detected,
+                Err(message) => return reject(message),
+            }
+        }
+    }
+
+    let disallowed_type = file_data
+        .as_ref()
+        .map(|_| file_type.as_deref().unwrap_or("application/octet-stream"))
+        .into_iter()
+        .chain(attachments.iter().map(|a| a.file_type.as_str()))
+        .find(|t| !media::is_allowed_file_type(t, &state.config.allowed_file_types));
+    if let Some(disallowed) = disallowed_type {
+        tracing::warn!("Rejected file of type {} from {}", disallowed, from_user_id);
+        return vec![ServerMessage::Error {
+            message: format!("Files of type {} can't be sent", disallowed),
+            code: Some(ErrorCode::FileTypeNotAllowed),
+        }];
+    }
+
+    if let Err(message) = media::validate_voice_message(file_type.as_deref(), audio_duration) {
+        tracing::warn!("Rejected voice message from {}: {}", from_user_id, message);
+        return vec![ServerMessage::Error { message, code: None }];
+    }
+
+    let content = match filter_content(state, content, encrypted) {
+        Ok(content) => content,
+        Err(message) => {
+            tracing::info!("Message from {} blocked by the content filter", from_user_id);
+            return vec![ServerMessage::Error { message, code: None }];
+        }
+    };
+
+    if quoted_text.as_ref().is_some_and(|text| text.chars().count() > MAX_QUOTED_TEXT_CHARS) {
+        return vec![ServerMessage::Error {
+            message: format!("Quoted text must be at most {} characters", MAX_QUOTED_TEXT_CHARS),
+            code: None,
+        }];
+    }
+    if let Err(message) = check_quote(state, from_user_id, quoted_message_id.as_deref(), quoted_text.as_deref()).await {
+        return vec![ServerMessage::Error { message: message.to_string(), code: None }];
+    }
+
+    // A retry of a message we already stored: confirm it again, don't store twice
+    if let Some(key) = &client_msg_id {
+        if let Some(replies) = confirm_stored_message(state, from_user_id, key).await {
+            return replies;
+        }
+    }
+
+    // Files count against the sender's storage quota, as stored (base64)
+    let storage_bytes = (file_data.as_deref().map_or(0, |data| state.db.stored_file_size(data.len()))
+        + attachments.iter().map(|a| state.db.stored_file_size(a.file_data.len())).sum::<usize>()) as u64;
+    if storage_bytes > 0 {
+        match state.db.reserve_storage(from_user_id, storage_bytes, state.config.storage_quota_bytes).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return vec![ServerMessage::Error {
+                    message: "Storage quota exceeded".to_string(),
+                    code: Some(ErrorCode::QuotaExceeded),
+                }];
+            }
+            Err(e) => {
+                tracing::error!("Failed to check storage quota: {:?}", e);
+                return vec![ServerMessage::Error {
+                    message: "Failed to send message".to_string(),
+                    code: None,
+                }];
+            }
+        }
+    }
+
+    // Image previews and dimensions are worked out once at save time
+    let (thumbnail_data, dimensions) = match (&file_data, &file_type) {
+        (Some(data), Some(mime)) if mime.starts_with("image/") => {
+            let (data, mime) = (data.clone(), mime.clone());
+            tokio::task::spawn_blocking(move || {
+                (media::generate_thumbnail(&data, &mime), media::image_dimensions(&data, &mime))
+            })
+            .await
+            .unwrap_or((None, None))
+        }
+        _ => (None, None),
+    };
+
+    for attachment in attachments.iter_mut() {
+        // Whatever the client claimed, only dimensions read here are stored
+        attachment.media_width = None;
+        attachment.media_height = None;
+        if !attachment.file_type.starts_with("image/") {
+            continue;
+        }
+        let (data, mime) = (attachment.file_data.clone(), attachment.file_type.clone());
+        let (thumbnail, dimensions) = tokio::task::spawn_blocking(move || {
+            (media::generate_thumbnail(&data, &mime), media::image_dimensions(&data, &mime))
+        })
+        .await
+        .unwrap_or((None, None));
+        attachment.thumbnail_data = thumbnail.map(FileData::from);
+        attachment.media_width = dimensions.map(|(width, _)| width);
+        attachment.media_height = dimensions.map(|(_, height)| height);
+    }
+
+    // The server can't read encrypted text, so only plain messages have mentions
+    let mentions = if encrypted {
+        Vec::new()
+    } else {
+        state
+            .db
+            .get_user_ids_by_usernames(&mentions::find_mentions(&content))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("Failed to resolve mentions: {:?}", e);
+                Vec::new()
+            })
+    };
+
+    // A note to self is already with its only reader
+    let to_self = to_user_id == from_user_id;
+    let mut message = ChatMessage {
+        id: Uuid::new_v4().to_string(),
+        from_user_id: from_user_id.to_string(),
+        to_user_id: to_user_id.clone(),
+        content: content.clone(),
+        timestamp: Utc::now(),
+        read: to_self,
+        file_data: file_data.clone(),
+        file_name: file_name.clone(),
+        file_type: file_type.clone(),
+        audio_duration,
+        thumbnail_data: thumbnail_data.map(FileData::from),
+        media_width: dimensions.map(|(width, _)| width),
+        media_height: dimensions.map(|(_, height)| height),
+        device_id,
+        device_name,
+        encrypted,
+        delivered: to_self,
+        attachments,
+        link_preview: None,
+        expires_at: expires_in_seconds
+            .map(|secs| Utc::now() + chrono::Duration::seconds(secs as i64)),
+        reactions: HashMap::new(),
+        reaction_summary: HashMap::new(),
+        mentions,
+        file_data_omitted: false,
+        seq: 0,
+        quoted_message_id,
+        quoted_text,
+    };
+
+    // Save to database
+    let db_msg = DbMessage {
+        id: message.id.clone(),
+        from_user_id: message.from_user_id.clone(),
+        to_user_id: message.to_user_id.clone(),
+        content: message.content.clone(),
+        timestamp: message.timestamp.timestamp_millis(),
+        read: message.read,
+        file_data: message.file_data.clone(),
+        file_name: message.file_name.clone(),
+        file_type: message.file_type.clone(),
+        audio_duration: message.audio_duration,
+        thumbnail_data: message.thumbnail_data.clone(),
+        encrypted: message.encrypted,
+        delivered: message.delivered,
+        client_msg_id: client_msg_id.clone(),
+        expires_at: message.expires_at.map(db::sortable_timestamp),
+        media_width: message.media_width,
+        media_height: message.media_height,
+        device_id: message.device_id.clone(),
+        device_name: message.device_name.clone(),
+        seq: 0,
+        quoted_message_id: message.quoted_message_id.clone(),
+        quoted_text: message.quoted_text.clone(),
+    };
+
+    let db_attachments: Vec<DbAttachment> = message
+        .attachments
+        .iter()
+        .enumerate()
+        .map(|(position, a)| DbAttachment {
+            message_id: message.id.clone(),
+            position: position as i32,
+            file_data: a.file_data.clone(),
+            file_name: a.file_name.clone(),
+            file_type: a.file_type.clone(),
+            thumbnail_data: a.thumbnail_data.clone(),
+            media_width: a.media_width,
+            media_height: a.media_height,
+        })
+        .collect();
+
+    match state.db.save_message(&db_msg, &db_attachments).await {
+        Ok(seq) => message.seq = seq,
+        Err(e) => {
+            // Give back the storage reserved for files that weren't kept
+            if storage_bytes > 0 {
+                if let Err(e) = state.db.refresh_storage_used(from_user_id).await {
+                    tracing::error!("Failed to recount storage: {:?}", e);
+                }
+            }
+
+            // A concurrent retry stored it first; confirm that one
+            if db::is_unique_violation(&e) {
+                if let Some(replies) = confirm_stored_message(state, from_user_id, client_msg_id.as_deref().unwrap_or_default()).await {
+                    return replies;
+                }
+            }
+
+            // The recipient doesn't exist; nothing to deliver
+            if db::is_foreign_key_violation(&e) {
+                return vec![ServerMessage::Error {
+                    message: "User not found".to_string(),
+                    code: None,
+                }];
+            }
+
+            tracing::error!("Failed to save message: {:?}", e);
+            return vec![ServerMessage::Error {
+                message: "Failed to send message".to_string(),
+                code: None,
+            }];
+        }
+    }
+
+    if let Err(e) = state.db.save_mentions(&message.id, &message.mentions).await {
+        tracing::error!("Failed to save mentions: {:?}", e);
+    }
+
+    // The server can't read encrypted content, so only plain messages get previews
+    if !message.encrypted {
+        spawn_link_preview(state, &message);
+    }
+
+    // Whatever was being drafted for this chat has now been sent
+    if let Err(e) = state.db.delete_draft(from_user_id, &to_user_id).await {
+        tracing::error!("Failed to clear draft: {:?}", e);
+    }
+
+    // Send to recipient if online; a note to self only needs the confirmation below
+    if !to_self {
+        unarchive_for_recipient(state, &message).await;
+        deliver_to_recipient(state, &mut message).await;
+    }
+
+    let mut replies = Vec::new();
+    if let Some(client_msg_id) = client_msg_id {
+        replies.push(ServerMessage::MessageAck {
+            client_msg_id,
+            message_id: message.id.clone(),
+        });
+    }
+
+    // Also send to sender for confirmation
+    replies.push(ServerMessage::NewMessage {
+        message: Box::new(message),
+        muted: false,
+        mentioned: false,
+        notify: false,
+    });
+    replies
+}
+
+/// Close a call's history record, scoring it from the stats reported during the call
+async fn end_call(state: &AppState, call_id: &str, user_id: &str) {
+    let quality_score = match state.db.get_call_stats_averages(call_id).await {
+        Ok(averages) => call_quality_score(&averages),
+        Err(e) => {
+            tracing::error!("Failed to load call stats: {:?}", e);
+            None
+        }
+    };
+
+    if let Err(e) = state.db.end_call(call_id, user_id, quality_score).await {
+        tracing::error!("Failed to record end of call {}: {:?}", call_id, e);
+    }
+}
+
+/// Rough call quality from 1 (unusable) to 5 (excellent), in the spirit of a MOS.
+/// Each percent of packet loss costs a quarter point; delay (round trip plus twice
+/// the jitter) costs a point per 100 ms above 150 ms. `None` without loss or delay data.
+fn call_quality_score(averages: &db::DbCallStats) -> Option<f64> {
+    if averages.packet_loss_percent.is_none() && averages.round_trip_time_ms.is_none() {
+        return None;
+    }
+
+    let loss_penalty = averages.packet_loss_percent.unwrap_or(0.0) * 0.25;
+    let delay = averages.round_trip_time_ms.unwrap_or(0.0) + 2.0 * averages.jitter_ms.unwrap_or(0.0);
+    let delay_penalty = (delay - 150.0).max(0.0) / 100.0;
+
+    Some((5.0 - loss_penalty - delay_penalty).clamp(1.0, 5.0))
+}
+
+/// Presence cleanup when a connection ends: mark its user offline, unless the
+/// connection was already replaced by a newer one or removed by an admin disconnect
+async fn disconnect_cleanup(
+    state: &AppState,
+    current_user_id: Option<String>,
+    user_tx: &ClientSender,
+) {
+    let owned_socket = current_user_id.filter(|user_id| {
+        state
+            .user_sockets
+            .remove_if(user_id, |_, tx| tx.same_channel(user_tx))
+            .is_some()
+    });
+
+    if let Some(user_id) = owned_socket {
+        state.online_users.remove(&user_id);
+        state.event_log.went_offline(&user_id);
+        state.typing_throttle.forget(&user_id);
+        group_call::leave_all(state, &user_id);
+
+        // Update last seen in database
+        let _ = state.db.update_last_seen(&user_id).await;
+
+        // Notify users about offline user
+        state.broadcast_presence(&user_id, ServerMessage::UserOffline { user_id: user_id.clone() }).await;
+
+        tracing::info!("User disconnected: {}", user_id);
+    }
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, compress: bool, protocol: ProtocolVersion) {
+    // Every log line from this connection carries its id, plus the user id once authenticated
+    let span = tracing::info_span!(
+        "ws",
+        conn_id = %Uuid::new_v4(),
+        ?protocol,
+        user_id = tracing::field::Empty,
+    );
+    span.in_scope(|| tracing::debug!("WebSocket connected"));
+
+    let (mut sender, mut receiver) = socket.split();
+    let (user_tx, mut user_rx) = outbox::channel(state.config.outbound_queue_capacity, state.outbox_stats.clone());
+    let mut current_user_id: Option<String> = None;
+
+    // The receive task tells the send task to finish the close handshake once the client
+    // hangs up; the send task tells the receive task to stop if the connection can't be written to
+    let (close_tx, mut close_rx) = tokio::sync::oneshot::channel::<()>();
+    let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // Task to send messages to the client
+    let mut send_task = tokio::spawn(async move {
+        loop {
+            let outgoing = tokio::select! {
+                outgoing = user_rx.recv() => match outgoing {
+                    Some(outgoing) => outgoing,
+                    None => break,
+                },
+                _ = &mut close_rx => {
+                    // The protocol layer has queued the echo of the client's close
+                    // frame (same code and reason); closing the sink flushes it
+                    let _ = sender.close().await;
+                    break;
+                }
+            };
 
-                    ClientMessage::CallEnd { to_user_id } => {
-                        if let Some(from_user_id) = &current_user_id {
-                            if let Some(recipient_tx) = state.user_sockets.get(&to_user_id) {
-                                let _ = recipient_tx.send(ServerMessage::CallEnd {
-                                    from_user_id: from_user_id.clone(),
-                                });
-                            }
-                        }
+            // A forced disconnect is forwarded to the client, then the connection is closed
+            let disconnect = matches!(outgoing.message, ServerMessage::Disconnected { .. });
+
+            if let Ok(text) = outgoing.to_json() {
+                let send = sender.send(encode_frame(text, compress));
+                let sent = if disconnect {
+                    // Best effort: this may be a slow client that stopped reading
+                    tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, send).await.is_ok_and(|sent| sent.is_ok())
+                } else {
+                    tokio::select! {
+                        sent = send => sent.is_ok(),
+                        // The client stopped reading and its queue overflowed meanwhile
+                        _ = user_rx.overflowed() => false,
                     }
+                };
+                if !sent {
+                    break;
                 }
             }
+
+            if disconnect {
+                let _ = tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, sender.send(Message::Close(None))).await;
+                break;
+            }
         }
+    }.instrument(span.clone()));
 
-        // User disconnected - mark as offline
-        if let Some(user_id) = current_user_id {
-            state.online_users.remove(&user_id);
-            state.user_sockets.remove(&user_id);
-            
-            // Update last seen in database
-            let _ = state.db.update_last_seen(&user_id).await;
-            
-            // Notify all users about offline user
-            for entry in state.user_sockets.iter() {
-                let _ = entry.value().send(ServerMessage::UserOffline {
-                    user_id: user_id.clone(),
-                });
+    let state_clone = state.clone();
+    let user_tx_clone = user_tx.clone();
+
+    // Task to receive messages from the client
+    let mut recv_task = tokio::spawn(async move {
+        let state = state_clone;
+        let user_tx = user_tx_clone;
+
+        // A connection that doesn't log in or register in time is closed
+        let auth_deadline = tokio::time::sleep(Duration::from_secs(state.config.auth_timeout_secs));
+        tokio::pin!(auth_deadline);
+        let mut awaiting_auth = true;
+        let mut auth_timed_out = false;
+
+        loop {
+            let frame = tokio::select! {
+                frame = receiver.next() => frame,
+                _ = &mut stop_rx => break,
+                _ = &mut auth_deadline, if awaiting_auth => {
+                    tracing::info!("Closing connection that didn't authenticate in time");
+                    awaiting_auth = false;
+                    auth_timed_out = true;
+                    let _ = user_tx.send(ServerMessage::AuthError {
+                        message: "Authentication timed out".to_string(),
+                    });
+                    // The send task closes the connection after this
+                    let _ = user_tx.send(ServerMessage::Disconnected {
+                        reason: "Authentication timed out".to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            let text = match frame {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(frame))) => {
+                    match frame {
+                        Some(frame) => tracing::info!("Client closed the connection: {} {:?}", frame.code, frame.reason),
+                        None => tracing::info!("Client closed the connection without a code"),
+                    }
+                    break;
+                }
+                // Pings are answered by the protocol layer; binary frames aren't part of the protocol
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    tracing::debug!("WebSocket read failed: {}", e);
+                    break;
+                }
+                None => break,
+            };
+
+            // The connection is being closed; it's too late to log in
+            if auth_timed_out {
+                continue;
+            }
+
+            // Replies keep failing, so the client is gone; skip the frames it left behind
+            if user_tx.is_failing() {
+                tracing::info!("Client stopped receiving, closing connection");
+                break;
             }
 
-            tracing::info!("User disconnected: {}", user_id);
+            let client_msg = match serde_json::from_str::<ClientMessage>(&text) {
+                Ok(client_msg) => client_msg,
+                Err(e) => {
+                    tracing::debug!("Rejected malformed client message: {}", e);
+                    let _ = user_tx.send(ServerMessage::Error {
+                        message: describe_parse_error(&e),
+                        code: None,
+                    });
+                    continue;
+                }
+            };
+            handle_client_message(&state, &mut current_user_id, &user_tx, client_msg).await;
+            if current_user_id.is_some() {
+                awaiting_auth = false;
+            }
         }
-    });
+
+        let _ = close_tx.send(());
+
+        disconnect_cleanup(&state, current_user_id, &user_tx).await;
+    }.instrument(span));
 
     tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
+        _ = (&mut send_task) => {
+            // Let the receive task wind down so the offline cleanup still runs
+            let _ = stop_tx.send(());
+            let _ = recv_task.await;
+        }
+        _ = (&mut recv_task) => {
+            if tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, &mut send_task).await.is_err() {
+                send_task.abort();
+            }
+        }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// State over a fresh in-memory database with users "alice" and "bob"
+    async fn test_state() -> AppState {
+        let db = Database::new("sqlite::memory:", None).await.unwrap();
+        for id in ["alice", "bob"] {
+            db.create_user(id, id, "").await.unwrap();
+        }
+        AppState::new(Config::from_env(), db, None, None)
+    }
+
+    fn outgoing(request: serde_json::Value) -> OutgoingMessage {
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn error_message(replies: &[ServerMessage]) -> &str {
+        match replies {
+            [ServerMessage::Error { message, .. }] => message,
+            other => panic!("expected a single error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_message_stores_and_confirms() {
+        let state = test_state().await;
+
+        let replies = handle_send_message(
+            &state,
+            "alice",
+            outgoing(json!({ "to_user_id": "bob", "content": "hi bob", "client_msg_id": "c1" })),
+        )
+        .await;
+
+        let [ServerMessage::MessageAck { client_msg_id, message_id }, ServerMessage::NewMessage { message, .. }] = replies.as_slice() else {
+            panic!("expected an ack and the sender's copy, got {:?}", replies);
+        };
+        assert_eq!(client_msg_id, "c1");
+        assert_eq!(message_id, &message.id);
+        assert_eq!(message.content, "hi bob");
+        assert_eq!(message.seq, 1);
+
+        let stored = state.db.get_messages_between_users("alice", "bob", 10, 0).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, message.id);
+    }
+
+    #[tokio::test]
+    async fn send_message_retry_returns_the_stored_message() {
+        let state = test_state().await;
+        let request = json!({ "to_user_id": "bob", "content": "once", "client_msg_id": "c1" });
+
+        let first = handle_send_message(&state, "alice", outgoing(request.clone())).await;
+        let retry = handle_send_message(&state, "alice", outgoing(request)).await;
+
+        let message_id = |replies: &[ServerMessage]| match replies {
+            [ServerMessage::MessageAck { message_id, .. }, _] => message_id.clone(),
+            other => panic!("expected an ack, got {:?}", other),
+        };
+        assert_eq!(message_id(&first), message_id(&retry));
+        let stored = state.db.get_messages_between_users("alice", "bob", 10, 0).await.unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_retries_store_one_message() {
+        let state = test_state().await;
+        let request = json!({ "to_user_id": "bob", "content": "once", "client_msg_id": "c1" });
+
+        let (first, second) = tokio::join!(
+            handle_send_message(&state, "alice", outgoing(request.clone())),
+            handle_send_message(&state, "alice", outgoing(request)),
+        );
+
+        let acked = |replies: &[ServerMessage]| match replies {
+            [ServerMessage::MessageAck { message_id, .. }, ServerMessage::NewMessage { message, .. }] => {
+                assert_eq!(message_id, &message.id);
+                message_id.clone()
+            }
+            other => panic!("expected an ack and the message, got {:?}", other),
+        };
+        let stored = state.db.get_messages_between_users("alice", "bob", 10, 0).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(acked(&first), stored[0].id);
+        assert_eq!(acked(&second), stored[0].id);
+    }
+
+    #[tokio::test]
+    async fn send_message_to_unknown_user_is_an_error() {
+        let state = test_state().await;
+
+        let replies = handle_send_message(&state, "alice", outgoing(json!({ "to_user_id": "nobody", "content": "hello?" }))).await;
+
+        assert_eq!(error_message(&replies), "User not found");
+    }
+
+    #[tokio::test]
+    async fn messages_to_a_vanished_socket_stay_pending() {
+        let state = test_state().await;
+        let (bob_tx, bob_rx) = outbox::channel(16, state.outbox_stats.clone());
+        state.user_sockets.insert("bob".to_string(), bob_tx);
+        drop(bob_rx);
+
+        let replies = handle_send_message(&state, "alice", outgoing(json!({ "to_user_id": "bob", "content": "still there?" }))).await;
+
+        let [ServerMessage::NewMessage { message, .. }] = replies.as_slice() else {
+            panic!("expected the sender's copy, got {:?}", replies);
+        };
+        assert!(!message.delivered);
+        assert!(!state.user_sockets.contains_key("bob"), "the stale socket should be dropped");
+        let pending = state.db.get_undelivered_messages("bob", 10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, message.id);
+    }
+
+    #[tokio::test]
+    async fn send_message_rejects_invalid_requests() {
+        let state = test_state().await;
+
+        let requests = [
+            json!({ "to_user_id": "bob", "content": "gone soon", "expires_in_seconds": 0 }),
+            json!({ "to_user_id": "bob", "content": "hi", "device_name": "a\nb" }),
+            json!({ "to_user_id": "bob", "content": "hi", "quoted_text": "no source" }),
+            json!({ "to_user_id": "bob", "content": "hi", "quoted_message_id": "missing" }),
+        ];
+        for request in requests {
+            let replies = handle_send_message(&state, "alice", outgoing(request.clone())).await;
+            assert!(matches!(replies.as_slice(), [ServerMessage::Error { .. }]), "{} gave {:?}", request, replies);
+        }
+
+        let stored = state.db.get_messages_between_users("alice", "bob", 10, 0).await.unwrap();
+        assert!(stored.is_empty());
+    }
+
+    fn offering(protocols: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for protocol in protocols {
+            headers.append(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(protocol).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn negotiate_accepts_supported_subprotocols() {
+        assert_eq!(ProtocolVersion::negotiate(&offering(&["chat.v1"])), Some(ProtocolVersion::V1));
+        assert_eq!(ProtocolVersion::negotiate(&offering(&["chat.v9, chat.v1"])), Some(ProtocolVersion::V1));
+        assert_eq!(ProtocolVersion::negotiate(&offering(&["chat.v9", "chat.v1"])), Some(ProtocolVersion::V1));
+    }
+
+    #[test]
+    fn negotiate_defaults_to_v1_without_an_offer() {
+        assert_eq!(ProtocolVersion::negotiate(&offering(&[])), Some(ProtocolVersion::V1));
+        assert_eq!(ProtocolVersion::negotiate(&offering(&[" "])), Some(ProtocolVersion::V1));
+    }
+
+    #[test]
+    fn negotiate_rejects_unsupported_subprotocols() {
+        assert_eq!(ProtocolVersion::negotiate(&offering(&["chat.v2"])), None);
+        assert_eq!(ProtocolVersion::negotiate(&offering(&["graphql-ws, chat.v0"])), None);
+    }
+
+    #[tokio::test]
+    async fn typing_starts_dropped_by_the_limit_are_not_throttled() {
+        let mut state = test_state().await;
+        state.typing_limiter = Arc::new(RateLimiter::new(2, RATE_LIMIT_WINDOW));
+        let (bob_tx, mut bob_rx) = outbox::channel(16, state.outbox_stats.clone());
+        state.user_sockets.insert("bob".to_string(), bob_tx);
+        let (alice_tx, _alice_rx) = outbox::channel(16, state.outbox_stats.clone());
+        let mut alice = Some("alice".to_string());
+        let typing = |is_typing| ClientMessage::Typing { to_user_id: "bob".to_string(), is_typing };
+
+        for is_typing in [true, false, true, false] {
+            handle_client_message(&state, &mut alice, &alice_tx, typing(is_typing)).await;
+        }
+        // Over the limit: dropped
+        handle_client_message(&state, &mut alice, &alice_tx, typing(true)).await;
+        // The window has passed, and the dropped start didn't begin a throttle interval
+        state.typing_limiter.reset("alice");
+        handle_client_message(&state, &mut alice, &alice_tx, typing(true)).await;
+
+        state.user_sockets.clear();
+        let mut forwarded = Vec::new();
+        while let Some(outgoing) = bob_rx.recv().await {
+            let ServerMessage::Typing { is_typing, .. } = outgoing.message else {
+                panic!("unexpected message: {:?}", outgoing.message);
+            };
+            forwarded.push(is_typing);
+        }
+        assert_eq!(forwarded, [true, false, true, false, true]);
+    }
+
+    #[tokio::test]
+    async fn only_get_users_answers_before_login() {
+        let state = test_state().await;
+        let (tx, mut rx) = outbox::channel(16, state.outbox_stats.clone());
+        let mut nobody = None;
+
+        handle_client_message(&state, &mut nobody, &tx, ClientMessage::GetContacts).await;
+        handle_client_message(&state, &mut nobody, &tx, ClientMessage::GetUsers { ids: vec!["bob".to_string()] }).await;
+
+        drop(tx);
+        let mut replies = Vec::new();
+        while let Some(outgoing) = rx.recv().await {
+            replies.push(outgoing.message);
+        }
+        assert_eq!(error_message(&replies), "Not authenticated");
+    }
+
+    #[tokio::test]
+    async fn drafts_are_saved_and_read_back() {
+        let state = test_state().await;
+
+        let replies = handle_save_draft(&state, "alice", "bob", &"x".repeat(MAX_DRAFT_LENGTH + 1)).await;
+        assert_eq!(error_message(&replies), "Draft too long");
+        assert!(handle_save_draft(&state, "alice", "bob", "see you").await.is_empty());
+
+        let replies = handle_get_draft(&state, "alice", "bob".to_string()).await;
+        assert!(
+            matches!(&replies[..], [ServerMessage::Draft { content: Some(content), .. }] if content == "see you"),
+            "unexpected replies: {:?}",
+            replies
+        );
+    }
+
+    #[test]
+    fn logged_login_requests_hide_the_password() {
+        let login: ClientMessage =
+            serde_json::from_value(json!({ "type": "Login", "username": "alice", "password": "hunter2" })).unwrap();
+
+        let logged = format!("{:?}", login);
+
+        assert!(logged.contains("***"), "{}", logged);
+        assert!(!logged.contains("hunter2"), "{}", logged);
+    }
+
+    #[test]
+    fn malformed_stored_timestamps_map_to_the_epoch() {
+        assert_eq!(stored_timestamp("last tuesday", "message", "m1"), DateTime::UNIX_EPOCH);
+        assert_eq!(stored_millis(i64::MAX, "message", "m1"), DateTime::UNIX_EPOCH);
+
+        let valid = stored_timestamp("2024-01-01T12:00:00+02:00", "message", "m1");
+        assert_eq!(valid.timestamp_millis(), 1_704_103_200_000);
+        assert_eq!(stored_millis(1_704_103_200_000, "message", "m1"), valid);
+    }
+}
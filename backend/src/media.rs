@@ -0,0 +1,360 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use image::{DynamicImage, ImageFormat};
+use std::io::Cursor;
+
+/// Longest edge of a generated thumbnail, in pixels
+pub const THUMBNAIL_MAX_DIMENSION: u32 = 320;
+
+/// Longest edge of a stored avatar, in pixels
+pub const AVATAR_MAX_DIMENSION: u32 = 256;
+
+/// Largest avatar upload accepted, in decoded bytes
+pub const MAX_AVATAR_BYTES: usize = 2 * 1024 * 1024;
+
+/// Image types accepted as avatars
+const ACCEPTED_AVATAR_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Longest voice message accepted, in seconds
+pub const MAX_VOICE_DURATION_SECS: f64 = 300.0;
+
+/// Audio containers the clients' MediaRecorder can produce and browsers can play back
+const ACCEPTED_AUDIO_TYPES: &[&str] = &[
+    "audio/webm",
+    "audio/ogg",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/aac",
+    "audio/wav",
+];
+
+/// Base64 characters decoded when sniffing a file's type; enough for every
+/// signature `infer` knows without decoding the whole file
+const SNIFF_PREFIX_CHARS: usize = 8192;
+
+/// Reported for files whose content matches no known signature
+const UNKNOWN_FILE_TYPE: &str = "application/octet-stream";
+
+/// Decode attachment data as sent by clients.
+/// Accepts data URLs (`data:image/png;base64,...`) as well as bare base64.
+pub fn decode_file_data(file_data: &str) -> Option<Vec<u8>> {
+    let encoded = match file_data.split_once(";base64,") {
+        Some((_, payload)) => payload,
+        None => file_data,
+    };
+    STANDARD.decode(encoded.trim()).ok()
+}
+
+/// Encode raw bytes as a data URL so clients can use it directly as an `src`
+pub fn encode_data_url(mime_type: &str, bytes: &[u8]) -> String {
+    format!("data:{};base64,{}", mime_type, STANDARD.encode(bytes))
+}
+
+/// Work out the type to store for an attachment from its content rather than
+/// trusting the client's label.
+///
+/// A recognised type replaces the claimed one, so an executable labeled
+/// `image/png` can't reach recipients as an image; executables are rejected
+/// outright. Audio and video share containers (WebM, MP4, Ogg), so a claimed
+/// audio or video type is kept when the content is either. Content with no
+/// known signature (plain text, CSV, ...) keeps its label unless that claims
+/// a media type, in which case it's stored as `application/octet-stream`.
+pub fn sniff_file_type(file_data: &str, claimed: Option<&str>) -> Result<String, String> {
+    let bytes = decode_prefix(file_data).ok_or_else(|| "File is not valid base64".to_string())?;
+    let claimed = claimed.map(|t| t.trim().to_ascii_lowercase()).filter(|t| !t.is_empty());
+
+    let Some(kind) = infer::get(&bytes) else {
+        return Ok(match claimed {
+            Some(t) if !is_media_type(&t) => t,
+            _ => UNKNOWN_FILE_TYPE.to_string(),
+        });
+    };
+
+    if kind.matcher_type() == infer::MatcherType::App {
+        return Err(format!("Executable files can't be sent ({})", kind.mime_type()));
+    }
+
+    let detected = kind.mime_type();
+    match claimed {
+        Some(t) if is_audio_or_video(&t) && is_audio_or_video(detected) => Ok(t),
+        Some(t) if base_type(&t) == detected => Ok(t),
+        Some(t) => {
+            tracing::debug!("Correcting attachment type {} to {}", t, detected);
+            Ok(detected.to_string())
+        }
+        None => Ok(detected.to_string()),
+    }
+}
+
+/// Whether `file_type` matches an entry of the `ALLOWED_FILE_TYPES` allowlist.
+/// Parameters are ignored, so `audio/webm;codecs=opus` matches `audio/webm`.
+pub fn is_allowed_file_type(file_type: &str, allowed: &[String]) -> bool {
+    let file_type = base_type(file_type).to_ascii_lowercase();
+    allowed.iter().any(|pattern| {
+        if pattern == "*" {
+            return true;
+        }
+        match pattern.strip_suffix("/*") {
+            Some(prefix) => file_type.split_once('/').is_some_and(|(top, _)| top == prefix),
+            None => *pattern == file_type,
+        }
+    })
+}
+
+/// Decode just the start of attachment data, for signature checks
+fn decode_prefix(file_data: &str) -> Option<Vec<u8>> {
+    let encoded = match file_data.split_once(";base64,") {
+        Some((_, payload)) => payload,
+        None => file_data,
+    };
+    let encoded = encoded.trim();
+    // A multiple of 4 characters, so the cut falls between whole base64 groups
+    STANDARD.decode(encoded.get(..SNIFF_PREFIX_CHARS).unwrap_or(encoded)).ok()
+}
+
+/// MIME type without parameters, e.g. `audio/webm` for `audio/webm;codecs=opus`
+fn base_type(mime_type: &str) -> &str {
+    mime_type.split(';').next().unwrap_or(mime_type).trim()
+}
+
+fn is_audio_or_video(mime_type: &str) -> bool {
+    mime_type.starts_with("audio/") || mime_type.starts_with("video/")
+}
+
+fn is_media_type(mime_type: &str) -> bool {
+    mime_type.starts_with("image/") || is_audio_or_video(mime_type)
+}
+
+/// Generate a downscaled preview for an image attachment.
+///
+/// Returns `None` when the attachment isn't an image, can't be decoded,
+/// or is already small enough to be shown as-is. This is CPU-bound, so
+/// call it from `tokio::task::spawn_blocking`.
+pub fn generate_thumbnail(file_data: &str, file_type: &str) -> Option<String> {
+    if !file_type.starts_with("image/") {
+        return None;
+    }
+
+    let bytes = decode_file_data(file_data)?;
+    let image = match image::load_from_memory(&bytes) {
+        Ok(image) => image,
+        Err(e) => {
+            tracing::debug!("Skipping thumbnail for unsupported image: {}", e);
+            return None;
+        }
+    };
+
+    if image.width() <= THUMBNAIL_MAX_DIMENSION && image.height() <= THUMBNAIL_MAX_DIMENSION {
+        return None;
+    }
+
+    let thumbnail = image.thumbnail(THUMBNAIL_MAX_DIMENSION, THUMBNAIL_MAX_DIMENSION);
+
+    // JPEG has no alpha channel, so keep transparent images as PNG
+    let (format, mime_type, thumbnail) = if thumbnail.color().has_alpha() {
+        (ImageFormat::Png, "image/png", thumbnail)
+    } else {
+        (ImageFormat::Jpeg, "image/jpeg", DynamicImage::ImageRgb8(thumbnail.to_rgb8()))
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    if let Err(e) = thumbnail.write_to(&mut encoded, format) {
+        tracing::error!("Failed to encode thumbnail: {:?}", e);
+        return None;
+    }
+
+    Some(encode_data_url(mime_type, &encoded.into_inner()))
+}
+
+/// Read the pixel size of an image attachment from its header, without decoding it.
+///
+/// Returns `None` when the attachment isn't an image or the header can't be
+/// parsed (e.g. a corrupt or unsupported file).
+pub fn image_dimensions(file_data: &str, file_type: &str) -> Option<(u32, u32)> {
+    if !file_type.starts_with("image/") {
+        return None;
+    }
+
+    let bytes = decode_file_data(file_data)?;
+    let reader = image::ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?;
+    match reader.into_dimensions() {
+        Ok(dimensions) => Some(dimensions),
+        Err(e) => {
+            tracing::debug!("Could not read image dimensions: {}", e);
+            None
+        }
+    }
+}
+
+/// Validate an avatar upload and re-encode it at `AVATAR_MAX_DIMENSION`.
+///
+/// Re-encoding also drops any metadata (e.g. EXIF location) from the upload.
+/// Returns the encoded bytes and their MIME type. CPU-bound, like
+/// `generate_thumbnail`.
+pub fn process_avatar(file_data: &str, file_type: &str) -> Result<(Vec<u8>, &'static str), String> {
+    if !ACCEPTED_AVATAR_TYPES.contains(&file_type.trim().to_ascii_lowercase().as_str()) {
+        return Err(format!("Unsupported avatar format: {}", file_type));
+    }
+
+    let bytes = decode_file_data(file_data).ok_or_else(|| "Avatar is not valid base64".to_string())?;
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(format!("Avatar too large (max {} KiB)", MAX_AVATAR_BYTES / 1024));
+    }
+
+    let image = image::load_from_memory(&bytes).map_err(|_| "Avatar is not a readable image".to_string())?;
+    let image = image.thumbnail(AVATAR_MAX_DIMENSION, AVATAR_MAX_DIMENSION);
+
+    let (format, mime_type, image) = if image.color().has_alpha() {
+        (ImageFormat::Png, "image/png", image)
+    } else {
+        (ImageFormat::Jpeg, "image/jpeg", DynamicImage::ImageRgb8(image.to_rgb8()))
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format).map_err(|e| {
+        tracing::error!("Failed to encode avatar: {:?}", e);
+        "Failed to process avatar".to_string()
+    })?;
+
+    Ok((encoded.into_inner(), mime_type))
+}
+
+/// Validate a voice message's MIME type and declared duration.
+///
+/// Voice messages are the ones carrying an `audio_duration`; plain file
+/// attachments (including audio files) are accepted unchanged.
+pub fn validate_voice_message(file_type: Option<&str>, audio_duration: Option<f64>) -> Result<(), String> {
+    let Some(duration) = audio_duration else {
+        return Ok(());
+    };
+
+    // MediaRecorder types may carry codec parameters, e.g. "audio/webm;codecs=opus"
+    let base_type = file_type
+        .and_then(|t| t.split(';').next())
+        .map(|t| t.trim().to_ascii_lowercase());
+
+    match base_type {
+        Some(t) if ACCEPTED_AUDIO_TYPES.contains(&t.as_str()) => {}
+        Some(t) => return Err(format!("Unsupported audio format: {}", t)),
+        None => return Err("Voice message is missing its audio type".to_string()),
+    }
+
+    if !duration.is_finite() || duration <= 0.0 {
+        return Err("Invalid voice message duration".to_string());
+    }
+
+    if duration > MAX_VOICE_DURATION_SECS {
+        return Err(format!(
+            "Voice message too long (max {} seconds)",
+            MAX_VOICE_DURATION_SECS
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png() -> String {
+        png_of_size(4, 3)
+    }
+
+    fn png_of_size(width: u32, height: u32) -> String {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        STANDARD.encode(bytes)
+    }
+
+    #[test]
+    fn correctly_labeled_image_keeps_its_type() {
+        assert_eq!(sniff_file_type(&png(), Some("image/png")).unwrap(), "image/png");
+        assert_eq!(sniff_file_type(&png(), None).unwrap(), "image/png");
+        assert_eq!(image_dimensions(&png(), "image/png"), Some((4, 3)));
+    }
+
+    #[test]
+    fn data_urls_are_sniffed_by_their_payload() {
+        let data_url = encode_data_url("image/png", &STANDARD.decode(png()).unwrap());
+
+        assert_eq!(sniff_file_type(&data_url, Some("image/png")).unwrap(), "image/png");
+    }
+
+    #[test]
+    fn mislabeled_image_gets_its_real_type() {
+        assert_eq!(sniff_file_type(&png(), Some("image/jpeg")).unwrap(), "image/png");
+        assert_eq!(sniff_file_type(&png(), Some("text/plain")).unwrap(), "image/png");
+    }
+
+    #[test]
+    fn text_claiming_to_be_an_image_is_stored_as_unknown() {
+        let text = STANDARD.encode("just some text, not a picture");
+
+        assert_eq!(sniff_file_type(&text, Some("image/png")).unwrap(), UNKNOWN_FILE_TYPE);
+        assert_eq!(sniff_file_type(&text, Some("text/plain")).unwrap(), "text/plain");
+    }
+
+    #[test]
+    fn executables_are_rejected_whatever_the_label() {
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(64, 0);
+        let mut exe = b"MZ".to_vec();
+        exe.resize(64, 0);
+
+        assert!(sniff_file_type(&STANDARD.encode(elf), Some("image/png")).is_err());
+        assert!(sniff_file_type(&STANDARD.encode(exe), Some("application/pdf")).is_err());
+    }
+
+    #[test]
+    fn invalid_base64_is_rejected() {
+        assert!(sniff_file_type("not base64 at all!", Some("image/png")).is_err());
+    }
+
+    #[test]
+    fn allowlist_supports_wildcards_and_ignores_parameters() {
+        let allowed = vec!["image/png".to_string(), "audio/*".to_string()];
+
+        assert!(is_allowed_file_type("image/png", &allowed));
+        assert!(is_allowed_file_type("audio/webm;codecs=opus", &allowed));
+        assert!(!is_allowed_file_type("image/svg+xml", &allowed));
+        assert!(is_allowed_file_type("application/zip", &["*".to_string()]));
+    }
+
+    #[test]
+    fn large_images_get_a_downscaled_thumbnail() {
+        let thumbnail = generate_thumbnail(&png_of_size(640, 480), "image/png").unwrap();
+
+        let (header, data) = thumbnail.split_once(',').unwrap();
+        assert_eq!(header, "data:image/jpeg;base64");
+        let image = image::load_from_memory(&STANDARD.decode(data).unwrap()).unwrap();
+        assert_eq!((image.width(), image.height()), (THUMBNAIL_MAX_DIMENSION, 240));
+    }
+
+    #[test]
+    fn small_images_and_other_files_get_no_thumbnail() {
+        let text = STANDARD.encode("just some text, not a picture");
+
+        assert_eq!(generate_thumbnail(&png(), "image/png"), None);
+        assert_eq!(generate_thumbnail(&text, "text/plain"), None);
+        assert_eq!(generate_thumbnail(&text, "image/png"), None);
+    }
+
+    #[test]
+    fn voice_notes_within_bounds_are_accepted() {
+        assert_eq!(validate_voice_message(Some("audio/webm;codecs=opus"), Some(12.5)), Ok(()));
+        assert_eq!(validate_voice_message(Some("audio/ogg"), Some(MAX_VOICE_DURATION_SECS)), Ok(()));
+        // Plain attachments carry no duration and aren't voice messages
+        assert_eq!(validate_voice_message(Some("application/zip"), None), Ok(()));
+    }
+
+    #[test]
+    fn voice_notes_out_of_bounds_are_rejected() {
+        assert!(validate_voice_message(Some("audio/webm"), Some(MAX_VOICE_DURATION_SECS + 1.0)).is_err());
+        assert!(validate_voice_message(Some("audio/webm"), Some(0.0)).is_err());
+        assert!(validate_voice_message(Some("audio/webm"), Some(f64::NAN)).is_err());
+        assert!(validate_voice_message(Some("image/png"), Some(3.0)).is_err());
+        assert!(validate_voice_message(None, Some(3.0)).is_err());
+    }
+}
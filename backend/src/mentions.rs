@@ -0,0 +1,23 @@
+/// Most distinct users a single message can mention
+const MAX_MENTIONS_PER_MESSAGE: usize = 50;
+
+/// Usernames written as `@username` in message text, lowercased for lookup and
+/// without repeats. A mention has to start a word, so email addresses don't count.
+pub fn find_mentions(content: &str) -> Vec<String> {
+    let mut usernames: Vec<String> = Vec::new();
+    for word in content.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        // Drop punctuation that usually follows a name in a sentence
+        let name = name.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']).to_lowercase();
+        if name.is_empty() || usernames.contains(&name) {
+            continue;
+        }
+        usernames.push(name);
+        if usernames.len() == MAX_MENTIONS_PER_MESSAGE {
+            break;
+        }
+    }
+    usernames
+}
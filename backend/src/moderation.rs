@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// What happens to a message that contains a filtered word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Refuse the message; the sender gets an error
+    Reject,
+    /// Deliver it with each filtered word replaced by `*`s
+    Mask,
+}
+
+impl FromStr for FilterAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "mask" => Ok(Self::Mask),
+            other => Err(format!("unknown content filter action: {}", other)),
+        }
+    }
+}
+
+/// Checks message text against an operator-supplied wordlist. Matching is by
+/// whole word and ignores case, so "class" doesn't trip on "ass".
+pub struct ContentFilter {
+    words: HashSet<String>,
+    action: FilterAction,
+}
+
+impl ContentFilter {
+    /// Load a wordlist with one word per line; blank lines and `#` comments are skipped
+    pub fn load(path: &str, action: FilterAction) -> std::io::Result<Self> {
+        let list = std::fs::read_to_string(path)?;
+        Ok(Self::new(list.lines(), action))
+    }
+
+    pub fn new<'a>(words: impl IntoIterator<Item = &'a str>, action: FilterAction) -> Self {
+        let words = words
+            .into_iter()
+            .map(str::trim)
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+        Self { words, action }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn action(&self) -> FilterAction {
+        self.action
+    }
+
+    /// Apply the filter to a message's text. Returns the text to store (masked
+    /// if needed), or the reason it was refused.
+    pub fn apply(&self, content: String) -> Result<String, String> {
+        let mut filtered = String::with_capacity(content.len());
+        let mut matched = false;
+        let mut rest = content.as_str();
+
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            filtered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(|c: char| !c.is_alphanumeric()).unwrap_or(rest.len());
+            let word = &rest[..end];
+
+            if self.words.contains(&word.to_lowercase()) {
+                matched = true;
+                filtered.extend(std::iter::repeat_n('*', word.chars().count()));
+            } else {
+                filtered.push_str(word);
+            }
+            rest = &rest[end..];
+        }
+        filtered.push_str(rest);
+
+        match (matched, self.action) {
+            (false, _) => Ok(content),
+            (true, FilterAction::Reject) => Err("Message contains words that aren't allowed".to_string()),
+            (true, FilterAction::Mask) => Ok(filtered),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_mode_refuses_filtered_words() {
+        let filter = ContentFilter::new(["darn", "heck"], FilterAction::Reject);
+
+        assert!(filter.apply("oh darn it".to_string()).is_err());
+        assert!(filter.apply("What the HECK!".to_string()).is_err());
+        assert_eq!(filter.apply("all good here".to_string()).unwrap(), "all good here");
+    }
+
+    #[test]
+    fn mask_mode_stars_out_filtered_words() {
+        let filter = ContentFilter::new(["darn", "heck"], FilterAction::Mask);
+
+        assert_eq!(filter.apply("Darn, what the heck?!".to_string()).unwrap(), "****, what the ****?!");
+    }
+
+    #[test]
+    fn only_whole_words_match() {
+        let filter = ContentFilter::new(["ass"], FilterAction::Reject);
+
+        assert_eq!(filter.apply("first class passage".to_string()).unwrap(), "first class passage");
+        assert!(filter.apply("you ass".to_string()).is_err());
+
+        let filter = ContentFilter::new(["ass"], FilterAction::Mask);
+        assert_eq!(filter.apply("class, ass.".to_string()).unwrap(), "class, ***.");
+    }
+
+    #[test]
+    fn wordlist_skips_blank_lines_and_comments() {
+        let filter = ContentFilter::new(["# swear words", "", "  Darn  "], FilterAction::Reject);
+
+        assert_eq!(filter.len(), 1);
+        assert!(filter.apply("darn".to_string()).is_err());
+    }
+
+    #[test]
+    fn actions_parse_case_insensitively() {
+        assert_eq!("Mask".parse::<FilterAction>(), Ok(FilterAction::Mask));
+        assert_eq!("reject".parse::<FilterAction>(), Ok(FilterAction::Reject));
+        assert!("drop".parse::<FilterAction>().is_err());
+    }
+}
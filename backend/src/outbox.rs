@@ -0,0 +1,231 @@
+use crate::ServerMessage;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+
+/// Sent to a client whose queue overflowed, just before its connection is closed
+const OVERFLOW_REASON: &str = "Connection too slow: too many undelivered messages";
+
+/// Failed sends in a row after which the connection is treated as gone
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Backpressure counters, reported by `/api/debug/state`
+#[derive(Debug, Default)]
+pub struct OutboxStats {
+    /// Messages that didn't fit in a full queue
+    pub dropped_messages: AtomicU64,
+    /// Connections closed because their queue overflowed
+    pub overflow_disconnects: AtomicU64,
+}
+
+/// The queue is closed: the connection is gone or being closed
+#[derive(Debug)]
+pub struct QueueClosed;
+
+/// A queued message, with its number in the user's `EventLog` if it was recorded there
+#[derive(Debug)]
+pub struct Outgoing {
+    pub message: ServerMessage,
+    pub seq: Option<u64>,
+}
+
+impl Outgoing {
+    /// The message as sent to the client; recorded events carry their number as `seq`
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let Some(seq) = self.seq else {
+            return serde_json::to_string(&self.message);
+        };
+
+        let mut value = serde_json::to_value(&self.message)?;
+        if let Some(fields) = value.as_object_mut() {
+            fields.insert("seq".to_string(), seq.into());
+        }
+        serde_json::to_string(&value)
+    }
+}
+
+/// Sending half of a connection's outgoing queue.
+///
+/// The queue holds at most `OUTBOUND_QUEUE_CAPACITY` messages, so a client that
+/// reads too slowly can't make the server buffer without limit. Once it is
+/// full, typing indicators are dropped; anything else closes the connection,
+/// since the client has already missed messages it can't do without.
+#[derive(Clone)]
+pub struct ClientSender {
+    tx: mpsc::Sender<Outgoing>,
+    overflowed: Arc<AtomicBool>,
+    overflow: Arc<Notify>,
+    /// Sends that failed since the last one that went through
+    failures: Arc<AtomicU32>,
+    stats: Arc<OutboxStats>,
+}
+
+/// Receiving half, read by the task writing to the socket or event stream
+pub struct ClientReceiver {
+    rx: mpsc::Receiver<Outgoing>,
+    overflowed: Arc<AtomicBool>,
+    overflow: Arc<Notify>,
+}
+
+pub fn channel(capacity: usize, stats: Arc<OutboxStats>) -> (ClientSender, ClientReceiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let overflowed = Arc::new(AtomicBool::new(false));
+    let overflow = Arc::new(Notify::new());
+    let sender = ClientSender {
+        tx,
+        overflowed: overflowed.clone(),
+        overflow: overflow.clone(),
+        failures: Arc::new(AtomicU32::new(0)),
+        stats,
+    };
+    (sender, ClientReceiver { rx, overflowed, overflow })
+}
+
+impl ClientSender {
+    /// Queue a message without waiting. A typing indicator that doesn't fit is
+    /// dropped; any other message that doesn't fit disconnects the client.
+    pub fn send(&self, msg: ServerMessage) -> Result<(), QueueClosed> {
+        self.queue(Outgoing { message: msg, seq: None })
+    }
+
+    /// Queue an event recorded in the user's `EventLog` under `seq`
+    pub fn send_sequenced(&self, msg: ServerMessage, seq: u64) -> Result<(), QueueClosed> {
+        self.queue(Outgoing { message: msg, seq: Some(seq) })
+    }
+
+    /// Queue a batch of messages, waiting for room instead of overflowing. It goes
+    /// in parts of at most half the queue, leaving room for live events meanwhile.
+    pub async fn send_batch(&self, messages: Vec<ServerMessage>) -> Result<(), QueueClosed> {
+        let part = (self.tx.max_capacity() / 2).max(1);
+        let mut messages = messages.into_iter();
+        while !messages.as_slice().is_empty() {
+            let permits = self.tx.reserve_many(part.min(messages.len())).await.map_err(|_| QueueClosed)?;
+            for (permit, message) in permits.zip(&mut messages) {
+                permit.send(Outgoing { message, seq: None });
+            }
+        }
+        Ok(())
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> Result<(), QueueClosed> {
+        let queued = self.try_queue(outgoing);
+        match queued {
+            Ok(()) => self.failures.store(0, Ordering::Relaxed),
+            Err(QueueClosed) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        queued
+    }
+
+    fn try_queue(&self, outgoing: Outgoing) -> Result<(), QueueClosed> {
+        match self.tx.try_send(outgoing) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(QueueClosed),
+            Err(mpsc::error::TrySendError::Full(outgoing)) => {
+                self.stats.dropped_messages.fetch_add(1, Ordering::Relaxed);
+                if matches!(outgoing.message, ServerMessage::Typing { .. }) {
+                    return Ok(());
+                }
+                if !self.overflowed.swap(true, Ordering::Relaxed) {
+                    self.stats.overflow_disconnects.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!("Outgoing queue full, disconnecting slow client");
+                    self.overflow.notify_one();
+                }
+                Err(QueueClosed)
+            }
+        }
+    }
+
+    /// Whether the last few sends all failed, i.e. nobody is reading anymore
+    /// and work done for this connection would be wasted
+    pub fn is_failing(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) >= MAX_CONSECUTIVE_FAILURES
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Resolves once the connection's writer has gone away
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    pub fn same_channel(&self, other: &Self) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+}
+
+impl ClientReceiver {
+    /// The next message to write. After an overflow this is a `Disconnected`
+    /// with the reason, after which the writer is expected to stop.
+    pub async fn recv(&mut self) -> Option<Outgoing> {
+        tokio::select! {
+            biased;
+            _ = wait_for_overflow(&self.overflowed, &self.overflow) => {
+                Some(Outgoing {
+                    message: ServerMessage::Disconnected {
+                        reason: OVERFLOW_REASON.to_string(),
+                    },
+                    seq: None,
+                })
+            }
+            msg = self.rx.recv() => msg,
+        }
+    }
+
+    /// Resolves once the queue has overflowed. A client that stopped reading
+    /// also stalls writes to its socket, so writers race those against this.
+    pub async fn overflowed(&self) {
+        wait_for_overflow(&self.overflowed, &self.overflow).await
+    }
+}
+
+async fn wait_for_overflow(overflowed: &AtomicBool, overflow: &Notify) {
+    while !overflowed.load(Ordering::Relaxed) {
+        overflow.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unread(count: i32) -> ServerMessage {
+        ServerMessage::TotalUnread { count }
+    }
+
+    #[tokio::test]
+    async fn a_stalled_client_is_disconnected_instead_of_buffered() {
+        let stats = Arc::new(OutboxStats::default());
+        let (tx, mut rx) = channel(4, stats.clone());
+
+        let queued = (0..1000).filter(|&i| tx.send(unread(i)).is_ok()).count();
+
+        assert_eq!(queued, 4);
+        assert_eq!(stats.dropped_messages.load(Ordering::Relaxed), 996);
+        assert_eq!(stats.overflow_disconnects.load(Ordering::Relaxed), 1);
+        // The reason goes out ahead of what was still queued
+        let first = rx.recv().await.unwrap();
+        assert!(matches!(first.message, ServerMessage::Disconnected { .. }));
+    }
+
+    #[tokio::test]
+    async fn typing_indicators_that_dont_fit_are_dropped() {
+        let stats = Arc::new(OutboxStats::default());
+        let (tx, mut rx) = channel(1, stats.clone());
+
+        tx.send(unread(1)).unwrap();
+        let typing = ServerMessage::Typing {
+            from_user_id: "alice".to_string(),
+            is_typing: true,
+        };
+        assert!(tx.send(typing).is_ok());
+
+        assert_eq!(stats.dropped_messages.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.overflow_disconnects.load(Ordering::Relaxed), 0);
+        let first = rx.recv().await.unwrap();
+        assert!(matches!(first.message, ServerMessage::TotalUnread { count: 1 }));
+    }
+}
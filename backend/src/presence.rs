@@ -0,0 +1,242 @@
+use crate::outbox::ClientSender;
+use crate::{AppState, ServerMessage, User};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+use tracing::Instrument;
+
+/// Events kept for subscribers that fall behind; a subscriber further behind
+/// than this gets a fresh `OnlineUsers` list instead
+const PRESENCE_CHANNEL_CAPACITY: usize = 1024;
+
+/// Presence changes (`UserOnline`, `UserOffline`, `UserUpdated`) published once and
+/// forwarded by each logged-in connection to its own client
+pub type PresenceSender = broadcast::Sender<PresenceEvent>;
+
+pub fn channel() -> PresenceSender {
+    broadcast::channel(PRESENCE_CHANNEL_CAPACITY).0
+}
+
+#[derive(Debug, Clone)]
+pub struct PresenceEvent {
+    user_id: String,
+    message: ServerMessage,
+    audience: Audience,
+}
+
+#[derive(Debug, Clone)]
+enum Audience {
+    /// Every connected user, including the one the event is about
+    Everyone,
+    /// Users who have the user as a contact. Users who haven't added any contacts
+    /// yet still see each other, as with the original global directory, but not
+    /// those who keep a contact list.
+    Contacts {
+        watchers: Arc<HashSet<String>>,
+        with_contacts: Arc<HashSet<String>>,
+    },
+}
+
+impl PresenceEvent {
+    fn is_for(&self, recipient: &str) -> bool {
+        match &self.audience {
+            Audience::Everyone => true,
+            Audience::Contacts { watchers, with_contacts } => {
+                recipient != self.user_id
+                    && (watchers.contains(recipient)
+                        || !(with_contacts.contains(recipient) || with_contacts.contains(&self.user_id)))
+            }
+        }
+    }
+}
+
+/// Online/offline changes collected during `PRESENCE_BATCH_MS`, sent as one
+/// `PresenceUpdate` with each user's latest state
+#[derive(Default)]
+struct PresenceBatch {
+    online: HashMap<String, User>,
+    offline: HashSet<String>,
+}
+
+impl PresenceBatch {
+    /// Fold a change into the batch; any other message is handed back to be sent as is
+    fn add(&mut self, message: ServerMessage) -> Option<ServerMessage> {
+        match message {
+            ServerMessage::UserOnline { user } => {
+                self.offline.remove(&user.id);
+                self.online.insert(user.id.clone(), user);
+                None
+            }
+            ServerMessage::UserOffline { user_id } => {
+                self.online.remove(&user_id);
+                self.offline.insert(user_id);
+                None
+            }
+            message => Some(message),
+        }
+    }
+
+    fn take(&mut self) -> ServerMessage {
+        let batch = std::mem::take(self);
+        ServerMessage::PresenceUpdate {
+            online: batch.online.into_values().collect(),
+            offline: batch.offline.into_iter().collect(),
+        }
+    }
+}
+
+/// Publish a change to `user_id`'s presence to the users who have them as a contact
+pub async fn publish_to_contacts(state: &AppState, user_id: &str, message: ServerMessage) {
+    let watchers = state.db.get_contact_owner_ids(user_id).await.unwrap_or_else(|e| {
+        tracing::error!("Failed to load contact owners: {:?}", e);
+        HashSet::new()
+    });
+    let with_contacts = state.db.get_users_with_contacts().await.unwrap_or_else(|e| {
+        tracing::error!("Failed to load contact lists: {:?}", e);
+        HashSet::new()
+    });
+
+    publish(state, user_id, message, Audience::Contacts {
+        watchers: Arc::new(watchers),
+        with_contacts: Arc::new(with_contacts),
+    });
+}
+
+/// Publish a change about `user_id` to every connected user
+pub fn publish_to_everyone(state: &AppState, user_id: &str, message: ServerMessage) {
+    publish(state, user_id, message, Audience::Everyone);
+}
+
+fn publish(state: &AppState, user_id: &str, message: ServerMessage, audience: Audience) {
+    // An error only means nobody is subscribed, i.e. nobody is online
+    let _ = state.presence.send(PresenceEvent {
+        user_id: user_id.to_string(),
+        message,
+        audience,
+    });
+}
+
+/// Whose presence one user is told about, by the same rule as `publish_to_contacts`
+pub struct Visibility {
+    viewer_id: String,
+    contacts: HashSet<String>,
+    with_contacts: HashSet<String>,
+}
+
+impl Visibility {
+    pub async fn load(state: &AppState, viewer_id: &str) -> Self {
+        let contacts = state.db.get_contact_ids(viewer_id).await.unwrap_or_else(|e| {
+            tracing::error!("Failed to load contacts: {:?}", e);
+            HashSet::new()
+        });
+        let with_contacts = state.db.get_users_with_contacts().await.unwrap_or_else(|e| {
+            tracing::error!("Failed to load contact lists: {:?}", e);
+            HashSet::new()
+        });
+
+        Self {
+            viewer_id: viewer_id.to_string(),
+            contacts,
+            with_contacts,
+        }
+    }
+
+    /// Their contacts, or, for a viewer without any, everyone else who has none either
+    pub fn sees(&self, user_id: &str) -> bool {
+        if user_id == self.viewer_id {
+            return false;
+        }
+        if self.contacts.is_empty() {
+            return !self.with_contacts.contains(user_id);
+        }
+        self.contacts.contains(user_id)
+    }
+}
+
+/// Online users whose presence `viewer_id` is told about
+pub async fn visible_online_users(state: &AppState, viewer_id: &str) -> Vec<User> {
+    let visibility = Visibility::load(state, viewer_id).await;
+
+    state
+        .online_users
+        .iter()
+        .filter(|u| visibility.sees(u.key()))
+        .map(|u| u.value().clone())
+        .collect()
+}
+
+/// Forward presence events meant for `user_id` to their connection, until the
+/// connection closes or stops being the user's live socket (logout, a newer
+/// login, an admin disconnect). Call right after registering the socket.
+///
+/// With `PRESENCE_BATCH_MS` set, `UserOnline`/`UserOffline` events are held for
+/// that long and sent together as one `PresenceUpdate`; anything else flushes
+/// the batch first, so the order of changes is kept.
+pub fn subscribe(state: &AppState, user_id: &str, user_tx: &ClientSender) {
+    let mut events = state.presence.subscribe();
+    let state = state.clone();
+    let user_id = user_id.to_string();
+    let user_tx = user_tx.clone();
+    let batch_window = Duration::from_millis(state.config.presence_batch_ms);
+
+    tokio::spawn(
+        async move {
+            let mut batch = PresenceBatch::default();
+            let mut flush_at: Option<Instant> = None;
+
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                        flush_at = None;
+                        let _ = user_tx.send(batch.take());
+                        continue;
+                    }
+                    _ = user_tx.closed() => break,
+                };
+
+                let is_live = state
+                    .user_sockets
+                    .get(&user_id)
+                    .is_some_and(|tx| tx.same_channel(&user_tx));
+                if !is_live {
+                    break;
+                }
+
+                match event {
+                    Ok(event) if event.is_for(&user_id) => {
+                        if batch_window.is_zero() {
+                            let _ = user_tx.send(event.message);
+                            continue;
+                        }
+                        match batch.add(event.message) {
+                            None => {
+                                flush_at.get_or_insert_with(|| Instant::now() + batch_window);
+                            }
+                            Some(message) => {
+                                if flush_at.take().is_some() {
+                                    let _ = user_tx.send(batch.take());
+                                }
+                                let _ = user_tx.send(message);
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        // Rather than leave the client with a stale picture, resend who is online
+                        tracing::warn!("Presence subscriber lagged, {} events missed; resyncing", missed);
+                        // The list supersedes whatever was batched
+                        batch = PresenceBatch::default();
+                        flush_at = None;
+                        let users = visible_online_users(&state, &user_id).await;
+                        let _ = user_tx.send(ServerMessage::OnlineUsers { users });
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+}
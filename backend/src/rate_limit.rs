@@ -0,0 +1,105 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Sliding-window event counter keyed by an arbitrary string (user id, username, ...)
+pub struct RateLimiter {
+    events: DashMap<String, VecDeque<Instant>>,
+    max_events: usize,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(max_events: usize, window: Duration) -> Self {
+        Self {
+            events: DashMap::new(),
+            max_events,
+            window,
+        }
+    }
+
+    /// Whether `key` has used up its allowance for the current window
+    pub fn is_limited(&self, key: &str) -> bool {
+        let Some(mut events) = self.events.get_mut(key) else {
+            return false;
+        };
+        Self::prune(&mut events, self.window);
+        events.len() >= self.max_events
+    }
+
+    /// Record an event for `key`
+    pub fn record(&self, key: &str) {
+        let mut events = self.events.entry(key.to_string()).or_default();
+        Self::prune(&mut events, self.window);
+        events.push_back(Instant::now());
+    }
+
+    /// Record an event for `key` unless it has used up its allowance; returns whether it was allowed
+    pub fn try_record(&self, key: &str) -> bool {
+        let mut events = self.events.entry(key.to_string()).or_default();
+        Self::prune(&mut events, self.window);
+        if events.len() >= self.max_events {
+            return false;
+        }
+        events.push_back(Instant::now());
+        true
+    }
+
+    /// Forget all events for `key`
+    pub fn reset(&self, key: &str) {
+        self.events.remove(key);
+    }
+
+    fn prune(events: &mut VecDeque<Instant>, window: Duration) {
+        while events.front().is_some_and(|at| at.elapsed() > window) {
+            events.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_after_max_events() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+        limiter.record("alice");
+        assert!(!limiter.is_limited("alice"));
+        limiter.record("alice");
+        assert!(limiter.is_limited("alice"));
+        assert!(!limiter.is_limited("bob"));
+    }
+
+    #[test]
+    fn try_record_refuses_once_the_allowance_is_used() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+        assert!(limiter.try_record("alice"));
+        assert!(limiter.try_record("alice"));
+        assert!(!limiter.try_record("alice"));
+        assert!(limiter.try_record("bob"));
+    }
+
+    #[test]
+    fn events_expire_after_the_window() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(50));
+
+        limiter.record("alice");
+        assert!(limiter.is_limited("alice"));
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(!limiter.is_limited("alice"));
+        assert!(limiter.try_record("alice"));
+    }
+
+    #[test]
+    fn reset_forgets_events() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+
+        limiter.record("alice");
+        limiter.reset("alice");
+
+        assert!(!limiter.is_limited("alice"));
+    }
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+
+/// How much of an attachment payload `Debug` shows before truncating
+const FILE_DATA_DEBUG_PREFIX: usize = 32;
+
+/// A credential (e.g. a password) that must never appear in logs.
+/// `Debug` always prints `***`; use `expose` where the raw value is needed.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Base64 attachment payload. `Debug` shows a short prefix and the total size
+/// instead of dumping the whole blob.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FileData(String);
+
+impl From<String> for FileData {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl Deref for FileData {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for FileData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.len() <= FILE_DATA_DEBUG_PREFIX {
+            return write!(f, "{:?}", self.0);
+        }
+
+        let mut end = FILE_DATA_DEBUG_PREFIX;
+        while !self.0.is_char_boundary(end) {
+            end -= 1;
+        }
+        write!(f, "\"{}…\" ({} bytes)", &self.0[..end], self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_debug_hides_the_value() {
+        let password = Secret::from("hunter2".to_string());
+
+        assert_eq!(format!("{:?}", password), "***");
+        assert_eq!(format!("{:#?}", Some(password.clone())), "Some(\n    ***,\n)");
+        assert_eq!(password.expose(), "hunter2");
+    }
+
+    #[test]
+    fn secret_serializes_as_the_plain_string() {
+        let password: Secret = serde_json::from_str("\"hunter2\"").unwrap();
+
+        assert_eq!(password.expose(), "hunter2");
+        assert_eq!(serde_json::to_string(&password).unwrap(), "\"hunter2\"");
+    }
+
+    #[test]
+    fn file_data_debug_is_truncated() {
+        let data = FileData::from("A".repeat(1000));
+
+        assert_eq!(format!("{:?}", data), format!("\"{}…\" (1000 bytes)", "A".repeat(FILE_DATA_DEBUG_PREFIX)));
+        assert_eq!(format!("{:?}", FileData::from("short".to_string())), "\"short\"");
+    }
+}
@@ -0,0 +1,38 @@
+use crate::AppState;
+use chrono::Utc;
+use std::time::Duration;
+
+/// How often messages past the retention window are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Pause between deletion batches so a large backlog doesn't hold up other writers
+const BATCH_PAUSE: Duration = Duration::from_millis(50);
+
+/// Delete messages older than `retention_days`. Runs for the lifetime of the server;
+/// only started when `MESSAGE_RETENTION_DAYS` is set.
+pub async fn run(state: AppState, retention_days: u32) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let cutoff = Utc::now() - chrono::Duration::days(retention_days as i64);
+        let mut purged = 0;
+        loop {
+            match state.db.purge_messages_before(cutoff.timestamp_millis()).await {
+                Ok(0) => break,
+                Ok(count) => purged += count,
+                Err(e) => {
+                    tracing::error!("Failed to purge old messages: {:?}", e);
+                    break;
+                }
+            }
+            tokio::time::sleep(BATCH_PAUSE).await;
+        }
+
+        if purged > 0 {
+            tracing::info!("Purged {} messages older than {} days", purged, retention_days);
+        }
+    }
+}
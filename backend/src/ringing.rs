@@ -0,0 +1,85 @@
+use crate::{AppState, ServerMessage};
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::AbortHandle;
+
+/// Calls that were offered but not yet answered, by call id
+pub type RingingCalls = Arc<DashMap<String, RingingCall>>;
+
+pub struct RingingCall {
+    caller_id: String,
+    callee_id: String,
+    timer: AbortHandle,
+}
+
+impl RingingCall {
+    fn is_between(&self, user_a: &str, user_b: &str) -> bool {
+        (self.caller_id == user_a && self.callee_id == user_b) || (self.caller_id == user_b && self.callee_id == user_a)
+    }
+}
+
+/// Start ringing a newly offered call; unless it is answered or ended first,
+/// it is marked as missed after `CALL_RING_TIMEOUT_SECS`
+pub fn start(state: &AppState, call_id: &str, caller_id: &str, callee_id: &str) {
+    let timeout = Duration::from_secs(state.config.call_ring_timeout_secs);
+    let timer = tokio::spawn({
+        let state = state.clone();
+        let call_id = call_id.to_string();
+        async move {
+            tokio::time::sleep(timeout).await;
+            time_out(&state, &call_id).await;
+        }
+    })
+    .abort_handle();
+
+    state.ringing_calls.insert(
+        call_id.to_string(),
+        RingingCall {
+            caller_id: caller_id.to_string(),
+            callee_id: callee_id.to_string(),
+            timer,
+        },
+    );
+}
+
+/// Stop ringing the call between two users, found by `call_id` or, for clients
+/// that don't send one, by its participants. Returns the id of the call stopped.
+pub fn stop(state: &AppState, call_id: Option<&str>, user_a: &str, user_b: &str) -> Option<String> {
+    let call_id = match call_id {
+        Some(call_id) => call_id.to_string(),
+        None => state
+            .ringing_calls
+            .iter()
+            .find(|entry| entry.is_between(user_a, user_b))
+            .map(|entry| entry.key().clone())?,
+    };
+
+    let (call_id, call) = state
+        .ringing_calls
+        .remove_if(&call_id, |_, call| call.is_between(user_a, user_b))?;
+    call.timer.abort();
+    Some(call_id)
+}
+
+/// Nobody answered: record the call as missed and tell both sides
+async fn time_out(state: &AppState, call_id: &str) {
+    let Some((_, call)) = state.ringing_calls.remove(call_id) else {
+        return;
+    };
+
+    if let Err(e) = state.db.mark_call_missed(call_id).await {
+        tracing::error!("Failed to mark call {} as missed: {:?}", call_id, e);
+    }
+
+    for (user_id, other_user_id) in [(&call.caller_id, &call.callee_id), (&call.callee_id, &call.caller_id)] {
+        if let Some(tx) = state.user_sockets.get(user_id) {
+            let _ = tx.send(ServerMessage::CallTimeout {
+                call_id: call_id.to_string(),
+                user_id: other_user_id.clone(),
+            });
+        }
+    }
+
+    tracing::info!("Call {} was not answered", call_id);
+}
@@ -0,0 +1,84 @@
+use crate::db::{sortable_timestamp, DbMessage, DbScheduledMessage};
+use crate::{db_message_to_chat_message, deliver_to_recipient, unarchive_for_recipient, AppState, ServerMessage};
+use chrono::Utc;
+use std::time::Duration;
+
+/// How often due scheduled messages are looked for
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Deliver scheduled messages as they fall due. Runs for the lifetime of the server.
+pub async fn run(state: AppState) {
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let due = match state.db.get_due_scheduled_messages(&sortable_timestamp(Utc::now())).await {
+            Ok(due) => due,
+            Err(e) => {
+                tracing::error!("Failed to load scheduled messages: {:?}", e);
+                continue;
+            }
+        };
+
+        for scheduled in due {
+            send_scheduled(&state, scheduled).await;
+        }
+    }
+}
+
+async fn send_scheduled(state: &AppState, scheduled: DbScheduledMessage) {
+    // The scheduled id is kept so clients can match the delivered message
+    // to the `MessageScheduled` confirmation they got earlier
+    let db_msg = DbMessage {
+        id: scheduled.id.clone(),
+        from_user_id: scheduled.from_user_id,
+        to_user_id: scheduled.to_user_id,
+        content: scheduled.content,
+        timestamp: Utc::now().timestamp_millis(),
+        read: false,
+        file_data: None,
+        file_name: None,
+        file_type: None,
+        audio_duration: None,
+        thumbnail_data: None,
+        encrypted: scheduled.encrypted,
+        delivered: false,
+        client_msg_id: None,
+        expires_at: None,
+        media_width: None,
+        media_height: None,
+        device_id: None,
+        device_name: None,
+        seq: 0,
+        quoted_message_id: None,
+        quoted_text: None,
+    };
+
+    let seq = match state.db.promote_scheduled_message(&scheduled.id, &db_msg).await {
+        Ok(Some(seq)) => seq,
+        // Cancelled between loading and sending
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("Failed to send scheduled message {}: {:?}", scheduled.id, e);
+            return;
+        }
+    };
+
+    let mut message = db_message_to_chat_message(DbMessage { seq, ..db_msg }, None);
+    unarchive_for_recipient(state, &message).await;
+    deliver_to_recipient(state, &mut message).await;
+
+    // An offline sender sees it in their history next time
+    if let Some(sender_tx) = state.user_sockets.get(&message.from_user_id) {
+        let _ = sender_tx.send(ServerMessage::NewMessage {
+            message: Box::new(message.clone()),
+            muted: false,
+            mentioned: false,
+            notify: false,
+        });
+    }
+
+    tracing::info!("Sent scheduled message {}", message.id);
+}
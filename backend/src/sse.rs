@@ -0,0 +1,173 @@
+use crate::outbox::{self, ClientSender};
+use crate::{describe_parse_error, disconnect_cleanup, handle_client_message, AppState, ClientMessage, ConnectionSlot, ServerMessage};
+use axum::{
+    extract::{ConnectInfo, DefaultBodyLimit, Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Router,
+};
+use dashmap::DashMap;
+use futures_util::{stream, StreamExt};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Open event streams by connection id
+pub type SseConnections = Arc<DashMap<String, Arc<SseConnection>>>;
+
+/// Server-Sent Events fallback for networks that block WebSockets. A client opens
+/// `GET /api/events`, whose first event (`connected`) carries a connection id, and
+/// then POSTs each `ClientMessage` to `/api/events/:connection_id`. Replies and pushes
+/// arrive on the stream as the same JSON the WebSocket sends. A stream that doesn't
+/// log in within `AUTH_TIMEOUT_SECS` is closed, as a WebSocket would be.
+pub fn router(max_message_size: usize) -> Router<AppState> {
+    Router::new()
+        .route("/api/events", get(open_stream))
+        .route(
+            "/api/events/:connection_id",
+            post(send_message).layer(DefaultBodyLimit::max(max_message_size)),
+        )
+}
+
+/// The stream counterpart of a WebSocket connection
+pub struct SseConnection {
+    user_tx: ClientSender,
+    /// Login state; holding the lock also keeps a connection's messages in order
+    current_user_id: Mutex<Option<String>>,
+    span: tracing::Span,
+}
+
+/// Lives as long as the response stream: when the client goes away the
+/// connection is forgotten and its user cleaned up as for a closed WebSocket
+struct StreamGuard {
+    state: AppState,
+    connection_id: String,
+    _slot: ConnectionSlot,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let Some((_, connection)) = self.state.sse_connections.remove(&self.connection_id) else {
+            return;
+        };
+
+        let state = self.state.clone();
+        let span = connection.span.clone();
+        tokio::spawn(
+            async move {
+                let current_user_id = connection.current_user_id.lock().await.take();
+                disconnect_cleanup(&state, current_user_id, &connection.user_tx).await;
+                tracing::debug!("Event stream closed");
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn open_stream(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>) -> Response {
+    let ip = addr.ip();
+    let Some(slot) = ConnectionSlot::acquire(&state.connections_per_ip, ip, state.config.max_connections_per_ip) else {
+        tracing::warn!("Rejected event stream from {}: connection limit reached", ip);
+        return (StatusCode::TOO_MANY_REQUESTS, "Too many connections").into_response();
+    };
+
+    let connection_id = Uuid::new_v4().to_string();
+    let span = tracing::info_span!("sse", conn_id = %connection_id, user_id = tracing::field::Empty);
+    span.in_scope(|| tracing::debug!("Event stream opened"));
+
+    let (user_tx, user_rx) = outbox::channel(state.config.outbound_queue_capacity, state.outbox_stats.clone());
+    state.sse_connections.insert(
+        connection_id.clone(),
+        Arc::new(SseConnection {
+            user_tx,
+            current_user_id: Mutex::new(None),
+            span: span.clone(),
+        }),
+    );
+    tokio::spawn(close_if_unauthenticated(state.clone(), connection_id.clone()).instrument(span));
+
+    let connected = Event::default().event("connected").data(connection_id.clone());
+    let guard = StreamGuard {
+        state,
+        connection_id,
+        _slot: slot,
+    };
+
+    // Ends after forwarding a forced disconnect, like the WebSocket does
+    let messages = stream::unfold((user_rx, guard, false), |(mut user_rx, guard, finished)| async move {
+        if finished {
+            return None;
+        }
+        let outgoing = user_rx.recv().await?;
+        let disconnect = matches!(outgoing.message, ServerMessage::Disconnected { .. });
+        let event = match outgoing.to_json() {
+            Ok(json) => Event::default().data(json),
+            Err(e) => {
+                tracing::error!("Failed to encode event: {:?}", e);
+                Event::default().comment("encoding error")
+            }
+        };
+        Some((Ok::<_, Infallible>(event), (user_rx, guard, disconnect)))
+    });
+
+    Sse::new(stream::once(async { Ok(connected) }).chain(messages))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Close a stream that hasn't logged in or registered within `AUTH_TIMEOUT_SECS`,
+/// as the WebSocket does. Its id stops accepting messages right away.
+async fn close_if_unauthenticated(state: AppState, connection_id: String) {
+    tokio::time::sleep(Duration::from_secs(state.config.auth_timeout_secs)).await;
+
+    let Some(connection) = state.sse_connections.get(&connection_id).map(|entry| entry.value().clone()) else {
+        return;
+    };
+    // Waits for a login that is in progress
+    let current_user_id = connection.current_user_id.lock().await;
+    if current_user_id.is_some() {
+        return;
+    }
+
+    tracing::info!("Closing event stream that didn't authenticate in time");
+    state.sse_connections.remove(&connection_id);
+    let _ = connection.user_tx.send(ServerMessage::AuthError {
+        message: "Authentication timed out".to_string(),
+    });
+    // The stream ends after this
+    let _ = connection.user_tx.send(ServerMessage::Disconnected {
+        reason: "Authentication timed out".to_string(),
+    });
+}
+
+async fn send_message(State(state): State<AppState>, Path(connection_id): Path<String>, body: String) -> Response {
+    let Some(connection) = state.sse_connections.get(&connection_id).map(|entry| entry.value().clone()) else {
+        return (StatusCode::NOT_FOUND, "Unknown event stream").into_response();
+    };
+
+    let client_msg = match serde_json::from_str::<ClientMessage>(&body) {
+        Ok(client_msg) => client_msg,
+        Err(e) => return (StatusCode::BAD_REQUEST, describe_parse_error(&e)).into_response(),
+    };
+
+    let span = connection.span.clone();
+    async {
+        let mut current_user_id = connection.current_user_id.lock().await;
+        // Closed while this request waited for the lock, e.g. by the auth timeout
+        if !state.sse_connections.contains_key(&connection_id) {
+            return (StatusCode::NOT_FOUND, "Unknown event stream").into_response();
+        }
+        handle_client_message(&state, &mut current_user_id, &connection.user_tx, client_msg).await;
+        StatusCode::ACCEPTED.into_response()
+    }
+    .instrument(span)
+    .await
+}
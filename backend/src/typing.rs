@@ -0,0 +1,68 @@
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+/// Coalesces typing indicators so a client sending `Typing` on every keystroke
+/// doesn't flood the recipient. Clients clear an indicator on their own after a
+/// few seconds, so one `is_typing: true` per interval keeps it showing.
+pub struct TypingThrottle {
+    /// When a start was last forwarded, per (from, to) pair
+    last_forwarded: DashMap<(String, String), Instant>,
+    interval: Duration,
+}
+
+impl TypingThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            last_forwarded: DashMap::new(),
+            interval,
+        }
+    }
+
+    /// Whether a start would repeat one forwarded within the interval. Stops
+    /// are never throttled, and the next start after a stop goes through.
+    pub fn is_throttled(&self, from_user_id: &str, to_user_id: &str) -> bool {
+        self.last_forwarded
+            .get(&(from_user_id.to_string(), to_user_id.to_string()))
+            .is_some_and(|at| at.elapsed() < self.interval)
+    }
+
+    /// Note an indicator that was forwarded, which starts a new interval
+    pub fn forwarded(&self, from_user_id: &str, to_user_id: &str, is_typing: bool) {
+        let key = (from_user_id.to_string(), to_user_id.to_string());
+        if is_typing {
+            self.last_forwarded.insert(key, Instant::now());
+        } else {
+            self.last_forwarded.remove(&key);
+        }
+    }
+
+    /// Drop the state for a user's indicators once they disconnect
+    pub fn forget(&self, user_id: &str) {
+        self.last_forwarded.retain(|(from_user_id, _), _| from_user_id != user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_within_the_interval_are_throttled() {
+        let throttle = TypingThrottle::new(Duration::from_secs(60));
+
+        assert!(!throttle.is_throttled("alice", "bob"));
+        throttle.forwarded("alice", "bob", true);
+        assert!(throttle.is_throttled("alice", "bob"));
+        assert!(!throttle.is_throttled("alice", "carol"));
+    }
+
+    #[test]
+    fn a_stop_ends_the_interval() {
+        let throttle = TypingThrottle::new(Duration::from_secs(60));
+
+        throttle.forwarded("alice", "bob", true);
+        throttle.forwarded("alice", "bob", false);
+
+        assert!(!throttle.is_throttled("alice", "bob"));
+    }
+}
@@ -0,0 +1,3706 @@
+//! End-to-end tests: each test starts the server binary on a free port with a
+//! fresh in-memory database and talks to it over a real WebSocket.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use sqlx::{Row, SqlitePool};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// How long to wait for the server to start or for an expected message
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Presence updates come from a separate task, so their order relative to
+/// direct replies isn't fixed; tests that care ask for them explicitly
+const PRESENCE_EVENTS: &[&str] = &["UserOnline", "UserOffline", "UserUpdated", "UserStatusChanged", "PresenceUpdate"];
+
+/// A 1x1 transparent PNG, base64-encoded
+const ONE_PIXEL_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==";
+
+/// A running server, killed when dropped
+struct TestServer {
+    _process: Child,
+    addr: String,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        Self::start_with(&[]).await
+    }
+
+    /// Start with extra environment variables, e.g. to shorten a timeout
+    async fn start_with(env: &[(&str, &str)]) -> Self {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_chat-backend"))
+            .env("DATABASE_URL", "sqlite::memory:")
+            .env("DISABLE_TLS", "1")
+            .env("PORT", "0")
+            // The lowest cost bcrypt accepts keeps registration fast
+            .env("BCRYPT_COST", "4")
+            .envs(env.iter().copied())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("failed to start server");
+
+        let mut lines = BufReader::new(process.stdout.take().unwrap()).lines();
+        let addr = tokio::time::timeout(TIMEOUT, async {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if let Some((_, addr)) = line.split_once("Server running on http://") {
+                    return addr.trim().replace("0.0.0.0", "127.0.0.1");
+                }
+            }
+            panic!("server exited before listening");
+        })
+        .await
+        .expect("server didn't start in time");
+
+        // Keep reading the log so the server never blocks on a full pipe
+        tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+
+        Self { _process: process, addr }
+    }
+
+    /// GET `path` over plain HTTP/1.0 and parse the JSON body
+    async fn get_json(&self, path: &str) -> Value {
+        let (head, body) = self.get(path, &[]).await;
+        assert!(head.starts_with("HTTP/1.0 200") || head.starts_with("HTTP/1.1 200"), "unexpected response: {}", head);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// GET `path` over plain HTTP/1.0 with extra request headers; returns the
+    /// response head (status line first) and the raw body
+    async fn get(&self, path: &str, headers: &[(&str, &str)]) -> (String, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = TcpStream::connect(&self.addr).await.expect("failed to connect");
+        let mut request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n", path, self.addr);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut response))
+            .await
+            .expect("timed out waiting for a response")
+            .unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").expect("malformed response");
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    /// POST `body` as JSON to `path` over plain HTTP/1.0; returns the whole response
+    async fn post(&self, path: &str, body: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = TcpStream::connect(&self.addr).await.expect("failed to connect");
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            self.addr,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+            .await
+            .expect("timed out waiting for a response")
+            .unwrap();
+        response
+    }
+
+    /// Open an SSE event stream; returns its connection id and the stream's lines
+    async fn open_event_stream(&self) -> (String, Lines<BufReader<TcpStream>>) {
+        use tokio::io::AsyncWriteExt;
+
+        let mut stream = TcpStream::connect(&self.addr).await.expect("failed to connect");
+        stream
+            .write_all(format!("GET /api/events HTTP/1.0\r\nHost: {}\r\n\r\n", self.addr).as_bytes())
+            .await
+            .unwrap();
+        let mut events = BufReader::new(stream).lines();
+        let connection_id = next_event_data(&mut events).await.expect("event stream closed");
+        (connection_id, events)
+    }
+
+    async fn connect(&self) -> Client {
+        self.connect_to("/ws").await
+    }
+
+    /// Connect to the WebSocket at `path`, e.g. to pass query parameters
+    async fn connect_to(&self, path: &str) -> Client {
+        let mut request = format!("ws://{}{}", self.addr, path).into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", "chat.v1".parse().unwrap());
+        let (ws, _) = tokio_tungstenite::connect_async(request).await.expect("failed to connect");
+        Client { ws }
+    }
+}
+
+struct Client {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Client {
+    async fn send(&mut self, message: Value) {
+        self.ws.send(Message::text(message.to_string())).await.unwrap();
+    }
+
+    /// The next message, skipping presence updates
+    async fn recv(&mut self) -> Value {
+        loop {
+            let frame = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for a message")
+                .expect("connection closed")
+                .unwrap();
+            let Message::Text(text) = frame else { continue };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if !PRESENCE_EVENTS.contains(&message["type"].as_str().unwrap_or_default()) {
+                return message;
+            }
+        }
+    }
+
+    /// Wait for a presence update of type `expected`, skipping everything else
+    async fn wait_for_presence(&mut self, expected: &str) -> Value {
+        loop {
+            let frame = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for a presence update")
+                .expect("connection closed")
+                .unwrap();
+            let Message::Text(text) = frame else { continue };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["type"] == expected {
+                return message;
+            }
+        }
+    }
+
+    /// The next message, which must be of type `expected`
+    async fn expect(&mut self, expected: &str) -> Value {
+        let message = self.recv().await;
+        assert_eq!(message["type"], expected, "unexpected message: {}", message);
+        message
+    }
+
+    /// Register `username` and return the new user's id
+    async fn register(&mut self, username: &str) -> String {
+        self.send(json!({ "type": "Register", "username": username, "password": "secret" }))
+            .await;
+        let user_id = self.expect("RegisterSuccess").await["user"]["id"].as_str().unwrap().to_string();
+        self.expect("OnlineUsers").await;
+        user_id
+    }
+}
+
+#[tokio::test]
+async fn register_login_send_and_read_history() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "hello bob",
+            "client_msg_id": "m1",
+        }))
+        .await;
+    let ack = alice.expect("MessageAck").await;
+    assert_eq!(ack["client_msg_id"], "m1");
+    let echo = alice.expect("NewMessage").await;
+    assert_eq!(echo["message"]["id"], ack["message_id"]);
+
+    let received = bob.expect("NewMessage").await;
+    assert_eq!(received["message"]["id"], ack["message_id"]);
+    assert_eq!(received["message"]["from_user_id"], alice_id.as_str());
+    assert_eq!(received["message"]["content"], "hello bob");
+
+    // A second session logs in with the password and sees the stored message
+    let mut bob_again = server.connect().await;
+    bob_again
+        .send(json!({ "type": "Login", "username": "bob", "password": "secret" }))
+        .await;
+    let login = bob_again.expect("LoginSuccess").await;
+    assert_eq!(login["user"]["id"], bob_id.as_str());
+    bob_again.expect("OnlineUsers").await;
+
+    bob_again
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob_again.expect("MessageHistory").await;
+    assert_eq!(history["total_count"], 1);
+    assert_eq!(history["has_more"], false);
+    let messages = history["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["id"], ack["message_id"]);
+    assert_eq!(messages[0]["content"], "hello bob");
+}
+
+#[tokio::test]
+async fn usernames_are_unique_and_matched_regardless_of_case() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("Alice").await;
+
+    let mut impostor = server.connect().await;
+    impostor
+        .send(json!({ "type": "Register", "username": "alice", "password": "secret" }))
+        .await;
+    assert_eq!(impostor.expect("AuthError").await["message"], "Username already exists");
+
+    let mut alice_again = server.connect().await;
+    alice_again
+        .send(json!({ "type": "Login", "username": "ALICE", "password": "secret" }))
+        .await;
+    let login = alice_again.expect("LoginSuccess").await;
+    assert_eq!(login["user"]["id"], alice_id.as_str());
+    // The name is kept as it was registered
+    assert_eq!(login["user"]["username"], "Alice");
+}
+
+#[tokio::test]
+async fn login_rejects_wrong_password() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    let mut intruder = server.connect().await;
+    intruder
+        .send(json!({ "type": "Login", "username": "alice", "password": "guess" }))
+        .await;
+    intruder.expect("AuthError").await;
+}
+
+#[tokio::test]
+async fn repeated_wrong_passwords_lock_the_account() {
+    let server = TestServer::start_with(&[("LOGIN_MAX_FAILURES", "3")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    let mut intruder = server.connect().await;
+    for username in ["alice", "Alice", "ALICE"] {
+        intruder
+            .send(json!({ "type": "Login", "username": username, "password": "guess" }))
+            .await;
+        assert_eq!(intruder.expect("AuthError").await["message"], "Invalid password");
+    }
+
+    // Locked out now, even with the right password
+    let mut owner = server.connect().await;
+    owner
+        .send(json!({ "type": "Login", "username": "alice", "password": "secret" }))
+        .await;
+    assert_eq!(owner.expect("AuthError").await["message"], "Too many attempts");
+}
+
+#[tokio::test]
+async fn logging_in_upgrades_a_hash_below_the_configured_cost() {
+    let path = std::env::temp_dir().join(format!("chat-backend-{}.db", uuid::Uuid::new_v4()));
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let server = TestServer::start_with(&[("DATABASE_URL", &url), ("BCRYPT_COST", "5")]).await;
+    let pool = SqlitePool::connect(&url).await.unwrap();
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    drop(alice);
+
+    // As if stored back when the cost was lower
+    let old_hash = bcrypt::hash("secret", 4).unwrap();
+    sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+        .bind(&old_hash)
+        .bind(&alice_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let mut alice = server.connect().await;
+    alice
+        .send(json!({ "type": "Login", "username": "alice", "password": "secret" }))
+        .await;
+    alice.expect("LoginSuccess").await;
+
+    // The re-hash happens in the background after the login
+    let upgraded = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let hash: String = sqlx::query("SELECT password_hash FROM users WHERE id = ?")
+                .bind(&alice_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get(0);
+            if hash != old_hash {
+                return hash;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the hash was never upgraded");
+    assert!(upgraded.starts_with("$2b$05$"), "{}", upgraded);
+    assert!(bcrypt::verify("secret", &upgraded).unwrap());
+
+    pool.close().await;
+    drop(server);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn passwordless_logins_count_toward_the_lockout() {
+    let server = TestServer::start_with(&[("LOGIN_MAX_FAILURES", "2")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    // An account with a password can't be entered by leaving it out
+    let mut intruder = server.connect().await;
+    for _ in 0..2 {
+        intruder.send(json!({ "type": "Login", "username": "alice" })).await;
+        assert_eq!(intruder.expect("AuthError").await["message"], "Invalid password");
+    }
+
+    // Locked out on every path
+    intruder.send(json!({ "type": "Login", "username": "alice" })).await;
+    assert_eq!(intruder.expect("AuthError").await["message"], "Too many attempts");
+    let mut owner = server.connect().await;
+    owner
+        .send(json!({ "type": "Login", "username": "alice", "password": "secret" }))
+        .await;
+    assert_eq!(owner.expect("AuthError").await["message"], "Too many attempts");
+}
+
+#[tokio::test]
+async fn public_keys_round_trip() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+
+    // No key until one is set
+    bob.send(json!({ "type": "GetPublicKey", "user_id": alice_id })).await;
+    assert_eq!(bob.expect("PublicKey").await["public_key"], Value::Null);
+
+    let public_key = "MCowBQYDK2VuAyEAm8lJ0ExvE4w2YAk8nUqLq0Y2qvN8k9u1ZyR7w3V1a0s=";
+    alice.send(json!({ "type": "SetPublicKey", "public_key": public_key })).await;
+    alice.expect("Success").await;
+
+    // Peers learn about the new key once, through `UserUpdated`
+    let updated = bob.wait_for_presence("UserUpdated").await;
+    assert_eq!(updated["user"]["public_key"], public_key);
+
+    bob.send(json!({ "type": "GetPublicKey", "user_id": alice_id })).await;
+    let fetched = bob.expect("PublicKey").await;
+    assert_eq!(fetched["user_id"], alice_id.as_str());
+    assert_eq!(fetched["public_key"], public_key);
+}
+
+#[tokio::test]
+async fn uploaded_avatars_are_served_back() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+
+    // Nothing to serve until one is uploaded
+    let (head, _) = server.get(&format!("/api/avatars/{}", alice_id), &[]).await;
+    assert!(head.starts_with("HTTP/1.0 404"), "unexpected response: {}", head);
+
+    alice
+        .send(json!({ "type": "SetAvatar", "file_data": ONE_PIXEL_PNG, "file_type": "image/png" }))
+        .await;
+    let updated = alice.wait_for_presence("UserUpdated").await;
+    let avatar_url = updated["user"]["avatar_url"].as_str().unwrap();
+    assert!(avatar_url.starts_with(&format!("/api/avatars/{}?v=", alice_id)), "unexpected url: {}", avatar_url);
+
+    let (head, body) = server.get(avatar_url, &[]).await;
+    assert!(head.starts_with("HTTP/1.0 200"), "unexpected response: {}", head);
+    assert!(head.to_ascii_lowercase().contains("content-type: image/png"), "unexpected response: {}", head);
+    assert!(body.starts_with(b"\x89PNG"));
+
+    // Payloads that aren't images are refused, and the stored avatar is kept
+    alice
+        .send(json!({ "type": "SetAvatar", "file_data": "bm90IGFuIGltYWdl", "file_type": "image/png" }))
+        .await;
+    assert_eq!(alice.expect("Error").await["message"], "Avatar is not a readable image");
+    alice
+        .send(json!({ "type": "SetAvatar", "file_data": ONE_PIXEL_PNG, "file_type": "text/plain" }))
+        .await;
+    assert_eq!(alice.expect("Error").await["message"], "Unsupported avatar format: text/plain");
+
+    let (head, body_again) = server.get(avatar_url, &[]).await;
+    assert!(head.starts_with("HTTP/1.0 200"), "unexpected response: {}", head);
+    assert_eq!(body_again, body);
+}
+
+#[tokio::test]
+async fn display_name_changes_are_stored_and_broadcast() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+
+    alice.send(json!({ "type": "SetDisplayName", "name": "  Alice Liddell " })).await;
+    let updated = loop {
+        let updated = bob.wait_for_presence("UserUpdated").await;
+        if updated["user"]["id"] == alice_id.as_str() {
+            break updated;
+        }
+    };
+    assert_eq!(updated["user"]["display_name"], "Alice Liddell");
+    assert_eq!(updated["user"]["username"], "alice");
+
+    bob.send(json!({ "type": "GetUsers", "ids": [alice_id] })).await;
+    assert_eq!(bob.expect("Users").await["users"][0]["display_name"], "Alice Liddell");
+
+    // Too long a name is refused and leaves the stored one alone
+    alice.send(json!({ "type": "SetDisplayName", "name": "x".repeat(65) })).await;
+    alice.expect("Error").await;
+    bob.send(json!({ "type": "GetUsers", "ids": [alice_id] })).await;
+    assert_eq!(bob.expect("Users").await["users"][0]["display_name"], "Alice Liddell");
+}
+
+#[tokio::test]
+async fn contacts_receive_profile_updates() {
+    /// The next `UserUpdated` about `user_id`
+    async fn next_update(client: &mut Client, user_id: &str) -> Value {
+        loop {
+            let updated = client.wait_for_presence("UserUpdated").await;
+            if updated["user"]["id"] == user_id {
+                return updated;
+            }
+        }
+    }
+
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+    bob.send(json!({ "type": "AddContact", "user_id": alice_id })).await;
+    bob.expect("Contacts").await;
+
+    alice.send(json!({ "type": "SetDisplayName", "name": "Alice" })).await;
+    assert_eq!(next_update(&mut bob, &alice_id).await["user"]["display_name"], "Alice");
+
+    alice
+        .send(json!({ "type": "SetAvatar", "file_data": ONE_PIXEL_PNG, "file_type": "image/png" }))
+        .await;
+    let updated = next_update(&mut bob, &alice_id).await;
+    assert!(updated["user"]["avatar_url"].as_str().is_some_and(|url| url.starts_with("/api/avatars/")));
+    // Each update carries the whole profile
+    assert_eq!(updated["user"]["display_name"], "Alice");
+}
+
+#[tokio::test]
+async fn messages_require_login() {
+    let server = TestServer::start().await;
+
+    let mut anonymous = server.connect().await;
+    anonymous
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": "someone" }))
+        .await;
+    anonymous
+        .send(json!({ "type": "Register", "username": "carol", "password": "secret" }))
+        .await;
+    // Nothing answers the history request; the next reply is the registration's
+    anonymous.expect("RegisterSuccess").await;
+}
+
+#[tokio::test]
+async fn malformed_messages_get_an_error_and_keep_the_connection() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.ws.send(Message::text("{\"type\": \"Login\", ")).await.unwrap();
+    let error = alice.expect("Error").await;
+    assert_eq!(error["message"], "Invalid message format: malformed JSON at line 1, column 18");
+
+    alice.send(json!({ "type": "Lgoin", "username": "alice" })).await;
+    assert_eq!(alice.expect("Error").await["message"], "Invalid message format: unknown message type");
+
+    // Bad values aren't echoed back, since they may be passwords
+    alice
+        .send(json!({ "type": "Register", "username": "alice", "password": ["hunter2"] }))
+        .await;
+    let error = alice.expect("Error").await;
+    assert!(!error.to_string().contains("hunter2"), "{}", error);
+
+    // The connection is still usable
+    let alice_id = alice.register("alice").await;
+    assert!(!alice_id.is_empty());
+}
+
+#[tokio::test]
+async fn send_message_retry_is_not_stored_twice() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    let message = json!({
+        "type": "SendMessage",
+        "to_user_id": bob_id,
+        "content": "only once",
+        "client_msg_id": "retry-me",
+    });
+    alice.send(message.clone()).await;
+    let first = alice.expect("MessageAck").await;
+    alice.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+
+    alice.send(message).await;
+    let second = alice.expect("MessageAck").await;
+    assert_eq!(second["message_id"], first["message_id"]);
+    alice.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    // The retry isn't delivered again: the history reply comes next
+    let history = bob.expect("MessageHistory").await;
+    assert_eq!(history["total_count"], 1);
+}
+
+#[tokio::test]
+async fn acks_echo_each_client_msg_id() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for (client_msg_id, content) in [("a1", "first"), ("a2", "second")] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content, "client_msg_id": client_msg_id }))
+            .await;
+    }
+    for (client_msg_id, content) in [("a1", "first"), ("a2", "second")] {
+        let ack = alice.expect("MessageAck").await;
+        assert_eq!(ack["client_msg_id"], client_msg_id);
+        let echo = alice.expect("NewMessage").await;
+        assert_eq!(echo["message"]["id"], ack["message_id"]);
+        assert_eq!(echo["message"]["content"], content);
+    }
+
+    bob.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+
+    // Ids are only unique per sender: bob's "a1" is a message of its own
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "reply", "client_msg_id": "a1" }))
+        .await;
+    let ack = bob.expect("MessageAck").await;
+    assert_eq!(ack["client_msg_id"], "a1");
+    let echo = bob.expect("NewMessage").await;
+    assert_eq!(echo["message"]["content"], "reply");
+}
+
+#[tokio::test]
+async fn send_message_rejects_invalid_messages() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    let attachment = json!({ "file_data": "aGVsbG8=", "file_name": "a.txt", "file_type": "text/plain" });
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "",
+            "attachments": vec![attachment; 11],
+        }))
+        .await;
+    let error = alice.expect("Error").await;
+    assert!(error["message"].as_str().unwrap().contains("Too many attachments"));
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "hi",
+            "device_name": "x".repeat(65),
+        }))
+        .await;
+    alice.expect("Error").await;
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "hi",
+            "expires_in_seconds": 0,
+        }))
+        .await;
+    alice.expect("Error").await;
+
+    // A zip archive, whatever the client claims it is
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "",
+            "file_data": "UEsDBBQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            "file_name": "photo.png",
+            "file_type": "image/png",
+        }))
+        .await;
+    let error = alice.expect("Error").await;
+    assert_eq!(error["code"], "FILE_TYPE_NOT_ALLOWED");
+
+    // None of them reached the recipient or the history
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    assert_eq!(history["total_count"], 0);
+}
+
+#[tokio::test]
+async fn messages_carry_several_attachments_in_order() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let attachments: Vec<Value> = ["one", "two", "three"]
+        .iter()
+        .map(|name| json!({ "file_data": "ZmlsZQ==", "file_name": format!("{}.txt", name), "file_type": "text/plain" }))
+        .collect();
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "three files", "attachments": attachments }))
+        .await;
+    let sent = alice.expect("NewMessage").await;
+    assert_eq!(sent["message"]["attachments"].as_array().unwrap().len(), 3);
+
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    let stored = history["messages"][0]["attachments"].as_array().unwrap();
+    let names: Vec<&str> = stored.iter().map(|a| a["file_name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["one.txt", "two.txt", "three.txt"]);
+    assert!(stored.iter().all(|a| a["file_data"] == "ZmlsZQ=="));
+}
+
+#[tokio::test]
+async fn scheduled_messages_are_delivered_when_due() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let send_at = chrono::Utc::now() + chrono::Duration::seconds(1);
+    alice
+        .send(json!({ "type": "ScheduleMessage", "to_user_id": bob_id, "content": "later", "send_at": send_at }))
+        .await;
+    let scheduled_id = alice.expect("MessageScheduled").await["id"].clone();
+
+    // Not in the conversation until it's sent
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+        .await;
+    assert_eq!(alice.expect("MessageHistory").await["total_count"], 0);
+
+    let delivered = bob.expect("NewMessage").await;
+    assert_eq!(delivered["message"]["id"], scheduled_id);
+    assert_eq!(delivered["message"]["content"], "later");
+    assert_eq!(alice.expect("NewMessage").await["message"]["id"], scheduled_id);
+}
+
+#[tokio::test]
+async fn scheduled_messages_are_sent_while_the_sender_is_offline() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let send_at = chrono::Utc::now() + chrono::Duration::seconds(1);
+    alice
+        .send(json!({ "type": "ScheduleMessage", "to_user_id": bob_id, "content": "while away", "send_at": send_at }))
+        .await;
+    alice.expect("MessageScheduled").await;
+    drop(alice);
+
+    assert_eq!(bob.expect("NewMessage").await["message"]["content"], "while away");
+}
+
+#[tokio::test]
+async fn cancelled_scheduled_messages_are_never_sent() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let send_at = chrono::Utc::now() + chrono::Duration::seconds(1);
+    alice
+        .send(json!({ "type": "ScheduleMessage", "to_user_id": bob_id, "content": "never mind", "send_at": send_at }))
+        .await;
+    let scheduled_id = alice.expect("MessageScheduled").await["id"].clone();
+
+    // Only the sender may cancel
+    bob.send(json!({ "type": "CancelScheduledMessage", "id": scheduled_id }))
+        .await;
+    assert_eq!(bob.expect("Error").await["message"], "Scheduled message not found");
+    alice
+        .send(json!({ "type": "CancelScheduledMessage", "id": scheduled_id }))
+        .await;
+    assert_eq!(alice.expect("ScheduledMessageCancelled").await["id"], scheduled_id);
+
+    // Give the scheduler a couple of ticks past the send time
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("MessageHistory").await["total_count"], 0);
+}
+
+#[tokio::test]
+async fn uploads_past_the_storage_quota_are_refused() {
+    let server = TestServer::start_with(&[("STORAGE_QUOTA_BYTES", "20")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let upload = json!({
+        "type": "SendMessage",
+        "to_user_id": bob_id,
+        "content": "",
+        "file_data": "ZmlsZQ==",
+        "file_name": "file.txt",
+        "file_type": "text/plain",
+    });
+    for _ in 0..2 {
+        alice.send(upload.clone()).await;
+        alice.expect("NewMessage").await;
+    }
+    alice.send(json!({ "type": "GetStorageUsage" })).await;
+    let usage = alice.expect("StorageUsage").await;
+    assert_eq!((usage["used"].as_u64(), usage["quota"].as_u64()), (Some(16), Some(20)));
+
+    alice.send(upload).await;
+    let error = alice.expect("Error").await;
+    assert_eq!(error["code"], "QUOTA_EXCEEDED");
+
+    // Text still goes through, and the refused file took up nothing
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "no room" }))
+        .await;
+    alice.expect("NewMessage").await;
+    alice.send(json!({ "type": "GetStorageUsage" })).await;
+    assert_eq!(alice.expect("StorageUsage").await["used"], 16);
+}
+
+#[tokio::test]
+async fn unread_counts_follow_the_read_marker() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    let mut timestamps = Vec::new();
+    for text in ["one", "two", "three"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": text }))
+            .await;
+        alice.expect("NewMessage").await;
+        timestamps.push(bob.expect("NewMessage").await["message"]["timestamp"].clone());
+        // Distinct timestamps, so the marker falls between messages
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let unread_from_alice = |conversations: Value| {
+        conversations["conversations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["user"]["id"] == alice_id.as_str())
+            .map(|c| c["unread_count"].clone())
+            .unwrap()
+    };
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(unread_from_alice(bob.expect("Conversations").await), 3);
+
+    bob.send(json!({ "type": "UpdateReadMarker", "other_user_id": alice_id, "last_read_ts": timestamps[1] }))
+        .await;
+    let marker = alice.expect("ReadMarker").await;
+    assert_eq!(marker["user_id"], bob_id.as_str());
+    assert_eq!(marker["last_read_seq"], 2);
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(unread_from_alice(bob.expect("Conversations").await), 1);
+
+    // An older marker doesn't make anything unread again
+    bob.send(json!({ "type": "UpdateReadMarker", "other_user_id": alice_id, "last_read_ts": timestamps[0] }))
+        .await;
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(unread_from_alice(bob.expect("Conversations").await), 1);
+
+    // Messages behind the marker show up as read to the sender
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    let read: Vec<(String, bool)> = history["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| (m["content"].as_str().unwrap().to_string(), m["read"].as_bool().unwrap()))
+        .collect();
+    assert_eq!(
+        read,
+        [("one".to_string(), true), ("two".to_string(), true), ("three".to_string(), false)]
+    );
+
+    // A marker by number stops at that message, whatever the time
+    bob.send(json!({ "type": "UpdateReadMarker", "other_user_id": alice_id, "last_read_ts": timestamps[2], "last_read_seq": 2 }))
+        .await;
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(unread_from_alice(bob.expect("Conversations").await), 1);
+}
+
+#[tokio::test]
+async fn toggling_a_reaction_twice_removes_it() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "react to me" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    let toggle = json!({ "type": "ToggleReaction", "message_id": message_id, "emoji": "👍" });
+    bob.send(toggle.clone()).await;
+    let added = bob.expect("MessageReaction").await;
+    assert_eq!(added["emoji"], "👍");
+    assert_eq!(added["reactions"][&bob_id], "👍");
+
+    bob.send(toggle).await;
+    let removed = bob.expect("MessageReaction").await;
+    assert_eq!(removed["emoji"], Value::Null);
+    assert_eq!(removed["removed_emoji"], "👍");
+    assert_eq!(removed["reactions"], json!({}));
+}
+
+#[tokio::test]
+async fn reactions_past_the_cap_are_refused() {
+    let server = TestServer::start_with(&[("MAX_REACTIONS_PER_MESSAGE", "1")]).await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "popular" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👍" })).await;
+    bob.expect("MessageReaction").await;
+    alice.expect("MessageReaction").await;
+
+    // A second reactor is over the cap, by either route
+    for kind in ["AddReaction", "ToggleReaction"] {
+        alice.send(json!({ "type": kind, "message_id": message_id, "emoji": "🎉" })).await;
+        assert_eq!(
+            alice.expect("Error").await["message"],
+            "This message has reached the maximum number of reactions"
+        );
+    }
+
+    // Changing an existing reaction is still allowed
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "❤️" })).await;
+    let changed = bob.expect("MessageReaction").await;
+    assert_eq!(changed["reactions"], json!({ bob_id.as_str(): "❤️" }));
+
+    // Overlong emoji are refused before the cap is looked at
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "x".repeat(65) })).await;
+    assert_eq!(bob.expect("Error").await["message"], "Invalid reaction");
+}
+
+#[tokio::test]
+async fn reaction_details_name_the_reactors() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut carol = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+    carol.register("carol").await;
+
+    alice.send(json!({ "type": "SetDisplayName", "name": "Alice L." })).await;
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "react, please" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    alice.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👍" })).await;
+    alice.expect("MessageReaction").await;
+    bob.expect("MessageReaction").await;
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "🎉" })).await;
+    bob.expect("MessageReaction").await;
+
+    bob.send(json!({ "type": "GetReactionDetails", "message_id": message_id })).await;
+    let details = bob.expect("ReactionDetails").await;
+    assert_eq!(details["message_id"], message_id);
+    let mut reactions: Vec<(&str, &str, &str, Option<&str>)> = details["reactions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            (
+                r["emoji"].as_str().unwrap(),
+                r["user_id"].as_str().unwrap(),
+                r["username"].as_str().unwrap(),
+                r["display_name"].as_str(),
+            )
+        })
+        .collect();
+    reactions.sort();
+    let mut expected = [
+        ("👍", alice_id.as_str(), "alice", Some("Alice L.")),
+        ("🎉", bob_id.as_str(), "bob", None),
+    ];
+    expected.sort();
+    assert_eq!(reactions, expected);
+
+    // Only participants may ask
+    carol.send(json!({ "type": "GetReactionDetails", "message_id": message_id })).await;
+    assert_eq!(carol.expect("Error").await["message"], "Message not found");
+}
+
+#[tokio::test]
+async fn reactions_only_reach_the_conversation() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut carol = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+    carol.register("carol").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "just between us" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "🔥" })).await;
+    assert_eq!(bob.expect("MessageReaction").await["emoji"], "🔥");
+    assert_eq!(alice.expect("MessageReaction").await["reactions"][&bob_id], "🔥");
+
+    // Had carol been sent the reaction, it would be queued ahead of this reply
+    carol.send(json!({ "type": "GetConversations" })).await;
+    carol.expect("Conversations").await;
+
+    // Nor can she react to it
+    for request in [
+        json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👎" }),
+        json!({ "type": "ToggleReaction", "message_id": message_id, "emoji": "👎" }),
+        json!({ "type": "RemoveReaction", "message_id": message_id }),
+    ] {
+        carol.send(request).await;
+        assert_eq!(carol.expect("Error").await["message"], "Message not found");
+    }
+    bob.send(json!({ "type": "GetReactionDetails", "message_id": message_id })).await;
+    assert_eq!(bob.expect("ReactionDetails").await["reactions"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn single_messages_are_fetched_by_id_for_participants_only() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "link me" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👍" }))
+        .await;
+    bob.expect("MessageReaction").await;
+    alice.expect("MessageReaction").await;
+
+    alice.send(json!({ "type": "GetMessage", "message_id": message_id })).await;
+    let fetched = alice.expect("Message").await;
+    assert_eq!(fetched["message"]["content"], "link me");
+    assert_eq!(fetched["message"]["reactions"][&bob_id], "👍");
+
+    // Someone else's message reads the same as one that doesn't exist
+    let mut carol = server.connect().await;
+    carol.register("carol").await;
+    carol.send(json!({ "type": "GetMessage", "message_id": message_id })).await;
+    assert_eq!(carol.expect("Error").await["message"], "Message not found");
+    alice.send(json!({ "type": "GetMessage", "message_id": "no-such-message" })).await;
+    assert_eq!(alice.expect("Error").await["message"], "Message not found");
+}
+
+#[tokio::test]
+async fn references_to_missing_rows_are_refused() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    alice
+        .send(json!({ "type": "AddReaction", "message_id": "no-such-message", "emoji": "👍" }))
+        .await;
+    let error = alice.expect("Error").await;
+    assert_eq!(error["message"], "Message not found");
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": "no-such-user", "content": "hello?" }))
+        .await;
+    let error = alice.expect("Error").await;
+    assert_eq!(error["message"], "User not found");
+}
+
+#[tokio::test]
+async fn clearing_a_conversation_removes_it_for_both() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "soon gone" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👍" }))
+        .await;
+    bob.expect("MessageReaction").await;
+    alice.expect("MessageReaction").await;
+
+    alice
+        .send(json!({ "type": "ClearConversation", "other_user_id": bob_id }))
+        .await;
+    assert_eq!(alice.expect("ConversationCleared").await["user_id"], bob_id.as_str());
+    assert_eq!(bob.expect("ConversationCleared").await["user_id"], alice_id.as_str());
+
+    for (client, other_id) in [(&mut alice, &bob_id), (&mut bob, &alice_id)] {
+        client
+            .send(json!({ "type": "GetMessageHistory", "other_user_id": other_id }))
+            .await;
+        assert_eq!(client.expect("MessageHistory").await["total_count"], 0);
+    }
+    // Its reactions went with it
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "🎉" }))
+        .await;
+    assert_eq!(bob.expect("Error").await["message"], "Message not found");
+}
+
+#[tokio::test]
+async fn clearing_a_conversation_for_me_keeps_the_other_side() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "keep me" }))
+        .await;
+    alice.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "ClearConversation", "other_user_id": alice_id, "only_for_me": true }))
+        .await;
+    assert_eq!(bob.expect("ConversationCleared").await["user_id"], alice_id.as_str());
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("MessageHistory").await["messages"], json!([]));
+
+    // Alice isn't told and still has the message
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    assert_eq!(history["messages"][0]["content"], "keep me");
+}
+
+#[tokio::test]
+async fn drafts_follow_the_user_and_clear_on_send() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "hi" }))
+        .await;
+    alice.expect("NewMessage").await;
+    alice
+        .send(json!({ "type": "SaveDraft", "other_user_id": bob_id, "content": "half a tho" }))
+        .await;
+    alice
+        .send(json!({ "type": "SaveDraft", "other_user_id": bob_id, "content": "half a thought" }))
+        .await;
+    alice.send(json!({ "type": "GetDraft", "other_user_id": bob_id })).await;
+    assert_eq!(alice.expect("Draft").await["content"], "half a thought");
+    drop(alice);
+
+    // Another device picks it up with the conversation list
+    let mut alice = server.connect().await;
+    alice
+        .send(json!({ "type": "Login", "username": "alice", "password": "secret" }))
+        .await;
+    alice.expect("LoginSuccess").await;
+    alice.expect("OnlineUsers").await;
+    alice.send(json!({ "type": "GetConversations" })).await;
+    let conversations = alice.expect("Conversations").await;
+    assert_eq!(conversations["conversations"][0]["draft"], "half a thought");
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "half a thought, finished" }))
+        .await;
+    alice.expect("NewMessage").await;
+    alice.send(json!({ "type": "GetDraft", "other_user_id": bob_id })).await;
+    assert_eq!(alice.expect("Draft").await["content"], Value::Null);
+}
+
+#[tokio::test]
+async fn delete_my_messages_keeps_the_other_side() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "from alice" }))
+        .await;
+    let alice_message = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "from bob" }))
+        .await;
+    bob.expect("NewMessage").await;
+    alice.expect("NewMessage").await;
+
+    alice
+        .send(json!({ "type": "DeleteMyMessages", "other_user_id": bob_id }))
+        .await;
+    let deleted = alice.expect("MessagesDeleted").await;
+    assert_eq!(deleted["message_ids"], json!([alice_message]));
+    // Each side's copy carries that side's own event `seq`
+    assert_eq!(bob.expect("MessagesDeleted").await["message_ids"], deleted["message_ids"]);
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    let contents: Vec<&str> = history["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, ["from bob"]);
+}
+
+#[tokio::test]
+async fn starred_messages_span_conversations() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut carol = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    bob.register("bob").await;
+    carol.register("carol").await;
+
+    let mut message_ids = Vec::new();
+    for (sender, text) in [(&mut bob, "from bob"), (&mut carol, "from carol")] {
+        sender
+            .send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": text }))
+            .await;
+        sender.expect("NewMessage").await;
+        message_ids.push(alice.expect("NewMessage").await["message"]["id"].clone());
+    }
+
+    for message_id in &message_ids {
+        alice.send(json!({ "type": "StarMessage", "message_id": message_id })).await;
+        let starred = alice.expect("MessageStarred").await;
+        assert_eq!(starred["starred"], true);
+    }
+
+    // Only participants can star a message
+    carol
+        .send(json!({ "type": "StarMessage", "message_id": message_ids[0] }))
+        .await;
+    carol.expect("Error").await;
+
+    alice.send(json!({ "type": "GetStarredMessages" })).await;
+    let starred = alice.expect("StarredMessages").await;
+    let contents: Vec<&str> = starred["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, ["from carol", "from bob"]);
+
+    alice
+        .send(json!({ "type": "UnstarMessage", "message_id": message_ids[1] }))
+        .await;
+    alice.expect("MessageStarred").await;
+    alice.send(json!({ "type": "GetStarredMessages" })).await;
+    let starred = alice.expect("StarredMessages").await;
+    assert_eq!(starred["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(starred["messages"][0]["id"], message_ids[0]);
+}
+
+#[tokio::test]
+async fn notes_to_self_are_stored_and_delivered_once() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "remember the milk" }))
+        .await;
+    let note = alice.expect("NewMessage").await;
+    assert_eq!(note["message"]["read"], true);
+
+    // No second copy arrives: the history reply comes next
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    assert_eq!(history["total_count"], 1);
+
+    alice.send(json!({ "type": "GetConversations" })).await;
+    let conversations = alice.expect("Conversations").await;
+    let conversation = &conversations["conversations"][0];
+    assert_eq!(conversation["user"]["id"], alice_id);
+    assert_eq!(conversation["notes_to_self"], true);
+    assert_eq!(conversation["unread_count"], 0);
+}
+
+#[tokio::test]
+async fn search_spans_conversations() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut carol = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+    let carol_id = carol.register("carol").await;
+
+    for (from_bob, text) in [
+        (true, "lunch at noon?"),
+        (false, "Lunch tomorrow"),
+        (false, "unrelated"),
+        (true, "100% lunch_time"),
+    ] {
+        let sender = if from_bob { &mut bob } else { &mut carol };
+        sender
+            .send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": text }))
+            .await;
+        sender.expect("NewMessage").await;
+        alice.expect("NewMessage").await;
+        // Distinct timestamps, so results come back in a known order
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    alice
+        .send(json!({ "type": "SearchAllMessages", "query": "lunch", "limit": 2 }))
+        .await;
+    let page = alice.expect("SearchResults").await;
+    assert_eq!(page["has_more"], true);
+    let results = page["results"].as_array().unwrap();
+    assert_eq!(results[0]["other_user_id"], bob_id);
+    assert_eq!(results[0]["message"]["content"], "100% lunch_time");
+    assert_eq!(results[1]["other_user_id"], carol_id);
+
+    alice
+        .send(json!({ "type": "SearchAllMessages", "query": "lunch", "limit": 2, "offset": 2 }))
+        .await;
+    let page = alice.expect("SearchResults").await;
+    assert_eq!(page["has_more"], false);
+    assert_eq!(page["results"][0]["message"]["content"], "lunch at noon?");
+
+    // Wildcards in the query match literally
+    alice.send(json!({ "type": "SearchAllMessages", "query": "0%" })).await;
+    let page = alice.expect("SearchResults").await;
+    assert_eq!(page["results"].as_array().unwrap().len(), 1);
+
+    // Other users' conversations aren't searched
+    carol.send(json!({ "type": "SearchAllMessages", "query": "noon" })).await;
+    let page = carol.expect("SearchResults").await;
+    assert!(page["results"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn rapid_typing_indicators_are_coalesced() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    for _ in 0..5 {
+        alice
+            .send(json!({ "type": "Typing", "to_user_id": bob_id, "is_typing": true }))
+            .await;
+    }
+    alice
+        .send(json!({ "type": "Typing", "to_user_id": bob_id, "is_typing": false }))
+        .await;
+    alice
+        .send(json!({ "type": "Typing", "to_user_id": bob_id, "is_typing": true }))
+        .await;
+
+    // One start for the burst, then the stop, then a fresh start
+    assert_eq!(bob.expect("Typing").await["is_typing"], true);
+    assert_eq!(bob.expect("Typing").await["is_typing"], false);
+    assert_eq!(bob.expect("Typing").await["is_typing"], true);
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "hi" }))
+        .await;
+    bob.expect("NewMessage").await;
+}
+
+#[tokio::test]
+async fn messages_with_equal_timestamps_keep_a_stable_order() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    // Sent back to back, so several share a millisecond
+    for n in 0..10 {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": n.to_string() }))
+            .await;
+    }
+    for _ in 0..10 {
+        alice.expect("NewMessage").await;
+    }
+
+    let mut fetches = Vec::new();
+    for _ in 0..3 {
+        alice
+            .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+            .await;
+        let history = alice.expect("MessageHistory").await;
+        let order: Vec<(String, String)> = history["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| (m["timestamp"].as_str().unwrap().to_string(), m["id"].as_str().unwrap().to_string()))
+            .collect();
+        fetches.push(order);
+    }
+    assert_eq!(fetches[0].len(), 10);
+    assert!(fetches.iter().all(|order| order == &fetches[0]));
+    // Ties are broken by id
+    assert!(fetches[0]
+        .windows(2)
+        .all(|pair| pair[0].0 != pair[1].0 || pair[0].1 < pair[1].1));
+
+    // Ties don't produce a second entry for the conversation
+    alice.send(json!({ "type": "GetConversations" })).await;
+    let conversations = alice.expect("Conversations").await;
+    assert_eq!(conversations["conversations"].as_array().unwrap().len(), 1);
+    assert_eq!(conversations["conversations"][0]["last_message"]["id"], json!(fetches[0][9].1));
+}
+
+#[tokio::test]
+async fn connections_past_the_per_ip_limit_are_refused() {
+    use tokio_tungstenite::tungstenite::Error;
+
+    let server = TestServer::start_with(&[("MAX_CONNECTIONS_PER_IP", "2")]).await;
+    let url = format!("ws://{}/ws", server.addr);
+
+    let first = server.connect().await;
+    let _second = server.connect().await;
+    match tokio_tungstenite::connect_async(&url).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 429),
+        other => panic!("expected a 429, got {:?}", other.map(|_| ())),
+    }
+
+    // Closing a connection frees its slot
+    drop(first);
+    tokio::time::timeout(TIMEOUT, async {
+        while tokio_tungstenite::connect_async(&url).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the slot was never released");
+}
+
+#[tokio::test]
+async fn silent_connections_are_closed_after_the_auth_timeout() {
+    let server = TestServer::start_with(&[("AUTH_TIMEOUT_SECS", "1")]).await;
+
+    let mut silent = server.connect().await;
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    silent.expect("AuthError").await;
+    silent.expect("Disconnected").await;
+    let closed = tokio::time::timeout(TIMEOUT, async {
+        while let Some(Ok(frame)) = silent.ws.next().await {
+            if let Message::Close(_) = frame {
+                return;
+            }
+        }
+    })
+    .await;
+    assert!(closed.is_ok(), "the connection stayed open");
+
+    // Logging in in time keeps the connection
+    alice.send(json!({ "type": "GetConversations" })).await;
+    alice.expect("Conversations").await;
+}
+
+#[tokio::test]
+async fn oversized_frames_close_the_connection() {
+    let server = TestServer::start_with(&[("MAX_FILE_SIZE", "1024")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+
+    // Well past the file size limit plus the allowance for the surrounding JSON
+    let file_data = "A".repeat(256 * 1024);
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "", "file_data": file_data }))
+        .await;
+
+    // The protocol layer refuses the frame and the server closes the connection
+    let closed = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match alice.ws.next().await {
+                Some(Ok(Message::Close(_))) => return true,
+                Some(Ok(Message::Text(text))) => assert!(!text.contains("NewMessage"), "oversized message was accepted"),
+                Some(Ok(_)) => continue,
+                _ => return false,
+            }
+        }
+    })
+    .await
+    .expect("the connection stayed open");
+    assert!(closed, "the connection was dropped without a close frame");
+
+    assert_eq!(bob.wait_for_presence("UserOffline").await["user_id"], alice_id.as_str());
+}
+
+#[tokio::test]
+async fn closing_the_connection_is_acknowledged_and_marks_the_user_offline() {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+
+    alice
+        .ws
+        .send(Message::Close(Some(CloseFrame { code: CloseCode::Away, reason: "switching networks".into() })))
+        .await
+        .unwrap();
+
+    // The server answers with its own close frame rather than just dropping the socket
+    let reply = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match alice.ws.next().await {
+                Some(Ok(Message::Close(frame))) => return Some(frame),
+                Some(Ok(_)) => continue,
+                _ => return None,
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for the close frame");
+    assert!(reply.is_some(), "the connection was dropped without a close frame");
+
+    assert_eq!(bob.wait_for_presence("UserOffline").await["user_id"], alice_id.as_str());
+}
+
+#[tokio::test]
+async fn messages_pushed_on_login_carry_their_reactions() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    drop(bob);
+    alice.wait_for_presence("UserOffline").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "while you were out" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    alice
+        .send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👍" }))
+        .await;
+    alice.expect("MessageReaction").await;
+
+    let mut bob = server.connect().await;
+    bob.send(json!({ "type": "Login", "username": "bob", "password": "secret" }))
+        .await;
+    bob.expect("LoginSuccess").await;
+    bob.expect("OnlineUsers").await;
+    let pushed = bob.expect("NewMessage").await;
+    assert_eq!(pushed["message"]["id"], message_id);
+    assert_eq!(pushed["message"]["reactions"][&alice_id], "👍");
+    assert_eq!(pushed["message"]["reaction_summary"]["👍"], 1);
+}
+
+#[tokio::test]
+async fn resuming_replays_events_missed_while_disconnected() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "delete me" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    let received = bob.expect("NewMessage").await;
+    assert_eq!(received["message"]["id"], message_id);
+    let last_seq = received["seq"].as_u64().expect("pushed events are numbered");
+
+    drop(bob);
+    alice.wait_for_presence("UserOffline").await;
+
+    alice
+        .send(json!({ "type": "DeleteMyMessages", "other_user_id": bob_id }))
+        .await;
+    alice.expect("MessagesDeleted").await;
+
+    let mut bob = server.connect().await;
+    bob.send(json!({ "type": "Login", "username": "bob", "password": "secret" }))
+        .await;
+    let login = bob.expect("LoginSuccess").await;
+    assert_eq!(login["last_seq"], last_seq + 1);
+    bob.expect("OnlineUsers").await;
+
+    bob.send(json!({ "type": "Resume", "last_seq": last_seq })).await;
+    let missed = bob.expect("MessagesDeleted").await;
+    assert_eq!(missed["seq"], last_seq + 1);
+    assert_eq!(missed["message_ids"], json!([message_id]));
+    let resumed = bob.expect("Resumed").await;
+    assert_eq!(resumed["last_seq"], last_seq + 1);
+    assert_eq!(resumed["complete"], true);
+
+    // A cursor the server never handed out can't be resumed from
+    bob.send(json!({ "type": "Resume", "last_seq": last_seq + 100 })).await;
+    assert_eq!(bob.expect("Resumed").await["complete"], false);
+}
+
+#[tokio::test]
+async fn a_long_backlog_is_delivered_without_overflowing_the_queue() {
+    let server = TestServer::start_with(&[("OUTBOUND_QUEUE_CAPACITY", "4")]).await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    drop(bob);
+    alice.wait_for_presence("UserOffline").await;
+
+    for i in 0..20 {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": format!("message {}", i) }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    let mut bob = server.connect().await;
+    bob.send(json!({ "type": "Login", "username": "bob", "password": "secret" }))
+        .await;
+    bob.expect("LoginSuccess").await;
+    bob.expect("OnlineUsers").await;
+    for i in 0..20 {
+        let pending = bob.expect("NewMessage").await;
+        assert_eq!(pending["message"]["content"], format!("message {}", i));
+    }
+}
+
+#[tokio::test]
+async fn messages_sent_while_offline_are_not_replayed_after_login() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    drop(bob);
+    alice.wait_for_presence("UserOffline").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "while you were out" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+
+    let mut bob = server.connect().await;
+    bob.send(json!({ "type": "Login", "username": "bob", "password": "secret" }))
+        .await;
+    let last_seq = bob.expect("LoginSuccess").await["last_seq"].clone();
+    bob.expect("OnlineUsers").await;
+    let pending = bob.expect("NewMessage").await;
+    assert_eq!(pending["message"]["id"], message_id);
+
+    // Already delivered as pending, so the log has nothing to replay
+    bob.send(json!({ "type": "Resume", "last_seq": 0 })).await;
+    let resumed = bob.expect("Resumed").await;
+    assert_eq!(resumed["last_seq"], last_seq);
+    assert_eq!(resumed["complete"], true);
+}
+
+#[tokio::test]
+async fn mentions_are_resolved_to_user_ids() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    let content = "hey @Bob, ask @nobody or mail bob@example.com (cc @alice)";
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+        .await;
+
+    let received = bob.expect("NewMessage").await;
+    assert_eq!(received["mentioned"], true);
+    assert_eq!(received["message"]["content"], content);
+    let mut mentions: Vec<String> = serde_json::from_value(received["message"]["mentions"].clone()).unwrap();
+    mentions.sort();
+    let mut expected = vec![alice_id.clone(), bob_id.clone()];
+    expected.sort();
+    assert_eq!(mentions, expected);
+
+    // The sender's own copy isn't flagged for them
+    let echo = alice.expect("NewMessage").await;
+    assert!(echo.get("mentioned").is_none());
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    assert_eq!(history["messages"][0]["mentions"].as_array().unwrap().len(), 2);
+
+    // No mentions, no field
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "plain" }))
+        .await;
+    let received = bob.expect("NewMessage").await;
+    assert!(received.get("mentioned").is_none());
+    assert!(received["message"].get("mentions").is_none());
+}
+
+#[tokio::test]
+async fn expired_messages_are_hidden_before_they_are_swept() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "fleeting", "expires_in_seconds": 1 }))
+        .await;
+    alice.expect("NewMessage").await;
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "lasting" }))
+        .await;
+    alice.expect("NewMessage").await;
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    let contents: Vec<&str> = history["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, ["lasting"]);
+}
+
+#[tokio::test]
+async fn expired_messages_are_deleted_with_their_reactions() {
+    let path = std::env::temp_dir().join(format!("chat-backend-{}.db", uuid::Uuid::new_v4()));
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let server = TestServer::start_with(&[("DATABASE_URL", &url)]).await;
+    let pool = SqlitePool::connect(&url).await.unwrap();
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "fleeting", "expires_in_seconds": 1 }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "👍" }))
+        .await;
+    bob.expect("MessageReaction").await;
+    alice.expect("MessageReaction").await;
+
+    // The sweeper tells both sides once it has removed the message
+    assert_eq!(alice.expect("MessageDeleted").await["message_id"], message_id);
+    assert_eq!(bob.expect("MessageDeleted").await["message_id"], message_id);
+
+    for table in ["messages", "reactions"] {
+        let count: i64 = sqlx::query(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 0, "{} left behind", table);
+    }
+
+    pool.close().await;
+    drop(server);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn unread_counts_skip_hidden_and_expired_messages() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+
+    // Cleared by bob, so hidden for him
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "cleared" }))
+        .await;
+    alice.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+    bob.send(json!({ "type": "ClearConversation", "other_user_id": alice_id, "only_for_me": true }))
+        .await;
+    bob.expect("ConversationCleared").await;
+
+    for request in [
+        json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "fleeting", "expires_in_seconds": 1 }),
+        json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "first" }),
+        json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "second" }),
+    ] {
+        alice.send(request).await;
+        alice.expect("NewMessage").await;
+    }
+    let mut unread_ids = Vec::new();
+    for _ in 0..3 {
+        unread_ids.push(bob.expect("NewMessage").await["message"]["id"].clone());
+    }
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+
+    // Reading one message doesn't read the conversation
+    bob.send(json!({ "type": "MarkAsRead", "message_id": unread_ids[2] })).await;
+    alice.expect("MessageRead").await;
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    let conversations = bob.expect("Conversations").await;
+    assert_eq!(conversations["conversations"][0]["unread_count"], 2);
+    bob.send(json!({ "type": "GetTotalUnread" })).await;
+    assert_eq!(bob.expect("TotalUnread").await["count"], 2);
+
+    // The badge and the "first unread" pointer agree
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("MessageHistory").await["first_unread_message_id"], unread_ids[1]);
+}
+
+#[tokio::test]
+async fn history_points_at_the_first_unread_message() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let mut received = Vec::new();
+    for content in ["one", "two", "three"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+        received.push(bob.expect("NewMessage").await["message"].clone());
+        // Distinct timestamps, so the marker falls between messages
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    // Bob's own reply, unread by alice, isn't something for him to scroll to
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "four" }))
+        .await;
+    bob.expect("NewMessage").await;
+    alice.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "UpdateReadMarker", "other_user_id": alice_id, "last_read_ts": received[0]["timestamp"] }))
+        .await;
+    alice.expect("ReadMarker").await;
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("MessageHistory").await["first_unread_message_id"], received[1]["id"]);
+
+    bob.send(json!({ "type": "MarkConversationRead", "other_user_id": alice_id }))
+        .await;
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    assert!(history.get("first_unread_message_id").is_none(), "{}", history);
+}
+
+#[tokio::test]
+async fn conversation_list_shows_files_by_name_only() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    let file_data = "aGVsbG8g".repeat(1_000);
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "",
+            "file_data": file_data,
+            "file_name": "hello.txt",
+            "file_type": "text/plain",
+        }))
+        .await;
+    alice.expect("NewMessage").await;
+
+    alice.send(json!({ "type": "GetConversations" })).await;
+    let conversations = alice.expect("Conversations").await;
+    let last_message = &conversations["conversations"][0]["last_message"];
+    assert_eq!(last_message["content"], "📎 hello.txt");
+    assert!(last_message.get("file_data").is_none(), "{}", last_message);
+    assert!(!conversations.to_string().contains("aGVsbG8g"));
+}
+
+#[tokio::test]
+async fn archiving_is_per_user_and_reversible() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "hi" }))
+        .await;
+    alice.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "ArchiveConversation", "other_user_id": alice_id })).await;
+    let archived = bob.expect("ConversationArchived").await;
+    assert_eq!(archived["user_id"], alice_id.as_str());
+    assert_eq!(archived["archived"], true);
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(bob.expect("Conversations").await["conversations"][0]["archived"], true);
+    bob.send(json!({ "type": "GetArchivedConversations" })).await;
+    let listed = bob.expect("Conversations").await;
+    assert_eq!(listed["conversations"].as_array().unwrap().len(), 1);
+    assert_eq!(listed["conversations"][0]["user"]["id"], alice_id.as_str());
+
+    // Alice's side is untouched
+    alice.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(alice.expect("Conversations").await["conversations"][0]["archived"], false);
+    alice.send(json!({ "type": "GetArchivedConversations" })).await;
+    assert_eq!(alice.expect("Conversations").await["conversations"], json!([]));
+
+    bob.send(json!({ "type": "UnarchiveConversation", "other_user_id": alice_id })).await;
+    assert_eq!(bob.expect("ConversationArchived").await["archived"], false);
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(bob.expect("Conversations").await["conversations"][0]["archived"], false);
+    bob.send(json!({ "type": "GetArchivedConversations" })).await;
+    assert_eq!(bob.expect("Conversations").await["conversations"], json!([]));
+}
+
+#[tokio::test]
+async fn new_messages_unarchive_the_conversation_for_the_recipient() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "first" }))
+        .await;
+    alice.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+
+    // Both archive it; only the recipient's archive is lifted by a new message
+    for (client, other_user_id) in [(&mut alice, &bob_id), (&mut bob, &alice_id)] {
+        client.send(json!({ "type": "ArchiveConversation", "other_user_id": other_user_id })).await;
+        client.expect("ConversationArchived").await;
+    }
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "second" }))
+        .await;
+    alice.expect("NewMessage").await;
+
+    let unarchived = bob.expect("ConversationArchived").await;
+    assert_eq!(unarchived["user_id"], alice_id.as_str());
+    assert_eq!(unarchived["archived"], false);
+    assert_eq!(bob.expect("NewMessage").await["message"]["content"], "second");
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(bob.expect("Conversations").await["conversations"][0]["archived"], false);
+    alice.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(alice.expect("Conversations").await["conversations"][0]["archived"], true);
+}
+
+#[tokio::test]
+async fn total_unread_matches_the_conversation_counts() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut carol = server.connect().await;
+    let mut bob = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    carol.register("carol").await;
+    let bob_id = bob.register("bob").await;
+
+    for (sender, count) in [(&mut alice, 2), (&mut carol, 3)] {
+        for n in 0..count {
+            sender
+                .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": n.to_string() }))
+                .await;
+            sender.expect("NewMessage").await;
+        }
+    }
+    for _ in 0..5 {
+        bob.expect("NewMessage").await;
+    }
+    // Bob's own message doesn't count for him
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "reply" }))
+        .await;
+    bob.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "GetTotalUnread" })).await;
+    assert_eq!(bob.expect("TotalUnread").await["count"], 5);
+
+    bob.send(json!({ "type": "MarkConversationRead", "other_user_id": alice_id }))
+        .await;
+    bob.send(json!({ "type": "GetTotalUnread" })).await;
+    let total = bob.expect("TotalUnread").await["count"].as_i64().unwrap();
+    assert_eq!(total, 3);
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    let conversations = bob.expect("Conversations").await;
+    let sum: i64 = conversations["conversations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c["unread_count"].as_i64().unwrap())
+        .sum();
+    assert_eq!(total, sum);
+}
+
+/// The steps of SQLite's plan for `sql`, e.g. "SEARCH messages USING INDEX ..."
+async fn query_plan(pool: &SqlitePool, sql: &str) -> Vec<String> {
+    sqlx::query(&format!("EXPLAIN QUERY PLAN {}", sql))
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get::<String, _>("detail"))
+        .collect()
+}
+
+#[tokio::test]
+async fn unread_and_history_queries_use_indexes() {
+    let path = std::env::temp_dir().join(format!("chat-backend-{}.db", uuid::Uuid::new_v4()));
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    // The server creates the schema before it starts listening
+    let server = TestServer::start_with(&[("DATABASE_URL", &url)]).await;
+    let pool = SqlitePool::connect(&url).await.unwrap();
+
+    let unread = query_plan(&pool, "SELECT COUNT(*) FROM messages WHERE to_user_id = 'a' AND read = 0").await;
+    assert!(unread.iter().any(|step| step.contains("idx_messages_to_read")), "{:?}", unread);
+
+    let conversation_unread = query_plan(
+        &pool,
+        "SELECT COUNT(*) FROM messages WHERE to_user_id = 'a' AND from_user_id = 'b' AND timestamp > 0",
+    )
+    .await;
+    assert!(
+        conversation_unread.iter().any(|step| step.contains("idx_messages_conversation")),
+        "{:?}",
+        conversation_unread
+    );
+
+    // The filters of `get_messages_between_users`: both directions are index lookups
+    let history = query_plan(
+        &pool,
+        "SELECT id FROM messages \
+         WHERE ((from_user_id = 'a' AND to_user_id = 'b') OR (from_user_id = 'b' AND to_user_id = 'a')) \
+           AND NOT ((from_user_id = 'a' AND hidden_for_sender = 1) OR (to_user_id = 'a' AND hidden_for_recipient = 1)) \
+           AND (expires_at IS NULL OR expires_at > '2026') \
+         ORDER BY timestamp DESC, id DESC LIMIT 50",
+    )
+    .await;
+    assert!(history.iter().all(|step| !step.starts_with("SCAN messages")), "{:?}", history);
+    let lookups = history.iter().filter(|step| step.contains("idx_messages_conversation")).count();
+    assert_eq!(lookups, 2, "{:?}", history);
+
+    // `get_messages_after` pages through a conversation by `seq`
+    let catch_up = query_plan(
+        &pool,
+        "SELECT id FROM messages \
+         WHERE ((from_user_id = 'a' AND to_user_id = 'b') OR (from_user_id = 'b' AND to_user_id = 'a')) \
+           AND seq > 10 \
+         ORDER BY seq ASC LIMIT 50",
+    )
+    .await;
+    let lookups = catch_up.iter().filter(|step| step.contains("idx_messages_conversation_seq")).count();
+    assert_eq!(lookups, 2, "{:?}", catch_up);
+
+    pool.close().await;
+    drop(server);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn reads_send_no_receipts_when_disabled() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    bob.send(json!({ "type": "SetReadReceiptPrivacy", "enabled": false }))
+        .await;
+    assert_eq!(bob.expect("ReadReceiptPrivacy").await["enabled"], false);
+
+    let mut ids = Vec::new();
+    for content in ["one", "two"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+        ids.push(bob.expect("NewMessage").await["message"]["id"].clone());
+    }
+    bob.send(json!({ "type": "MarkAsRead", "message_id": ids[0] })).await;
+    bob.send(json!({ "type": "MarkConversationRead", "other_user_id": alice_id }))
+        .await;
+
+    // The reads still count for bob
+    bob.send(json!({ "type": "GetTotalUnread" })).await;
+    assert_eq!(bob.expect("TotalUnread").await["count"], 0);
+
+    // A receipt would have reached alice ahead of this reply
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "seen nothing" }))
+        .await;
+    bob.expect("NewMessage").await;
+    assert_eq!(alice.expect("NewMessage").await["message"]["content"], "seen nothing");
+}
+
+#[tokio::test]
+async fn only_the_recipient_can_mark_a_message_read() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut mallory = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let bob_id = bob.register("bob").await;
+    mallory.register("mallory").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "for bob only" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    // Neither a bystander nor the sender can mark it read for bob
+    mallory.send(json!({ "type": "MarkAsRead", "message_id": message_id })).await;
+    mallory.expect("Error").await;
+    alice.send(json!({ "type": "MarkAsRead", "message_id": message_id })).await;
+    alice.expect("Error").await;
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("MessageHistory").await["messages"][0]["read"], false);
+
+    bob.send(json!({ "type": "MarkAsRead", "message_id": message_id })).await;
+    let receipt = alice.expect("MessageRead").await;
+    assert_eq!(receipt["message_id"], message_id);
+    assert_eq!(receipt["user_id"], bob_id.as_str());
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("MessageHistory").await["messages"][0]["read"], true);
+}
+
+#[tokio::test]
+async fn marking_a_conversation_read_clears_it_with_one_receipt() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for content in ["one", "two", "three"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+        bob.expect("NewMessage").await;
+    }
+
+    bob.send(json!({ "type": "MarkConversationRead", "other_user_id": alice_id }))
+        .await;
+    bob.send(json!({ "type": "GetConversations" })).await;
+    let conversations = bob.expect("Conversations").await;
+    assert_eq!(conversations["conversations"][0]["unread_count"], 0);
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    assert!(history["messages"].as_array().unwrap().iter().all(|m| m["read"] == true));
+
+    // One receipt for the batch, then nothing until alice's next message
+    assert_eq!(alice.expect("ConversationRead").await["user_id"], bob_id.as_str());
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "read them" }))
+        .await;
+    alice.expect("NewMessage").await;
+}
+
+#[tokio::test]
+async fn presence_changes_reach_every_connected_user() {
+    let server = TestServer::start().await;
+
+    let mut watchers = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        let mut client = server.connect().await;
+        client.register(name).await;
+        watchers.push(client);
+    }
+
+    let mut dave = server.connect().await;
+    let dave_id = dave.register("dave").await;
+
+    for watcher in &mut watchers {
+        loop {
+            let online = watcher.wait_for_presence("UserOnline").await;
+            if online["user"]["id"] == dave_id.as_str() {
+                break;
+            }
+        }
+    }
+    drop(dave);
+    for watcher in &mut watchers {
+        assert_eq!(watcher.wait_for_presence("UserOffline").await["user_id"], dave_id.as_str());
+    }
+}
+
+#[tokio::test]
+async fn presence_changes_are_batched_within_the_window() {
+    let server = TestServer::start_with(&[("PRESENCE_BATCH_MS", "2000")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    let carol_id = carol.register("carol").await;
+    let mut dave = server.connect().await;
+    let dave_id = dave.register("dave").await;
+    drop(dave);
+
+    // Nothing goes out one by one; the first presence frame is the whole batch
+    let update = loop {
+        let frame = tokio::time::timeout(TIMEOUT, alice.ws.next())
+            .await
+            .expect("timed out waiting for a presence update")
+            .expect("connection closed")
+            .unwrap();
+        let Message::Text(text) = frame else { continue };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        assert!(
+            message["type"] != "UserOnline" && message["type"] != "UserOffline",
+            "unbatched presence event: {}",
+            message
+        );
+        if message["type"] == "PresenceUpdate" {
+            break message;
+        }
+    };
+
+    let mut online: Vec<&str> = update["online"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["id"].as_str().unwrap())
+        .collect();
+    online.sort();
+    let mut expected = vec![bob_id.as_str(), carol_id.as_str()];
+    expected.sort();
+    assert_eq!(online, expected);
+    // Dave came and went within the window, so only his latest state is sent
+    assert_eq!(update["offline"], json!([dave_id]));
+}
+
+#[tokio::test]
+async fn history_page_sizes_are_bounded() {
+    let server = TestServer::start_with(&[("MAX_PAGE_SIZE", "2")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for content in ["one", "two", "three"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    // Huge limits are clamped on both transports
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id, "limit": 1_000_000 }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    assert_eq!(history["messages"].as_array().unwrap().len(), 2);
+    assert_eq!(history["has_more"], true);
+    let page = server
+        .get_json(&format!("/api/messages/{}/{}?limit=1000000", alice_id, bob_id))
+        .await;
+    assert_eq!(page["messages"].as_array().unwrap().len(), 2);
+
+    // Negative values are refused
+    for (limit, offset) in [(-1, 0), (10, -5)] {
+        alice
+            .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id, "limit": limit, "offset": offset }))
+            .await;
+        alice.expect("Error").await;
+    }
+}
+
+#[tokio::test]
+async fn rest_history_matches_websocket_ordering() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for content in ["one", "two", "three"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id, "limit": 2 }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    let page = server
+        .get_json(&format!("/api/messages/{}/{}?limit=2", alice_id, bob_id))
+        .await;
+
+    let contents = |messages: &Value| -> Vec<String> {
+        messages
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"].as_str().unwrap().to_string())
+            .collect()
+    };
+    // The newest two, oldest first, over both transports
+    assert_eq!(contents(&history["messages"]), ["two", "three"]);
+    assert_eq!(contents(&page["messages"]), contents(&history["messages"]));
+    assert_eq!(page["total_count"], history["total_count"]);
+    assert_eq!(page["has_more"], history["has_more"]);
+    assert_eq!(page["has_more"], true);
+}
+
+#[tokio::test]
+async fn rest_history_pages_carry_their_metadata() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for content in ["one", "two", "three", "four", "five"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    let mut pages = Vec::new();
+    for offset in [0, 2, 4] {
+        let page = server
+            .get_json(&format!("/api/messages/{}/{}?limit=2&offset={}", alice_id, bob_id, offset))
+            .await;
+        assert_eq!(page["total_count"], 5);
+        let contents: Vec<String> = page["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"].as_str().unwrap().to_string())
+            .collect();
+        pages.push((contents, page["has_more"].clone(), page["next_offset"].clone()));
+    }
+    assert_eq!(
+        pages,
+        [
+            (vec!["four".to_string(), "five".to_string()], json!(true), json!(2)),
+            (vec!["two".to_string(), "three".to_string()], json!(true), json!(4)),
+            (vec!["one".to_string()], json!(false), json!(5)),
+        ]
+    );
+}
+
+/// The data of the next server-sent event, skipping headers, event names and
+/// keep-alives; `None` once the stream has ended
+async fn next_event_data(events: &mut Lines<BufReader<TcpStream>>) -> Option<String> {
+    loop {
+        let line = tokio::time::timeout(TIMEOUT, events.next_line())
+            .await
+            .expect("timed out waiting for an event")
+            .unwrap()?;
+        if let Some(data) = line.strip_prefix("data: ") {
+            return Some(data.to_string());
+        }
+    }
+}
+
+#[tokio::test]
+async fn event_streams_receive_pushed_messages() {
+    let server = TestServer::start().await;
+
+    let (connection_id, mut events) = server.open_event_stream().await;
+
+    // Messages go up as POSTs tied to the stream
+    let register = json!({ "type": "Register", "username": "bob", "password": "secret" });
+    let response = server.post(&format!("/api/events/{}", connection_id), &register.to_string()).await;
+    assert!(response.starts_with("HTTP/1.0 202"), "unexpected response: {}", response);
+
+    let registered: Value = serde_json::from_str(&next_event_data(&mut events).await.unwrap()).unwrap();
+    assert_eq!(registered["type"], "RegisterSuccess");
+    let bob_id = registered["user"]["id"].as_str().unwrap().to_string();
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "over SSE" }))
+        .await;
+    alice.expect("NewMessage").await;
+
+    let pushed = loop {
+        let event: Value = serde_json::from_str(&next_event_data(&mut events).await.unwrap()).unwrap();
+        if event["type"] == "NewMessage" {
+            break event;
+        }
+    };
+    assert_eq!(pushed["message"]["content"], "over SSE");
+}
+
+#[tokio::test]
+async fn silent_event_streams_are_closed_after_the_auth_timeout() {
+    let server = TestServer::start_with(&[("AUTH_TIMEOUT_SECS", "1")]).await;
+
+    let (connection_id, mut events) = server.open_event_stream().await;
+
+    let timed_out: Value = serde_json::from_str(&next_event_data(&mut events).await.unwrap()).unwrap();
+    assert_eq!(timed_out["type"], "AuthError");
+    let disconnected: Value = serde_json::from_str(&next_event_data(&mut events).await.unwrap()).unwrap();
+    assert_eq!(disconnected["type"], "Disconnected");
+    assert_eq!(next_event_data(&mut events).await, None, "the stream stayed open");
+
+    // The connection id is gone, so it's too late to log in
+    let register = json!({ "type": "Register", "username": "bob", "password": "secret" });
+    let response = server.post(&format!("/api/events/{}", connection_id), &register.to_string()).await;
+    assert!(response.starts_with("HTTP/1.0 404"), "unexpected response: {}", response);
+}
+
+#[tokio::test]
+async fn unanswered_calls_time_out_as_missed() {
+    let server = TestServer::start_with(&[("CALL_RING_TIMEOUT_SECS", "1"), ("ADMIN_TOKEN", "operator-secret")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "CallOffer", "to_user_id": bob_id, "offer": "sdp", "call_type": "audio" }))
+        .await;
+    let call_id = alice.expect("CallStarted").await["call_id"].clone();
+    let offer = bob.expect("CallOffer").await;
+    assert_eq!(offer["call_id"], call_id);
+    assert_eq!(offer["call_type"], "audio");
+
+    let timeout = alice.expect("CallTimeout").await;
+    assert_eq!((&timeout["call_id"], &timeout["user_id"]), (&call_id, &json!(bob_id)));
+    let timeout = bob.expect("CallTimeout").await;
+    assert_eq!((&timeout["call_id"], &timeout["user_id"]), (&call_id, &json!(alice_id)));
+
+    let (_, body) = server
+        .get("/api/admin/calls", &[("Authorization", "Bearer operator-secret")])
+        .await;
+    let calls: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(calls[0]["id"], call_id);
+    assert_eq!(calls[0]["status"], "missed");
+}
+
+#[tokio::test]
+async fn declined_calls_are_recorded_apart_from_ended_ones() {
+    let server = TestServer::start_with(&[("ADMIN_TOKEN", "operator-secret")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    // Declined while ringing
+    alice
+        .send(json!({ "type": "CallOffer", "to_user_id": bob_id, "offer": "sdp" }))
+        .await;
+    let declined_id = alice.expect("CallStarted").await["call_id"].clone();
+    // Offers without a type come from clients that only made video calls
+    assert_eq!(bob.expect("CallOffer").await["call_type"], "video");
+    bob.send(json!({ "type": "CallReject", "to_user_id": alice_id, "call_id": declined_id }))
+        .await;
+    let rejected = alice.expect("CallRejected").await;
+    assert_eq!(rejected["from_user_id"], bob_id.as_str());
+
+    // Unknown call types are refused rather than relayed
+    alice
+        .send(json!({ "type": "CallOffer", "to_user_id": bob_id, "offer": "sdp", "call_type": "hologram" }))
+        .await;
+    alice.expect("Error").await;
+
+    // Answered, then hung up
+    alice
+        .send(json!({ "type": "CallOffer", "to_user_id": bob_id, "offer": "sdp", "call_type": "screen" }))
+        .await;
+    let ended_id = alice.expect("CallStarted").await["call_id"].clone();
+    assert_eq!(bob.expect("CallOffer").await["call_type"], "screen");
+    bob.send(json!({ "type": "CallAnswer", "to_user_id": alice_id, "answer": "sdp", "call_id": ended_id, "call_type": "screen" }))
+        .await;
+    assert_eq!(alice.expect("CallAnswer").await["call_type"], "screen");
+    bob.send(json!({ "type": "CallEnd", "to_user_id": alice_id, "call_id": ended_id }))
+        .await;
+    alice.expect("CallEnd").await;
+
+    let (_, body) = server
+        .get("/api/admin/calls", &[("Authorization", "Bearer operator-secret")])
+        .await;
+    let calls: Value = serde_json::from_slice(&body).unwrap();
+    let call = |call_id: &Value| calls.as_array().unwrap().iter().find(|call| &call["id"] == call_id).unwrap().clone();
+    assert_eq!(call(&declined_id)["status"], "rejected");
+    assert_eq!(call(&ended_id)["status"], "ended");
+    assert_eq!(call(&declined_id)["call_type"], "video");
+    assert_eq!(call(&ended_id)["call_type"], "screen");
+}
+
+#[tokio::test]
+async fn call_stats_are_averaged_for_admins() {
+    let server = TestServer::start_with(&[("ADMIN_TOKEN", "operator-secret")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    carol.register("carol").await;
+
+    alice
+        .send(json!({ "type": "CallOffer", "to_user_id": bob_id, "offer": "sdp" }))
+        .await;
+    let call_id = alice.expect("CallStarted").await["call_id"].clone();
+    bob.expect("CallOffer").await;
+    bob.send(json!({ "type": "CallAnswer", "to_user_id": alice_id, "answer": "sdp", "call_id": call_id }))
+        .await;
+    alice.expect("CallAnswer").await;
+
+    let good = json!({ "bitrate_kbps": 800.0, "packet_loss_percent": 1.0, "round_trip_time_ms": 100.0, "jitter_ms": 10.0 });
+    let bad = json!({ "bitrate_kbps": 0.0, "packet_loss_percent": 100.0, "round_trip_time_ms": 5000.0, "jitter_ms": 500.0 });
+    // An hour's worth of samples from alice, then one past the per-user cap
+    for _ in 0..720 {
+        alice.send(json!({ "type": "CallStats", "call_id": call_id, "stats": good })).await;
+    }
+    alice.send(json!({ "type": "CallStats", "call_id": call_id, "stats": bad })).await;
+    // Bob has a cap of his own; carol isn't in the call
+    bob.send(json!({ "type": "CallStats", "call_id": call_id, "stats": good })).await;
+    carol.send(json!({ "type": "CallStats", "call_id": call_id, "stats": bad })).await;
+
+    // Stats get no reply; these ones come back after every sample was handled
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.send(json!({ "type": "GetTotalUnread" })).await;
+        client.expect("TotalUnread").await;
+    }
+
+    bob.send(json!({ "type": "CallEnd", "to_user_id": alice_id, "call_id": call_id }))
+        .await;
+    alice.expect("CallEnd").await;
+
+    let (head, body) = server
+        .get("/api/admin/calls", &[("Authorization", "Bearer operator-secret")])
+        .await;
+    assert!(head.contains(" 200 "), "{}", head);
+    let calls: Value = serde_json::from_slice(&body).unwrap();
+    let call = calls.as_array().unwrap().iter().find(|call| call["id"] == call_id).unwrap();
+    assert_eq!(call["stats_count"], 721);
+    assert_eq!(call["avg_bitrate_kbps"], 800.0);
+    assert_eq!(call["avg_packet_loss_percent"], 1.0);
+    assert_eq!(call["avg_round_trip_time_ms"], 100.0);
+    assert_eq!(call["avg_jitter_ms"], 10.0);
+    // A quarter point off for the packet loss; the delay is within bounds
+    assert_eq!(call["quality_score"], 4.75);
+}
+
+#[tokio::test]
+async fn group_calls_relay_signals_among_three_participants() {
+    /// Sorted ids from a JSON array
+    fn ids(value: &Value) -> Vec<String> {
+        let mut ids: Vec<String> = value.as_array().unwrap().iter().map(|id| id.as_str().unwrap().to_string()).collect();
+        ids.sort();
+        ids
+    }
+    fn sorted(ids: &[&String]) -> Vec<String> {
+        let mut ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        ids.sort();
+        ids
+    }
+
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    let carol_id = carol.register("carol").await;
+    let mut dave = server.connect().await;
+    dave.register("dave").await;
+
+    alice
+        .send(json!({ "type": "StartGroupCall", "user_ids": [bob_id, carol_id] }))
+        .await;
+    let started = alice.expect("GroupCallStarted").await;
+    let call_id = started["call_id"].clone();
+    assert_eq!(ids(&started["invited"]), sorted(&[&bob_id, &carol_id]));
+    for invitee in [&mut bob, &mut carol] {
+        let invite = invitee.expect("GroupCallInvite").await;
+        assert_eq!(invite["call_id"], call_id);
+        assert_eq!(invite["from_user_id"], alice_id.as_str());
+    }
+
+    // Joiners learn who is already there; those already there learn about the joiner
+    bob.send(json!({ "type": "JoinCall", "call_id": call_id })).await;
+    assert_eq!(ids(&bob.expect("CallParticipants").await["user_ids"]), sorted(&[&alice_id]));
+    assert_eq!(alice.expect("CallParticipantJoined").await["user_id"], bob_id.as_str());
+
+    carol.send(json!({ "type": "JoinCall", "call_id": call_id })).await;
+    assert_eq!(ids(&carol.expect("CallParticipants").await["user_ids"]), sorted(&[&alice_id, &bob_id]));
+    for participant in [&mut alice, &mut bob] {
+        assert_eq!(participant.expect("CallParticipantJoined").await["user_id"], carol_id.as_str());
+    }
+
+    // The joiner offers to everyone in the call; each pair negotiates on its own
+    for (to, to_id) in [(&mut alice, &alice_id), (&mut bob, &bob_id)] {
+        carol
+            .send(json!({ "type": "GroupCallOffer", "call_id": call_id, "to_user_id": to_id, "offer": format!("offer for {}", to_id) }))
+            .await;
+        let offer = to.expect("GroupCallOffer").await;
+        assert_eq!(offer["from_user_id"], carol_id.as_str());
+        assert_eq!(offer["to_user_id"], to_id.as_str());
+        assert_eq!(offer["offer"], format!("offer for {}", to_id));
+    }
+    alice
+        .send(json!({ "type": "GroupCallAnswer", "call_id": call_id, "to_user_id": carol_id, "answer": "answer" }))
+        .await;
+    let answer = carol.expect("GroupCallAnswer").await;
+    assert_eq!((answer["from_user_id"].as_str(), answer["answer"].as_str()), (Some(alice_id.as_str()), Some("answer")));
+    bob.send(json!({ "type": "GroupIceCandidate", "call_id": call_id, "to_user_id": carol_id, "candidate": "ice" }))
+        .await;
+    let candidate = carol.expect("GroupIceCandidate").await;
+    assert_eq!((candidate["from_user_id"].as_str(), candidate["candidate"].as_str()), (Some(bob_id.as_str()), Some("ice")));
+
+    // Outsiders can't signal into the call, nor can anyone after leaving it
+    dave.send(json!({ "type": "GroupCallOffer", "call_id": call_id, "to_user_id": alice_id, "offer": "intrusion" }))
+        .await;
+    dave.send(json!({ "type": "JoinCall", "call_id": call_id })).await;
+    assert_eq!(dave.expect("Error").await["message"], "Not invited to this call");
+
+    bob.send(json!({ "type": "LeaveCall", "call_id": call_id })).await;
+    for participant in [&mut alice, &mut carol] {
+        assert_eq!(participant.expect("CallParticipantLeft").await["user_id"], bob_id.as_str());
+    }
+    bob.send(json!({ "type": "GroupCallOffer", "call_id": call_id, "to_user_id": alice_id, "offer": "late" }))
+        .await;
+    carol
+        .send(json!({ "type": "GroupCallOffer", "call_id": call_id, "to_user_id": alice_id, "offer": "still here" }))
+        .await;
+    // Neither dropped offer was queued ahead of this one
+    assert_eq!(alice.expect("GroupCallOffer").await["offer"], "still here");
+}
+
+#[tokio::test]
+async fn reported_messages_are_listed_for_admins() {
+    let server = TestServer::start_with(&[("ADMIN_TOKEN", "operator-secret")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "something abusive" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    // Only someone in the conversation can report it, and only once
+    let mut carol = server.connect().await;
+    carol.register("carol").await;
+    carol
+        .send(json!({ "type": "ReportMessage", "message_id": message_id, "reason": "spam" }))
+        .await;
+    assert_eq!(carol.expect("Error").await["message"], "Message not found");
+    bob.send(json!({ "type": "ReportMessage", "message_id": message_id, "reason": "harassment" }))
+        .await;
+    assert_eq!(bob.expect("MessageReported").await["message_id"], message_id);
+    bob.send(json!({ "type": "ReportMessage", "message_id": message_id, "reason": "harassment" }))
+        .await;
+    assert_eq!(bob.expect("Error").await["message"], "You already reported this message");
+
+    let (_, body) = server
+        .get("/api/admin/reports", &[("Authorization", "Bearer operator-secret")])
+        .await;
+    let reports: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(reports.as_array().unwrap().len(), 1);
+    let report = &reports[0];
+    assert_eq!(report["reporter_id"], bob_id.as_str());
+    assert_eq!(report["message_id"], message_id);
+    assert_eq!(report["reason"], "harassment");
+    assert_eq!(report["message"]["from_user_id"], alice_id.as_str());
+    assert_eq!(report["message"]["content"], "something abusive");
+}
+
+#[tokio::test]
+async fn admin_routes_require_the_admin_token() {
+    let server = TestServer::start_with(&[("ADMIN_TOKEN", "operator-secret")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+
+    for headers in [vec![], vec![("Authorization", "Bearer wrong-secret")], vec![("Authorization", "operator-secret")]] {
+        let (head, _) = server.get("/api/admin/users", &headers).await;
+        assert!(head.contains(" 401 "), "{:?} gave {}", headers, head);
+    }
+
+    let (head, body) = server
+        .get("/api/admin/users", &[("Authorization", "Bearer operator-secret")])
+        .await;
+    assert!(head.contains(" 200 "), "{}", head);
+    let users: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(users[0]["id"], alice_id);
+}
+
+#[tokio::test]
+async fn debug_state_shows_presence_but_no_content() {
+    let server = TestServer::start_with(&[("ADMIN_TOKEN", "operator-secret")]).await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    // Connected, but not logged in
+    let _anonymous = server.connect().await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "the launch code is 0000" }))
+        .await;
+    alice.expect("NewMessage").await;
+    bob.expect("NewMessage").await;
+
+    for headers in [vec![], vec![("Authorization", "Bearer wrong-secret")]] {
+        let (head, _) = server.get("/api/debug/state", &headers).await;
+        assert!(head.contains(" 401 "), "{:?} gave {}", headers, head);
+    }
+
+    let (head, body) = server
+        .get("/api/debug/state", &[("Authorization", "Bearer operator-secret")])
+        .await;
+    assert!(head.contains(" 200 "), "{}", head);
+    let snapshot: Value = serde_json::from_slice(&body).unwrap();
+    let mut online: Vec<&str> = snapshot["online_users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|u| u["id"].as_str().unwrap())
+        .collect();
+    online.sort();
+    let mut expected = [alice_id.as_str(), bob_id.as_str()];
+    expected.sort();
+    assert_eq!(online, expected);
+    assert_eq!(snapshot["socket_count"], 2);
+    assert_eq!(snapshot["connection_count"], 3);
+    assert_eq!(snapshot["sockets_without_presence"], json!([]));
+
+    // Presence metadata only: no message content and no credentials
+    let text = String::from_utf8(body).unwrap();
+    assert!(!text.contains("launch code"), "{}", text);
+    assert!(!text.contains("password") && !text.contains("$2"), "{}", text);
+}
+
+#[tokio::test]
+async fn the_user_list_api_does_not_reveal_presence() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    let (_, body) = server.get("/api/users", &[]).await;
+    let users: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(users[0]["username"], "alice");
+    assert_eq!(users[0]["online"], false);
+    assert_eq!(users[0]["status"], "offline");
+}
+
+#[tokio::test]
+async fn only_allowed_origins_get_cors_headers() {
+    let server = TestServer::start_with(&[("CORS_ALLOWED_ORIGINS", "https://chat.example")]).await;
+
+    let (allowed, _) = server.get("/api/users", &[("Origin", "https://chat.example")]).await;
+    assert!(
+        allowed.to_lowercase().contains("access-control-allow-origin: https://chat.example"),
+        "{}",
+        allowed
+    );
+
+    // The browser refuses to hand the response to a page from anywhere else
+    let (refused, _) = server.get("/api/users", &[("Origin", "https://evil.example")]).await;
+    assert!(!refused.to_lowercase().contains("access-control-allow-origin"), "{}", refused);
+}
+
+#[tokio::test]
+async fn rest_responses_are_gzipped_when_the_client_accepts_it() {
+    use std::io::Read;
+
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for i in 0..20 {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": format!("message {} {}", i, "lorem ipsum ".repeat(50)) }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    let path = format!("/api/messages/{}/{}", alice_id, bob_id);
+    let (plain_head, plain) = server.get(&path, &[]).await;
+    assert!(!plain_head.to_lowercase().contains("content-encoding"), "{}", plain_head);
+    let (head, gzipped) = server.get(&path, &[("Accept-Encoding", "gzip")]).await;
+    assert!(head.to_lowercase().contains("content-encoding: gzip"), "{}", head);
+
+    assert!(gzipped.len() * 4 < plain.len(), "{} -> {} bytes", plain.len(), gzipped.len());
+    let mut body = Vec::new();
+    flate2::read::GzDecoder::new(&gzipped[..]).read_to_end(&mut body).unwrap();
+    let page: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(page["messages"].as_array().unwrap().len(), 20);
+}
+
+#[tokio::test]
+async fn rest_history_leaves_out_attachment_contents() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "to_user_id": bob_id,
+            "content": "private",
+            "file_data": "c2VjcmV0IGZpbGU=",
+            "file_name": "secret.txt",
+            "file_type": "text/plain",
+        }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+
+    // No login on the REST API, so the file itself never leaves through it
+    let page = server.get_json(&format!("/api/messages/{}/{}", alice_id, bob_id)).await;
+    let message = &page["messages"][0];
+    assert_eq!(message["id"], message_id);
+    assert!(message.get("file_data").is_none(), "{}", message);
+    assert_eq!(message["file_data_omitted"], true);
+    assert_eq!(message["file_name"], "secret.txt");
+    assert!(!page.to_string().contains("c2VjcmV0IGZpbGU="));
+
+    alice.send(json!({ "type": "GetMessage", "message_id": message_id })).await;
+    assert_eq!(alice.expect("Message").await["message"]["file_data"], "c2VjcmV0IGZpbGU=");
+}
+
+#[tokio::test]
+async fn large_frames_are_compressed_for_clients_that_opt_in() {
+    use std::io::Read;
+
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect_to("/ws?compress=deflate").await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+
+    let content = "all work and no play makes jack a dull boy. ".repeat(1_000);
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": content }))
+        .await;
+    // The sender didn't opt in, so its copy stays plain text
+    assert_eq!(bob.expect("NewMessage").await["message"]["content"], content.as_str());
+
+    let compressed = loop {
+        let frame = tokio::time::timeout(TIMEOUT, alice.ws.next())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("connection closed")
+            .unwrap();
+        if let Message::Binary(bytes) = frame {
+            break bytes;
+        }
+    };
+    let mut json = String::new();
+    flate2::read::ZlibDecoder::new(&compressed[..]).read_to_string(&mut json).unwrap();
+    let message: Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(message["type"], "NewMessage");
+    assert_eq!(message["message"]["content"], content.as_str());
+    assert!(compressed.len() * 10 < json.len(), "{} -> {} bytes", json.len(), compressed.len());
+}
+
+#[tokio::test]
+async fn frames_left_behind_by_a_vanished_client_are_not_processed() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    // Queue a burst of messages, then vanish without reading any replies
+    const BURST: usize = 200;
+    for i in 0..BURST {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": format!("message {}", i) }))
+            .await;
+    }
+    drop(alice);
+
+    // Once replies can't be delivered, the rest of the burst is dropped
+    let mut delivered = 0;
+    while let Ok(Some(Ok(frame))) = tokio::time::timeout(Duration::from_secs(2), bob.ws.next()).await {
+        let Message::Text(text) = frame else { continue };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        if message["type"] == "NewMessage" {
+            delivered += 1;
+        }
+    }
+    assert!(delivered < BURST, "every message was still processed");
+
+    // The server carries on as usual
+    bob.send(json!({ "type": "GetConversations" })).await;
+    bob.expect("Conversations").await;
+}
+
+#[tokio::test]
+async fn history_pages_stay_within_the_size_limit() {
+    const MAX_BYTES: usize = 250_000;
+    let server = TestServer::start_with(&[("HISTORY_MAX_BYTES", "250000")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    // About 100 KB each, so only two fit whole
+    let file_data = "aGVsbG8g".repeat(12_500);
+    let mut ids = Vec::new();
+    for i in 0..6 {
+        alice
+            .send(json!({
+                "type": "SendMessage",
+                "to_user_id": bob_id,
+                "content": format!("file {}", i),
+                "file_data": file_data,
+                "file_name": "hello.txt",
+                "file_type": "text/plain",
+            }))
+            .await;
+        ids.push(alice.expect("NewMessage").await["message"]["id"].clone());
+    }
+
+    alice
+        .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id }))
+        .await;
+    let history = alice.expect("MessageHistory").await;
+    assert!(history.to_string().len() < MAX_BYTES, "history frame over the limit");
+
+    let messages = history["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 6);
+    assert_eq!(history["has_more"], false);
+    // The newest keep their files; older ones only say the file was left out
+    for message in &messages[4..] {
+        assert_eq!(message["file_data"], file_data.as_str());
+        assert!(message.get("file_data_omitted").is_none());
+    }
+    for message in &messages[..4] {
+        assert!(message.get("file_data").is_none());
+        assert_eq!(message["file_data_omitted"], true);
+        assert_eq!(message["file_name"], "hello.txt");
+    }
+
+    // The full message is still a request away
+    alice.send(json!({ "type": "GetMessage", "message_id": ids[0] })).await;
+    let full = alice.expect("Message").await;
+    assert_eq!(full["message"]["file_data"], file_data.as_str());
+}
+
+#[tokio::test]
+async fn history_pages_cut_short_by_the_size_limit_continue_where_they_ended() {
+    let server = TestServer::start_with(&[("HISTORY_MAX_BYTES", "2000")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    // About 600 bytes each, so only a few fit in a page
+    let sent: Vec<String> = (0..5).map(|i| format!("{}{}", i, "x".repeat(600))).collect();
+    for content in &sent {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    // Paging by `next_offset` walks back through every message exactly once
+    let mut received = Vec::new();
+    let mut offset = 0;
+    let mut pages = 0;
+    loop {
+        alice
+            .send(json!({ "type": "GetMessageHistory", "other_user_id": bob_id, "limit": 10, "offset": offset }))
+            .await;
+        let history = alice.expect("MessageHistory").await;
+        pages += 1;
+        let contents = history["messages"].as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap().to_string());
+        received.splice(0..0, contents);
+        offset = history["next_offset"].as_i64().unwrap();
+        if history["has_more"] == false {
+            break;
+        }
+    }
+    assert!(pages > 1, "the size limit should have ended the first page early");
+    assert_eq!(received, sent);
+}
+
+#[tokio::test]
+async fn status_changes_reach_peers() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    bob.send(json!({ "type": "SetStatus", "status": "busy" })).await;
+    let confirmed = bob.wait_for_presence("UserStatusChanged").await;
+    assert_eq!(confirmed["status"], "busy");
+
+    let changed = alice.wait_for_presence("UserStatusChanged").await;
+    assert_eq!(changed["user_id"], bob_id);
+    assert_eq!(changed["status"], "busy");
+
+    // Presence lists carry it too
+    alice.send(json!({ "type": "GetOnlineUsers" })).await;
+    let online = alice.expect("OnlineUsers").await;
+    let bob_entry = online["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|user| user["id"] == bob_id)
+        .expect("bob is online");
+    assert_eq!(bob_entry["status"], "busy");
+    assert_eq!(bob_entry["online"], true);
+
+    // Offline only comes from disconnecting
+    bob.send(json!({ "type": "SetStatus", "status": "offline" })).await;
+    bob.expect("Error").await;
+}
+
+#[tokio::test]
+async fn users_are_looked_up_by_id_with_their_presence() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    let carol_id = carol.register("carol").await;
+    drop(carol);
+    alice.wait_for_presence("UserOffline").await;
+
+    alice
+        .send(json!({ "type": "GetUsers", "ids": [carol_id, "no-such-user", bob_id] }))
+        .await;
+    let response = alice.expect("Users").await;
+    let mut users: Vec<(&str, bool)> = response["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|u| (u["username"].as_str().unwrap(), u["online"].as_bool().unwrap()))
+        .collect();
+    users.sort();
+    assert_eq!(users, [("bob", true), ("carol", false)]);
+
+    // Once bob keeps a contact list without alice, his presence is hidden from her
+    bob.send(json!({ "type": "AddContact", "user_id": carol_id })).await;
+    bob.expect("Contacts").await;
+    alice.send(json!({ "type": "GetUsers", "ids": [bob_id] })).await;
+    let response = alice.expect("Users").await;
+    assert_eq!(response["users"][0]["username"], "bob");
+    assert_eq!(response["users"][0]["online"], false);
+}
+
+#[tokio::test]
+async fn looking_up_users_requires_login() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+
+    let mut anonymous = server.connect().await;
+    anonymous.send(json!({ "type": "GetUsers", "ids": [alice_id] })).await;
+    assert_eq!(anonymous.expect("Error").await["message"], "Not authenticated");
+}
+
+#[tokio::test]
+async fn online_user_lists_are_scoped_to_contacts() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    let carol_id = carol.register("carol").await;
+
+    alice.send(json!({ "type": "AddContact", "user_id": bob_id })).await;
+    alice.expect("Contacts").await;
+
+    alice.send(json!({ "type": "GetOnlineUsers" })).await;
+    let online = alice.expect("OnlineUsers").await;
+    let ids: Vec<&str> = online["users"].as_array().unwrap().iter().map(|u| u["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, [bob_id.as_str()]);
+
+    alice
+        .send(json!({ "type": "GetOnlineUsersSince", "known_ids": [carol_id] }))
+        .await;
+    let delta = alice.expect("OnlineUsersDelta").await;
+    assert_eq!(delta["added"].as_array().unwrap().len(), 1);
+    assert_eq!(delta["added"][0]["id"], bob_id);
+    assert_eq!(delta["removed"], json!([carol_id]));
+
+    // Without contacts, carol still sees the others without any, but not alice
+    carol.send(json!({ "type": "GetOnlineUsers" })).await;
+    let online = carol.expect("OnlineUsers").await;
+    let ids: Vec<&str> = online["users"].as_array().unwrap().iter().map(|u| u["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, [bob_id.as_str()]);
+}
+
+#[tokio::test]
+async fn new_accounts_only_see_users_without_contact_lists() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    alice.send(json!({ "type": "AddContact", "user_id": bob_id })).await;
+    alice.expect("Contacts").await;
+
+    for login in [
+        json!({ "type": "Register", "username": "dave", "password": "secret" }),
+        json!({ "type": "Login", "username": "erin" }),
+    ] {
+        let mut newcomer = server.connect().await;
+        newcomer.send(login).await;
+        let success = newcomer.recv().await;
+        assert!(success["type"] == "RegisterSuccess" || success["type"] == "LoginSuccess", "unexpected message: {}", success);
+        let online = newcomer.expect("OnlineUsers").await;
+        let ids: Vec<&str> = online["users"].as_array().unwrap().iter().map(|u| u["id"].as_str().unwrap()).collect();
+        assert!(ids.contains(&bob_id.as_str()));
+        assert!(!ids.contains(&alice_id.as_str()), "alice leaked to a stranger: {}", online);
+    }
+}
+
+#[tokio::test]
+async fn removed_contacts_drop_out_of_the_online_list() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    let carol_id = carol.register("carol").await;
+
+    for contact_id in [&bob_id, &carol_id] {
+        alice.send(json!({ "type": "AddContact", "user_id": contact_id })).await;
+        alice.expect("Contacts").await;
+    }
+
+    alice.send(json!({ "type": "RemoveContact", "user_id": carol_id })).await;
+    let contacts = alice.expect("Contacts").await;
+    let ids: Vec<&str> = contacts["contacts"].as_array().unwrap().iter().map(|c| c["user"]["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, [bob_id.as_str()]);
+
+    alice.send(json!({ "type": "GetOnlineUsers" })).await;
+    let online = alice.expect("OnlineUsers").await;
+    let ids: Vec<&str> = online["users"].as_array().unwrap().iter().map(|u| u["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, [bob_id.as_str()]);
+
+    // Going offline is no longer reported to alice
+    drop(carol);
+    drop(bob);
+    assert_eq!(alice.wait_for_presence("UserOffline").await["user_id"], bob_id.as_str());
+}
+
+#[tokio::test]
+async fn messages_are_numbered_per_conversation() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+    let mut carol = server.connect().await;
+    let carol_id = carol.register("carol").await;
+
+    // Both directions share one sequence, and both sides see the same number
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "one" }))
+        .await;
+    assert_eq!(alice.expect("NewMessage").await["message"]["seq"], 1);
+    assert_eq!(bob.expect("NewMessage").await["message"]["seq"], 1);
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "two" }))
+        .await;
+    assert_eq!(bob.expect("NewMessage").await["message"]["seq"], 2);
+    assert_eq!(alice.expect("NewMessage").await["message"]["seq"], 2);
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "three" }))
+        .await;
+    assert_eq!(alice.expect("NewMessage").await["message"]["seq"], 3);
+    bob.expect("NewMessage").await;
+
+    // Other conversations count on their own
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": carol_id, "content": "hi" }))
+        .await;
+    assert_eq!(alice.expect("NewMessage").await["message"]["seq"], 1);
+    carol.expect("NewMessage").await;
+
+    // Deleting messages, the latest included, leaves gaps that are never refilled
+    alice
+        .send(json!({ "type": "DeleteMyMessages", "other_user_id": bob_id }))
+        .await;
+    alice.expect("MessagesDeleted").await;
+    bob.expect("MessagesDeleted").await;
+
+    bob.send(json!({ "type": "SendMessage", "to_user_id": alice_id, "content": "still there?" }))
+        .await;
+    assert_eq!(bob.expect("NewMessage").await["message"]["seq"], 4);
+    alice.expect("NewMessage").await;
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    let seqs: Vec<i64> = history["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["seq"].as_i64().unwrap())
+        .collect();
+    assert_eq!(seqs, [2, 4]);
+}
+
+#[tokio::test]
+async fn messages_after_a_seq_are_only_the_delta() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    // Sent back to back, so some may share a timestamp
+    let mut seqs = Vec::new();
+    for content in ["one", "two", "three"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        seqs.push(alice.expect("NewMessage").await["message"]["seq"].clone());
+    }
+
+    alice
+        .send(json!({ "type": "GetMessagesAfter", "other_user_id": bob_id, "after_seq": seqs[0] }))
+        .await;
+    let delta = alice.expect("MessagesAfter").await;
+    let contents: Vec<&str> = delta["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, ["two", "three"]);
+    assert_eq!(delta["has_more"], false);
+
+    // Paged, oldest first, continuing from the last seq of each page
+    alice
+        .send(json!({ "type": "GetMessagesAfter", "other_user_id": bob_id, "after_seq": seqs[0], "limit": 1 }))
+        .await;
+    let page = alice.expect("MessagesAfter").await;
+    assert_eq!(page["messages"][0]["content"], "two");
+    assert_eq!(page["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(page["has_more"], true);
+
+    alice
+        .send(json!({ "type": "GetMessagesAfter", "other_user_id": bob_id, "after_seq": page["messages"][0]["seq"], "limit": 1 }))
+        .await;
+    let page = alice.expect("MessagesAfter").await;
+    assert_eq!(page["messages"][0]["content"], "three");
+    assert_eq!(page["has_more"], false);
+
+    // Nothing new after the latest
+    alice
+        .send(json!({ "type": "GetMessagesAfter", "other_user_id": bob_id, "after_seq": seqs[2] }))
+        .await;
+    assert_eq!(alice.expect("MessagesAfter").await["messages"], json!([]));
+}
+
+#[tokio::test]
+async fn messages_after_with_a_zero_limit_still_make_progress() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for content in ["one", "two"] {
+        alice
+            .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": content }))
+            .await;
+        alice.expect("NewMessage").await;
+    }
+
+    alice
+        .send(json!({ "type": "GetMessagesAfter", "other_user_id": bob_id, "after_seq": 0, "limit": 0 }))
+        .await;
+    let page = alice.expect("MessagesAfter").await;
+    assert_eq!(page["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(page["messages"][0]["content"], "one");
+    assert_eq!(page["has_more"], true);
+}
+
+#[tokio::test]
+async fn reaction_bursts_beyond_the_limit_are_dropped() {
+    let server = TestServer::start_with(&[("REACTION_RATE_LIMIT", "3")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "react to this" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    // Rejected requests don't count against the limit
+    bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": "" }))
+        .await;
+    assert_eq!(bob.expect("Error").await["message"], "Invalid reaction");
+    bob.send(json!({ "type": "AddReaction", "message_id": "no-such-message", "emoji": "😀" }))
+        .await;
+    assert_eq!(bob.expect("Error").await["message"], "Message not found");
+
+    for emoji in ["😀", "😃", "😄", "😁", "😆"] {
+        bob.send(json!({ "type": "AddReaction", "message_id": message_id, "emoji": emoji }))
+            .await;
+    }
+    bob.send(json!({ "type": "RemoveReaction", "message_id": message_id }))
+        .await;
+
+    // Only the first three changes were applied, and only those were broadcast
+    bob.send(json!({ "type": "GetMessage", "message_id": message_id })).await;
+    let mut broadcasts = 0;
+    let message = loop {
+        let reply = bob.recv().await;
+        match reply["type"].as_str() {
+            Some("MessageReaction") => broadcasts += 1,
+            Some("Message") => break reply,
+            _ => panic!("unexpected message: {}", reply),
+        }
+    };
+    assert_eq!(broadcasts, 3);
+    assert_eq!(message["message"]["reactions"][&bob_id], "😄");
+}
+
+#[tokio::test]
+async fn typing_bursts_beyond_the_limit_are_dropped() {
+    let server = TestServer::start_with(&[("TYPING_RATE_LIMIT", "2")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    for is_typing in [true, false, true, false, true, false] {
+        alice
+            .send(json!({ "type": "Typing", "to_user_id": bob_id, "is_typing": is_typing }))
+            .await;
+    }
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "done typing" }))
+        .await;
+
+    // The third start is over the limit; stops always go through
+    let mut forwarded = Vec::new();
+    loop {
+        let message = bob.recv().await;
+        match message["type"].as_str() {
+            Some("Typing") => forwarded.push(message["is_typing"].as_bool().unwrap()),
+            Some("NewMessage") => break,
+            _ => panic!("unexpected message: {}", message),
+        }
+    }
+    assert_eq!(forwarded, [true, false, true, false, false]);
+}
+
+#[tokio::test]
+async fn concurrent_reaction_changes_end_on_the_stored_state() {
+    let server = TestServer::start_with(&[("REACTION_RATE_LIMIT", "1000")]).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "react to this" }))
+        .await;
+    let message_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    // Both sides add and remove at once; every change is broadcast to both
+    const CHANGES: usize = 21;
+    let changes = |emoji: &str| -> Vec<Value> {
+        (0..CHANGES)
+            .map(|i| match i % 2 {
+                0 => json!({ "type": "AddReaction", "message_id": message_id, "emoji": emoji }),
+                _ => json!({ "type": "RemoveReaction", "message_id": message_id }),
+            })
+            .collect()
+    };
+    let (alice_changes, bob_changes) = (changes("👍"), changes("🎉"));
+    tokio::join!(
+        async {
+            for change in alice_changes {
+                alice.send(change).await;
+            }
+        },
+        async {
+            for change in bob_changes {
+                bob.send(change).await;
+            }
+        }
+    );
+
+    for client in [&mut alice, &mut bob] {
+        let mut last = Value::Null;
+        for _ in 0..2 * CHANGES {
+            last = client.expect("MessageReaction").await;
+        }
+        client.send(json!({ "type": "GetMessage", "message_id": message_id })).await;
+        let stored = client.expect("Message").await;
+        // Both sides' last change was an add
+        assert_eq!(stored["message"]["reactions"].as_object().unwrap().len(), 2);
+        assert_eq!(last["reactions"], stored["message"]["reactions"]);
+    }
+}
+
+#[tokio::test]
+async fn muted_conversations_still_deliver_but_flag_messages() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    bob.send(json!({ "type": "MuteConversation", "other_user_id": alice_id }))
+        .await;
+    let muted = bob.expect("ConversationMuted").await;
+    assert_eq!(muted["muted"], true);
+    assert!(muted.get("muted_until").is_none(), "{}", muted);
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "psst" }))
+        .await;
+    alice.expect("NewMessage").await;
+    // Flags are only sent when set
+    let delivered = bob.expect("NewMessage").await;
+    assert_eq!(delivered["muted"], true);
+    assert!(delivered.get("notify").is_none(), "{}", delivered);
+
+    bob.send(json!({ "type": "GetConversations" })).await;
+    assert_eq!(bob.expect("Conversations").await["conversations"][0]["muted"], true);
+    bob.send(json!({ "type": "GetMutedConversations" })).await;
+    let listed = bob.expect("MutedConversations").await;
+    assert_eq!(listed["conversations"], json!([{ "user_id": alice_id }]));
+
+    bob.send(json!({ "type": "UnmuteConversation", "other_user_id": alice_id }))
+        .await;
+    assert_eq!(bob.expect("ConversationMuted").await["muted"], false);
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "hello?" }))
+        .await;
+    alice.expect("NewMessage").await;
+    let delivered = bob.expect("NewMessage").await;
+    assert!(delivered.get("muted").is_none(), "{}", delivered);
+    assert_eq!(delivered["notify"], true);
+}
+
+#[tokio::test]
+async fn timed_mutes_lift_themselves() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+
+    let until = chrono::Utc::now() + chrono::Duration::seconds(1);
+    bob.send(json!({ "type": "MuteConversation", "other_user_id": alice_id, "until": until }))
+        .await;
+    let muted = bob.expect("ConversationMuted").await;
+    assert_eq!(muted["muted"], true);
+    assert!(muted["muted_until"].is_string(), "{}", muted);
+
+    // The sweeper says so once the mute has run out
+    let lifted = bob.expect("ConversationMuted").await;
+    assert_eq!(lifted["user_id"], alice_id.as_str());
+    assert_eq!(lifted["muted"], false);
+
+    bob.send(json!({ "type": "GetMutedConversations" })).await;
+    assert_eq!(bob.expect("MutedConversations").await["conversations"], json!([]));
+}
+
+#[tokio::test]
+async fn notification_prefs_are_stored_per_conversation() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    bob.send(json!({ "type": "SetNotificationPref", "other_user_id": alice_id, "level": "mentions" }))
+        .await;
+    let changed = bob.expect("NotificationPrefChanged").await;
+    assert_eq!(changed["user_id"], alice_id);
+    assert_eq!(changed["level"], "mentions");
+
+    bob.send(json!({ "type": "GetNotificationPrefs" })).await;
+    let prefs = bob.expect("NotificationPrefs").await;
+    assert_eq!(prefs["conversations"], json!([{ "user_id": alice_id, "level": "mentions" }]));
+
+    // Only messages that mention bob ask for a notification
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "just chatting" }))
+        .await;
+    alice.expect("NewMessage").await;
+    let plain = bob.expect("NewMessage").await;
+    assert!(plain.get("notify").is_none());
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "@bob look" }))
+        .await;
+    alice.expect("NewMessage").await;
+    let mention = bob.expect("NewMessage").await;
+    assert_eq!(mention["notify"], true);
+
+    // The conversation list carries the level
+    bob.send(json!({ "type": "GetConversations" })).await;
+    let conversations = bob.expect("Conversations").await;
+    assert_eq!(conversations["conversations"][0]["notification_level"], "mentions");
+
+    // Back to the default
+    bob.send(json!({ "type": "SetNotificationPref", "other_user_id": alice_id, "level": "all" }))
+        .await;
+    bob.expect("NotificationPrefChanged").await;
+    bob.send(json!({ "type": "GetNotificationPrefs" })).await;
+    assert_eq!(bob.expect("NotificationPrefs").await["conversations"], json!([]));
+}
+
+#[tokio::test]
+async fn quoted_text_survives_deleting_the_original() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect().await;
+    let alice_id = alice.register("alice").await;
+    let mut bob = server.connect().await;
+    let bob_id = bob.register("bob").await;
+
+    alice
+        .send(json!({ "type": "SendMessage", "to_user_id": bob_id, "content": "the quick brown fox" }))
+        .await;
+    let original_id = alice.expect("NewMessage").await["message"]["id"].clone();
+    bob.expect("NewMessage").await;
+
+    // Only text that is actually in the original can be quoted
+    bob.send(json!({
+        "type": "SendMessage",
+        "to_user_id": alice_id,
+        "content": "what fox?",
+        "quoted_message_id": original_id,
+        "quoted_text": "lazy dog",
+    }))
+    .await;
+    bob.expect("Error").await;
+
+    bob.send(json!({
+        "type": "SendMessage",
+        "to_user_id": alice_id,
+        "content": "which one?",
+        "quoted_message_id": original_id,
+        "quoted_text": "quick brown",
+    }))
+    .await;
+    let reply = bob.expect("NewMessage").await;
+    assert_eq!(reply["message"]["quoted_message_id"], original_id);
+    assert_eq!(reply["message"]["quoted_text"], "quick brown");
+    assert_eq!(alice.expect("NewMessage").await["message"]["quoted_text"], "quick brown");
+
+    alice
+        .send(json!({ "type": "DeleteMyMessages", "other_user_id": bob_id }))
+        .await;
+    alice.expect("MessagesDeleted").await;
+    bob.expect("MessagesDeleted").await;
+
+    bob.send(json!({ "type": "GetMessageHistory", "other_user_id": alice_id }))
+        .await;
+    let history = bob.expect("MessageHistory").await;
+    let messages = history["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["content"], "which one?");
+    assert_eq!(messages[0]["quoted_message_id"], original_id);
+    assert_eq!(messages[0]["quoted_text"], "quick brown");
+}
@@ -107,7 +107,12 @@ function App() {
         console.log('User offline:', message.user_id);
         setOnlineUsers(prev => prev.filter(u => u.id !== message.user_id));
         break;
-      
+
+      case 'UserUpdated':
+        // Profile change (display name, avatar, ...): merge into the cached entry
+        setOnlineUsers(prev => prev.map(u => u.id === message.user.id ? { ...u, ...message.user } : u));
+        break;
+
       case 'OnlineUsers':
         console.log('Online users list received:', message.users);
         setOnlineUsers(prev => {
@@ -155,6 +160,7 @@ function App() {
             [key]: {
               totalCount: message.total_count,
               hasMore: message.has_more,
+              nextOffset: message.next_offset,
               loadedCount: (prev[key]?.loadedCount || 0) + message.messages.length
             }
           }));
@@ -194,8 +200,9 @@ function App() {
           const sender = onlineUsersRef.current.find(u => u.id === fromUserId);
           const senderName = sender?.username || 'Someone';
           
-          // Show browser notification
-          if ('Notification' in window && Notification.permission === 'granted') {
+          // Show browser notification when the server says this message notifies
+          // (not muted, and allowed by the conversation's notification level)
+          if (message.notify && 'Notification' in window && Notification.permission === 'granted') {
             const notification = new Notification(`New message from ${senderName}`, {
               body: message.message.content || 'Sent a file',
               icon: '/favicon.ico',
@@ -278,13 +285,14 @@ function App() {
           Object.keys(updated).forEach(key => {
             updated[key] = updated[key].map(msg => {
               if (msg.id === message.message_id) {
-                const reactions = { ...(msg.reactions || {}) };
-                if (message.emoji) {
-                  reactions[message.user_id] = message.emoji;
-                } else {
-                  delete reactions[message.user_id];
-                }
-                return { ...msg, reactions };
+                // The server sends the stored set, so fast toggles can't drift
+                const reactions = { ...(message.reactions || {}) };
+                // Keep the server-computed counts in step with live changes
+                const reaction_summary = {};
+                Object.values(reactions).forEach(emoji => {
+                  reaction_summary[emoji] = (reaction_summary[emoji] || 0) + 1;
+                });
+                return { ...msg, reactions, reaction_summary };
               }
               return msg;
             });
@@ -297,6 +305,19 @@ function App() {
         console.log('Call ended by:', message.from_user_id);
         handleEndCall();
         break;
+
+      case 'CallRejected':
+        console.log('Call declined by:', message.from_user_id);
+        setCurrentCall(null);
+        setCallState(null);
+        break;
+
+      case 'CallTimeout':
+        console.log('Call not answered:', message.call_id);
+        setIncomingCall(null);
+        setCurrentCall(null);
+        setCallState(null);
+        break;
       
       default:
         // Only log unknown messages if they're not dev server messages
@@ -316,7 +337,7 @@ function App() {
       const wsHost = window.location.hostname;
       const wsUrl = `${wsProtocol}//${wsHost}:3002/ws`;
       console.log('WebSocket URL:', wsUrl);
-      const websocket = new WebSocket(wsUrl);
+      const websocket = new WebSocket(wsUrl, ['chat.v1']);
       let isConnected = false;
       
       websocket.onopen = () => {
@@ -435,7 +456,7 @@ function App() {
       
       if (!meta || meta.hasMore) {
         setLoadingHistory(true);
-        const offset = messages[key]?.length || 0;
+        const offset = meta?.nextOffset ?? (messages[key]?.length || 0);
         
         ws.send(JSON.stringify({
           type: 'GetMessageHistory',
@@ -465,7 +486,7 @@ function App() {
   const handleRejectCall = () => {
     if (incomingCall && ws) {
       ws.send(JSON.stringify({
-        type: 'CallEnd',
+        type: 'CallReject',
         to_user_id: incomingCall.caller.id
       }));
       setIncomingCall(null);
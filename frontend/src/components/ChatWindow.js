@@ -200,7 +200,7 @@ function ChatWindow({
         {isImage ? (
           <div className="message-image-container">
             <img 
-              src={message.file_data} 
+              src={message.thumbnail_data || message.file_data} 
               alt={message.file_name}
               className="message-image"
               onClick={() => window.open(message.file_data, '_blank')}
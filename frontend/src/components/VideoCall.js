@@ -255,7 +255,8 @@ function VideoCall({
         ws.send(JSON.stringify({
           type: 'CallAnswer',
           to_user_id: otherUser.id,
-          answer: JSON.stringify(answer)
+          answer: JSON.stringify(answer),
+          call_type: 'video'
         }));
       } else {
         // If caller, create offer
@@ -269,7 +270,8 @@ function VideoCall({
         ws.send(JSON.stringify({
           type: 'CallOffer',
           to_user_id: otherUser.id,
-          offer: JSON.stringify(offer)
+          offer: JSON.stringify(offer),
+          call_type: 'video'
         }));
       }
     } catch (error) {